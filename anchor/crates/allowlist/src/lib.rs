@@ -0,0 +1,266 @@
+//! Off-chain builder for deal allowlists.
+//!
+//! Reads `wallet,allowance` rows, builds the merkle tree the program checks in
+//! `mint_coupon`, and hands out the root for `create_deal` plus a proof per
+//! wallet. Hashing goes through the program's own helpers so the two sides
+//! can't drift apart.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+
+use anchor_lang::prelude::Pubkey;
+use basic::{allowlist_leaf, allowlist_node};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub wallet: Pubkey,
+    pub allowance: u64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AllowlistError {
+    Empty,
+    InvalidWallet { line: usize, value: String },
+    InvalidAllowance { line: usize, value: String },
+    DuplicateWallet { line: usize, wallet: Pubkey },
+}
+
+impl fmt::Display for AllowlistError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AllowlistError::Empty => write!(f, "allowlist has no wallets"),
+            AllowlistError::InvalidWallet { line, value } => {
+                write!(f, "line {line}: invalid wallet address {value:?}")
+            }
+            AllowlistError::InvalidAllowance { line, value } => {
+                write!(f, "line {line}: allowance must be a positive integer, got {value:?}")
+            }
+            AllowlistError::DuplicateWallet { line, wallet } => {
+                write!(f, "line {line}: wallet {wallet} is listed more than once")
+            }
+        }
+    }
+}
+
+impl std::error::Error for AllowlistError {}
+
+/// Parses a CSV of `wallet[,allowance]` rows. A missing allowance means one
+/// coupon; blank lines, `#` comments and a leading `wallet,...` header are
+/// skipped.
+pub fn parse_csv(input: &str) -> Result<Vec<Entry>, AllowlistError> {
+    let mut entries = Vec::new();
+    let mut seen = HashSet::new();
+
+    for (index, raw) in input.lines().enumerate() {
+        let line = index + 1;
+        let row = raw.trim();
+        if row.is_empty() || row.starts_with('#') {
+            continue;
+        }
+
+        let mut columns = row.split(',').map(str::trim);
+        let wallet = columns.next().unwrap_or_default();
+        if entries.is_empty() && wallet.eq_ignore_ascii_case("wallet") {
+            continue;
+        }
+        let wallet = Pubkey::from_str(wallet).map_err(|_| AllowlistError::InvalidWallet {
+            line,
+            value: wallet.to_string(),
+        })?;
+
+        let allowance = match columns.next() {
+            None | Some("") => 1,
+            Some(value) => value
+                .parse::<u64>()
+                .ok()
+                .filter(|allowance| *allowance > 0)
+                .ok_or_else(|| AllowlistError::InvalidAllowance {
+                    line,
+                    value: value.to_string(),
+                })?,
+        };
+
+        if !seen.insert(wallet) {
+            return Err(AllowlistError::DuplicateWallet { line, wallet });
+        }
+        entries.push(Entry { wallet, allowance });
+    }
+
+    if entries.is_empty() {
+        return Err(AllowlistError::Empty);
+    }
+    Ok(entries)
+}
+
+/// Every level of the tree, leaves first. An odd node out is carried up to the
+/// next level as-is, so it simply contributes no sibling to its proofs.
+pub struct Allowlist {
+    entries: Vec<Entry>,
+    levels: Vec<Vec<[u8; 32]>>,
+}
+
+impl Allowlist {
+    pub fn new(entries: Vec<Entry>) -> Result<Self, AllowlistError> {
+        if entries.is_empty() {
+            return Err(AllowlistError::Empty);
+        }
+
+        let leaves: Vec<[u8; 32]> = entries
+            .iter()
+            .map(|entry| allowlist_leaf(&entry.wallet, entry.allowance))
+            .collect();
+        let mut levels = vec![leaves];
+        while levels.last().map_or(0, Vec::len) > 1 {
+            let next = levels
+                .last()
+                .unwrap()
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => allowlist_node(left, right),
+                    [single] => *single,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(next);
+        }
+
+        Ok(Self { entries, levels })
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// The value to pass as `allowlist_root` when creating the deal.
+    pub fn root(&self) -> [u8; 32] {
+        self.levels.last().unwrap()[0]
+    }
+
+    /// Sibling hashes from the wallet's leaf up to the root, or `None` if the
+    /// wallet isn't on the list.
+    pub fn proof(&self, wallet: &Pubkey) -> Option<Vec<[u8; 32]>> {
+        let mut index = self.entries.iter().position(|entry| entry.wallet == *wallet)?;
+        let mut proof = Vec::new();
+        for level in &self.levels[..self.levels.len() - 1] {
+            if let Some(sibling) = level.get(index ^ 1) {
+                proof.push(*sibling);
+            }
+            index /= 2;
+        }
+        Some(proof)
+    }
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use basic::verify_allowlist_proof;
+
+    fn entries(count: u64) -> Vec<Entry> {
+        (1..=count)
+            .map(|allowance| Entry {
+                wallet: Pubkey::new_unique(),
+                allowance,
+            })
+            .collect()
+    }
+
+    fn assert_proofs_verify(count: u64) {
+        let allowlist = Allowlist::new(entries(count)).unwrap();
+        let root = allowlist.root();
+        for entry in allowlist.entries() {
+            let proof = allowlist.proof(&entry.wallet).unwrap();
+            let leaf = allowlist_leaf(&entry.wallet, entry.allowance);
+            assert!(verify_allowlist_proof(&root, leaf, &proof), "{count} leaves");
+        }
+    }
+
+    #[test]
+    fn single_leaf_is_its_own_root() {
+        let allowlist = Allowlist::new(entries(1)).unwrap();
+        let entry = &allowlist.entries()[0];
+        let leaf = allowlist_leaf(&entry.wallet, entry.allowance);
+
+        assert_eq!(allowlist.root(), leaf);
+        assert_eq!(allowlist.proof(&entry.wallet), Some(Vec::new()));
+        assert!(verify_allowlist_proof(&allowlist.root(), leaf, &[]));
+    }
+
+    #[test]
+    fn proofs_verify_on_chain_for_even_and_odd_counts() {
+        for count in [2, 3, 4, 5, 7] {
+            assert_proofs_verify(count);
+        }
+    }
+
+    #[test]
+    fn proof_fails_for_a_different_allowance_or_wallet() {
+        let allowlist = Allowlist::new(entries(5)).unwrap();
+        let entry = &allowlist.entries()[4];
+        let proof = allowlist.proof(&entry.wallet).unwrap();
+
+        let inflated = allowlist_leaf(&entry.wallet, entry.allowance + 1);
+        assert!(!verify_allowlist_proof(&allowlist.root(), inflated, &proof));
+        assert_eq!(allowlist.proof(&Pubkey::new_unique()), None);
+    }
+
+    #[test]
+    fn parses_header_comments_and_default_allowance() {
+        let first = Pubkey::new_unique();
+        let second = Pubkey::new_unique();
+        let csv = format!("wallet,allowance\n# team wallets\n{first},3\n\n{second}\n");
+
+        let parsed = parse_csv(&csv).unwrap();
+        assert_eq!(
+            parsed,
+            vec![
+                Entry {
+                    wallet: first,
+                    allowance: 3,
+                },
+                Entry {
+                    wallet: second,
+                    allowance: 1,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_duplicate_wallets() {
+        let wallet = Pubkey::new_unique();
+        let csv = format!("{wallet},2\n{wallet},1\n");
+
+        assert_eq!(
+            parse_csv(&csv),
+            Err(AllowlistError::DuplicateWallet { line: 2, wallet })
+        );
+    }
+
+    #[test]
+    fn rejects_bad_rows_and_empty_input() {
+        assert_eq!(
+            parse_csv("not-a-wallet,1"),
+            Err(AllowlistError::InvalidWallet {
+                line: 1,
+                value: "not-a-wallet".to_string(),
+            })
+        );
+
+        let wallet = Pubkey::new_unique();
+        assert_eq!(
+            parse_csv(&format!("{wallet},0")),
+            Err(AllowlistError::InvalidAllowance {
+                line: 1,
+                value: "0".to_string(),
+            })
+        );
+
+        assert_eq!(parse_csv("wallet,allowance\n# nobody yet\n"), Err(AllowlistError::Empty));
+    }
+}
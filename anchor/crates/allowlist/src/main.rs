@@ -0,0 +1,52 @@
+//! Usage: `cargo run -p allowlist -- wallets.csv > allowlist.json`
+//!
+//! Prints the root (hex and as a byte array for `create_deal`) and, for every
+//! wallet, the allowance and proof to pass to `mint_coupon`.
+
+use std::process::ExitCode;
+
+use allowlist::{parse_csv, to_hex, Allowlist};
+use serde_json::{json, Map, Value};
+
+fn run() -> Result<Value, Box<dyn std::error::Error>> {
+    let path = std::env::args()
+        .nth(1)
+        .ok_or("usage: allowlist <wallets.csv>")?;
+    let csv = std::fs::read_to_string(&path).map_err(|err| format!("{path}: {err}"))?;
+    let allowlist = Allowlist::new(parse_csv(&csv)?)?;
+
+    let mut claims = Map::new();
+    for entry in allowlist.entries() {
+        let proof = allowlist
+            .proof(&entry.wallet)
+            .expect("every entry has a proof");
+        claims.insert(
+            entry.wallet.to_string(),
+            json!({
+                "allowance": entry.allowance,
+                "proof": proof.iter().map(|node| to_hex(node)).collect::<Vec<_>>(),
+            }),
+        );
+    }
+
+    let root = allowlist.root();
+    Ok(json!({
+        "root": to_hex(&root),
+        "rootBytes": root.to_vec(),
+        "wallets": allowlist.entries().len(),
+        "claims": claims,
+    }))
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(output) => {
+            println!("{}", serde_json::to_string_pretty(&output).unwrap());
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
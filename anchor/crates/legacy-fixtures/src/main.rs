@@ -0,0 +1,256 @@
+//! Usage: `cargo run -p legacy-fixtures -- tests/fixtures`
+//!
+//! Writes accounts in the layouts the program used before their migrations, as
+//! `solana-test-validator` account files, and prints the `Anchor.toml` entries
+//! that load them. Wallets are derived from fixed labels, so the tests can sign
+//! as them with `Keypair.fromSeed(sha256(label))`.
+
+use std::path::Path;
+use std::process::ExitCode;
+
+use anchor_lang::prelude::{AnchorSerialize, Pubkey, Rent};
+use anchor_lang::{Discriminator, Space};
+use anchor_spl::metadata::mpl_token_metadata::accounts::Metadata;
+use anchor_spl::metadata::mpl_token_metadata::types::Key;
+use anchor_spl::metadata::mpl_token_metadata::{MAX_NAME_LENGTH, MAX_SYMBOL_LENGTH, MAX_URI_LENGTH};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use basic::{
+    Coupon, Deal, LegacyCoupon, LegacyDeal, LegacyListing, LegacyRewardsPool, LegacyStakedCoupon, Listing,
+    RewardsPool, StakedCoupon,
+};
+use curve25519_dalek::EdwardsPoint;
+use serde_json::json;
+use sha2::{Digest, Sha256, Sha512};
+
+const MERCHANT: &str = "legacy-merchant";
+const HOLDER: &str = "legacy-holder";
+const SELLER: &str = "legacy-seller";
+const DEAL_TITLE: &str = "Legacy Coffee Deal";
+const MINTED_AT: i64 = 1_700_000_000;
+
+// Metaplex's MAX_METADATA_LEN, which every metadata account was created with
+const METADATA_SPACE: usize = 679;
+
+struct Fixture {
+    name: String,
+    address: Pubkey,
+    owner: Pubkey,
+    data: Vec<u8>,
+}
+
+// Public key of the ed25519 keypair seeded with sha256(label)
+fn wallet(label: &str) -> Pubkey {
+    let seed = Sha256::digest(label.as_bytes());
+    let expanded = Sha512::digest(seed);
+    let scalar: [u8; 32] = expanded[..32].try_into().expect("sha512 is 64 bytes");
+    Pubkey::new_from_array(EdwardsPoint::mul_base_clamped(scalar).compress().to_bytes())
+}
+
+// Discriminator and fields, zero-padded to the space the account was created with
+fn account_data(discriminator: &[u8], fields: &impl AnchorSerialize, space: usize) -> Vec<u8> {
+    let mut data = discriminator.to_vec();
+    fields.serialize(&mut data).expect("writing to a Vec can't fail");
+    assert!(data.len() <= space, "fields overflow {space} bytes");
+    data.resize(space, 0);
+    data
+}
+
+// Metaplex stores names, symbols and uris NUL-padded to their maximum length
+fn padded(value: &str, len: usize) -> String {
+    format!("{value:\0<len$}")
+}
+
+// The deal's coupon at `index`, and the metadata of its mint, which the tests
+// create from the `legacy-coupon-{index}` keypair
+fn coupon_fixtures(deal: Pubkey, index: u64, owner: Pubkey) -> [Fixture; 2] {
+    let mint = wallet(&format!("legacy-coupon-{index}"));
+    let (coupon, coupon_bump) =
+        Pubkey::find_program_address(&[b"coupon", deal.as_ref(), &index.to_le_bytes()], &basic::ID);
+    let legacy_coupon = LegacyCoupon {
+        deal,
+        owner,
+        mint,
+        is_redeemed: false,
+        minted_at: MINTED_AT,
+        redeemed_at: None,
+        bump: coupon_bump,
+    };
+
+    // Legacy coupons left the mint as the update authority
+    let metadata_program = anchor_spl::metadata::ID;
+    let (metadata, _) =
+        Pubkey::find_program_address(&[b"metadata", metadata_program.as_ref(), mint.as_ref()], &metadata_program);
+    let legacy_metadata = Metadata {
+        key: Key::MetadataV1,
+        update_authority: mint,
+        mint,
+        name: padded(&format!("{DEAL_TITLE} - Coupon #{}", index + 1), MAX_NAME_LENGTH),
+        symbol: padded("DEAL", MAX_SYMBOL_LENGTH),
+        uri: padded(&format!("ipfs://legacy-coupon-{index}"), MAX_URI_LENGTH),
+        seller_fee_basis_points: 0,
+        creators: None,
+        primary_sale_happened: false,
+        is_mutable: true,
+        edition_nonce: None,
+        token_standard: None,
+        collection: None,
+        uses: None,
+        collection_details: None,
+        programmable_config: None,
+    };
+
+    [
+        Fixture {
+            name: format!("legacy-coupon-{index}"),
+            address: coupon,
+            owner: basic::ID,
+            data: account_data(Coupon::DISCRIMINATOR, &legacy_coupon, 8 + LegacyCoupon::INIT_SPACE),
+        },
+        Fixture {
+            name: format!("legacy-coupon-{index}-metadata"),
+            address: metadata,
+            owner: metadata_program,
+            data: account_data(&[], &legacy_metadata, METADATA_SPACE),
+        },
+    ]
+}
+
+// A marketplace listing of the coupon, which legacy listings never escrowed
+fn listing_fixture(coupon: &Fixture, seller: Pubkey, is_active: bool) -> Fixture {
+    let (listing, listing_bump) = Pubkey::find_program_address(&[b"listing", coupon.address.as_ref()], &basic::ID);
+    let legacy_listing = LegacyListing {
+        coupon: coupon.address,
+        seller,
+        price_lamports: 15_000_000,
+        is_active,
+        created_at: MINTED_AT + 7_200,
+        bump: listing_bump,
+    };
+    Fixture {
+        name: format!("{}-listing", coupon.name),
+        address: listing,
+        owner: basic::ID,
+        data: account_data(Listing::DISCRIMINATOR, &legacy_listing, 8 + LegacyListing::INIT_SPACE),
+    }
+}
+
+fn fixtures() -> Vec<Fixture> {
+    let merchant = wallet(MERCHANT);
+    let (deal, deal_bump) =
+        Pubkey::find_program_address(&[b"deal", merchant.as_ref(), DEAL_TITLE.as_bytes()], &basic::ID);
+
+    // Four coupons sold at 0.01 SOL, with no ratings yet
+    let legacy_deal = LegacyDeal {
+        merchant,
+        title: DEAL_TITLE.to_string(),
+        description: "Created before deals had ids".to_string(),
+        discount_percent: 25,
+        max_supply: 10,
+        current_supply: 4,
+        expiry_timestamp: 4_102_444_800,
+        category: "Food".to_string(),
+        price_lamports: 10_000_000,
+        is_active: true,
+        total_ratings: 0,
+        rating_sum: 0,
+        bump: deal_bump,
+    };
+
+    let mut fixtures = vec![Fixture {
+        name: "legacy-deal".to_string(),
+        address: deal,
+        owner: basic::ID,
+        data: account_data(Deal::DISCRIMINATOR, &legacy_deal, 8 + LegacyDeal::INIT_SPACE),
+    }];
+
+    // #0 is a plain unredeemed coupon
+    let holder = wallet(HOLDER);
+    fixtures.extend(coupon_fixtures(deal, 0, holder));
+
+    // #1 is staked in a pool the merchant administers
+    let [staked, metadata] = coupon_fixtures(deal, 1, holder);
+    let (staked_coupon, staked_bump) =
+        Pubkey::find_program_address(&[b"staked_coupon", staked.address.as_ref()], &basic::ID);
+    let legacy_stake = LegacyStakedCoupon {
+        coupon: staked.address,
+        staker: holder,
+        staked_at: MINTED_AT + 3_600,
+        last_claim_at: MINTED_AT + 3_600,
+        bump: staked_bump,
+    };
+    fixtures.push(Fixture {
+        name: "legacy-staked-coupon-1".to_string(),
+        address: staked_coupon,
+        owner: basic::ID,
+        data: account_data(StakedCoupon::DISCRIMINATOR, &legacy_stake, 8 + LegacyStakedCoupon::INIT_SPACE),
+    });
+    fixtures.extend([staked, metadata]);
+
+    // #2 is still listed by its holder
+    let [listed, metadata] = coupon_fixtures(deal, 2, holder);
+    fixtures.push(listing_fixture(&listed, holder, true));
+    fixtures.extend([listed, metadata]);
+
+    // #3 was bought from another wallet, and the sale left its listing open
+    let [sold, metadata] = coupon_fixtures(deal, 3, holder);
+    fixtures.push(listing_fixture(&sold, wallet(SELLER), false));
+    fixtures.extend([sold, metadata]);
+
+    let (rewards_pool, pool_bump) = Pubkey::find_program_address(&[b"rewards_pool"], &basic::ID);
+    let legacy_pool = LegacyRewardsPool {
+        total_staked: 1,
+        reward_rate_per_day: 100_000,
+        admin: merchant,
+        bump: pool_bump,
+    };
+    fixtures.push(Fixture {
+        name: "legacy-rewards-pool".to_string(),
+        address: rewards_pool,
+        owner: basic::ID,
+        data: account_data(RewardsPool::DISCRIMINATOR, &legacy_pool, 8 + LegacyRewardsPool::INIT_SPACE),
+    });
+
+    fixtures
+}
+
+fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = std::env::args()
+        .nth(1)
+        .ok_or("usage: legacy-fixtures <output dir>")?;
+    std::fs::create_dir_all(&dir).map_err(|err| format!("{dir}: {err}"))?;
+
+    let rent = Rent::default();
+    for fixture in fixtures() {
+        let path = Path::new(&dir).join(format!("{}.json", fixture.name));
+        let account = json!({
+            "pubkey": fixture.address.to_string(),
+            "account": {
+                "lamports": rent.minimum_balance(fixture.data.len()),
+                "data": [STANDARD.encode(&fixture.data), "base64"],
+                "owner": fixture.owner.to_string(),
+                "executable": false,
+                "rentEpoch": 0,
+                "space": fixture.data.len(),
+            },
+        });
+        std::fs::write(&path, serde_json::to_string_pretty(&account)? + "\n")
+            .map_err(|err| format!("{}: {err}", path.display()))?;
+
+        println!("[[test.validator.account]]");
+        println!("address = \"{}\"", fixture.address);
+        println!("filename = \"{}\"", path.display());
+        println!();
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
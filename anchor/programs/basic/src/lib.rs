@@ -1,20 +1,134 @@
+// Anchor's generated CPI client mirrors the long instruction signatures
+#![allow(clippy::too_many_arguments)]
+
 use anchor_lang::prelude::*;
-use anchor_lang::system_program::{transfer, Transfer};
+use anchor_lang::solana_program::{hash::hashv, instruction::AccountMeta, program::invoke};
+use anchor_lang::system_program::{create_account, transfer, CreateAccount, Transfer};
 use anchor_spl::{
-    associated_token::AssociatedToken,
+    associated_token::{self, AssociatedToken},
     metadata::{
-        create_metadata_accounts_v3, mpl_token_metadata::types::DataV2,
-        CreateMetadataAccountsV3, Metadata,
+        create_master_edition_v3, create_metadata_accounts_v3,
+        mpl_token_metadata::types::{Collection, CollectionDetails, Creator, DataV2},
+        sign_metadata, update_metadata_accounts_v2, verify_sized_collection_item,
+        CreateMasterEditionV3, CreateMetadataAccountsV3, Metadata, MetadataAccount, SignMetadata,
+        UpdateMetadataAccountsV2, VerifySizedCollectionItem,
+    },
+    token::{
+        close_account, mint_to, transfer_checked, CloseAccount, Mint, MintTo, Token, TokenAccount,
+        TransferChecked,
+    },
+    token_interface::{
+        self, metadata_pointer_initialize, non_transferable_mint_initialize,
+        spl_pod::optional_keys::OptionalNonZeroPubkey,
+        spl_token_2022::{
+            self,
+            extension::{BaseStateWithExtensions, ExtensionType, StateWithExtensions},
+        },
+        spl_token_metadata_interface::state::{Field, TokenMetadata},
+        token_metadata_initialize, token_metadata_update_field, transfer_hook_initialize,
+        MetadataPointerInitialize, NonTransferableMintInitialize, Token2022, TokenInterface,
+        TokenMetadataInitialize, TokenMetadataUpdateField, TransferHookInitialize,
     },
-    token::{mint_to, Mint, MintTo, Token, TokenAccount},
 };
+use spl_transfer_hook_interface::instruction::initialize_extra_account_meta_list;
 
 declare_id!("GUudyUKazJCyL2f7dTG6Nm7EgUsro3acDtbbMWFuUrRd");
 
+// Hard ceiling on the marketplace fee, whatever the config says (10%)
+pub const MAX_PLATFORM_FEE_BPS: u16 = 1_000;
+pub const BPS_DENOMINATOR: u64 = 10_000;
+pub const MAX_RESALE_ROYALTY_BPS: u16 = 2_500;
+
+// Fixed-point scale for the staking reward-per-share accumulator
+pub const REWARD_PRECISION: u128 = 1_000_000_000_000;
+pub const SECONDS_PER_DAY: i64 = 86_400;
+
+// Upper bound on recurring redemption windows per deal
+pub const MAX_REDEMPTION_WINDOWS: usize = 8;
+pub const MINUTES_PER_DAY: u16 = 1_440;
+
+// Metaplex metadata field limits
+pub const MAX_METADATA_NAME_LEN: usize = 32;
+pub const MAX_METADATA_URI_LEN: usize = 200;
+// Prepended to a coupon's name once it has been used up
+pub const REDEEMED_PREFIX: &str = "REDEEMED ";
+
+// Token-2022 transfer hook that keeps Coupon.owner in step with the NFT
+pub const COUPON_HOOK_PROGRAM_ID: Pubkey = pubkey!("95rxFZaAjHeeh3aoTn8u4MiVV9e8HkQyURyX3Y3GyW6f");
+// The hook's signing PDA, the only caller sync_coupon_owner accepts
+pub const HOOK_AUTHORITY_SEED: &[u8] = b"hook_authority";
+
 #[program]
 pub mod basic {
     use super::*;
 
+    pub fn initialize_config(
+        ctx: Context<InitializeConfig>,
+        treasury: Pubkey,
+        fee_bps: u16,
+        max_fee_lamports: u64,
+    ) -> Result<()> {
+        require!(fee_bps <= MAX_PLATFORM_FEE_BPS, DealError::FeeTooHigh);
+
+        let config = &mut ctx.accounts.platform_config;
+        config.admin = ctx.accounts.admin.key();
+        config.treasury = treasury;
+        config.fee_bps = fee_bps;
+        config.max_fee_lamports = max_fee_lamports;
+        config.bump = ctx.bumps.platform_config;
+
+        emit_cpi!(ConfigUpdated {
+            platform_config: config.key(),
+            admin: config.admin,
+            treasury: config.treasury,
+            fee_bps: config.fee_bps,
+            max_fee_lamports: config.max_fee_lamports,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+
+        msg!("Platform config initialized: {} bps to {}", fee_bps, treasury);
+        Ok(())
+    }
+
+    pub fn update_config(
+        ctx: Context<UpdateConfig>,
+        new_admin: Option<Pubkey>,
+        treasury: Option<Pubkey>,
+        fee_bps: Option<u16>,
+        max_fee_lamports: Option<u64>,
+    ) -> Result<()> {
+        let config = &mut ctx.accounts.platform_config;
+
+        if let Some(admin) = new_admin {
+            config.admin = admin;
+        }
+
+        if let Some(treasury) = treasury {
+            config.treasury = treasury;
+        }
+
+        if let Some(fee) = fee_bps {
+            require!(fee <= MAX_PLATFORM_FEE_BPS, DealError::FeeTooHigh);
+            config.fee_bps = fee;
+        }
+
+        if let Some(cap) = max_fee_lamports {
+            config.max_fee_lamports = cap;
+        }
+
+        emit_cpi!(ConfigUpdated {
+            platform_config: config.key(),
+            admin: config.admin,
+            treasury: config.treasury,
+            fee_bps: config.fee_bps,
+            max_fee_lamports: config.max_fee_lamports,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+
+        msg!("Platform config updated: {} bps to {}", config.fee_bps, config.treasury);
+        Ok(())
+    }
+
     pub fn create_deal(
         ctx: Context<CreateDeal>,
         title: String,
@@ -24,18 +138,70 @@ pub mod basic {
         expiry_timestamp: i64,
         category: String,
         price_lamports: u64,
+        escrow_payments: bool,
+        max_per_wallet: u64,
+        allowlist_root: Option<[u8; 32]>,
+        token_gate: Option<TokenGate>,
+        start_timestamp: i64,
+        redemption_windows: Vec<RedemptionWindow>,
+        uses_per_coupon: u64,
+        kind: CouponKind,
+        collection_uri: String,
+        resale_royalty_bps: u16,
+        redemption_action: RedemptionAction,
+        transferable: bool,
     ) -> Result<()> {
+        require!(title.len() <= 100, DealError::TitleTooLong);
+        require!(
+            collection_uri.len() <= MAX_METADATA_URI_LEN,
+            DealError::MetadataUriTooLong
+        );
+        require!(description.len() <= 500, DealError::DescriptionTooLong);
+        require!(category.len() <= 50, DealError::CategoryTooLong);
         require!(discount_percent <= 100, DealError::InvalidDiscount);
         require!(max_supply > 0, DealError::InvalidSupply);
-        require!(expiry_timestamp > Clock::get()?.unix_timestamp, DealError::InvalidExpiry);
+        require!(
+            resale_royalty_bps <= MAX_RESALE_ROYALTY_BPS,
+            DealError::RoyaltyTooHigh
+        );
+        require!(uses_per_coupon > 0, DealError::InvalidUsesPerCoupon);
+        if let CouponKind::StoredValue { face_value } = kind {
+            // Gift cards are spent by amount, not by count
+            require!(face_value > 0 && uses_per_coupon == 1, DealError::InvalidCouponKind);
+            // and redeem_amount always burns a spent card
+            require!(
+                redemption_action == RedemptionAction::Burn,
+                DealError::InvalidCouponKind
+            );
+        }
+        let now = Clock::get()?.unix_timestamp;
+        require!(expiry_timestamp > now, DealError::InvalidExpiry);
+        require!(start_timestamp < expiry_timestamp, DealError::InvalidStartTime);
+        require!(
+            redemption_windows.len() <= MAX_REDEMPTION_WINDOWS,
+            DealError::TooManyRedemptionWindows
+        );
+        require!(
+            redemption_windows.iter().all(RedemptionWindow::is_valid),
+            DealError::InvalidRedemptionWindow
+        );
+        if let Some(TokenGate::TokenBalance { min_amount, .. }) = token_gate {
+            require!(min_amount > 0, DealError::InvalidTokenGate);
+        }
+
+        let merchant_state = &mut ctx.accounts.merchant_state;
+        merchant_state.merchant = ctx.accounts.merchant.key();
+        merchant_state.bump = ctx.bumps.merchant_state;
 
         let deal = &mut ctx.accounts.deal;
         deal.merchant = ctx.accounts.merchant.key();
+        deal.deal_id = merchant_state.deal_count;
         deal.title = title;
         deal.description = description;
         deal.discount_percent = discount_percent;
         deal.max_supply = max_supply;
         deal.current_supply = 0;
+        deal.redeemed_count = 0;
         deal.expiry_timestamp = expiry_timestamp;
         deal.category = category;
         deal.price_lamports = price_lamports;
@@ -43,8 +209,139 @@ pub mod basic {
         deal.total_ratings = 0;
         deal.rating_sum = 0;
         deal.bump = ctx.bumps.deal;
+        deal.payment_mint = ctx.accounts.payment_mint.as_ref().map(|mint| mint.key());
+        deal.escrow_payments = escrow_payments;
+        deal.refunded_count = 0;
+        deal.is_cancelled = false;
+        deal.max_per_wallet = max_per_wallet;
+        deal.allowlist_root = allowlist_root;
+        deal.token_gate = token_gate;
+        deal.start_timestamp = start_timestamp;
+        deal.redemption_windows = redemption_windows;
+        deal.uses_per_coupon = uses_per_coupon;
+        deal.kind = kind;
+        deal.collection_mint = Some(ctx.accounts.collection_mint.key());
+        deal.resale_royalty_bps = resale_royalty_bps;
+        deal.redemption_action = redemption_action;
+        deal.transferable = transferable;
+        deal.outstanding_payments = 0;
+
+        // Every deal gets its own sized collection NFT, owned and signed for by
+        // the deal PDA, so mint_coupon can verify each coupon into it
+        let merchant_key = deal.merchant;
+        let deal_id_bytes = deal.deal_id.to_le_bytes();
+        let deal_bump = [deal.bump];
+        let deal_seeds: &[&[&[u8]]] = &[&[b"deal", merchant_key.as_ref(), &deal_id_bytes, &deal_bump]];
+
+        mint_to(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                MintTo {
+                    mint: ctx.accounts.collection_mint.to_account_info(),
+                    to: ctx.accounts.collection_token_account.to_account_info(),
+                    authority: deal.to_account_info(),
+                },
+                deal_seeds,
+            ),
+            1,
+        )?;
+
+        create_metadata_accounts_v3(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_metadata_program.to_account_info(),
+                CreateMetadataAccountsV3 {
+                    metadata: ctx.accounts.collection_metadata.to_account_info(),
+                    mint: ctx.accounts.collection_mint.to_account_info(),
+                    mint_authority: deal.to_account_info(),
+                    update_authority: deal.to_account_info(),
+                    payer: ctx.accounts.merchant.to_account_info(),
+                    system_program: ctx.accounts.system_program.to_account_info(),
+                    rent: ctx.accounts.rent.to_account_info(),
+                },
+                deal_seeds,
+            ),
+            DataV2 {
+                name: metadata_name(&deal.title),
+                symbol: "DEAL".to_string(),
+                uri: collection_uri,
+                seller_fee_basis_points: 0,
+                creators: None,
+                collection: None,
+                uses: None,
+            },
+            true,
+            true,
+            Some(CollectionDetails::V1 { size: 0 }),
+        )?;
+
+        create_master_edition_v3(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_metadata_program.to_account_info(),
+                CreateMasterEditionV3 {
+                    edition: ctx.accounts.collection_master_edition.to_account_info(),
+                    mint: ctx.accounts.collection_mint.to_account_info(),
+                    update_authority: deal.to_account_info(),
+                    mint_authority: deal.to_account_info(),
+                    payer: ctx.accounts.merchant.to_account_info(),
+                    metadata: ctx.accounts.collection_metadata.to_account_info(),
+                    token_program: ctx.accounts.token_program.to_account_info(),
+                    system_program: ctx.accounts.system_program.to_account_info(),
+                    rent: ctx.accounts.rent.to_account_info(),
+                },
+                deal_seeds,
+            ),
+            Some(0),
+        )?;
+
+        // A SOL vault must be rent-exempt before it can hold small payments, so
+        // the merchant seeds it with the reserve up front
+        if escrow_payments && deal.payment_mint.is_none() {
+            let reserve = Rent::get()?.minimum_balance(0);
+            let shortfall = reserve.saturating_sub(ctx.accounts.deal_vault.lamports());
+            if shortfall > 0 {
+                let transfer_ctx = CpiContext::new(
+                    ctx.accounts.system_program.to_account_info(),
+                    Transfer {
+                        from: ctx.accounts.merchant.to_account_info(),
+                        to: ctx.accounts.deal_vault.to_account_info(),
+                    },
+                );
+                transfer(transfer_ctx, shortfall)?;
+            }
+        }
 
-        msg!("Deal created: {}", deal.title);
+        merchant_state.deal_count = merchant_state
+            .deal_count
+            .checked_add(1)
+            .ok_or(DealError::MathOverflow)?;
+
+        emit_cpi!(DealCreated {
+            deal: deal.key(),
+            merchant: deal.merchant,
+            deal_id: deal.deal_id,
+            title: deal.title.clone(),
+            category: deal.category.clone(),
+            discount_percent: deal.discount_percent,
+            max_supply: deal.max_supply,
+            price_lamports: deal.price_lamports,
+            payment_mint: deal.payment_mint,
+            escrow_payments: deal.escrow_payments,
+            max_per_wallet: deal.max_per_wallet,
+            allowlisted: deal.allowlist_root.is_some(),
+            token_gate: deal.token_gate,
+            start_timestamp: deal.start_timestamp,
+            redemption_windows: deal.redemption_windows.clone(),
+            uses_per_coupon: deal.uses_per_coupon,
+            kind: deal.kind,
+            collection_mint: deal.collection_mint,
+            resale_royalty_bps: deal.resale_royalty_bps,
+            redemption_action: deal.redemption_action,
+            transferable: deal.transferable,
+            expiry_timestamp: deal.expiry_timestamp,
+            timestamp: now,
+        });
+
+        msg!("Deal created: {} (id {})", deal.title, deal.deal_id);
         Ok(())
     }
 
@@ -52,122 +349,1407 @@ pub mod basic {
         ctx: Context<UpdateDeal>,
         is_active: Option<bool>,
         price_lamports: Option<u64>,
+        title: Option<String>,
+        description: Option<String>,
+        category: Option<String>,
+        discount_percent: Option<u8>,
+        max_supply: Option<u64>,
+        expiry_timestamp: Option<i64>,
+        max_per_wallet: Option<u64>,
     ) -> Result<()> {
         let deal = &mut ctx.accounts.deal;
+        // Refunds are owed at the price the deal was cancelled with
+        require!(!deal.is_cancelled, DealError::DealCancelled);
+        let now = Clock::get()?.unix_timestamp;
+        let mut changed_fields = Vec::new();
+
+        if let Some(title) = title {
+            require!(title.len() <= 100, DealError::TitleTooLong);
+            if title != deal.title {
+                deal.title = title;
+                changed_fields.push(DealField::Title);
+            }
+        }
+
+        if let Some(description) = description {
+            require!(description.len() <= 500, DealError::DescriptionTooLong);
+            if description != deal.description {
+                deal.description = description;
+                changed_fields.push(DealField::Description);
+            }
+        }
+
+        if let Some(category) = category {
+            require!(category.len() <= 50, DealError::CategoryTooLong);
+            if category != deal.category {
+                deal.category = category;
+                changed_fields.push(DealField::Category);
+            }
+        }
+
+        if let Some(discount) = discount_percent {
+            require!(discount <= 100, DealError::InvalidDiscount);
+            if discount != deal.discount_percent {
+                deal.discount_percent = discount;
+                changed_fields.push(DealField::DiscountPercent);
+            }
+        }
+
+        if let Some(supply) = max_supply {
+            // Coupons already minted can't be taken back
+            require!(
+                supply > 0 && supply >= deal.current_supply,
+                DealError::InvalidSupply
+            );
+            if supply != deal.max_supply {
+                deal.max_supply = supply;
+                changed_fields.push(DealField::MaxSupply);
+            }
+        }
+
+        if let Some(expiry) = expiry_timestamp {
+            require!(expiry > now, DealError::InvalidExpiry);
+            require!(expiry > deal.start_timestamp, DealError::InvalidExpiry);
+            // Holders can only take escrowed payments back once the deal expires,
+            // so that date can be brought forward but never pushed out
+            require!(
+                expiry <= deal.expiry_timestamp
+                    || !deal.escrow_payments
+                    || deal.outstanding_coupons() == 0,
+                DealError::ExpiryExtensionLocked
+            );
+            if expiry != deal.expiry_timestamp {
+                deal.expiry_timestamp = expiry;
+                changed_fields.push(DealField::ExpiryTimestamp);
+            }
+        }
 
         if let Some(active) = is_active {
-            deal.is_active = active;
+            if active != deal.is_active {
+                deal.is_active = active;
+                changed_fields.push(DealField::IsActive);
+            }
         }
 
         if let Some(price) = price_lamports {
-            deal.price_lamports = price;
+            if price != deal.price_lamports {
+                deal.price_lamports = price;
+                changed_fields.push(DealField::PriceLamports);
+            }
+        }
+
+        if let Some(limit) = max_per_wallet {
+            if limit != deal.max_per_wallet {
+                deal.max_per_wallet = limit;
+                changed_fields.push(DealField::MaxPerWallet);
+            }
+        }
+
+        if !changed_fields.is_empty() {
+            emit_cpi!(DealUpdated {
+                deal: deal.key(),
+                merchant: deal.merchant,
+                changed_fields,
+                timestamp: now,
+            });
         }
 
         msg!("Deal updated: {}", deal.title);
         Ok(())
     }
 
-    pub fn mint_coupon(ctx: Context<MintCoupon>, deal_id: Pubkey, metadata_uri: String) -> Result<()> {
+    // Moves a deal created under the old title-seeded scheme onto the current
+    // layout. The account keeps its address (coupons reference it), but gets a
+    // numeric id from the merchant's counter and is grown to the new size.
+    pub fn migrate_deal(ctx: Context<MigrateDeal>) -> Result<()> {
+        let deal_info = ctx.accounts.deal.to_account_info();
+        let merchant_key = ctx.accounts.merchant.key();
+
+        require!(
+            deal_info.data_len() == 8 + LegacyDeal::INIT_SPACE,
+            DealError::DealAlreadyMigrated
+        );
+
+        let legacy = {
+            let data = deal_info.try_borrow_data()?;
+            require!(data[..8] == *Deal::DISCRIMINATOR, DealError::InvalidDeal);
+            LegacyDeal::deserialize(&mut &data[8..])?
+        };
+
+        require_keys_eq!(legacy.merchant, merchant_key, DealError::UnauthorizedMerchant);
+
+        let (legacy_address, _) = Pubkey::find_program_address(
+            &[b"deal", merchant_key.as_ref(), legacy.title.as_bytes()],
+            ctx.program_id,
+        );
+        require_keys_eq!(legacy_address, deal_info.key(), DealError::InvalidDeal);
+
+        grow_account(
+            &deal_info,
+            &ctx.accounts.merchant.to_account_info(),
+            &ctx.accounts.system_program,
+            8 + Deal::INIT_SPACE,
+        )?;
+
+        let merchant_state = &mut ctx.accounts.merchant_state;
+        merchant_state.merchant = merchant_key;
+        merchant_state.bump = ctx.bumps.merchant_state;
+
+        let outstanding_payments = legacy
+            .current_supply
+            .checked_mul(legacy.price_lamports)
+            .ok_or(DealError::MathOverflow)?;
+        let deal = Deal {
+            merchant: legacy.merchant,
+            deal_id: merchant_state.deal_count,
+            title: legacy.title,
+            description: legacy.description,
+            discount_percent: legacy.discount_percent,
+            max_supply: legacy.max_supply,
+            current_supply: legacy.current_supply,
+            // Legacy deals never tracked redemptions, so treat every minted coupon
+            // as outstanding until it is redeemed, or migrate_coupon finds it was
+            redeemed_count: 0,
+            expiry_timestamp: legacy.expiry_timestamp,
+            category: legacy.category,
+            price_lamports: legacy.price_lamports,
+            is_active: legacy.is_active,
+            total_ratings: legacy.total_ratings,
+            rating_sum: legacy.rating_sum,
+            bump: legacy.bump,
+            payment_mint: None,
+            escrow_payments: false,
+            refunded_count: 0,
+            is_cancelled: false,
+            max_per_wallet: 0,
+            allowlist_root: None,
+            token_gate: None,
+            start_timestamp: 0,
+            redemption_windows: Vec::new(),
+            uses_per_coupon: 1,
+            kind: CouponKind::Discount,
+            collection_mint: None,
+            resale_royalty_bps: 0,
+            redemption_action: RedemptionAction::Freeze,
+            transferable: true,
+            // Every legacy coupon was sold at the deal's one price
+            outstanding_payments,
+        };
+        deal.try_serialize(&mut &mut deal_info.try_borrow_mut_data()?[..])?;
+
+        merchant_state.deal_count = merchant_state
+            .deal_count
+            .checked_add(1)
+            .ok_or(DealError::MathOverflow)?;
+
+        emit_cpi!(DealMigrated {
+            deal: deal_info.key(),
+            merchant: merchant_key,
+            deal_id: deal.deal_id,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+
+        msg!("Deal migrated: {} (id {})", deal.title, deal.deal_id);
+        Ok(())
+    }
+
+    // Moves a coupon minted before coupon_index and the later lock, escrow and
+    // multi-use fields onto the current layout. The account keeps its address;
+    // the caller supplies the index it was minted at, which the PDA confirms.
+    pub fn migrate_coupon(ctx: Context<MigrateCoupon>, coupon_index: u64) -> Result<()> {
+        let coupon_info = ctx.accounts.coupon.to_account_info();
+
+        require!(
+            coupon_info.data_len() == 8 + LegacyCoupon::INIT_SPACE,
+            DealError::CouponAlreadyMigrated
+        );
+
+        let legacy = {
+            let data = coupon_info.try_borrow_data()?;
+            require!(data[..8] == *Coupon::DISCRIMINATOR, DealError::InvalidCoupon);
+            LegacyCoupon::deserialize(&mut &data[8..])?
+        };
+
         let deal = &mut ctx.accounts.deal;
+        require_keys_eq!(legacy.deal, deal.key(), DealError::CouponDealMismatch);
+
+        let legacy_address = Pubkey::create_program_address(
+            &[b"coupon", deal.key().as_ref(), &coupon_index.to_le_bytes(), &[legacy.bump]],
+            ctx.program_id,
+        )
+        .map_err(|_| DealError::InvalidCoupon)?;
+        require_keys_eq!(legacy_address, coupon_info.key(), DealError::InvalidCoupon);
+
+        // Stake records were already keyed by coupon, so carry the lock over
+        // rather than let a staked coupon move again
+        let is_staked = is_program_account(&ctx.accounts.staked_coupon, ctx.program_id);
+
+        // Nothing would clear is_listed if an active legacy listing carried it
+        // over, so its seller has to withdraw it with close_stale_listing first
+        if is_program_account(&ctx.accounts.listing, ctx.program_id) {
+            let data = ctx.accounts.listing.try_borrow_data()?;
+            require!(
+                !LegacyListing::deserialize(&mut &data[8..])?.is_active,
+                DealError::LegacyListingActive
+            );
+        }
 
-        require!(deal.is_active, DealError::DealInactive);
-        require!(deal.current_supply < deal.max_supply, DealError::MaxSupplyReached);
-        require!(Clock::get()?.unix_timestamp < deal.expiry_timestamp, DealError::DealExpired);
+        grow_account(
+            &coupon_info,
+            &ctx.accounts.payer.to_account_info(),
+            &ctx.accounts.system_program,
+            8 + Coupon::INIT_SPACE,
+        )?;
+
+        // Legacy coupons were single-use discounts paid straight to the merchant
+        let coupon = Coupon {
+            deal: legacy.deal,
+            owner: legacy.owner,
+            mint: legacy.mint,
+            coupon_index,
+            is_redeemed: legacy.is_redeemed,
+            minted_at: legacy.minted_at,
+            redeemed_at: legacy.redeemed_at,
+            bump: legacy.bump,
+            is_listed: false,
+            is_staked,
+            escrowed_amount: 0,
+            is_refunded: false,
+            uses_total: 1,
+            uses_remaining: if legacy.is_redeemed { 0 } else { 1 },
+            balance: 0,
+            redemption_count: 0,
+            in_program_transfer: false,
+            price_paid: deal.price_lamports,
+        };
+        coupon.try_serialize(&mut &mut coupon_info.try_borrow_mut_data()?[..])?;
+
+        // migrate_deal counted every legacy coupon as outstanding
+        if coupon.is_redeemed {
+            deal.redeemed_count = deal
+                .redeemed_count
+                .checked_add(1)
+                .ok_or(DealError::MathOverflow)?;
+            deal.settle_payment(coupon.price_paid);
+        }
 
-        // Transfer payment from user to merchant
-        if deal.price_lamports > 0 {
-            let transfer_ctx = CpiContext::new(
+        emit_cpi!(CouponMigrated {
+            deal: deal.key(),
+            coupon: coupon_info.key(),
+            coupon_index,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+
+        msg!("Coupon migrated: #{} of {}", coupon_index, deal.title);
+        Ok(())
+    }
+
+    pub fn cancel_deal(ctx: Context<CancelDeal>) -> Result<()> {
+        let deal = &mut ctx.accounts.deal;
+        require!(!deal.is_cancelled, DealError::DealCancelled);
+
+        // Every coupon still outstanding is owed the price it sold at. Payments
+        // already escrowed in the deal vault count towards that; the merchant
+        // covers the rest
+        let outstanding = deal.outstanding_coupons();
+        let owed = deal.outstanding_payments;
+
+        let funded = match deal.payment_mint {
+            None => {
+                // The vault also keeps its rent-exempt reserve so it can pay out to zero owed
+                let target = Rent::get()?
+                    .minimum_balance(0)
+                    .checked_add(owed)
+                    .ok_or(DealError::MathOverflow)?;
+                let shortfall = target.saturating_sub(ctx.accounts.deal_vault.lamports());
+                if shortfall > 0 {
+                    let transfer_ctx = CpiContext::new(
+                        ctx.accounts.system_program.to_account_info(),
+                        Transfer {
+                            from: ctx.accounts.merchant.to_account_info(),
+                            to: ctx.accounts.deal_vault.to_account_info(),
+                        },
+                    );
+                    transfer(transfer_ctx, shortfall)?;
+                }
+                shortfall
+            }
+            Some(payment_mint) => {
+                let mint = expect_payment_mint(&ctx.accounts.payment_mint, payment_mint)?;
+                let vault = expect_payment_account(
+                    &ctx.accounts.vault_payment_account,
+                    payment_mint,
+                    ctx.accounts.deal_vault.key(),
+                )?;
+                let from = expect_payment_account(
+                    &ctx.accounts.merchant_payment_account,
+                    payment_mint,
+                    deal.merchant,
+                )?;
+                let shortfall = owed.saturating_sub(vault.amount);
+                transfer_tokens(
+                    &ctx.accounts.token_program,
+                    mint,
+                    from,
+                    vault,
+                    &ctx.accounts.merchant.to_account_info(),
+                    &[],
+                    shortfall,
+                )?;
+                shortfall
+            }
+        };
+
+        deal.is_cancelled = true;
+        deal.is_active = false;
+
+        let now = Clock::get()?.unix_timestamp;
+        emit_cpi!(DealCancelled {
+            deal: deal.key(),
+            merchant: deal.merchant,
+            outstanding_coupons: outstanding,
+            refunds_owed: owed,
+            payment_mint: deal.payment_mint,
+            funded,
+            timestamp: now,
+        });
+
+        msg!("Deal cancelled: {}", deal.title);
+        Ok(())
+    }
+
+    pub fn close_deal(ctx: Context<CloseDeal>) -> Result<()> {
+        let deal = &ctx.accounts.deal;
+        let now = Clock::get()?.unix_timestamp;
+        require!(deal.is_closable(now), DealError::DealNotClosable);
+
+        let deal_key = deal.key();
+        let vault_bump = [ctx.bumps.deal_vault];
+        let vault_seeds: &[&[&[u8]]] = &[&[b"deal_vault", deal_key.as_ref(), &vault_bump]];
+
+        // Token-priced deals hold their escrow and cancellation funding in the
+        // vault's token account, which goes back to the merchant and is closed.
+        // Deals that never escrowed may not have one.
+        if let Some(payment_mint) = deal.payment_mint {
+            if ctx.accounts.vault_payment_account.is_some() || deal.escrow_payments || deal.is_cancelled {
+                let mint = expect_payment_mint(&ctx.accounts.payment_mint, payment_mint)?;
+                let vault = expect_payment_account(
+                    &ctx.accounts.vault_payment_account,
+                    payment_mint,
+                    ctx.accounts.deal_vault.key(),
+                )?;
+                if vault.amount > 0 {
+                    let to = expect_payment_account(
+                        &ctx.accounts.merchant_payment_account,
+                        payment_mint,
+                        deal.merchant,
+                    )?;
+                    transfer_tokens(
+                        &ctx.accounts.token_program,
+                        mint,
+                        vault,
+                        to,
+                        &ctx.accounts.deal_vault.to_account_info(),
+                        vault_seeds,
+                        vault.amount,
+                    )?;
+                }
+                close_account(CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    CloseAccount {
+                        account: vault.to_account_info(),
+                        destination: ctx.accounts.merchant.to_account_info(),
+                        authority: ctx.accounts.deal_vault.to_account_info(),
+                    },
+                    vault_seeds,
+                ))?;
+            }
+        }
+
+        // Every escrowed payment has been released or refunded, so whatever is
+        // left in the vault is the merchant's rent reserve
+        let vault_balance = ctx.accounts.deal_vault.lamports();
+        if vault_balance > 0 {
+            let transfer_ctx = CpiContext::new_with_signer(
                 ctx.accounts.system_program.to_account_info(),
                 Transfer {
-                    from: ctx.accounts.user.to_account_info(),
+                    from: ctx.accounts.deal_vault.to_account_info(),
                     to: ctx.accounts.merchant.to_account_info(),
                 },
+                vault_seeds,
             );
-            transfer(transfer_ctx, deal.price_lamports)?;
+            transfer(transfer_ctx, vault_balance)?;
         }
 
-        // Mint NFT to user
-        let cpi_context = CpiContext::new(
-            ctx.accounts.token_program.to_account_info(),
-            MintTo {
-                mint: ctx.accounts.mint.to_account_info(),
-                to: ctx.accounts.token_account.to_account_info(),
-                authority: ctx.accounts.mint.to_account_info(),
-            },
+        emit_cpi!(DealClosed {
+            deal: deal.key(),
+            merchant: deal.merchant,
+            rent_reclaimed: deal.to_account_info().lamports(),
+            timestamp: now,
+        });
+
+        msg!("Deal closed: {}", deal.title);
+        Ok(())
+    }
+
+    pub fn mint_coupon(
+        ctx: Context<MintCoupon>,
+        deal_id: Pubkey,
+        metadata_uri: String,
+        allowlist: Option<AllowlistProof>,
+    ) -> Result<()> {
+        let deal = &mut ctx.accounts.deal;
+
+        require_keys_eq!(deal_id, deal.key(), DealError::InvalidDeal);
+        require!(deal.is_active, DealError::DealInactive);
+        require!(deal.current_supply < deal.max_supply, DealError::MaxSupplyReached);
+        let now = Clock::get()?.unix_timestamp;
+        require!(now >= deal.start_timestamp, DealError::DealNotStarted);
+        require!(now < deal.expiry_timestamp, DealError::DealExpired);
+
+        // Count this wallet's mints against the deal's per-wallet cap
+        let purchase_record = &mut ctx.accounts.purchase_record;
+        require!(
+            deal.max_per_wallet == 0 || purchase_record.count < deal.max_per_wallet,
+            DealError::PurchaseLimitExceeded
         );
 
-        mint_to(cpi_context, 1)?;
-
-        // Create metadata with provided IPFS URI
-        let data = DataV2 {
-            name: format!("{} - Coupon #{}", deal.title, deal.current_supply + 1),
-            symbol: "DEAL".to_string(),
-            uri: metadata_uri,
-            seller_fee_basis_points: 0,
-            creators: None,
-            collection: None,
-            uses: None,
+        // Allowlisted deals only sell to wallets in the merkle tree, each up to
+        // the allowance committed in its leaf
+        if let Some(root) = deal.allowlist_root {
+            let allowlist = allowlist.ok_or(DealError::AllowlistProofRequired)?;
+            let leaf = allowlist_leaf(&ctx.accounts.user.key(), allowlist.allowance);
+            require!(
+                verify_allowlist_proof(&root, leaf, &allowlist.proof),
+                DealError::NotOnAllowlist
+            );
+            require!(
+                purchase_record.count < allowlist.allowance,
+                DealError::AllowanceExhausted
+            );
+        }
+
+        if let Some(gate) = &deal.token_gate {
+            verify_token_gate(gate, &ctx.accounts.user.key(), ctx.remaining_accounts)?;
+        }
+
+        purchase_record.deal = deal.key();
+        purchase_record.buyer = ctx.accounts.user.key();
+        purchase_record.count = purchase_record
+            .count
+            .checked_add(1)
+            .ok_or(DealError::MathOverflow)?;
+        purchase_record.bump = ctx.bumps.purchase_record;
+
+        // Transfer payment from user to merchant, or into the deal vault when the
+        // deal holds payments until each coupon is redeemed
+        if deal.price_lamports > 0 {
+            match deal.payment_mint {
+                None => {
+                    let recipient = if deal.escrow_payments {
+                        ctx.accounts.deal_vault.to_account_info()
+                    } else {
+                        ctx.accounts.merchant.to_account_info()
+                    };
+                    let transfer_ctx = CpiContext::new(
+                        ctx.accounts.system_program.to_account_info(),
+                        Transfer {
+                            from: ctx.accounts.user.to_account_info(),
+                            to: recipient,
+                        },
+                    );
+                    transfer(transfer_ctx, deal.price_lamports)?;
+                }
+                Some(payment_mint) => {
+                    let mint = expect_payment_mint(&ctx.accounts.payment_mint, payment_mint)?;
+                    let from = expect_payment_account(
+                        &ctx.accounts.user_payment_account,
+                        payment_mint,
+                        ctx.accounts.user.key(),
+                    )?;
+                    let to = if deal.escrow_payments {
+                        expect_payment_account(
+                            &ctx.accounts.vault_payment_account,
+                            payment_mint,
+                            ctx.accounts.deal_vault.key(),
+                        )?
+                    } else {
+                        expect_payment_account(
+                            &ctx.accounts.merchant_payment_account,
+                            payment_mint,
+                            deal.merchant,
+                        )?
+                    };
+                    transfer_tokens(
+                        &ctx.accounts.token_program,
+                        mint,
+                        from,
+                        to,
+                        &ctx.accounts.user.to_account_info(),
+                        &[],
+                        deal.price_lamports,
+                    )?;
+                }
+            }
+        }
+
+        // The deal PDA owns the metadata so the program can sign for it later.
+        // Deals migrated from the legacy layout keep their title-seeded address,
+        // which the program can't sign for once the title changes, so their
+        // coupons stay with the mint as update authority and can't be frozen.
+        let merchant_key = deal.merchant;
+        let deal_id_bytes = deal.deal_id.to_le_bytes();
+        let deal_bump = [deal.bump];
+        let deal_signs = deal.collection_mint.is_some();
+        let deal_seeds: &[&[&[u8]]] = if deal_signs {
+            &[&[b"deal", merchant_key.as_ref(), &deal_id_bytes, &deal_bump]]
+        } else {
+            &[]
+        };
+        let name = coupon_name(&deal.title, deal.current_supply + 1);
+
+        // Coupons are Token-2022 mints. Resellable ones run the coupon hook on
+        // every transfer and are described by Metaplex; soulbound ones have the
+        // NonTransferable extension and keep their metadata on the mint itself.
+        let mint_info = ctx.accounts.mint.to_account_info();
+        let coupon_token_program = ctx.accounts.token_2022_program.to_account_info();
+        let update_authority = if deal_signs {
+            deal.to_account_info()
+        } else {
+            mint_info.clone()
         };
+        let rent = Rent::get()?;
+        if deal.transferable {
+            let mint_len = ExtensionType::try_calculate_account_len::<spl_token_2022::state::Mint>(&[
+                ExtensionType::TransferHook,
+            ])?;
+            create_account(
+                CpiContext::new(
+                    ctx.accounts.system_program.to_account_info(),
+                    CreateAccount {
+                        from: ctx.accounts.user.to_account_info(),
+                        to: mint_info.clone(),
+                    },
+                ),
+                rent.minimum_balance(mint_len),
+                mint_len as u64,
+                &Token2022::id(),
+            )?;
+            // No hook authority, so the hook can never be swapped out
+            transfer_hook_initialize(
+                CpiContext::new(
+                    coupon_token_program.clone(),
+                    TransferHookInitialize {
+                        token_program_id: coupon_token_program.clone(),
+                        mint: mint_info.clone(),
+                    },
+                ),
+                None,
+                Some(COUPON_HOOK_PROGRAM_ID),
+            )?;
+        } else {
+            let mint_len = ExtensionType::try_calculate_account_len::<spl_token_2022::state::Mint>(&[
+                ExtensionType::NonTransferable,
+                ExtensionType::MetadataPointer,
+            ])?;
+            // Token-2022 grows the mint when the metadata is written, so fund that
+            // up front, along with room for the rename in redeem_coupon
+            let metadata_len = TokenMetadata {
+                update_authority: OptionalNonZeroPubkey::try_from(Some(update_authority.key()))?,
+                mint: mint_info.key(),
+                name: name.clone(),
+                symbol: "DEAL".to_string(),
+                uri: metadata_uri.clone(),
+                additional_metadata: Vec::new(),
+            }
+            .tlv_size_of()?
+                + REDEEMED_PREFIX.len();
+            create_account(
+                CpiContext::new(
+                    ctx.accounts.system_program.to_account_info(),
+                    CreateAccount {
+                        from: ctx.accounts.user.to_account_info(),
+                        to: mint_info.clone(),
+                    },
+                ),
+                rent.minimum_balance(mint_len + metadata_len),
+                mint_len as u64,
+                &Token2022::id(),
+            )?;
+            non_transferable_mint_initialize(CpiContext::new(
+                coupon_token_program.clone(),
+                NonTransferableMintInitialize {
+                    token_program_id: coupon_token_program.clone(),
+                    mint: mint_info.clone(),
+                },
+            ))?;
+            metadata_pointer_initialize(
+                CpiContext::new(
+                    coupon_token_program.clone(),
+                    MetadataPointerInitialize {
+                        token_program_id: coupon_token_program.clone(),
+                        mint: mint_info.clone(),
+                    },
+                ),
+                Some(update_authority.key()),
+                Some(mint_info.key()),
+            )?;
+        }
 
-        let cpi_context = CpiContext::new(
-            ctx.accounts.token_metadata_program.to_account_info(),
-            CreateMetadataAccountsV3 {
-                metadata: ctx.accounts.metadata.to_account_info(),
-                mint: ctx.accounts.mint.to_account_info(),
-                mint_authority: ctx.accounts.mint.to_account_info(),
-                update_authority: ctx.accounts.mint.to_account_info(),
+        token_interface::initialize_mint2(
+            CpiContext::new(
+                coupon_token_program.clone(),
+                token_interface::InitializeMint2 {
+                    mint: mint_info.clone(),
+                },
+            ),
+            0,
+            &mint_info.key(),
+            deal_signs.then(|| deal.key()).as_ref(),
+        )?;
+
+        associated_token::create(CpiContext::new(
+            ctx.accounts.associated_token_program.to_account_info(),
+            associated_token::Create {
                 payer: ctx.accounts.user.to_account_info(),
+                associated_token: ctx.accounts.token_account.to_account_info(),
+                authority: ctx.accounts.user.to_account_info(),
+                mint: mint_info.clone(),
                 system_program: ctx.accounts.system_program.to_account_info(),
-                rent: ctx.accounts.rent.to_account_info(),
+                token_program: coupon_token_program.clone(),
             },
-        );
+        ))?;
+
+        // Mint NFT to user
+        token_interface::mint_to(
+            CpiContext::new(
+                coupon_token_program.clone(),
+                token_interface::MintTo {
+                    mint: mint_info.clone(),
+                    to: ctx.accounts.token_account.to_account_info(),
+                    authority: mint_info.clone(),
+                },
+            ),
+            1,
+        )?;
+
+        if deal.transferable {
+            // Create metadata with provided IPFS URI. The merchant is the sole
+            // creator and royalty recipient; Metaplex only lets a creator verify
+            // themselves, so the merchant does that later with verify_coupon_creator.
+            let data = DataV2 {
+                name,
+                symbol: "DEAL".to_string(),
+                uri: metadata_uri,
+                seller_fee_basis_points: deal.resale_royalty_bps,
+                creators: Some(vec![Creator {
+                    address: merchant_key,
+                    verified: false,
+                    share: 100,
+                }]),
+                collection: deal.collection_mint.map(|key| Collection {
+                    verified: false,
+                    key,
+                }),
+                // Metaplex only lets uses count down through its own utilize
+                // instruction, so the count lives on the Coupon account instead
+                uses: None,
+            };
+
+            let cpi_context = CpiContext::new_with_signer(
+                ctx.accounts.token_metadata_program.to_account_info(),
+                CreateMetadataAccountsV3 {
+                    metadata: ctx.accounts.metadata.to_account_info(),
+                    mint: ctx.accounts.mint.to_account_info(),
+                    mint_authority: ctx.accounts.mint.to_account_info(),
+                    update_authority: update_authority.clone(),
+                    payer: ctx.accounts.user.to_account_info(),
+                    system_program: ctx.accounts.system_program.to_account_info(),
+                    rent: ctx.accounts.rent.to_account_info(),
+                },
+                deal_seeds,
+            );
+
+            create_metadata_accounts_v3(cpi_context, data, true, true, None)?;
+
+            // Deals migrated from the legacy layout have no collection to join
+            if deal.collection_mint.is_some() {
+                verify_sized_collection_item(
+                    CpiContext::new_with_signer(
+                        ctx.accounts.token_metadata_program.to_account_info(),
+                        VerifySizedCollectionItem {
+                            payer: ctx.accounts.user.to_account_info(),
+                            metadata: ctx.accounts.metadata.to_account_info(),
+                            collection_authority: deal.to_account_info(),
+                            collection_mint: ctx.accounts.collection_mint.to_account_info(),
+                            collection_metadata: ctx.accounts.collection_metadata.to_account_info(),
+                            collection_master_edition: ctx
+                                .accounts
+                                .collection_master_edition
+                                .to_account_info(),
+                        },
+                        deal_seeds,
+                    ),
+                    None,
+                )?;
+            }
+
+            // The hook writes the accounts Token-2022 should hand it on each
+            // transfer, while the mint can still sign as its own authority
+            let mut init_hook_ix = initialize_extra_account_meta_list(
+                &COUPON_HOOK_PROGRAM_ID,
+                &ctx.accounts.extra_account_meta_list.key(),
+                &mint_info.key(),
+                &mint_info.key(),
+                &[],
+            );
+            init_hook_ix.accounts.extend([
+                AccountMeta::new(ctx.accounts.user.key(), true),
+                AccountMeta::new_readonly(ctx.accounts.coupon.key(), false),
+            ]);
+            invoke(
+                &init_hook_ix,
+                &[
+                    ctx.accounts.extra_account_meta_list.to_account_info(),
+                    mint_info.clone(),
+                    ctx.accounts.system_program.to_account_info(),
+                    ctx.accounts.user.to_account_info(),
+                    ctx.accounts.coupon.to_account_info(),
+                    ctx.accounts.coupon_hook_program.to_account_info(),
+                ],
+            )?;
+        } else {
+            token_metadata_initialize(
+                CpiContext::new(
+                    coupon_token_program.clone(),
+                    TokenMetadataInitialize {
+                        program_id: coupon_token_program.clone(),
+                        metadata: mint_info.clone(),
+                        update_authority: update_authority.clone(),
+                        mint_authority: mint_info.clone(),
+                        mint: mint_info.clone(),
+                    },
+                ),
+                name,
+                "DEAL".to_string(),
+                metadata_uri,
+            )?;
+        }
 
-        create_metadata_accounts_v3(cpi_context, data, true, true, None)?;
+        // Nothing needs the mint to sign any more, so no one can mint a second copy
+        token_interface::set_authority(
+            CpiContext::new(
+                coupon_token_program.clone(),
+                token_interface::SetAuthority {
+                    current_authority: mint_info.clone(),
+                    account_or_mint: mint_info.clone(),
+                },
+            ),
+            spl_token_2022::instruction::AuthorityType::MintTokens,
+            None,
+        )?;
 
         // Initialize coupon account
         let coupon = &mut ctx.accounts.coupon;
         coupon.deal = deal.key();
         coupon.owner = ctx.accounts.user.key();
         coupon.mint = ctx.accounts.mint.key();
+        coupon.coupon_index = deal.current_supply;
         coupon.is_redeemed = false;
-        coupon.minted_at = Clock::get()?.unix_timestamp;
+        coupon.minted_at = now;
+        coupon.is_listed = false;
+        coupon.is_staked = false;
         coupon.bump = ctx.bumps.coupon;
+        coupon.price_paid = deal.price_lamports;
+        coupon.escrowed_amount = if deal.escrow_payments { coupon.price_paid } else { 0 };
+        coupon.is_refunded = false;
+        coupon.uses_total = deal.uses_per_coupon;
+        coupon.uses_remaining = deal.uses_per_coupon;
+        coupon.balance = match deal.kind {
+            CouponKind::StoredValue { face_value } => face_value,
+            CouponKind::Discount => 0,
+        };
+        coupon.redemption_count = 0;
+        coupon.in_program_transfer = false;
+
+        emit_cpi!(CouponMinted {
+            deal: deal.key(),
+            coupon: coupon.key(),
+            mint: coupon.mint,
+            owner: coupon.owner,
+            coupon_index: coupon.coupon_index,
+            price_lamports: deal.price_lamports,
+            payment_mint: deal.payment_mint,
+            escrowed_amount: coupon.escrowed_amount,
+            timestamp: now,
+        });
 
         deal.current_supply += 1;
+        deal.outstanding_payments = deal
+            .outstanding_payments
+            .checked_add(coupon.price_paid)
+            .ok_or(DealError::MathOverflow)?;
 
         msg!("Coupon minted for deal: {}", deal.title);
         Ok(())
     }
 
+    // The merchant signs a coupon's metadata to mark themselves a verified creator
+    pub fn verify_coupon_creator(ctx: Context<VerifyCouponCreator>) -> Result<()> {
+        sign_metadata(CpiContext::new(
+            ctx.accounts.token_metadata_program.to_account_info(),
+            SignMetadata {
+                creator: ctx.accounts.merchant.to_account_info(),
+                metadata: ctx.accounts.metadata.to_account_info(),
+            },
+        ))?;
+
+        emit_cpi!(CouponCreatorVerified {
+            deal: ctx.accounts.deal.key(),
+            coupon: ctx.accounts.coupon.key(),
+            merchant: ctx.accounts.merchant.key(),
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+
+        msg!("Creator verified for coupon: {}", ctx.accounts.coupon.key());
+        Ok(())
+    }
+
     pub fn redeem_coupon(ctx: Context<RedeemCoupon>) -> Result<()> {
         let coupon = &mut ctx.accounts.coupon;
-        let deal = &ctx.accounts.deal;
+        let deal = &mut ctx.accounts.deal;
 
+        let now = Clock::get()?.unix_timestamp;
         require!(!coupon.is_redeemed, DealError::AlreadyRedeemed);
-        require!(Clock::get()?.unix_timestamp < deal.expiry_timestamp, DealError::DealExpired);
-        require!(ctx.accounts.merchant.key() == deal.merchant, DealError::UnauthorizedMerchant);
+        require!(!coupon.is_refunded, DealError::CouponRefunded);
+        require!(!deal.is_cancelled, DealError::DealCancelled);
+        require!(!coupon.is_locked(), DealError::CouponLocked);
+        require!(now < deal.expiry_timestamp, DealError::DealExpired);
+        require!(deal.is_redeemable_at(now), DealError::OutsideRedemptionWindow);
+        require!(deal.kind == CouponKind::Discount, DealError::WrongCouponKind);
+
+        // Each use releases its share of the escrowed payment to the merchant,
+        // with the last use taking whatever rounding left behind
+        let released_amount = if coupon.uses_remaining == 1 {
+            coupon.escrowed_amount
+        } else {
+            coupon.escrowed_amount / coupon.uses_remaining
+        };
+        if released_amount > 0 {
+            let deal_key = deal.key();
+            let vault_bump = [ctx.bumps.deal_vault];
+            let vault_seeds: &[&[&[u8]]] = &[&[b"deal_vault", deal_key.as_ref(), &vault_bump]];
+            match deal.payment_mint {
+                None => {
+                    let transfer_ctx = CpiContext::new_with_signer(
+                        ctx.accounts.system_program.to_account_info(),
+                        Transfer {
+                            from: ctx.accounts.deal_vault.to_account_info(),
+                            to: ctx.accounts.merchant.to_account_info(),
+                        },
+                        vault_seeds,
+                    );
+                    transfer(transfer_ctx, released_amount)?;
+                }
+                Some(payment_mint) => {
+                    let mint = expect_payment_mint(&ctx.accounts.payment_mint, payment_mint)?;
+                    let from = expect_payment_account(
+                        &ctx.accounts.vault_payment_account,
+                        payment_mint,
+                        ctx.accounts.deal_vault.key(),
+                    )?;
+                    let to = expect_payment_account(
+                        &ctx.accounts.merchant_payment_account,
+                        payment_mint,
+                        deal.merchant,
+                    )?;
+                    transfer_tokens(
+                        &ctx.accounts.token_program,
+                        mint,
+                        from,
+                        to,
+                        &ctx.accounts.deal_vault.to_account_info(),
+                        vault_seeds,
+                        released_amount,
+                    )?;
+                }
+            }
+            coupon.escrowed_amount -= released_amount;
+        }
+
+        coupon.uses_remaining -= 1;
+        coupon.redeemed_at = Some(now);
+
+        // The coupon only counts as redeemed once its last use is spent, at
+        // which point the NFT is taken out of circulation
+        if coupon.uses_remaining == 0 {
+            coupon.is_redeemed = true;
+            deal.redeemed_count = deal
+                .redeemed_count
+                .checked_add(1)
+                .ok_or(DealError::MathOverflow)?;
+            deal.settle_payment(coupon.price_paid);
+
+            let merchant_key = deal.merchant;
+            let deal_id_bytes = deal.deal_id.to_le_bytes();
+            let deal_bump = [deal.bump];
+            let deal_seeds: &[&[&[u8]]] =
+                &[&[b"deal", merchant_key.as_ref(), &deal_id_bytes, &deal_bump]];
+
+            match deal.redemption_action {
+                RedemptionAction::Burn => {
+                    let holder = ctx
+                        .accounts
+                        .holder
+                        .as_ref()
+                        .ok_or(DealError::HolderSignatureRequired)?;
+                    let burn_ctx = CpiContext::new(
+                        ctx.accounts.coupon_token_program.to_account_info(),
+                        token_interface::Burn {
+                            mint: ctx.accounts.mint.to_account_info(),
+                            from: ctx.accounts.holder_token_account.to_account_info(),
+                            authority: holder.to_account_info(),
+                        },
+                    );
+                    token_interface::burn(burn_ctx, 1)?;
+
+                    let close_ctx = CpiContext::new(
+                        ctx.accounts.coupon_token_program.to_account_info(),
+                        token_interface::CloseAccount {
+                            account: ctx.accounts.holder_token_account.to_account_info(),
+                            destination: holder.to_account_info(),
+                            authority: holder.to_account_info(),
+                        },
+                    );
+                    token_interface::close_account(close_ctx)?;
+                }
+                // Coupons minted while the mint froze itself can't be frozen
+                // by the program, so they only get the metadata update
+                RedemptionAction::Freeze => {
+                    if ctx.accounts.mint.freeze_authority == Some(deal.key()).into() {
+                        let freeze_ctx = CpiContext::new_with_signer(
+                            ctx.accounts.coupon_token_program.to_account_info(),
+                            token_interface::FreezeAccount {
+                                account: ctx.accounts.holder_token_account.to_account_info(),
+                                mint: ctx.accounts.mint.to_account_info(),
+                                authority: deal.to_account_info(),
+                            },
+                            deal_seeds,
+                        );
+                        token_interface::freeze_account(freeze_ctx)?;
+                    }
+                }
+            }
+
+            if deal.transferable {
+                mark_metadata_redeemed(
+                    &ctx.accounts.metadata,
+                    &deal.to_account_info(),
+                    &ctx.accounts.token_metadata_program,
+                    deal_seeds,
+                )?;
+            } else {
+                mark_token_metadata_redeemed(
+                    &ctx.accounts.mint.to_account_info(),
+                    &deal.to_account_info(),
+                    &ctx.accounts.coupon_token_program.to_account_info(),
+                    deal_seeds,
+                )?;
+            }
+        }
 
-        coupon.is_redeemed = true;
-        coupon.redeemed_at = Some(Clock::get()?.unix_timestamp);
+        emit_cpi!(CouponRedeemed {
+            deal: deal.key(),
+            coupon: coupon.key(),
+            merchant: deal.merchant,
+            owner: coupon.owner,
+            released_amount,
+            uses_remaining: coupon.uses_remaining,
+            timestamp: now,
+        });
 
         msg!("Coupon redeemed for deal: {}", deal.title);
         Ok(())
     }
 
-    pub fn transfer_coupon(ctx: Context<TransferCoupon>) -> Result<()> {
+    pub fn redeem_amount(ctx: Context<RedeemAmount>, amount: u64) -> Result<()> {
+        let coupon = &mut ctx.accounts.coupon;
+        let deal = &mut ctx.accounts.deal;
+
+        let now = Clock::get()?.unix_timestamp;
+        require!(
+            matches!(deal.kind, CouponKind::StoredValue { .. }),
+            DealError::WrongCouponKind
+        );
+        require!(!coupon.is_redeemed, DealError::AlreadyRedeemed);
+        require!(!coupon.is_refunded, DealError::CouponRefunded);
+        require!(!deal.is_cancelled, DealError::DealCancelled);
+        require!(!coupon.is_locked(), DealError::CouponLocked);
+        require!(now < deal.expiry_timestamp, DealError::DealExpired);
+        require!(deal.is_redeemable_at(now), DealError::OutsideRedemptionWindow);
+        require!(amount > 0, DealError::InvalidRedeemAmount);
+        require!(amount <= coupon.balance, DealError::InsufficientBalance);
+
+        // Spending part of the balance releases the same share of any escrowed
+        // payment, and spending the rest releases whatever is left
+        let released_amount = if amount == coupon.balance {
+            coupon.escrowed_amount
+        } else {
+            u64::try_from(
+                (coupon.escrowed_amount as u128)
+                    .checked_mul(amount as u128)
+                    .and_then(|v| v.checked_div(coupon.balance as u128))
+                    .ok_or(DealError::MathOverflow)?,
+            )
+            .map_err(|_| DealError::MathOverflow)?
+        };
+        if released_amount > 0 {
+            let deal_key = deal.key();
+            let vault_bump = [ctx.bumps.deal_vault];
+            let vault_seeds: &[&[&[u8]]] = &[&[b"deal_vault", deal_key.as_ref(), &vault_bump]];
+            match deal.payment_mint {
+                None => {
+                    let transfer_ctx = CpiContext::new_with_signer(
+                        ctx.accounts.system_program.to_account_info(),
+                        Transfer {
+                            from: ctx.accounts.deal_vault.to_account_info(),
+                            to: ctx.accounts.merchant.to_account_info(),
+                        },
+                        vault_seeds,
+                    );
+                    transfer(transfer_ctx, released_amount)?;
+                }
+                Some(payment_mint) => {
+                    let mint = expect_payment_mint(&ctx.accounts.payment_mint, payment_mint)?;
+                    let from = expect_payment_account(
+                        &ctx.accounts.vault_payment_account,
+                        payment_mint,
+                        ctx.accounts.deal_vault.key(),
+                    )?;
+                    let to = expect_payment_account(
+                        &ctx.accounts.merchant_payment_account,
+                        payment_mint,
+                        deal.merchant,
+                    )?;
+                    transfer_tokens(
+                        &ctx.accounts.token_program,
+                        mint,
+                        from,
+                        to,
+                        &ctx.accounts.deal_vault.to_account_info(),
+                        vault_seeds,
+                        released_amount,
+                    )?;
+                }
+            }
+            coupon.escrowed_amount -= released_amount;
+        }
+
+        coupon.balance -= amount;
+        coupon.owner = ctx.accounts.holder.key();
+        coupon.redeemed_at = Some(now);
+
+        let entry = &mut ctx.accounts.redemption_entry;
+        entry.coupon = coupon.key();
+        entry.index = coupon.redemption_count;
+        entry.merchant = deal.merchant;
+        entry.holder = ctx.accounts.holder.key();
+        entry.amount = amount;
+        entry.balance_after = coupon.balance;
+        entry.timestamp = now;
+        entry.bump = ctx.bumps.redemption_entry;
+        coupon.redemption_count = coupon
+            .redemption_count
+            .checked_add(1)
+            .ok_or(DealError::MathOverflow)?;
+
+        emit_cpi!(BalanceRedeemed {
+            deal: deal.key(),
+            coupon: coupon.key(),
+            merchant: deal.merchant,
+            owner: coupon.owner,
+            amount,
+            balance_remaining: coupon.balance,
+            released_amount,
+            entry_index: entry.index,
+            timestamp: now,
+        });
+
+        // An empty card is spent: burn the NFT and close the coupon and its
+        // token account, returning the rent to the holder
+        if coupon.balance == 0 {
+            coupon.is_redeemed = true;
+            deal.redeemed_count = deal
+                .redeemed_count
+                .checked_add(1)
+                .ok_or(DealError::MathOverflow)?;
+            deal.settle_payment(coupon.price_paid);
+
+            let burn_ctx = CpiContext::new(
+                ctx.accounts.coupon_token_program.to_account_info(),
+                token_interface::Burn {
+                    mint: ctx.accounts.mint.to_account_info(),
+                    from: ctx.accounts.holder_token_account.to_account_info(),
+                    authority: ctx.accounts.holder.to_account_info(),
+                },
+            );
+            token_interface::burn(burn_ctx, 1)?;
+
+            let close_ctx = CpiContext::new(
+                ctx.accounts.coupon_token_program.to_account_info(),
+                token_interface::CloseAccount {
+                    account: ctx.accounts.holder_token_account.to_account_info(),
+                    destination: ctx.accounts.holder.to_account_info(),
+                    authority: ctx.accounts.holder.to_account_info(),
+                },
+            );
+            token_interface::close_account(close_ctx)?;
+
+            coupon.close(ctx.accounts.holder.to_account_info())?;
+            msg!("Gift card spent and closed for deal: {}", deal.title);
+        } else {
+            msg!("Gift card charged {} for deal: {}", amount, deal.title);
+        }
+        Ok(())
+    }
+
+    pub fn refund_unredeemed(ctx: Context<RefundUnredeemed>) -> Result<()> {
+        let coupon = &mut ctx.accounts.coupon;
+        let deal = &mut ctx.accounts.deal;
+
+        let now = Clock::get()?.unix_timestamp;
+        require!(now >= deal.expiry_timestamp, DealError::DealNotExpired);
+        require!(!coupon.is_redeemed, DealError::AlreadyRedeemed);
+        require!(!coupon.is_locked(), DealError::CouponLocked);
+        let amount = coupon.escrowed_amount;
+        require!(amount > 0, DealError::NothingToRefund);
+
+        // The coupon was never honored, so its escrowed payment goes back to the holder
+        let deal_key = deal.key();
+        let vault_bump = [ctx.bumps.deal_vault];
+        let vault_seeds: &[&[&[u8]]] = &[&[b"deal_vault", deal_key.as_ref(), &vault_bump]];
+        match deal.payment_mint {
+            None => {
+                let transfer_ctx = CpiContext::new_with_signer(
+                    ctx.accounts.system_program.to_account_info(),
+                    Transfer {
+                        from: ctx.accounts.deal_vault.to_account_info(),
+                        to: ctx.accounts.holder.to_account_info(),
+                    },
+                    vault_seeds,
+                );
+                transfer(transfer_ctx, amount)?;
+            }
+            Some(payment_mint) => {
+                let mint = expect_payment_mint(&ctx.accounts.payment_mint, payment_mint)?;
+                let from = expect_payment_account(
+                    &ctx.accounts.vault_payment_account,
+                    payment_mint,
+                    ctx.accounts.deal_vault.key(),
+                )?;
+                let to = expect_payment_account(
+                    &ctx.accounts.holder_payment_account,
+                    payment_mint,
+                    ctx.accounts.holder.key(),
+                )?;
+                transfer_tokens(
+                    &ctx.accounts.token_program,
+                    mint,
+                    from,
+                    to,
+                    &ctx.accounts.deal_vault.to_account_info(),
+                    vault_seeds,
+                    amount,
+                )?;
+            }
+        }
+
+        // Burn the coupon NFT so a refunded coupon can't be redeemed or resold
+        let burn_ctx = CpiContext::new(
+            ctx.accounts.coupon_token_program.to_account_info(),
+            token_interface::Burn {
+                mint: ctx.accounts.mint.to_account_info(),
+                from: ctx.accounts.holder_token_account.to_account_info(),
+                authority: ctx.accounts.holder.to_account_info(),
+            },
+        );
+        token_interface::burn(burn_ctx, 1)?;
+
+        coupon.owner = ctx.accounts.holder.key();
+        coupon.escrowed_amount = 0;
+        coupon.is_refunded = true;
+
+        deal.refunded_count = deal
+            .refunded_count
+            .checked_add(1)
+            .ok_or(DealError::MathOverflow)?;
+        deal.settle_payment(coupon.price_paid);
+
+        emit_cpi!(CouponRefunded {
+            deal: deal_key,
+            coupon: coupon.key(),
+            owner: coupon.owner,
+            amount,
+            payment_mint: deal.payment_mint,
+            timestamp: now,
+        });
+
+        msg!("Refunded unredeemed coupon for deal: {}", deal.title);
+        Ok(())
+    }
+
+    pub fn claim_cancellation_refund(ctx: Context<ClaimCancellationRefund>) -> Result<()> {
+        let coupon = &mut ctx.accounts.coupon;
+        let deal = &mut ctx.accounts.deal;
+
+        require!(deal.is_cancelled, DealError::DealNotCancelled);
+        require!(!coupon.is_redeemed, DealError::AlreadyRedeemed);
+        require!(!coupon.is_refunded, DealError::CouponRefunded);
+        require!(!coupon.is_locked(), DealError::CouponLocked);
+
+        // Partly used coupons are refunded only for the uses or balance left on them
+        let (left, total) = match deal.kind {
+            CouponKind::StoredValue { face_value } => (coupon.balance, face_value),
+            CouponKind::Discount => (coupon.uses_remaining, coupon.uses_total),
+        };
+        let pro_rata = u64::try_from(
+            (coupon.price_paid as u128)
+                .checked_mul(left as u128)
+                .and_then(|v| v.checked_div(total as u128))
+                .ok_or(DealError::MathOverflow)?,
+        )
+        .map_err(|_| DealError::MathOverflow)?;
+        // Never less than what the holder still has sitting in escrow
+        let amount = pro_rata.max(coupon.escrowed_amount);
+        let deal_key = deal.key();
+        let vault_bump = [ctx.bumps.deal_vault];
+        let vault_seeds: &[&[&[u8]]] = &[&[b"deal_vault", deal_key.as_ref(), &vault_bump]];
+        match deal.payment_mint {
+            None => {
+                if amount > 0 {
+                    let transfer_ctx = CpiContext::new_with_signer(
+                        ctx.accounts.system_program.to_account_info(),
+                        Transfer {
+                            from: ctx.accounts.deal_vault.to_account_info(),
+                            to: ctx.accounts.holder.to_account_info(),
+                        },
+                        vault_seeds,
+                    );
+                    transfer(transfer_ctx, amount)?;
+                }
+            }
+            Some(payment_mint) => {
+                let mint = expect_payment_mint(&ctx.accounts.payment_mint, payment_mint)?;
+                let from = expect_payment_account(
+                    &ctx.accounts.vault_payment_account,
+                    payment_mint,
+                    ctx.accounts.deal_vault.key(),
+                )?;
+                let to = expect_payment_account(
+                    &ctx.accounts.holder_payment_account,
+                    payment_mint,
+                    ctx.accounts.holder.key(),
+                )?;
+                transfer_tokens(
+                    &ctx.accounts.token_program,
+                    mint,
+                    from,
+                    to,
+                    &ctx.accounts.deal_vault.to_account_info(),
+                    vault_seeds,
+                    amount,
+                )?;
+            }
+        }
+
+        // Burn the coupon NFT so a refunded coupon can't be redeemed or resold
+        let burn_ctx = CpiContext::new(
+            ctx.accounts.coupon_token_program.to_account_info(),
+            token_interface::Burn {
+                mint: ctx.accounts.mint.to_account_info(),
+                from: ctx.accounts.holder_token_account.to_account_info(),
+                authority: ctx.accounts.holder.to_account_info(),
+            },
+        );
+        token_interface::burn(burn_ctx, 1)?;
+
+        coupon.owner = ctx.accounts.holder.key();
+        // Any escrowed payment was folded into the cancellation refund
+        coupon.escrowed_amount = 0;
+        coupon.is_refunded = true;
+
+        deal.refunded_count = deal
+            .refunded_count
+            .checked_add(1)
+            .ok_or(DealError::MathOverflow)?;
+        deal.settle_payment(coupon.price_paid);
+
+        emit_cpi!(CouponRefunded {
+            deal: deal_key,
+            coupon: coupon.key(),
+            owner: coupon.owner,
+            amount,
+            payment_mint: deal.payment_mint,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+
+        msg!("Cancellation refund claimed for deal: {}", deal.title);
+        Ok(())
+    }
+
+    pub fn transfer_coupon<'info>(ctx: Context<'_, '_, '_, 'info, TransferCoupon<'info>>) -> Result<()> {
         let coupon = &mut ctx.accounts.coupon;
 
         require!(!coupon.is_redeemed, DealError::AlreadyRedeemed);
-        require!(coupon.owner == ctx.accounts.current_owner.key(), DealError::NotOwner);
+        require!(!coupon.is_locked(), DealError::CouponLocked);
+
+        // The NFT moves with the record; holding it is what proves ownership
+        transfer_coupon_nft(
+            coupon,
+            &ctx.accounts.token_program.to_account_info(),
+            &ctx.accounts.from_token_account.to_account_info(),
+            &ctx.accounts.mint.to_account_info(),
+            &ctx.accounts.to_token_account.to_account_info(),
+            &ctx.accounts.current_owner.to_account_info(),
+            ctx.remaining_accounts,
+            &[],
+        )?;
 
         coupon.owner = ctx.accounts.new_owner.key();
 
+        emit_cpi!(CouponTransferred {
+            deal: coupon.deal,
+            coupon: coupon.key(),
+            from: ctx.accounts.current_owner.key(),
+            to: coupon.owner,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+
         msg!("Coupon transferred to new owner");
         Ok(())
     }
 
+    // Called by the coupon hook when a coupon moves through Token-2022 without
+    // going through this program, so the record follows the NFT
+    pub fn sync_coupon_owner(ctx: Context<SyncCouponOwner>) -> Result<()> {
+        let coupon = &mut ctx.accounts.coupon;
+        let from = coupon.owner;
+        coupon.owner = ctx.accounts.destination_token_account.owner;
+
+        emit_cpi!(CouponTransferred {
+            deal: coupon.deal,
+            coupon: coupon.key(),
+            from,
+            to: coupon.owner,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+
+        msg!("Coupon owner synced from transfer hook");
+        Ok(())
+    }
+
     pub fn rate_deal(ctx: Context<RateDeal>, rating: u8) -> Result<()> {
-        require!(rating >= 1 && rating <= 5, DealError::InvalidRating);
+        require!((1..=5).contains(&rating), DealError::InvalidRating);
 
         let deal = &mut ctx.accounts.deal;
         let deal_rating = &mut ctx.accounts.deal_rating;
@@ -183,6 +1765,15 @@ pub mod basic {
         deal_rating.created_at = Clock::get()?.unix_timestamp;
         deal_rating.bump = ctx.bumps.deal_rating;
 
+        emit_cpi!(DealRated {
+            deal: deal.key(),
+            user: deal_rating.user,
+            rating,
+            total_ratings: deal.total_ratings,
+            rating_sum: deal.rating_sum,
+            timestamp: deal_rating.created_at,
+        });
+
         msg!("Deal rated: {} stars", rating);
         Ok(())
     }
@@ -197,16 +1788,40 @@ pub mod basic {
         comment.created_at = timestamp;
         comment.bump = ctx.bumps.comment;
 
+        emit_cpi!(CommentAdded {
+            deal: comment.deal,
+            comment: comment.key(),
+            author: comment.author,
+            created_at: comment.created_at,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+
         msg!("Comment added to deal");
         Ok(())
     }
 
-    pub fn list_coupon(ctx: Context<ListCoupon>, price_lamports: u64) -> Result<()> {
+    pub fn list_coupon<'info>(ctx: Context<'_, '_, '_, 'info, ListCoupon<'info>>, price_lamports: u64) -> Result<()> {
         require!(price_lamports > 0, DealError::InvalidPrice);
 
-        let coupon = &ctx.accounts.coupon;
+        let coupon = &mut ctx.accounts.coupon;
         require!(!coupon.is_redeemed, DealError::AlreadyRedeemed);
-        require!(coupon.owner == ctx.accounts.seller.key(), DealError::NotOwner);
+        require!(!coupon.is_locked(), DealError::CouponLocked);
+
+        // Whoever holds the token is the owner, even if the record lagged behind
+        coupon.owner = ctx.accounts.seller.key();
+        coupon.is_listed = true;
+
+        // Park the NFT in escrow so the seller can't move it while it's for sale
+        transfer_coupon_nft(
+            coupon,
+            &ctx.accounts.token_program.to_account_info(),
+            &ctx.accounts.seller_token_account.to_account_info(),
+            &ctx.accounts.mint.to_account_info(),
+            &ctx.accounts.escrow_token_account.to_account_info(),
+            &ctx.accounts.seller.to_account_info(),
+            ctx.remaining_accounts,
+            &[],
+        )?;
 
         let listing = &mut ctx.accounts.listing;
         listing.coupon = ctx.accounts.coupon.key();
@@ -215,121 +1830,435 @@ pub mod basic {
         listing.is_active = true;
         listing.created_at = Clock::get()?.unix_timestamp;
         listing.bump = ctx.bumps.listing;
+        listing.payment_mint = ctx.accounts.payment_mint.as_ref().map(|mint| mint.key());
+
+        emit_cpi!(CouponListed {
+            listing: listing.key(),
+            coupon: listing.coupon,
+            seller: listing.seller,
+            price_lamports,
+            payment_mint: listing.payment_mint,
+            timestamp: listing.created_at,
+        });
 
         msg!("Coupon listed for sale at {} lamports", price_lamports);
         Ok(())
     }
 
-    pub fn buy_coupon(ctx: Context<BuyCoupon>) -> Result<()> {
-        let listing = &mut ctx.accounts.listing;
+    pub fn buy_coupon<'info>(ctx: Context<'_, '_, '_, 'info, BuyCoupon<'info>>) -> Result<()> {
+        let listing = &ctx.accounts.listing;
         let coupon = &mut ctx.accounts.coupon;
 
-        require!(listing.is_active, DealError::ListingInactive);
         require!(!coupon.is_redeemed, DealError::AlreadyRedeemed);
-        require!(coupon.owner == listing.seller, DealError::InvalidListing);
-
-        // Calculate platform fee (2.5%)
-        let platform_fee = (listing.price_lamports * 25) / 1000;
-        let seller_amount = listing.price_lamports - platform_fee;
 
-        // Transfer payment from buyer to seller
-        let transfer_to_seller = CpiContext::new(
-            ctx.accounts.system_program.to_account_info(),
-            Transfer {
-                from: ctx.accounts.buyer.to_account_info(),
-                to: ctx.accounts.seller.to_account_info(),
-            },
-        );
-        transfer(transfer_to_seller, seller_amount)?;
+        let platform_config = &ctx.accounts.platform_config;
+        let platform_fee = match listing.payment_mint {
+            None => platform_config.fee_for(listing.price_lamports)?,
+            // The per-sale cap is denominated in lamports, so token sales only pay the rate
+            Some(_) => platform_config.rate_fee(listing.price_lamports)?,
+        };
+        let royalty_amount = ctx.accounts.deal.royalty_for(listing.price_lamports)?;
+        let seller_amount = listing
+            .price_lamports
+            .checked_sub(platform_fee)
+            .and_then(|amount| amount.checked_sub(royalty_amount))
+            .ok_or(DealError::MathOverflow)?;
+
+        match listing.payment_mint {
+            None => {
+                // Transfer payment from buyer to seller
+                let transfer_to_seller = CpiContext::new(
+                    ctx.accounts.system_program.to_account_info(),
+                    Transfer {
+                        from: ctx.accounts.buyer.to_account_info(),
+                        to: ctx.accounts.seller.to_account_info(),
+                    },
+                );
+                transfer(transfer_to_seller, seller_amount)?;
+
+                // Transfer platform fee to platform wallet
+                let transfer_to_platform = CpiContext::new(
+                    ctx.accounts.system_program.to_account_info(),
+                    Transfer {
+                        from: ctx.accounts.buyer.to_account_info(),
+                        to: ctx.accounts.platform_wallet.to_account_info(),
+                    },
+                );
+                transfer(transfer_to_platform, platform_fee)?;
+
+                // Pay the merchant's resale royalty
+                if royalty_amount > 0 {
+                    let transfer_to_merchant = CpiContext::new(
+                        ctx.accounts.system_program.to_account_info(),
+                        Transfer {
+                            from: ctx.accounts.buyer.to_account_info(),
+                            to: ctx.accounts.merchant.to_account_info(),
+                        },
+                    );
+                    transfer(transfer_to_merchant, royalty_amount)?;
+                }
+            }
+            Some(payment_mint) => {
+                let mint = expect_payment_mint(&ctx.accounts.payment_mint, payment_mint)?;
+                let from = expect_payment_account(
+                    &ctx.accounts.buyer_payment_account,
+                    payment_mint,
+                    ctx.accounts.buyer.key(),
+                )?;
+                let to_seller = expect_payment_account(
+                    &ctx.accounts.seller_payment_account,
+                    payment_mint,
+                    listing.seller,
+                )?;
+                let to_treasury = expect_payment_account(
+                    &ctx.accounts.treasury_payment_account,
+                    payment_mint,
+                    platform_config.treasury,
+                )?;
+                let buyer = ctx.accounts.buyer.to_account_info();
+                let token_program = &ctx.accounts.token_program;
+                transfer_tokens(token_program, mint, from, to_seller, &buyer, &[], seller_amount)?;
+                transfer_tokens(token_program, mint, from, to_treasury, &buyer, &[], platform_fee)?;
+                if royalty_amount > 0 {
+                    let to_merchant = expect_payment_account(
+                        &ctx.accounts.merchant_payment_account,
+                        payment_mint,
+                        ctx.accounts.deal.merchant,
+                    )?;
+                    transfer_tokens(token_program, mint, from, to_merchant, &buyer, &[], royalty_amount)?;
+                }
+            }
+        }
 
-        // Transfer platform fee to platform wallet
-        let transfer_to_platform = CpiContext::new(
-            ctx.accounts.system_program.to_account_info(),
-            Transfer {
-                from: ctx.accounts.buyer.to_account_info(),
-                to: ctx.accounts.platform_wallet.to_account_info(),
-            },
-        );
-        transfer(transfer_to_platform, platform_fee)?;
+        // Release the NFT from escrow to the buyer
+        let coupon_key = coupon.key();
+        let listing_bump = [listing.bump];
+        let listing_seeds: &[&[&[u8]]] = &[&[b"listing", coupon_key.as_ref(), &listing_bump]];
+        release_escrow(
+            coupon,
+            &ctx.accounts.coupon_token_program,
+            &ctx.accounts.mint,
+            &ctx.accounts.escrow_token_account,
+            &ctx.accounts.buyer_token_account,
+            &listing.to_account_info(),
+            &ctx.accounts.seller.to_account_info(),
+            ctx.remaining_accounts,
+            listing_seeds,
+        )?;
 
         // Transfer ownership
         coupon.owner = ctx.accounts.buyer.key();
-
-        // Deactivate listing
-        listing.is_active = false;
+        coupon.is_listed = false;
+
+        emit_cpi!(CouponSold {
+            listing: listing.key(),
+            coupon: coupon.key(),
+            seller: listing.seller,
+            buyer: coupon.owner,
+            price_lamports: listing.price_lamports,
+            payment_mint: listing.payment_mint,
+            seller_amount,
+            platform_fee,
+            royalty_amount,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
 
         msg!("Coupon purchased for {} lamports", listing.price_lamports);
         Ok(())
     }
 
-    pub fn delist_coupon(ctx: Context<DelistCoupon>) -> Result<()> {
-        let listing = &mut ctx.accounts.listing;
+    pub fn delist_coupon<'info>(ctx: Context<'_, '_, '_, 'info, DelistCoupon<'info>>) -> Result<()> {
+        let listing = &ctx.accounts.listing;
 
         require!(listing.is_active, DealError::ListingInactive);
-        require!(listing.seller == ctx.accounts.seller.key(), DealError::NotOwner);
 
-        listing.is_active = false;
+        ctx.accounts.coupon.is_listed = false;
+
+        // Hand the NFT back to the seller
+        let coupon_key = ctx.accounts.coupon.key();
+        let listing_bump = [listing.bump];
+        let listing_seeds: &[&[&[u8]]] = &[&[b"listing", coupon_key.as_ref(), &listing_bump]];
+        release_escrow(
+            &mut ctx.accounts.coupon,
+            &ctx.accounts.token_program,
+            &ctx.accounts.mint,
+            &ctx.accounts.escrow_token_account,
+            &ctx.accounts.seller_token_account,
+            &listing.to_account_info(),
+            &ctx.accounts.seller.to_account_info(),
+            ctx.remaining_accounts,
+            listing_seeds,
+        )?;
+
+        emit_cpi!(CouponDelisted {
+            listing: listing.key(),
+            coupon: listing.coupon,
+            seller: listing.seller,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
 
         msg!("Coupon delisted from marketplace");
         Ok(())
     }
 
+    // Closes a listing that older versions of the program left open after a
+    // sale or delisting, which would otherwise stop list_coupon creating it
+    // again. Anyone can close an inactive listing, refunding its rent to the
+    // seller. An active legacy listing never escrowed the coupon, so its
+    // seller can also withdraw it here.
+    pub fn close_stale_listing(ctx: Context<CloseStaleListing>) -> Result<()> {
+        let listing_info = ctx.accounts.listing.to_account_info();
+
+        let listing = {
+            let data = listing_info.try_borrow_data()?;
+            require!(
+                data.len() >= 8 + LegacyListing::INIT_SPACE && data[..8] == *Listing::DISCRIMINATOR,
+                DealError::InvalidListing
+            );
+            LegacyListing::deserialize(&mut &data[8..])?
+        };
+        require_keys_eq!(listing.seller, ctx.accounts.seller.key(), DealError::InvalidListing);
+
+        if listing.is_active {
+            // Current listings hold the coupon in escrow, which delist_coupon returns
+            require!(
+                listing_info.data_len() == 8 + LegacyListing::INIT_SPACE,
+                DealError::ListingEscrowed
+            );
+            require_keys_eq!(ctx.accounts.authority.key(), listing.seller, DealError::NotOwner);
+        }
+
+        close_program_account(&listing_info, &ctx.accounts.seller.to_account_info())?;
+
+        emit_cpi!(StaleListingClosed {
+            listing: ctx.accounts.listing.key(),
+            coupon: listing.coupon,
+            seller: listing.seller,
+            was_active: listing.is_active,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+
+        msg!("Stale listing closed for coupon: {}", listing.coupon);
+        Ok(())
+    }
+
     pub fn initialize_rewards_pool(ctx: Context<InitializeRewardsPool>, reward_rate_per_day: u64) -> Result<()> {
         let pool = &mut ctx.accounts.rewards_pool;
         pool.total_staked = 0;
         pool.reward_rate_per_day = reward_rate_per_day;
         pool.admin = ctx.accounts.admin.key();
         pool.bump = ctx.bumps.rewards_pool;
+        pool.acc_reward_per_share = 0;
+        pool.last_update_ts = Clock::get()?.unix_timestamp;
+
+        emit_cpi!(RewardsPoolInitialized {
+            rewards_pool: pool.key(),
+            admin: pool.admin,
+            reward_rate_per_day,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
 
         msg!("Rewards pool initialized with rate: {} lamports/day", reward_rate_per_day);
         Ok(())
     }
 
-    pub fn stake_coupon(ctx: Context<StakeCouponCtx>) -> Result<()> {
-        let coupon = &ctx.accounts.coupon;
-        require!(!coupon.is_redeemed, DealError::AlreadyRedeemed);
-        require!(coupon.owner == ctx.accounts.staker.key(), DealError::NotOwner);
+    // Grows a pool created before the per-share accumulator. Emissions are
+    // tracked from the migration on; stakers' debts start at zero to match.
+    pub fn migrate_rewards_pool(ctx: Context<MigrateRewardsPool>) -> Result<()> {
+        let pool_info = ctx.accounts.rewards_pool.to_account_info();
 
-        let staked_coupon = &mut ctx.accounts.staked_coupon;
-        let current_time = Clock::get()?.unix_timestamp;
+        require!(
+            pool_info.data_len() == 8 + LegacyRewardsPool::INIT_SPACE,
+            DealError::RewardsPoolAlreadyMigrated
+        );
 
-        staked_coupon.coupon = ctx.accounts.coupon.key();
-        staked_coupon.staker = ctx.accounts.staker.key();
-        staked_coupon.staked_at = current_time;
-        staked_coupon.last_claim_at = current_time;
-        staked_coupon.bump = ctx.bumps.staked_coupon;
+        let legacy = {
+            let data = pool_info.try_borrow_data()?;
+            require!(data[..8] == *RewardsPool::DISCRIMINATOR, DealError::InvalidRewardsPool);
+            LegacyRewardsPool::deserialize(&mut &data[8..])?
+        };
+        require_keys_eq!(legacy.admin, ctx.accounts.admin.key(), DealError::UnauthorizedAdmin);
+
+        grow_account(
+            &pool_info,
+            &ctx.accounts.admin.to_account_info(),
+            &ctx.accounts.system_program,
+            8 + RewardsPool::INIT_SPACE,
+        )?;
+
+        let now = Clock::get()?.unix_timestamp;
+        let pool = RewardsPool {
+            total_staked: legacy.total_staked,
+            reward_rate_per_day: legacy.reward_rate_per_day,
+            admin: legacy.admin,
+            bump: legacy.bump,
+            acc_reward_per_share: 0,
+            last_update_ts: now,
+        };
+        pool.try_serialize(&mut &mut pool_info.try_borrow_mut_data()?[..])?;
 
-        let pool = &mut ctx.accounts.rewards_pool;
-        pool.total_staked += 1;
+        emit_cpi!(RewardsPoolMigrated {
+            rewards_pool: pool_info.key(),
+            total_staked: pool.total_staked,
+            timestamp: now,
+        });
 
-        msg!("Coupon staked successfully");
+        msg!("Rewards pool migrated with {} coupons staked", pool.total_staked);
         Ok(())
     }
 
-    pub fn unstake_coupon(ctx: Context<UnstakeCouponCtx>) -> Result<()> {
-        let staked_coupon = &ctx.accounts.staked_coupon;
-        require!(staked_coupon.staker == ctx.accounts.staker.key(), DealError::NotOwner);
+    // Grows a stake record created before reward_debt. A zero debt pays it
+    // everything accrued since the pool itself was migrated.
+    pub fn migrate_staked_coupon(ctx: Context<MigrateStakedCoupon>) -> Result<()> {
+        let staked_info = ctx.accounts.staked_coupon.to_account_info();
 
-        // Calculate and transfer rewards
-        let current_time = Clock::get()?.unix_timestamp;
-        let time_staked = current_time - staked_coupon.last_claim_at;
-        let rewards = calculate_rewards(time_staked, ctx.accounts.rewards_pool.reward_rate_per_day)?;
+        require!(
+            staked_info.data_len() == 8 + LegacyStakedCoupon::INIT_SPACE,
+            DealError::StakedCouponAlreadyMigrated
+        );
 
-        if rewards > 0 {
-            let transfer_ctx = CpiContext::new(
-                ctx.accounts.system_program.to_account_info(),
-                Transfer {
-                    from: ctx.accounts.rewards_pool.to_account_info(),
-                    to: ctx.accounts.staker.to_account_info(),
-                },
-            );
-            transfer(transfer_ctx, rewards)?;
-        }
+        let legacy = {
+            let data = staked_info.try_borrow_data()?;
+            require!(data[..8] == *StakedCoupon::DISCRIMINATOR, DealError::InvalidStakedCoupon);
+            LegacyStakedCoupon::deserialize(&mut &data[8..])?
+        };
 
-        let pool = &mut ctx.accounts.rewards_pool;
-        pool.total_staked -= 1;
+        let (legacy_address, _) =
+            Pubkey::find_program_address(&[b"staked_coupon", legacy.coupon.as_ref()], ctx.program_id);
+        require_keys_eq!(legacy_address, staked_info.key(), DealError::InvalidStakedCoupon);
+
+        grow_account(
+            &staked_info,
+            &ctx.accounts.payer.to_account_info(),
+            &ctx.accounts.system_program,
+            8 + StakedCoupon::INIT_SPACE,
+        )?;
+
+        let staked_coupon = StakedCoupon {
+            coupon: legacy.coupon,
+            staker: legacy.staker,
+            staked_at: legacy.staked_at,
+            last_claim_at: legacy.last_claim_at,
+            bump: legacy.bump,
+            reward_debt: 0,
+        };
+        staked_coupon.try_serialize(&mut &mut staked_info.try_borrow_mut_data()?[..])?;
 
-        msg!("Coupon unstaked, rewards claimed: {} lamports", rewards);
+        emit_cpi!(StakedCouponMigrated {
+            staked_coupon: staked_info.key(),
+            coupon: staked_coupon.coupon,
+            staker: staked_coupon.staker,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+
+        msg!("Stake record migrated for coupon: {}", staked_coupon.coupon);
+        Ok(())
+    }
+
+    pub fn fund_rewards_pool(ctx: Context<FundRewardsPool>, amount: u64) -> Result<()> {
+        require!(amount > 0, DealError::InvalidFundingAmount);
+
+        let transfer_ctx = CpiContext::new(
+            ctx.accounts.system_program.to_account_info(),
+            Transfer {
+                from: ctx.accounts.funder.to_account_info(),
+                to: ctx.accounts.rewards_vault.to_account_info(),
+            },
+        );
+        transfer(transfer_ctx, amount)?;
+
+        emit_cpi!(RewardsPoolFunded {
+            rewards_pool: ctx.accounts.rewards_pool.key(),
+            funder: ctx.accounts.funder.key(),
+            amount,
+            vault_balance: ctx.accounts.rewards_vault.lamports(),
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+
+        msg!("Rewards vault funded with {} lamports", amount);
+        Ok(())
+    }
+
+    pub fn stake_coupon(ctx: Context<StakeCouponCtx>) -> Result<()> {
+        let coupon = &mut ctx.accounts.coupon;
+        require!(!coupon.is_redeemed, DealError::AlreadyRedeemed);
+        require!(!coupon.is_locked(), DealError::CouponLocked);
+        coupon.owner = ctx.accounts.staker.key();
+        coupon.is_staked = true;
+
+        let current_time = Clock::get()?.unix_timestamp;
+
+        // Settle emissions owed to existing stakers before the share count changes
+        let pool = &mut ctx.accounts.rewards_pool;
+        pool.accrue(current_time)?;
+        pool.total_staked = pool
+            .total_staked
+            .checked_add(1)
+            .ok_or(DealError::MathOverflow)?;
+
+        let staked_coupon = &mut ctx.accounts.staked_coupon;
+        staked_coupon.coupon = ctx.accounts.coupon.key();
+        staked_coupon.staker = ctx.accounts.staker.key();
+        staked_coupon.staked_at = current_time;
+        staked_coupon.last_claim_at = current_time;
+        staked_coupon.bump = ctx.bumps.staked_coupon;
+        staked_coupon.reward_debt = pool.acc_reward_per_share;
+
+        emit_cpi!(CouponStaked {
+            staked_coupon: staked_coupon.key(),
+            coupon: staked_coupon.coupon,
+            staker: staked_coupon.staker,
+            total_staked: pool.total_staked,
+            timestamp: current_time,
+        });
+
+        msg!("Coupon staked successfully");
+        Ok(())
+    }
+
+    pub fn unstake_coupon(ctx: Context<UnstakeCouponCtx>, forfeit_rewards: bool) -> Result<()> {
+        let staked_coupon = &ctx.accounts.staked_coupon;
+        ctx.accounts.coupon.is_staked = false;
+
+        // Calculate and transfer rewards
+        let current_time = Clock::get()?.unix_timestamp;
+        let pool = &mut ctx.accounts.rewards_pool;
+        pool.accrue(current_time)?;
+        let owed = pool.pending_rewards(staked_coupon.reward_debt)?;
+
+        // An underfunded pool only releases the coupon if the staker agrees
+        // to forfeit what the vault cannot pay
+        let rewards = owed.min(rewards_available(&ctx.accounts.rewards_vault)?);
+        let forfeited = owed - rewards;
+        require!(forfeited == 0 || forfeit_rewards, DealError::InsufficientRewardsFunds);
+
+        if rewards > 0 {
+            pay_rewards(
+                &ctx.accounts.system_program,
+                &ctx.accounts.rewards_vault,
+                &ctx.accounts.staker.to_account_info(),
+                ctx.bumps.rewards_vault,
+                rewards,
+            )?;
+        }
+
+        let pool = &mut ctx.accounts.rewards_pool;
+        pool.total_staked = pool
+            .total_staked
+            .checked_sub(1)
+            .ok_or(DealError::MathOverflow)?;
+
+        emit_cpi!(CouponUnstaked {
+            staked_coupon: ctx.accounts.staked_coupon.key(),
+            coupon: ctx.accounts.coupon.key(),
+            staker: ctx.accounts.staker.key(),
+            rewards,
+            forfeited,
+            total_staked: pool.total_staked,
+            timestamp: current_time,
+        });
+
+        msg!("Coupon unstaked, rewards claimed: {} lamports, forfeited: {}", rewards, forfeited);
         Ok(())
     }
 
@@ -338,115 +2267,483 @@ pub mod basic {
         require!(staked_coupon.staker == ctx.accounts.staker.key(), DealError::NotOwner);
 
         let current_time = Clock::get()?.unix_timestamp;
-        let time_since_claim = current_time - staked_coupon.last_claim_at;
-        let rewards = calculate_rewards(time_since_claim, ctx.accounts.rewards_pool.reward_rate_per_day)?;
+        let pool = &mut ctx.accounts.rewards_pool;
+        pool.accrue(current_time)?;
+        let rewards = pool.pending_rewards(staked_coupon.reward_debt)?;
 
         require!(rewards > 0, DealError::NoRewardsToClaim);
 
+        pay_rewards(
+            &ctx.accounts.system_program,
+            &ctx.accounts.rewards_vault,
+            &ctx.accounts.staker.to_account_info(),
+            ctx.bumps.rewards_vault,
+            rewards,
+        )?;
+
+        staked_coupon.reward_debt = pool.acc_reward_per_share;
+        staked_coupon.last_claim_at = current_time;
+
+        emit_cpi!(RewardsClaimed {
+            staked_coupon: staked_coupon.key(),
+            coupon: staked_coupon.coupon,
+            staker: staked_coupon.staker,
+            amount: rewards,
+            timestamp: current_time,
+        });
+
+        msg!("Rewards claimed: {} lamports", rewards);
+        Ok(())
+    }
+}
+
+// Grows an account to a new layout's size, topping up its rent from the payer
+fn grow_account<'info>(
+    account: &AccountInfo<'info>,
+    payer: &AccountInfo<'info>,
+    system_program: &Program<'info, System>,
+    new_len: usize,
+) -> Result<()> {
+    let rent_due = Rent::get()?
+        .minimum_balance(new_len)
+        .saturating_sub(account.lamports());
+    if rent_due > 0 {
         let transfer_ctx = CpiContext::new(
-            ctx.accounts.system_program.to_account_info(),
+            system_program.to_account_info(),
             Transfer {
-                from: ctx.accounts.rewards_pool.to_account_info(),
-                to: ctx.accounts.staker.to_account_info(),
+                from: payer.clone(),
+                to: account.clone(),
             },
         );
-        transfer(transfer_ctx, rewards)?;
+        transfer(transfer_ctx, rent_due)?;
+    }
+    account.realloc(new_len, true)?;
+    Ok(())
+}
 
-        staked_coupon.last_claim_at = current_time;
+// Closes an account handled without a typed wrapper, refunding its rent
+fn close_program_account<'info>(account: &AccountInfo<'info>, destination: &AccountInfo<'info>) -> Result<()> {
+    let lamports = account.lamports();
+    account.sub_lamports(lamports)?;
+    destination.add_lamports(lamports)?;
+    account.assign(&System::id());
+    account.realloc(0, false)?;
+    Ok(())
+}
 
-        msg!("Rewards claimed: {} lamports", rewards);
-        Ok(())
+// Whether an optional PDA was ever created by this program
+fn is_program_account(account: &UncheckedAccount, program_id: &Pubkey) -> bool {
+    account.owner == program_id && !account.data_is_empty()
+}
+
+// Resolves the optional payment mint passed for a token-priced deal or listing
+fn expect_payment_mint<'a, 'info>(
+    mint: &'a Option<Box<Account<'info, Mint>>>,
+    expected: Pubkey,
+) -> Result<&'a Account<'info, Mint>> {
+    let mint = mint.as_deref().ok_or(DealError::MissingPaymentAccount)?;
+    require_keys_eq!(mint.key(), expected, DealError::InvalidPaymentMint);
+    Ok(mint)
+}
+
+// Resolves an optional payment token account, which must hold the payment
+// mint and belong to the expected wallet
+fn expect_payment_account<'a, 'info>(
+    account: &'a Option<Box<Account<'info, TokenAccount>>>,
+    mint: Pubkey,
+    owner: Pubkey,
+) -> Result<&'a Account<'info, TokenAccount>> {
+    let account = account.as_deref().ok_or(DealError::MissingPaymentAccount)?;
+    require_keys_eq!(account.mint, mint, DealError::InvalidPaymentAccount);
+    require_keys_eq!(account.owner, owner, DealError::InvalidPaymentAccount);
+    Ok(account)
+}
+
+fn transfer_tokens<'info>(
+    token_program: &Program<'info, Token>,
+    mint: &Account<'info, Mint>,
+    from: &Account<'info, TokenAccount>,
+    to: &Account<'info, TokenAccount>,
+    authority: &AccountInfo<'info>,
+    signer_seeds: &[&[&[u8]]],
+    amount: u64,
+) -> Result<()> {
+    if amount == 0 {
+        return Ok(());
+    }
+
+    let transfer_ctx = CpiContext::new_with_signer(
+        token_program.to_account_info(),
+        TransferChecked {
+            from: from.to_account_info(),
+            mint: mint.to_account_info(),
+            to: to.to_account_info(),
+            authority: authority.clone(),
+        },
+        signer_seeds,
+    );
+    transfer_checked(transfer_ctx, amount, mint.decimals)
+}
+
+// Allowlist leaves commit to a wallet and how many coupons it may mint. Leaves
+// and inner nodes are domain-separated, and each pair is hashed in sorted
+// order so proofs need no left/right flags. Shared with the off-chain
+// allowlist builder so both sides always hash the same way.
+pub fn allowlist_leaf(wallet: &Pubkey, allowance: u64) -> [u8; 32] {
+    hashv(&[&[0u8], wallet.as_ref(), &allowance.to_le_bytes()]).to_bytes()
+}
+
+pub fn allowlist_node(a: &[u8; 32], b: &[u8; 32]) -> [u8; 32] {
+    let (left, right) = if a <= b { (a, b) } else { (b, a) };
+    hashv(&[&[1u8], left, right]).to_bytes()
+}
+
+pub fn verify_allowlist_proof(root: &[u8; 32], leaf: [u8; 32], proof: &[[u8; 32]]) -> bool {
+    proof
+        .iter()
+        .fold(leaf, |node, sibling| allowlist_node(&node, sibling))
+        == *root
+}
+
+// Checks a token-gated deal against remaining_accounts: the holder's token
+// account first, then that NFT's metadata for collection gates
+fn verify_token_gate(gate: &TokenGate, holder: &Pubkey, accounts: &[AccountInfo]) -> Result<()> {
+    let token_info = accounts.first().ok_or(DealError::TokenGateAccountsMissing)?;
+    require!(
+        *token_info.owner == Token::id() || *token_info.owner == Token2022::id(),
+        DealError::InvalidGateAccount
+    );
+    let token_account =
+        token_interface::TokenAccount::try_deserialize(&mut &token_info.try_borrow_data()?[..])?;
+    require_keys_eq!(token_account.owner, *holder, DealError::InvalidGateAccount);
+
+    match gate {
+        TokenGate::TokenBalance { mint, min_amount } => {
+            require_keys_eq!(token_account.mint, *mint, DealError::InvalidGateAccount);
+            require!(
+                token_account.amount >= *min_amount,
+                DealError::InsufficientGateBalance
+            );
+        }
+        TokenGate::Collection { collection_mint } => {
+            require!(token_account.amount >= 1, DealError::InsufficientGateBalance);
+
+            // Only the metadata program can write a metadata account, and it
+            // records the mint it describes, so owner + mint pin it to this NFT
+            let metadata_info = accounts.get(1).ok_or(DealError::TokenGateAccountsMissing)?;
+            require_keys_eq!(*metadata_info.owner, Metadata::id(), DealError::InvalidGateAccount);
+            let metadata =
+                MetadataAccount::try_deserialize(&mut &metadata_info.try_borrow_data()?[..])?;
+            require_keys_eq!(metadata.mint, token_account.mint, DealError::InvalidGateAccount);
+            require!(
+                metadata
+                    .collection
+                    .as_ref()
+                    .is_some_and(|collection| collection.verified && collection.key == *collection_mint),
+                DealError::NotInGateCollection
+            );
+        }
     }
+    Ok(())
+}
+
+// Metaplex caps names at 32 bytes; long deal titles are cut at a char boundary
+fn metadata_name(title: &str) -> String {
+    truncate_at_char_boundary(title, MAX_METADATA_NAME_LEN).to_string()
+}
+
+// Coupon names keep their number, so only the title is shortened to fit
+fn coupon_name(title: &str, number: u64) -> String {
+    let suffix = format!(" - Coupon #{number}");
+    let title = truncate_at_char_boundary(title, MAX_METADATA_NAME_LEN.saturating_sub(suffix.len()));
+    format!("{title}{suffix}")
 }
 
-// Helper function to calculate rewards
-fn calculate_rewards(time_seconds: i64, rate_per_day: u64) -> Result<u64> {
-    const SECONDS_PER_DAY: i64 = 86400;
+fn truncate_at_char_boundary(text: &str, max_len: usize) -> &str {
+    let mut end = text.len().min(max_len);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
 
-    if time_seconds <= 0 {
-        return Ok(0);
+// Prefixes the coupon's name with REDEEMED so wallets show it as spent.
+// Coupons minted before the deal PDA became update authority are skipped.
+fn mark_metadata_redeemed<'info>(
+    metadata: &AccountInfo<'info>,
+    deal: &AccountInfo<'info>,
+    token_metadata_program: &AccountInfo<'info>,
+    deal_seeds: &[&[&[u8]]],
+) -> Result<()> {
+    let current = MetadataAccount::try_deserialize(&mut &metadata.try_borrow_data()?[..])?;
+    if current.update_authority != deal.key() {
+        return Ok(());
     }
 
-    let days_staked = time_seconds / SECONDS_PER_DAY;
-    let rewards = (days_staked as u64) * rate_per_day;
+    // Metaplex pads stored strings with NULs
+    let name = format!("{REDEEMED_PREFIX}{}", current.name.trim_end_matches('\0'));
+    let data = DataV2 {
+        name: metadata_name(&name),
+        symbol: current.symbol.trim_end_matches('\0').to_string(),
+        uri: current.uri.trim_end_matches('\0').to_string(),
+        seller_fee_basis_points: current.seller_fee_basis_points,
+        creators: current.creators.clone(),
+        collection: current.collection.clone(),
+        // Drops the stale uses of coupons minted while they were still set
+        uses: None,
+    };
+
+    update_metadata_accounts_v2(
+        CpiContext::new_with_signer(
+            token_metadata_program.clone(),
+            UpdateMetadataAccountsV2 {
+                metadata: metadata.clone(),
+                update_authority: deal.clone(),
+            },
+            deal_seeds,
+        ),
+        None,
+        Some(data),
+        None,
+        None,
+    )
+}
+
+// Same as mark_metadata_redeemed for soulbound coupons, whose metadata lives on
+// the Token-2022 mint. mint_coupon already paid rent for the longer name.
+fn mark_token_metadata_redeemed<'info>(
+    mint: &AccountInfo<'info>,
+    deal: &AccountInfo<'info>,
+    token_program: &AccountInfo<'info>,
+    deal_seeds: &[&[&[u8]]],
+) -> Result<()> {
+    let name = {
+        let data = mint.try_borrow_data()?;
+        let state = StateWithExtensions::<spl_token_2022::state::Mint>::unpack(&data)?;
+        state.get_variable_len_extension::<TokenMetadata>()?.name
+    };
+
+    token_metadata_update_field(
+        CpiContext::new_with_signer(
+            token_program.clone(),
+            TokenMetadataUpdateField {
+                program_id: token_program.clone(),
+                metadata: mint.clone(),
+                update_authority: deal.clone(),
+            },
+            deal_seeds,
+        ),
+        Field::Name,
+        format!("{REDEEMED_PREFIX}{name}"),
+    )
+}
+
+// Lamports the rewards vault can pay out while staying rent exempt
+fn rewards_available(vault: &SystemAccount) -> Result<u64> {
+    let reserve = Rent::get()?.minimum_balance(0);
+    Ok(vault.lamports().saturating_sub(reserve))
+}
+
+// Pays staking rewards out of the vault PDA, never touching its rent-exempt reserve
+fn pay_rewards<'info>(
+    system_program: &Program<'info, System>,
+    vault: &SystemAccount<'info>,
+    recipient: &AccountInfo<'info>,
+    vault_bump: u8,
+    amount: u64,
+) -> Result<()> {
+    require!(amount <= rewards_available(vault)?, DealError::InsufficientRewardsFunds);
+
+    let vault_seeds: &[&[&[u8]]] = &[&[b"rewards_vault", &[vault_bump]]];
+    let transfer_ctx = CpiContext::new_with_signer(
+        system_program.to_account_info(),
+        Transfer {
+            from: vault.to_account_info(),
+            to: recipient.clone(),
+        },
+        vault_seeds,
+    );
+    transfer(transfer_ctx, amount)
+}
+
+// Moves a single coupon NFT. Hooked Token-2022 coupons need the coupon hook's
+// accounts for the transfer, which callers pass in remaining_accounts.
+fn transfer_coupon_nft<'info>(
+    coupon: &mut Account<'info, Coupon>,
+    token_program: &AccountInfo<'info>,
+    from: &AccountInfo<'info>,
+    mint: &AccountInfo<'info>,
+    to: &AccountInfo<'info>,
+    authority: &AccountInfo<'info>,
+    hook_accounts: &[AccountInfo<'info>],
+    signer_seeds: &[&[&[u8]]],
+) -> Result<()> {
+    // The hook lets the transfer through when it sees this flag, however deep
+    // in a CPI chain the call is, so it's written out before the transfer
+    coupon.in_program_transfer = true;
+    coupon.exit(&crate::ID)?;
+
+    spl_token_2022::onchain::invoke_transfer_checked(
+        token_program.key,
+        from.clone(),
+        mint.clone(),
+        to.clone(),
+        authority.clone(),
+        hook_accounts,
+        1,
+        0,
+        signer_seeds,
+    )?;
+
+    coupon.in_program_transfer = false;
+    Ok(())
+}
 
-    Ok(rewards)
+// Moves an escrowed coupon NFT out of its listing and closes the escrow,
+// refunding its rent to the seller who paid for it
+fn release_escrow<'info>(
+    coupon: &mut Account<'info, Coupon>,
+    token_program: &Interface<'info, TokenInterface>,
+    mint: &InterfaceAccount<'info, token_interface::Mint>,
+    escrow: &InterfaceAccount<'info, token_interface::TokenAccount>,
+    destination: &InterfaceAccount<'info, token_interface::TokenAccount>,
+    listing: &AccountInfo<'info>,
+    seller: &AccountInfo<'info>,
+    hook_accounts: &[AccountInfo<'info>],
+    listing_seeds: &[&[&[u8]]],
+) -> Result<()> {
+    transfer_coupon_nft(
+        coupon,
+        &token_program.to_account_info(),
+        &escrow.to_account_info(),
+        &mint.to_account_info(),
+        &destination.to_account_info(),
+        listing,
+        hook_accounts,
+        listing_seeds,
+    )?;
+
+    let close_ctx = CpiContext::new_with_signer(
+        token_program.to_account_info(),
+        token_interface::CloseAccount {
+            account: escrow.to_account_info(),
+            destination: seller.clone(),
+            authority: listing.clone(),
+        },
+        listing_seeds,
+    );
+    token_interface::close_account(close_ctx)
 }
 
+#[event_cpi]
 #[derive(Accounts)]
-#[instruction(title: String)]
-pub struct CreateDeal<'info> {
+pub struct InitializeConfig<'info> {
     #[account(
         init,
-        payer = merchant,
-        space = 8 + Deal::INIT_SPACE,
-        seeds = [b"deal", merchant.key().as_ref(), title.as_bytes()],
+        payer = admin,
+        space = 8 + PlatformConfig::INIT_SPACE,
+        seeds = [b"platform_config"],
         bump
     )]
-    pub deal: Account<'info, Deal>,
+    pub platform_config: Account<'info, PlatformConfig>,
+
+    // Only the program's upgrade authority may bootstrap the config
+    #[account(
+        seeds = [crate::ID.as_ref()],
+        bump,
+        seeds::program = anchor_lang::solana_program::bpf_loader_upgradeable::ID,
+        constraint = program_data.upgrade_authority_address == Some(admin.key()) @ DealError::UnauthorizedAdmin
+    )]
+    pub program_data: Account<'info, ProgramData>,
 
     #[account(mut)]
-    pub merchant: Signer<'info>,
+    pub admin: Signer<'info>,
 
     pub system_program: Program<'info, System>,
 }
 
+#[event_cpi]
 #[derive(Accounts)]
-pub struct UpdateDeal<'info> {
+pub struct UpdateConfig<'info> {
     #[account(
         mut,
-        has_one = merchant
+        seeds = [b"platform_config"],
+        bump = platform_config.bump,
+        has_one = admin @ DealError::UnauthorizedAdmin
     )]
-    pub deal: Account<'info, Deal>,
+    pub platform_config: Account<'info, PlatformConfig>,
 
-    pub merchant: Signer<'info>,
+    pub admin: Signer<'info>,
 }
 
+#[event_cpi]
 #[derive(Accounts)]
-#[instruction(deal_id: Pubkey)]
-pub struct MintCoupon<'info> {
-    #[account(mut)]
-    pub deal: Account<'info, Deal>,
+pub struct CreateDeal<'info> {
+    #[account(
+        init_if_needed,
+        payer = merchant,
+        space = 8 + MerchantState::INIT_SPACE,
+        seeds = [b"merchant", merchant.key().as_ref()],
+        bump
+    )]
+    pub merchant_state: Account<'info, MerchantState>,
 
     #[account(
         init,
-        payer = user,
-        space = 8 + Coupon::INIT_SPACE,
-        seeds = [b"coupon", deal.key().as_ref(), deal.current_supply.to_le_bytes().as_ref()],
+        payer = merchant,
+        space = 8 + Deal::INIT_SPACE,
+        seeds = [b"deal", merchant.key().as_ref(), merchant_state.deal_count.to_le_bytes().as_ref()],
         bump
     )]
-    pub coupon: Account<'info, Coupon>,
+    pub deal: Account<'info, Deal>,
+
+    #[account(mut)]
+    pub merchant: Signer<'info>,
+
+    /// SPL token the deal is priced in; omit to price in SOL
+    pub payment_mint: Option<Account<'info, Mint>>,
 
+    /// Holds mint payments until redemption when the deal escrows payments
+    #[account(mut, seeds = [b"deal_vault", deal.key().as_ref()], bump)]
+    pub deal_vault: SystemAccount<'info>,
+
+    /// The deal's collection NFT, minted to and controlled by the deal PDA
     #[account(
         init,
-        payer = user,
+        payer = merchant,
+        seeds = [b"collection", deal.key().as_ref()],
+        bump,
         mint::decimals = 0,
-        mint::authority = mint,
-        mint::freeze_authority = mint,
+        mint::authority = deal,
+        mint::freeze_authority = deal,
     )]
-    pub mint: Account<'info, Mint>,
+    pub collection_mint: Box<Account<'info, Mint>>,
 
     #[account(
         init,
-        payer = user,
-        associated_token::mint = mint,
-        associated_token::authority = user,
+        payer = merchant,
+        associated_token::mint = collection_mint,
+        associated_token::authority = deal,
     )]
-    pub token_account: Account<'info, TokenAccount>,
-
-    /// CHECK: Metadata account
-    #[account(mut)]
-    pub metadata: UncheckedAccount<'info>,
+    pub collection_token_account: Box<Account<'info, TokenAccount>>,
 
-    /// CHECK: Merchant account to receive payment
+    /// CHECK: Collection metadata, created by the metadata program
     #[account(
         mut,
-        constraint = merchant.key() == deal.merchant @ DealError::UnauthorizedMerchant
+        seeds = [b"metadata", token_metadata_program.key().as_ref(), collection_mint.key().as_ref()],
+        seeds::program = token_metadata_program.key(),
+        bump
     )]
-    pub merchant: UncheckedAccount<'info>,
+    pub collection_metadata: UncheckedAccount<'info>,
 
-    #[account(mut)]
-    pub user: Signer<'info>,
+    /// CHECK: Collection master edition, created by the metadata program
+    #[account(
+        mut,
+        seeds = [b"metadata", token_metadata_program.key().as_ref(), collection_mint.key().as_ref(), b"edition"],
+        seeds::program = token_metadata_program.key(),
+        bump
+    )]
+    pub collection_master_edition: UncheckedAccount<'info>,
 
     pub rent: Sysvar<'info, Rent>,
     pub system_program: Program<'info, System>,
@@ -455,122 +2752,760 @@ pub struct MintCoupon<'info> {
     pub token_metadata_program: Program<'info, Metadata>,
 }
 
+#[event_cpi]
 #[derive(Accounts)]
-pub struct RedeemCoupon<'info> {
+pub struct MigrateDeal<'info> {
+    #[account(
+        init_if_needed,
+        payer = merchant,
+        space = 8 + MerchantState::INIT_SPACE,
+        seeds = [b"merchant", merchant.key().as_ref()],
+        bump
+    )]
+    pub merchant_state: Account<'info, MerchantState>,
+
+    /// CHECK: Legacy deal account, validated and rewritten in the handler
+    #[account(mut, owner = crate::ID)]
+    pub deal: UncheckedAccount<'info>,
+
     #[account(mut)]
-    pub coupon: Account<'info, Coupon>,
+    pub merchant: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
 
+#[event_cpi]
+#[derive(Accounts)]
+pub struct UpdateDeal<'info> {
+    #[account(
+        mut,
+        has_one = merchant
+    )]
     pub deal: Account<'info, Deal>,
 
     pub merchant: Signer<'info>,
 }
 
+#[event_cpi]
 #[derive(Accounts)]
-pub struct TransferCoupon<'info> {
+pub struct MigrateCoupon<'info> {
+    /// CHECK: Legacy coupon account, validated and rewritten in the handler
+    #[account(mut, owner = crate::ID)]
+    pub coupon: UncheckedAccount<'info>,
+
+    // Deals have to be migrated before their coupons
     #[account(mut)]
-    pub coupon: Account<'info, Coupon>,
+    pub deal: Account<'info, Deal>,
 
-    pub current_owner: Signer<'info>,
+    /// CHECK: Stake record for the coupon, if it was ever staked
+    #[account(seeds = [b"staked_coupon", coupon.key().as_ref()], bump)]
+    pub staked_coupon: UncheckedAccount<'info>,
 
-    /// CHECK: New owner pubkey
-    pub new_owner: UncheckedAccount<'info>,
-}
+    /// CHECK: Marketplace listing for the coupon, if it was ever listed
+    #[account(seeds = [b"listing", coupon.key().as_ref()], bump)]
+    pub listing: UncheckedAccount<'info>,
 
-#[derive(Accounts)]
-pub struct RateDeal<'info> {
     #[account(mut)]
-    pub deal: Account<'info, Deal>,
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
 
+#[event_cpi]
+#[derive(Accounts)]
+pub struct CancelDeal<'info> {
     #[account(
-        init_if_needed,
-        payer = user,
-        space = 8 + DealRating::INIT_SPACE,
-        seeds = [b"rating", deal.key().as_ref(), user.key().as_ref()],
-        bump
+        mut,
+        has_one = merchant @ DealError::UnauthorizedMerchant
     )]
-    pub deal_rating: Account<'info, DealRating>,
+    pub deal: Account<'info, Deal>,
+
+    /// Refund vault, shared with buyer-protection escrow
+    #[account(mut, seeds = [b"deal_vault", deal.key().as_ref()], bump)]
+    pub deal_vault: SystemAccount<'info>,
 
     #[account(mut)]
-    pub user: Signer<'info>,
+    pub merchant: Signer<'info>,
+
+    // Only needed when the deal is priced in an SPL token
+    pub payment_mint: Option<Box<Account<'info, Mint>>>,
+    #[account(mut)]
+    pub merchant_payment_account: Option<Box<Account<'info, TokenAccount>>>,
+    #[account(mut)]
+    pub vault_payment_account: Option<Box<Account<'info, TokenAccount>>>,
 
     pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
 }
 
+#[event_cpi]
 #[derive(Accounts)]
-#[instruction(timestamp: i64)]
-pub struct AddComment<'info> {
-    pub deal: Account<'info, Deal>,
-
+pub struct CloseDeal<'info> {
     #[account(
-        init,
-        payer = author,
-        space = 8 + Comment::INIT_SPACE,
-        seeds = [b"comment", deal.key().as_ref(), author.key().as_ref(), &timestamp.to_le_bytes()],
-        bump
+        mut,
+        has_one = merchant,
+        close = merchant
     )]
-    pub comment: Account<'info, Comment>,
+    pub deal: Account<'info, Deal>,
+
+    #[account(mut, seeds = [b"deal_vault", deal.key().as_ref()], bump)]
+    pub deal_vault: SystemAccount<'info>,
 
     #[account(mut)]
-    pub author: Signer<'info>,
+    pub merchant: Signer<'info>,
+
+    // Only needed to sweep the vault of a token-priced deal
+    pub payment_mint: Option<Box<Account<'info, Mint>>>,
+    #[account(mut)]
+    pub merchant_payment_account: Option<Box<Account<'info, TokenAccount>>>,
+    #[account(mut)]
+    pub vault_payment_account: Option<Box<Account<'info, TokenAccount>>>,
 
     pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
 }
 
+#[event_cpi]
 #[derive(Accounts)]
-pub struct ListCoupon<'info> {
+#[instruction(deal_id: Pubkey)]
+pub struct MintCoupon<'info> {
     #[account(mut)]
-    pub coupon: Account<'info, Coupon>,
+    pub deal: Account<'info, Deal>,
 
     #[account(
         init,
-        payer = seller,
-        space = 8 + Listing::INIT_SPACE,
-        seeds = [b"listing", coupon.key().as_ref()],
+        payer = user,
+        space = 8 + Coupon::INIT_SPACE,
+        seeds = [b"coupon", deal.key().as_ref(), deal.current_supply.to_le_bytes().as_ref()],
         bump
     )]
-    pub listing: Account<'info, Listing>,
+    pub coupon: Account<'info, Coupon>,
 
+    // Created in the handler under whichever token program the deal uses
     #[account(mut)]
-    pub seller: Signer<'info>,
-
-    pub system_program: Program<'info, System>,
-}
+    pub mint: Signer<'info>,
 
-#[derive(Accounts)]
-pub struct BuyCoupon<'info> {
+    /// CHECK: The user's associated token account, created in the handler; the
+    /// associated token program checks the address
     #[account(mut)]
-    pub listing: Account<'info, Listing>,
+    pub token_account: UncheckedAccount<'info>,
 
+    /// CHECK: Metaplex metadata account; unused for soulbound coupons
     #[account(mut)]
-    pub coupon: Account<'info, Coupon>,
+    pub metadata: UncheckedAccount<'info>,
 
-    /// CHECK: Seller receiving payment
+    /// CHECK: Merchant account to receive payment
     #[account(
         mut,
-        constraint = seller.key() == listing.seller @ DealError::InvalidListing
+        constraint = merchant.key() == deal.merchant @ DealError::UnauthorizedMerchant
     )]
-    pub seller: UncheckedAccount<'info>,
-
-    #[account(mut)]
-    pub buyer: Signer<'info>,
+    pub merchant: UncheckedAccount<'info>,
 
-    /// CHECK: Platform wallet receiving fees
     #[account(mut)]
-    pub platform_wallet: UncheckedAccount<'info>,
+    pub user: Signer<'info>,
 
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = 8 + PurchaseRecord::INIT_SPACE,
+        seeds = [b"purchase", deal.key().as_ref(), user.key().as_ref()],
+        bump
+    )]
+    pub purchase_record: Box<Account<'info, PurchaseRecord>>,
+
+    // Only needed when the deal is priced in an SPL token
+    pub payment_mint: Option<Box<Account<'info, Mint>>>,
+    #[account(mut)]
+    pub user_payment_account: Option<Box<Account<'info, TokenAccount>>>,
+    #[account(mut)]
+    pub merchant_payment_account: Option<Box<Account<'info, TokenAccount>>>,
+
+    #[account(mut, seeds = [b"deal_vault", deal.key().as_ref()], bump)]
+    pub deal_vault: SystemAccount<'info>,
+    // The vault's token account, used instead of the merchant's for escrowed deals
+    #[account(mut)]
+    pub vault_payment_account: Option<Box<Account<'info, TokenAccount>>>,
+
+    /// CHECK: The deal's collection mint; unused for legacy deals without one
+    #[account(seeds = [b"collection", deal.key().as_ref()], bump)]
+    pub collection_mint: UncheckedAccount<'info>,
+    /// CHECK: Collection metadata, checked by the metadata program
+    #[account(
+        mut,
+        seeds = [b"metadata", token_metadata_program.key().as_ref(), collection_mint.key().as_ref()],
+        seeds::program = token_metadata_program.key(),
+        bump
+    )]
+    pub collection_metadata: UncheckedAccount<'info>,
+    /// CHECK: Collection master edition, checked by the metadata program
+    #[account(
+        seeds = [b"metadata", token_metadata_program.key().as_ref(), collection_mint.key().as_ref(), b"edition"],
+        seeds::program = token_metadata_program.key(),
+        bump
+    )]
+    pub collection_master_edition: UncheckedAccount<'info>,
+
+    pub rent: Sysvar<'info, Rent>,
     pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+    pub token_2022_program: Program<'info, Token2022>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub token_metadata_program: Program<'info, Metadata>,
+
+    /// CHECK: The coupon hook's account list for this mint, created by the hook;
+    /// unused for soulbound coupons
+    #[account(
+        mut,
+        seeds = [b"extra-account-metas", mint.key().as_ref()],
+        seeds::program = coupon_hook_program.key(),
+        bump
+    )]
+    pub extra_account_meta_list: UncheckedAccount<'info>,
+    /// CHECK: The coupon transfer-hook program
+    #[account(address = COUPON_HOOK_PROGRAM_ID)]
+    pub coupon_hook_program: UncheckedAccount<'info>,
 }
 
+#[event_cpi]
 #[derive(Accounts)]
-pub struct DelistCoupon<'info> {
+pub struct VerifyCouponCreator<'info> {
+    #[account(
+        seeds = [b"coupon", deal.key().as_ref(), coupon.coupon_index.to_le_bytes().as_ref()],
+        bump = coupon.bump,
+        has_one = deal @ DealError::CouponDealMismatch
+    )]
+    pub coupon: Account<'info, Coupon>,
+
+    #[account(has_one = merchant @ DealError::UnauthorizedMerchant)]
+    pub deal: Account<'info, Deal>,
+
+    pub merchant: Signer<'info>,
+
+    /// CHECK: Coupon metadata; Metaplex checks the merchant is listed as a creator
+    #[account(
+        mut,
+        seeds = [b"metadata", token_metadata_program.key().as_ref(), coupon.mint.as_ref()],
+        seeds::program = token_metadata_program.key(),
+        bump
+    )]
+    pub metadata: UncheckedAccount<'info>,
+
+    pub token_metadata_program: Program<'info, Metadata>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct RedeemCoupon<'info> {
+    #[account(
+        mut,
+        seeds = [b"coupon", deal.key().as_ref(), coupon.coupon_index.to_le_bytes().as_ref()],
+        bump = coupon.bump,
+        has_one = deal @ DealError::CouponDealMismatch,
+        has_one = mint
+    )]
+    pub coupon: Account<'info, Coupon>,
+
+    #[account(
+        mut,
+        has_one = merchant @ DealError::UnauthorizedMerchant
+    )]
+    pub deal: Account<'info, Deal>,
+
     #[account(mut)]
+    pub merchant: Signer<'info>,
+
+    #[account(mut, seeds = [b"deal_vault", deal.key().as_ref()], bump)]
+    pub deal_vault: SystemAccount<'info>,
+
+    // Only needed to release an escrowed token payment
+    pub payment_mint: Option<Box<Account<'info, Mint>>>,
+    #[account(mut)]
+    pub vault_payment_account: Option<Box<Account<'info, TokenAccount>>>,
+    #[account(mut)]
+    pub merchant_payment_account: Option<Box<Account<'info, TokenAccount>>>,
+
+    #[account(mut, mint::token_program = coupon_token_program)]
+    pub mint: Box<InterfaceAccount<'info, token_interface::Mint>>,
+
+    #[account(
+        mut,
+        associated_token::mint = mint,
+        associated_token::authority = coupon.owner,
+        associated_token::token_program = coupon_token_program,
+        constraint = holder_token_account.amount == 1 @ DealError::NotOwner
+    )]
+    pub holder_token_account: Box<InterfaceAccount<'info, token_interface::TokenAccount>>,
+
+    // Only needed for the last use of a coupon on a deal that burns
+    #[account(mut, address = coupon.owner @ DealError::NotOwner)]
+    pub holder: Option<Signer<'info>>,
+
+    /// CHECK: Coupon metadata, renamed once the coupon is used up
+    #[account(
+        mut,
+        seeds = [b"metadata", token_metadata_program.key().as_ref(), mint.key().as_ref()],
+        seeds::program = token_metadata_program.key(),
+        bump
+    )]
+    pub metadata: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+    // The mint's owner: Token-2022, or SPL Token for coupons minted before the hook
+    pub coupon_token_program: Interface<'info, TokenInterface>,
+    pub token_metadata_program: Program<'info, Metadata>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct RedeemAmount<'info> {
+    #[account(
+        mut,
+        seeds = [b"coupon", deal.key().as_ref(), coupon.coupon_index.to_le_bytes().as_ref()],
+        bump = coupon.bump,
+        has_one = deal @ DealError::CouponDealMismatch,
+        has_one = mint
+    )]
+    pub coupon: Account<'info, Coupon>,
+
+    #[account(
+        mut,
+        has_one = merchant @ DealError::UnauthorizedMerchant
+    )]
+    pub deal: Account<'info, Deal>,
+
+    // Log entries are never closed, so the card's history outlives the card
+    #[account(
+        init,
+        payer = merchant,
+        space = 8 + RedemptionEntry::INIT_SPACE,
+        seeds = [b"redemption", coupon.key().as_ref(), coupon.redemption_count.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub redemption_entry: Account<'info, RedemptionEntry>,
+
+    #[account(mut, mint::token_program = coupon_token_program)]
+    pub mint: InterfaceAccount<'info, token_interface::Mint>,
+
+    #[account(
+        mut,
+        token::mint = mint,
+        token::authority = holder,
+        token::token_program = coupon_token_program,
+        constraint = holder_token_account.amount == 1 @ DealError::NotOwner
+    )]
+    pub holder_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    // The merchant charges the card and the holder approves the charge
+    #[account(mut)]
+    pub merchant: Signer<'info>,
+    #[account(mut)]
+    pub holder: Signer<'info>,
+
+    #[account(mut, seeds = [b"deal_vault", deal.key().as_ref()], bump)]
+    pub deal_vault: SystemAccount<'info>,
+
+    // Only needed to release an escrowed token payment
+    pub payment_mint: Option<Box<Account<'info, Mint>>>,
+    #[account(mut)]
+    pub vault_payment_account: Option<Box<Account<'info, TokenAccount>>>,
+    #[account(mut)]
+    pub merchant_payment_account: Option<Box<Account<'info, TokenAccount>>>,
+
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+    pub coupon_token_program: Interface<'info, TokenInterface>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct RefundUnredeemed<'info> {
+    #[account(
+        mut,
+        seeds = [b"coupon", deal.key().as_ref(), coupon.coupon_index.to_le_bytes().as_ref()],
+        bump = coupon.bump,
+        has_one = deal @ DealError::CouponDealMismatch,
+        has_one = mint
+    )]
+    pub coupon: Account<'info, Coupon>,
+
+    #[account(mut)]
+    pub deal: Account<'info, Deal>,
+
+    #[account(mut, mint::token_program = coupon_token_program)]
+    pub mint: InterfaceAccount<'info, token_interface::Mint>,
+
+    #[account(
+        mut,
+        token::mint = mint,
+        token::authority = holder,
+        token::token_program = coupon_token_program,
+        constraint = holder_token_account.amount == 1 @ DealError::NotOwner
+    )]
+    pub holder_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    #[account(mut, seeds = [b"deal_vault", deal.key().as_ref()], bump)]
+    pub deal_vault: SystemAccount<'info>,
+
+    #[account(mut)]
+    pub holder: Signer<'info>,
+
+    // Only needed to refund a token payment
+    pub payment_mint: Option<Box<Account<'info, Mint>>>,
+    #[account(mut)]
+    pub vault_payment_account: Option<Box<Account<'info, TokenAccount>>>,
+    #[account(mut)]
+    pub holder_payment_account: Option<Box<Account<'info, TokenAccount>>>,
+
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+    pub coupon_token_program: Interface<'info, TokenInterface>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct ClaimCancellationRefund<'info> {
+    #[account(
+        mut,
+        seeds = [b"coupon", deal.key().as_ref(), coupon.coupon_index.to_le_bytes().as_ref()],
+        bump = coupon.bump,
+        has_one = deal @ DealError::CouponDealMismatch,
+        has_one = mint
+    )]
+    pub coupon: Account<'info, Coupon>,
+
+    #[account(mut)]
+    pub deal: Account<'info, Deal>,
+
+    #[account(mut, mint::token_program = coupon_token_program)]
+    pub mint: InterfaceAccount<'info, token_interface::Mint>,
+
+    #[account(
+        mut,
+        token::mint = mint,
+        token::authority = holder,
+        token::token_program = coupon_token_program,
+        constraint = holder_token_account.amount == 1 @ DealError::NotOwner
+    )]
+    pub holder_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    #[account(mut, seeds = [b"deal_vault", deal.key().as_ref()], bump)]
+    pub deal_vault: SystemAccount<'info>,
+
+    #[account(mut)]
+    pub holder: Signer<'info>,
+
+    // Only needed to refund a token payment
+    pub payment_mint: Option<Box<Account<'info, Mint>>>,
+    #[account(mut)]
+    pub vault_payment_account: Option<Box<Account<'info, TokenAccount>>>,
+    #[account(mut)]
+    pub holder_payment_account: Option<Box<Account<'info, TokenAccount>>>,
+
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+    pub coupon_token_program: Interface<'info, TokenInterface>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct TransferCoupon<'info> {
+    #[account(mut, has_one = mint, has_one = deal @ DealError::CouponDealMismatch)]
+    pub coupon: Account<'info, Coupon>,
+
+    #[account(constraint = deal.transferable @ DealError::CouponNotTransferable)]
+    pub deal: Account<'info, Deal>,
+
+    pub mint: InterfaceAccount<'info, token_interface::Mint>,
+
+    #[account(
+        mut,
+        token::mint = mint,
+        token::authority = current_owner,
+        token::token_program = token_program,
+        constraint = from_token_account.amount == 1 @ DealError::NotOwner
+    )]
+    pub from_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    #[account(
+        init_if_needed,
+        payer = current_owner,
+        associated_token::mint = mint,
+        associated_token::authority = new_owner,
+        associated_token::token_program = token_program,
+    )]
+    pub to_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    #[account(mut)]
+    pub current_owner: Signer<'info>,
+
+    /// CHECK: New owner pubkey
+    pub new_owner: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+    // The coupon's own token program, so a soulbound coupon reaches the deal check
+    pub token_program: Interface<'info, TokenInterface>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    // remaining_accounts: the coupon hook's accounts, see transfer_coupon_nft
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct SyncCouponOwner<'info> {
+    #[account(mut, has_one = mint)]
+    pub coupon: Account<'info, Coupon>,
+
+    pub mint: InterfaceAccount<'info, token_interface::Mint>,
+
+    #[account(token::mint = mint)]
+    pub destination_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    // Only the coupon hook can sign for this PDA
+    #[account(seeds = [HOOK_AUTHORITY_SEED], bump, seeds::program = COUPON_HOOK_PROGRAM_ID)]
+    pub hook_authority: Signer<'info>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct RateDeal<'info> {
+    #[account(mut)]
+    pub deal: Account<'info, Deal>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = 8 + DealRating::INIT_SPACE,
+        seeds = [b"rating", deal.key().as_ref(), user.key().as_ref()],
+        bump
+    )]
+    pub deal_rating: Account<'info, DealRating>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+#[instruction(timestamp: i64)]
+pub struct AddComment<'info> {
+    pub deal: Account<'info, Deal>,
+
+    #[account(
+        init,
+        payer = author,
+        space = 8 + Comment::INIT_SPACE,
+        seeds = [b"comment", deal.key().as_ref(), author.key().as_ref(), &timestamp.to_le_bytes()],
+        bump
+    )]
+    pub comment: Account<'info, Comment>,
+
+    #[account(mut)]
+    pub author: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct ListCoupon<'info> {
+    #[account(mut, has_one = mint, has_one = deal @ DealError::CouponDealMismatch)]
+    pub coupon: Account<'info, Coupon>,
+
+    #[account(constraint = deal.transferable @ DealError::CouponNotTransferable)]
+    pub deal: Account<'info, Deal>,
+
+    pub mint: InterfaceAccount<'info, token_interface::Mint>,
+
+    #[account(
+        mut,
+        token::mint = mint,
+        token::authority = seller,
+        token::token_program = token_program,
+        constraint = seller_token_account.amount == 1 @ DealError::NotOwner
+    )]
+    pub seller_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    #[account(
+        init,
+        payer = seller,
+        space = 8 + Listing::INIT_SPACE,
+        seeds = [b"listing", coupon.key().as_ref()],
+        bump
+    )]
+    pub listing: Account<'info, Listing>,
+
+    #[account(
+        init,
+        payer = seller,
+        associated_token::mint = mint,
+        associated_token::authority = listing,
+        associated_token::token_program = token_program,
+    )]
+    pub escrow_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    #[account(mut)]
+    pub seller: Signer<'info>,
+
+    /// SPL token the coupon is priced in; omit to price in SOL
+    pub payment_mint: Option<Account<'info, Mint>>,
+
+    pub system_program: Program<'info, System>,
+    // The coupon's own token program, so a soulbound coupon reaches the deal check
+    pub token_program: Interface<'info, TokenInterface>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    // remaining_accounts: the coupon hook's accounts, see transfer_coupon_nft
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct BuyCoupon<'info> {
+    #[account(
+        mut,
+        seeds = [b"listing", coupon.key().as_ref()],
+        bump = listing.bump,
+        has_one = coupon @ DealError::InvalidListing,
+        constraint = listing.is_active @ DealError::ListingInactive,
+        // Closed so the coupon can be listed again
+        close = seller
+    )]
+    pub listing: Account<'info, Listing>,
+
+    #[account(mut, has_one = mint, has_one = deal)]
+    pub coupon: Account<'info, Coupon>,
+
+    #[account(
+        has_one = merchant @ DealError::UnauthorizedMerchant,
+        constraint = deal.transferable @ DealError::CouponNotTransferable
+    )]
+    pub deal: Box<Account<'info, Deal>>,
+
+    /// CHECK: Merchant receiving the resale royalty, must match the deal
+    #[account(mut)]
+    pub merchant: UncheckedAccount<'info>,
+
+    pub mint: InterfaceAccount<'info, token_interface::Mint>,
+
+    #[account(
+        mut,
+        associated_token::mint = mint,
+        associated_token::authority = listing,
+        associated_token::token_program = coupon_token_program,
+    )]
+    pub escrow_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    #[account(
+        init_if_needed,
+        payer = buyer,
+        associated_token::mint = mint,
+        associated_token::authority = buyer,
+        associated_token::token_program = coupon_token_program,
+    )]
+    pub buyer_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    /// CHECK: Seller receiving payment
+    #[account(
+        mut,
+        constraint = seller.key() == listing.seller @ DealError::InvalidListing
+    )]
+    pub seller: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub buyer: Signer<'info>,
+
+    #[account(
+        seeds = [b"platform_config"],
+        bump = platform_config.bump
+    )]
+    pub platform_config: Account<'info, PlatformConfig>,
+
+    /// CHECK: Treasury receiving fees, must match the platform config
+    #[account(
+        mut,
+        constraint = platform_wallet.key() == platform_config.treasury @ DealError::InvalidTreasury
+    )]
+    pub platform_wallet: UncheckedAccount<'info>,
+
+    // Only needed when the listing is priced in an SPL token
+    pub payment_mint: Option<Box<Account<'info, Mint>>>,
+    #[account(mut)]
+    pub buyer_payment_account: Option<Box<Account<'info, TokenAccount>>>,
+    #[account(mut)]
+    pub seller_payment_account: Option<Box<Account<'info, TokenAccount>>>,
+    #[account(mut)]
+    pub treasury_payment_account: Option<Box<Account<'info, TokenAccount>>>,
+    // Only needed for token sales of deals with a resale royalty
+    #[account(mut)]
+    pub merchant_payment_account: Option<Box<Account<'info, TokenAccount>>>,
+
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+    pub coupon_token_program: Interface<'info, TokenInterface>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    // remaining_accounts: the coupon hook's accounts, see transfer_coupon_nft
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct DelistCoupon<'info> {
+    #[account(
+        mut,
+        has_one = seller @ DealError::NotOwner,
+        has_one = coupon @ DealError::InvalidListing,
+        close = seller
+    )]
     pub listing: Account<'info, Listing>,
 
+    #[account(mut, has_one = mint)]
     pub coupon: Account<'info, Coupon>,
 
+    pub mint: InterfaceAccount<'info, token_interface::Mint>,
+
+    #[account(
+        mut,
+        associated_token::mint = mint,
+        associated_token::authority = listing,
+        associated_token::token_program = token_program,
+    )]
+    pub escrow_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    #[account(
+        init_if_needed,
+        payer = seller,
+        associated_token::mint = mint,
+        associated_token::authority = seller,
+        associated_token::token_program = token_program,
+    )]
+    pub seller_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    #[account(mut)]
     pub seller: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    // remaining_accounts: the coupon hook's accounts, see transfer_coupon_nft
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct CloseStaleListing<'info> {
+    /// CHECK: Listing in any layout, validated and closed in the handler
+    #[account(mut, owner = crate::ID, seeds = [b"listing", coupon.key().as_ref()], bump)]
+    pub listing: UncheckedAccount<'info>,
+
+    /// CHECK: Only used to derive the listing address
+    pub coupon: UncheckedAccount<'info>,
+
+    /// CHECK: Checked against the listing's seller, who gets its rent back
+    #[account(mut)]
+    pub seller: UncheckedAccount<'info>,
+
+    pub authority: Signer<'info>,
 }
 
+#[event_cpi]
 #[derive(Accounts)]
 pub struct InitializeRewardsPool<'info> {
     #[account(
@@ -588,10 +3523,68 @@ pub struct InitializeRewardsPool<'info> {
     pub system_program: Program<'info, System>,
 }
 
+#[event_cpi]
+#[derive(Accounts)]
+pub struct MigrateRewardsPool<'info> {
+    /// CHECK: Legacy rewards pool, validated and rewritten in the handler
+    #[account(mut, owner = crate::ID, seeds = [b"rewards_pool"], bump)]
+    pub rewards_pool: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct MigrateStakedCoupon<'info> {
+    /// CHECK: Legacy stake record, validated and rewritten in the handler
+    #[account(mut, owner = crate::ID)]
+    pub staked_coupon: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct FundRewardsPool<'info> {
+    #[account(
+        seeds = [b"rewards_pool"],
+        bump = rewards_pool.bump
+    )]
+    pub rewards_pool: Account<'info, RewardsPool>,
+
+    /// Lamport-only PDA that holds the SOL paid out as rewards
+    #[account(
+        mut,
+        seeds = [b"rewards_vault"],
+        bump
+    )]
+    pub rewards_vault: SystemAccount<'info>,
+
+    #[account(mut)]
+    pub funder: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[event_cpi]
 #[derive(Accounts)]
 pub struct StakeCouponCtx<'info> {
+    #[account(mut)]
     pub coupon: Account<'info, Coupon>,
 
+    #[account(
+        token::mint = coupon.mint,
+        token::authority = staker,
+        constraint = staker_token_account.amount == 1 @ DealError::NotOwner
+    )]
+    pub staker_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
     #[account(
         init,
         payer = staker,
@@ -601,7 +3594,11 @@ pub struct StakeCouponCtx<'info> {
     )]
     pub staked_coupon: Account<'info, StakedCoupon>,
 
-    #[account(mut)]
+    #[account(
+        mut,
+        seeds = [b"rewards_pool"],
+        bump = rewards_pool.bump
+    )]
     pub rewards_pool: Account<'info, RewardsPool>,
 
     #[account(mut)]
@@ -610,42 +3607,187 @@ pub struct StakeCouponCtx<'info> {
     pub system_program: Program<'info, System>,
 }
 
+#[event_cpi]
 #[derive(Accounts)]
 pub struct UnstakeCouponCtx<'info> {
     #[account(
         mut,
+        seeds = [b"staked_coupon", coupon.key().as_ref()],
+        bump,
+        has_one = coupon @ DealError::CouponMismatch,
+        has_one = staker @ DealError::NotOwner,
         close = staker
     )]
     pub staked_coupon: Account<'info, StakedCoupon>,
 
+    #[account(mut)]
     pub coupon: Account<'info, Coupon>,
 
-    #[account(mut)]
+    #[account(
+        mut,
+        seeds = [b"rewards_pool"],
+        bump = rewards_pool.bump
+    )]
     pub rewards_pool: Account<'info, RewardsPool>,
 
+    #[account(
+        mut,
+        seeds = [b"rewards_vault"],
+        bump
+    )]
+    pub rewards_vault: SystemAccount<'info>,
+
     #[account(mut)]
     pub staker: Signer<'info>,
 
     pub system_program: Program<'info, System>,
 }
 
+#[event_cpi]
 #[derive(Accounts)]
 pub struct ClaimRewardsCtx<'info> {
     #[account(mut)]
     pub staked_coupon: Account<'info, StakedCoupon>,
 
-    #[account(mut)]
+    #[account(
+        mut,
+        seeds = [b"rewards_pool"],
+        bump = rewards_pool.bump
+    )]
     pub rewards_pool: Account<'info, RewardsPool>,
 
+    #[account(
+        mut,
+        seeds = [b"rewards_vault"],
+        bump
+    )]
+    pub rewards_vault: SystemAccount<'info>,
+
     #[account(mut)]
     pub staker: Signer<'info>,
 
     pub system_program: Program<'info, System>,
 }
 
+#[account]
+#[derive(InitSpace)]
+pub struct PlatformConfig {
+    pub admin: Pubkey,
+    pub treasury: Pubkey,
+    pub fee_bps: u16,
+    pub max_fee_lamports: u64, // 0 = uncapped
+    pub bump: u8,
+}
+
+impl PlatformConfig {
+    /// Fee at the configured rate, without the per-sale lamport cap.
+    pub fn rate_fee(&self, amount: u64) -> Result<u64> {
+        let fee = (amount as u128)
+            .checked_mul(self.fee_bps as u128)
+            .ok_or(DealError::MathOverflow)?
+            / BPS_DENOMINATOR as u128;
+        Ok(u64::try_from(fee).map_err(|_| DealError::MathOverflow)?)
+    }
+
+    pub fn fee_for(&self, amount: u64) -> Result<u64> {
+        let fee = self.rate_fee(amount)?;
+
+        if self.max_fee_lamports > 0 {
+            Ok(fee.min(self.max_fee_lamports))
+        } else {
+            Ok(fee)
+        }
+    }
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct MerchantState {
+    pub merchant: Pubkey,
+    pub deal_count: u64,
+    pub bump: u8,
+}
+
 #[account]
 #[derive(InitSpace)]
 pub struct Deal {
+    pub merchant: Pubkey,
+    pub deal_id: u64,
+    #[max_len(100)]
+    pub title: String,
+    #[max_len(500)]
+    pub description: String,
+    pub discount_percent: u8,
+    pub max_supply: u64,
+    pub current_supply: u64,
+    pub redeemed_count: u64,
+    pub expiry_timestamp: i64,
+    #[max_len(50)]
+    pub category: String,
+    pub price_lamports: u64,
+    pub is_active: bool,
+    pub total_ratings: u64,
+    pub rating_sum: u64,
+    pub bump: u8,
+    pub payment_mint: Option<Pubkey>, // None = priced in lamports
+    pub escrow_payments: bool, // hold mint payments in the deal vault until redemption
+    pub refunded_count: u64,
+    pub is_cancelled: bool,
+    pub max_per_wallet: u64, // 0 = unlimited
+    pub allowlist_root: Option<[u8; 32]>, // None = open to every wallet
+    pub token_gate: Option<TokenGate>, // None = no holding required
+    pub start_timestamp: i64, // coupons can't be minted before this
+    #[max_len(MAX_REDEMPTION_WINDOWS)]
+    pub redemption_windows: Vec<RedemptionWindow>, // empty = redeemable any time
+    pub uses_per_coupon: u64, // e.g. 5 for a five-coffee punch card
+    pub kind: CouponKind,
+    pub collection_mint: Option<Pubkey>, // None only for deals migrated from the legacy layout
+    pub resale_royalty_bps: u16, // merchant's cut of every buy_coupon sale
+    pub redemption_action: RedemptionAction,
+    pub transferable: bool, // false = soulbound Token-2022 coupons that can't be resold
+    pub outstanding_payments: u64, // what holders paid for coupons not yet redeemed or refunded
+}
+
+impl Deal {
+    /// The merchant's royalty on a resale at `price`.
+    pub fn royalty_for(&self, price: u64) -> Result<u64> {
+        let royalty = (price as u128)
+            .checked_mul(self.resale_royalty_bps as u128)
+            .ok_or(DealError::MathOverflow)?
+            / BPS_DENOMINATOR as u128;
+        Ok(u64::try_from(royalty).map_err(|_| DealError::MathOverflow)?)
+    }
+
+    /// Takes a redeemed or refunded coupon's price out of what a cancellation owes.
+    pub fn settle_payment(&mut self, price_paid: u64) {
+        self.outstanding_payments = self.outstanding_payments.saturating_sub(price_paid);
+    }
+
+    /// Coupons sold that have been neither redeemed nor refunded.
+    pub fn outstanding_coupons(&self) -> u64 {
+        self.current_supply
+            .saturating_sub(self.redeemed_count)
+            .saturating_sub(self.refunded_count)
+    }
+
+    // A deal can be closed once it no longer sells coupons and every coupon
+    // it sold has been redeemed or refunded, so no holder is left with a
+    // dangling coupon
+    pub fn is_closable(&self, now: i64) -> bool {
+        let stopped = !self.is_active || now >= self.expiry_timestamp;
+        let settled = self.redeemed_count.saturating_add(self.refunded_count);
+        stopped && settled >= self.current_supply
+    }
+
+    pub fn is_redeemable_at(&self, now: i64) -> bool {
+        self.redemption_windows.is_empty()
+            || self.redemption_windows.iter().any(|window| window.contains(now))
+    }
+}
+
+// Layout of deals created before sequential ids, used only by migrate_deal
+#[derive(AnchorSerialize, AnchorDeserialize, InitSpace)]
+pub struct LegacyDeal {
     pub merchant: Pubkey,
     #[max_len(100)]
     pub title: String,
@@ -664,16 +3806,81 @@ pub struct Deal {
     pub bump: u8,
 }
 
+// Layout of coupons minted before coupon_index, used only by migrate_coupon
+#[derive(AnchorSerialize, AnchorDeserialize, InitSpace)]
+pub struct LegacyCoupon {
+    pub deal: Pubkey,
+    pub owner: Pubkey,
+    pub mint: Pubkey,
+    pub is_redeemed: bool,
+    pub minted_at: i64,
+    pub redeemed_at: Option<i64>,
+    pub bump: u8,
+}
+
 #[account]
 #[derive(InitSpace)]
 pub struct Coupon {
     pub deal: Pubkey,
     pub owner: Pubkey,
     pub mint: Pubkey,
+    pub coupon_index: u64,
     pub is_redeemed: bool,
     pub minted_at: i64,
     pub redeemed_at: Option<i64>,
     pub bump: u8,
+    /// Set while the NFT sits in a marketplace escrow
+    pub is_listed: bool,
+    /// Set while the coupon is staked for rewards
+    pub is_staked: bool,
+    /// Payment held in the deal vault until this coupon is redeemed or refunded
+    pub escrowed_amount: u64,
+    /// Set once the holder has been refunded; the coupon can no longer be redeemed
+    pub is_refunded: bool,
+    /// Uses granted at mint, copied from the deal's uses_per_coupon
+    pub uses_total: u64,
+    /// Uses left; the coupon is fully redeemed when this reaches zero
+    pub uses_remaining: u64,
+    /// Value left on a stored-value coupon, in the deal's face value units
+    pub balance: u64,
+    /// Number of redeem_amount entries logged, and the index of the next one
+    pub redemption_count: u64,
+    /// Set while this program moves the NFT, so the coupon hook lets it through
+    pub in_program_transfer: bool,
+    /// What the first holder paid at mint; refunds are worked out from it
+    pub price_paid: u64,
+}
+
+impl Coupon {
+    /// Listed or staked coupons can't change hands or be redeemed.
+    pub fn is_locked(&self) -> bool {
+        self.is_listed || self.is_staked
+    }
+}
+
+// Number of coupons a wallet has minted from a deal, for max_per_wallet and
+// allowlist allowances
+#[account]
+#[derive(InitSpace)]
+pub struct PurchaseRecord {
+    pub deal: Pubkey,
+    pub buyer: Pubkey,
+    pub count: u64,
+    pub bump: u8,
+}
+
+// One charge against a stored-value coupon, at [b"redemption", coupon, index]
+#[account]
+#[derive(InitSpace)]
+pub struct RedemptionEntry {
+    pub coupon: Pubkey,
+    pub index: u64,
+    pub merchant: Pubkey,
+    pub holder: Pubkey,
+    pub amount: u64,
+    pub balance_after: u64,
+    pub timestamp: i64,
+    pub bump: u8,
 }
 
 #[account]
@@ -697,6 +3904,18 @@ pub struct Comment {
     pub bump: u8,
 }
 
+// Layout of listings created before payment_mint, which never escrowed the
+// coupon. Later layouts only append fields, so this reads any listing.
+#[derive(AnchorSerialize, AnchorDeserialize, InitSpace)]
+pub struct LegacyListing {
+    pub coupon: Pubkey,
+    pub seller: Pubkey,
+    pub price_lamports: u64,
+    pub is_active: bool,
+    pub created_at: i64,
+    pub bump: u8,
+}
+
 #[account]
 #[derive(InitSpace)]
 pub struct Listing {
@@ -706,15 +3925,75 @@ pub struct Listing {
     pub is_active: bool,
     pub created_at: i64,
     pub bump: u8,
+    pub payment_mint: Option<Pubkey>, // None = priced in lamports
+}
+
+// Layout of pools created before the per-share accumulator, used only by
+// migrate_rewards_pool
+#[derive(AnchorSerialize, AnchorDeserialize, InitSpace)]
+pub struct LegacyRewardsPool {
+    pub total_staked: u64,
+    pub reward_rate_per_day: u64,
+    pub admin: Pubkey,
+    pub bump: u8,
 }
 
 #[account]
 #[derive(InitSpace)]
 pub struct RewardsPool {
     pub total_staked: u64,
-    pub reward_rate_per_day: u64,
+    pub reward_rate_per_day: u64, // Daily budget shared by all stakers
     pub admin: Pubkey,
     pub bump: u8,
+    pub acc_reward_per_share: u128, // Scaled by REWARD_PRECISION
+    pub last_update_ts: i64,
+}
+
+impl RewardsPool {
+    /// Folds the emissions since `last_update_ts` into `acc_reward_per_share`,
+    /// splitting the daily budget evenly across the coupons staked meanwhile.
+    pub fn accrue(&mut self, now: i64) -> Result<()> {
+        if now <= self.last_update_ts {
+            return Ok(());
+        }
+
+        if self.total_staked > 0 {
+            let elapsed = (now - self.last_update_ts) as u128;
+            let increment = elapsed
+                .checked_mul(self.reward_rate_per_day as u128)
+                .and_then(|v| v.checked_mul(REWARD_PRECISION))
+                .and_then(|v| v.checked_div(SECONDS_PER_DAY as u128 * self.total_staked as u128))
+                .ok_or(DealError::MathOverflow)?;
+            self.acc_reward_per_share = self
+                .acc_reward_per_share
+                .checked_add(increment)
+                .ok_or(DealError::MathOverflow)?;
+        }
+
+        self.last_update_ts = now;
+        Ok(())
+    }
+
+    /// Lamports owed to one staked coupon whose debt was recorded at `reward_debt`.
+    pub fn pending_rewards(&self, reward_debt: u128) -> Result<u64> {
+        let owed = self
+            .acc_reward_per_share
+            .checked_sub(reward_debt)
+            .ok_or(DealError::MathOverflow)?
+            / REWARD_PRECISION;
+        u64::try_from(owed).map_err(|_| error!(DealError::MathOverflow))
+    }
+}
+
+// Layout of stake records created before reward_debt, used only by
+// migrate_staked_coupon
+#[derive(AnchorSerialize, AnchorDeserialize, InitSpace)]
+pub struct LegacyStakedCoupon {
+    pub coupon: Pubkey,
+    pub staker: Pubkey,
+    pub staked_at: i64,
+    pub last_claim_at: i64,
+    pub bump: u8,
 }
 
 #[account]
@@ -725,6 +4004,347 @@ pub struct StakedCoupon {
     pub staked_at: i64,
     pub last_claim_at: i64,
     pub bump: u8,
+    pub reward_debt: u128, // acc_reward_per_share already paid out or skipped
+}
+
+// A buyer's allowlist entry and the sibling hashes leading from it to the root
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct AllowlistProof {
+    pub allowance: u64,
+    pub proof: Vec<[u8; 32]>,
+}
+
+// What a wallet must hold to mint from a token-gated deal
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug, InitSpace)]
+pub enum TokenGate {
+    /// Any NFT whose metadata is a verified member of this collection
+    Collection { collection_mint: Pubkey },
+    /// At least `min_amount` base units of a fungible token
+    TokenBalance { mint: Pubkey, min_amount: u64 },
+}
+
+// What a coupon entitles its holder to
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug, InitSpace)]
+pub enum CouponKind {
+    /// The deal's percentage discount, redeemed with redeem_coupon
+    Discount,
+    /// A gift card worth `face_value` (e.g. cents), spent with redeem_amount
+    StoredValue { face_value: u64 },
+}
+
+// What happens to a coupon NFT once its last use is redeemed
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug, InitSpace)]
+pub enum RedemptionAction {
+    /// The deal PDA freezes the holder's token account; only the merchant signs
+    Freeze,
+    /// The token is burned and its account closed; the holder must co-sign
+    Burn,
+}
+
+// A recurring daily slot, in UTC, when coupons can be redeemed. `days` is a
+// bitmask with bit 0 = Monday through bit 6 = Sunday; minutes count from
+// midnight and the end is exclusive, e.g. weekdays 15:00-18:00 is
+// { days: 0b0011111, start_minute: 900, end_minute: 1080 }
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug, InitSpace)]
+pub struct RedemptionWindow {
+    pub days: u8,
+    pub start_minute: u16,
+    pub end_minute: u16,
+}
+
+impl RedemptionWindow {
+    pub fn is_valid(&self) -> bool {
+        self.days != 0
+            && self.days < 1 << 7
+            && self.start_minute < self.end_minute
+            && self.end_minute <= MINUTES_PER_DAY
+    }
+
+    pub fn contains(&self, now: i64) -> bool {
+        let days_since_epoch = now.div_euclid(SECONDS_PER_DAY);
+        // 1970-01-01 was a Thursday, three days after Monday
+        let weekday = (days_since_epoch + 3).rem_euclid(7) as u8;
+        let minute = (now.rem_euclid(SECONDS_PER_DAY) / 60) as u16;
+        self.days & (1 << weekday) != 0 && minute >= self.start_minute && minute < self.end_minute
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DealField {
+    Title,
+    Description,
+    Category,
+    DiscountPercent,
+    MaxSupply,
+    ExpiryTimestamp,
+    IsActive,
+    PriceLamports,
+    MaxPerWallet,
+}
+
+#[event]
+pub struct ConfigUpdated {
+    pub platform_config: Pubkey,
+    pub admin: Pubkey,
+    pub treasury: Pubkey,
+    pub fee_bps: u16,
+    pub max_fee_lamports: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct DealCreated {
+    pub deal: Pubkey,
+    pub merchant: Pubkey,
+    pub deal_id: u64,
+    pub title: String,
+    pub category: String,
+    pub discount_percent: u8,
+    pub max_supply: u64,
+    pub price_lamports: u64,
+    pub payment_mint: Option<Pubkey>,
+    pub escrow_payments: bool,
+    pub max_per_wallet: u64,
+    pub allowlisted: bool,
+    pub token_gate: Option<TokenGate>,
+    pub start_timestamp: i64,
+    pub redemption_windows: Vec<RedemptionWindow>,
+    pub uses_per_coupon: u64,
+    pub kind: CouponKind,
+    pub collection_mint: Option<Pubkey>,
+    pub resale_royalty_bps: u16,
+    pub redemption_action: RedemptionAction,
+    pub transferable: bool,
+    pub expiry_timestamp: i64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct DealUpdated {
+    pub deal: Pubkey,
+    pub merchant: Pubkey,
+    pub changed_fields: Vec<DealField>,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct DealMigrated {
+    pub deal: Pubkey,
+    pub merchant: Pubkey,
+    pub deal_id: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct CouponMigrated {
+    pub deal: Pubkey,
+    pub coupon: Pubkey,
+    pub coupon_index: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct DealCancelled {
+    pub deal: Pubkey,
+    pub merchant: Pubkey,
+    pub outstanding_coupons: u64,
+    pub refunds_owed: u64,
+    pub payment_mint: Option<Pubkey>,
+    pub funded: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct DealClosed {
+    pub deal: Pubkey,
+    pub merchant: Pubkey,
+    pub rent_reclaimed: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct CouponMinted {
+    pub deal: Pubkey,
+    pub coupon: Pubkey,
+    pub mint: Pubkey,
+    pub owner: Pubkey,
+    pub coupon_index: u64,
+    pub price_lamports: u64,
+    pub payment_mint: Option<Pubkey>,
+    pub escrowed_amount: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct CouponCreatorVerified {
+    pub deal: Pubkey,
+    pub coupon: Pubkey,
+    pub merchant: Pubkey,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct CouponRedeemed {
+    pub deal: Pubkey,
+    pub coupon: Pubkey,
+    pub merchant: Pubkey,
+    pub owner: Pubkey,
+    pub released_amount: u64,
+    pub uses_remaining: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct BalanceRedeemed {
+    pub deal: Pubkey,
+    pub coupon: Pubkey,
+    pub merchant: Pubkey,
+    pub owner: Pubkey,
+    pub amount: u64,
+    pub balance_remaining: u64,
+    pub released_amount: u64,
+    pub entry_index: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct CouponRefunded {
+    pub deal: Pubkey,
+    pub coupon: Pubkey,
+    pub owner: Pubkey,
+    pub amount: u64,
+    pub payment_mint: Option<Pubkey>,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct CouponTransferred {
+    pub deal: Pubkey,
+    pub coupon: Pubkey,
+    pub from: Pubkey,
+    pub to: Pubkey,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct DealRated {
+    pub deal: Pubkey,
+    pub user: Pubkey,
+    pub rating: u8,
+    pub total_ratings: u64,
+    pub rating_sum: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct CommentAdded {
+    pub deal: Pubkey,
+    pub comment: Pubkey,
+    pub author: Pubkey,
+    pub created_at: i64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct CouponListed {
+    pub listing: Pubkey,
+    pub coupon: Pubkey,
+    pub seller: Pubkey,
+    pub price_lamports: u64,
+    pub payment_mint: Option<Pubkey>,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct CouponSold {
+    pub listing: Pubkey,
+    pub coupon: Pubkey,
+    pub seller: Pubkey,
+    pub buyer: Pubkey,
+    pub price_lamports: u64,
+    pub payment_mint: Option<Pubkey>,
+    pub seller_amount: u64,
+    pub platform_fee: u64,
+    pub royalty_amount: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct CouponDelisted {
+    pub listing: Pubkey,
+    pub coupon: Pubkey,
+    pub seller: Pubkey,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct StaleListingClosed {
+    pub listing: Pubkey,
+    pub coupon: Pubkey,
+    pub seller: Pubkey,
+    pub was_active: bool,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct RewardsPoolInitialized {
+    pub rewards_pool: Pubkey,
+    pub admin: Pubkey,
+    pub reward_rate_per_day: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct RewardsPoolFunded {
+    pub rewards_pool: Pubkey,
+    pub funder: Pubkey,
+    pub amount: u64,
+    pub vault_balance: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct RewardsPoolMigrated {
+    pub rewards_pool: Pubkey,
+    pub total_staked: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct StakedCouponMigrated {
+    pub staked_coupon: Pubkey,
+    pub coupon: Pubkey,
+    pub staker: Pubkey,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct CouponStaked {
+    pub staked_coupon: Pubkey,
+    pub coupon: Pubkey,
+    pub staker: Pubkey,
+    pub total_staked: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct CouponUnstaked {
+    pub staked_coupon: Pubkey,
+    pub coupon: Pubkey,
+    pub staker: Pubkey,
+    pub rewards: u64,
+    pub forfeited: u64,
+    pub total_staked: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct RewardsClaimed {
+    pub staked_coupon: Pubkey,
+    pub coupon: Pubkey,
+    pub staker: Pubkey,
+    pub amount: u64,
+    pub timestamp: i64,
 }
 
 #[error_code]
@@ -759,4 +4379,114 @@ pub enum DealError {
     InvalidListing,
     #[msg("No rewards to claim")]
     NoRewardsToClaim,
+    #[msg("Title too long")]
+    TitleTooLong,
+    #[msg("Description too long")]
+    DescriptionTooLong,
+    #[msg("Category too long")]
+    CategoryTooLong,
+    #[msg("Invalid deal account")]
+    InvalidDeal,
+    #[msg("Deal already uses the current layout")]
+    DealAlreadyMigrated,
+    #[msg("Arithmetic overflow")]
+    MathOverflow,
+    #[msg("Deal must be expired or inactive with every coupon redeemed")]
+    DealNotClosable,
+    #[msg("Coupon does not belong to this deal")]
+    CouponDealMismatch,
+    #[msg("Platform fee exceeds the maximum allowed")]
+    FeeTooHigh,
+    #[msg("Unauthorized platform admin")]
+    UnauthorizedAdmin,
+    #[msg("Platform wallet does not match the configured treasury")]
+    InvalidTreasury,
+    #[msg("Coupon is locked in a marketplace listing or stake")]
+    CouponLocked,
+    #[msg("Funding amount must be greater than zero")]
+    InvalidFundingAmount,
+    #[msg("Rewards vault has insufficient funds for this payout")]
+    InsufficientRewardsFunds,
+    #[msg("Staked coupon record does not match this coupon")]
+    CouponMismatch,
+    #[msg("Token payment accounts are required for this price")]
+    MissingPaymentAccount,
+    #[msg("Payment mint does not match the price currency")]
+    InvalidPaymentMint,
+    #[msg("Payment token account has the wrong mint or owner")]
+    InvalidPaymentAccount,
+    #[msg("Deal has not expired yet")]
+    DealNotExpired,
+    #[msg("Coupon has no escrowed payment to refund")]
+    NothingToRefund,
+    #[msg("Coupon has been refunded")]
+    CouponRefunded,
+    #[msg("Deal has been cancelled")]
+    DealCancelled,
+    #[msg("Deal has not been cancelled")]
+    DealNotCancelled,
+    #[msg("Wallet has reached this deal's purchase limit")]
+    PurchaseLimitExceeded,
+    #[msg("This deal is allowlisted; an allowlist proof is required")]
+    AllowlistProofRequired,
+    #[msg("Wallet is not on this deal's allowlist")]
+    NotOnAllowlist,
+    #[msg("Wallet has claimed its full allowlist allowance")]
+    AllowanceExhausted,
+    #[msg("Token gate minimum balance must be greater than zero")]
+    InvalidTokenGate,
+    #[msg("Token-gated deal requires the holder's gate accounts")]
+    TokenGateAccountsMissing,
+    #[msg("Gate account is not owned by the expected program, holder or mint")]
+    InvalidGateAccount,
+    #[msg("Holder does not hold enough of the gating token")]
+    InsufficientGateBalance,
+    #[msg("NFT is not a verified member of the gating collection")]
+    NotInGateCollection,
+    #[msg("Start time must be before the expiry")]
+    InvalidStartTime,
+    #[msg("Too many redemption windows")]
+    TooManyRedemptionWindows,
+    #[msg("Redemption windows need at least one day and a start before an end within the day")]
+    InvalidRedemptionWindow,
+    #[msg("Deal has not started yet")]
+    DealNotStarted,
+    #[msg("Coupon can't be redeemed outside the deal's redemption windows")]
+    OutsideRedemptionWindow,
+    #[msg("Coupons must have at least one use")]
+    InvalidUsesPerCoupon,
+    #[msg("Stored-value deals need a face value and single-use coupons")]
+    InvalidCouponKind,
+    #[msg("Instruction does not apply to this kind of coupon")]
+    WrongCouponKind,
+    #[msg("Redeem amount must be greater than zero")]
+    InvalidRedeemAmount,
+    #[msg("Amount exceeds the coupon's remaining balance")]
+    InsufficientBalance,
+    #[msg("Metadata URI too long")]
+    MetadataUriTooLong,
+    #[msg("Resale royalty exceeds the maximum")]
+    RoyaltyTooHigh,
+    #[msg("The holder must sign to burn the coupon")]
+    HolderSignatureRequired,
+    #[msg("Coupons of this deal can't be transferred or resold")]
+    CouponNotTransferable,
+    #[msg("Expiry can't be extended while escrowed payments are outstanding")]
+    ExpiryExtensionLocked,
+    #[msg("Invalid coupon account")]
+    InvalidCoupon,
+    #[msg("Coupon already uses the current layout")]
+    CouponAlreadyMigrated,
+    #[msg("Invalid rewards pool account")]
+    InvalidRewardsPool,
+    #[msg("Rewards pool already uses the current layout")]
+    RewardsPoolAlreadyMigrated,
+    #[msg("Invalid stake record")]
+    InvalidStakedCoupon,
+    #[msg("Stake record already uses the current layout")]
+    StakedCouponAlreadyMigrated,
+    #[msg("Listing holds the coupon in escrow; delist it instead")]
+    ListingEscrowed,
+    #[msg("Close the coupon's active legacy listing before migrating it")]
+    LegacyListingActive,
 }
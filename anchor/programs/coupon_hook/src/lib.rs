@@ -0,0 +1,186 @@
+//! Token-2022 transfer hook for coupon NFTs.
+//!
+//! Every transfer of a coupon mint runs through `transfer_hook`, so a coupon
+//! sent straight through Token-2022 (a wallet, another marketplace) still moves
+//! `Coupon.owner` along with it, and redeemed, staked or listed coupons can't
+//! leave their holder at all. Transfers the deals program makes itself are let
+//! through untouched: it has already checked and updated the record, and the
+//! runtime wouldn't let the hook call back into it anyway. The deals program
+//! flags the coupon record for the length of those transfers, so they're
+//! recognized even when another program reaches the deals program through CPI.
+
+use anchor_lang::prelude::*;
+use anchor_spl::token_interface::{
+    spl_token_2022::{
+        extension::{transfer_hook::TransferHookAccount, BaseStateWithExtensions, StateWithExtensions},
+        state::Account as Token2022Account,
+    },
+    Mint, TokenAccount,
+};
+use basic::{program::Basic, Coupon, HOOK_AUTHORITY_SEED};
+use spl_discriminator::SplDiscriminate;
+use spl_tlv_account_resolution::{account::ExtraAccountMeta, seeds::Seed, state::ExtraAccountMetaList};
+use spl_transfer_hook_interface::instruction::{
+    ExecuteInstruction, InitializeExtraAccountMetaListInstruction,
+};
+
+declare_id!("95rxFZaAjHeeh3aoTn8u4MiVV9e8HkQyURyX3Y3GyW6f");
+
+// Accounts appended to every Execute, after the validation account (index 4)
+pub const EXTRA_ACCOUNT_METAS_LEN: usize = 4;
+const BASIC_PROGRAM_INDEX: u8 = 6;
+
+#[program]
+pub mod coupon_hook {
+    use super::*;
+
+    // Called by the deals program from mint_coupon, while it still signs as the
+    // mint authority. The coupon record doesn't hold any data yet, so it's only
+    // pinned by address; Execute checks it against the mint.
+    #[instruction(discriminator = InitializeExtraAccountMetaListInstruction::SPL_DISCRIMINATOR_SLICE)]
+    pub fn initialize_extra_account_meta_list(
+        ctx: Context<InitializeExtraAccountMetaList>,
+    ) -> Result<()> {
+        let metas = extra_account_metas(&ctx.accounts.coupon.key())?;
+        let mut data = ctx.accounts.extra_account_meta_list.try_borrow_mut_data()?;
+        ExtraAccountMetaList::init::<ExecuteInstruction>(&mut data, &metas)?;
+        Ok(())
+    }
+
+    #[instruction(discriminator = ExecuteInstruction::SPL_DISCRIMINATOR_SLICE)]
+    pub fn transfer_hook(ctx: Context<TransferHook>, _amount: u64) -> Result<()> {
+        // Only Token-2022 mid-transfer may drive the owner sync
+        require!(
+            is_transferring(&ctx.accounts.source_token.to_account_info())?,
+            HookError::NotTransferring
+        );
+
+        // The deals program has already checked and updated the record
+        let coupon = &ctx.accounts.coupon;
+        if coupon.in_program_transfer {
+            return Ok(());
+        }
+
+        require!(!coupon.is_redeemed, HookError::CouponRedeemed);
+        require!(!coupon.is_staked, HookError::CouponStaked);
+        require!(!coupon.is_listed, HookError::CouponListed);
+
+        let hook_authority_bump = [ctx.bumps.hook_authority];
+        let signer_seeds: &[&[&[u8]]] = &[&[HOOK_AUTHORITY_SEED, &hook_authority_bump]];
+        basic::cpi::sync_coupon_owner(CpiContext::new_with_signer(
+            ctx.accounts.basic_program.to_account_info(),
+            basic::cpi::accounts::SyncCouponOwner {
+                coupon: ctx.accounts.coupon.to_account_info(),
+                mint: ctx.accounts.mint.to_account_info(),
+                destination_token_account: ctx.accounts.destination_token.to_account_info(),
+                hook_authority: ctx.accounts.hook_authority.to_account_info(),
+                event_authority: ctx.accounts.basic_event_authority.to_account_info(),
+                program: ctx.accounts.basic_program.to_account_info(),
+            },
+            signer_seeds,
+        ))
+    }
+}
+
+// What Token-2022 resolves for each Execute, in the order TransferHook expects
+pub fn extra_account_metas(coupon: &Pubkey) -> Result<Vec<ExtraAccountMeta>> {
+    Ok(vec![
+        ExtraAccountMeta::new_with_pubkey(coupon, false, true)?,
+        ExtraAccountMeta::new_with_pubkey(&basic::ID, false, false)?,
+        ExtraAccountMeta::new_external_pda_with_seeds(
+            BASIC_PROGRAM_INDEX,
+            &[Seed::Literal {
+                bytes: b"__event_authority".to_vec(),
+            }],
+            false,
+            false,
+        )?,
+        ExtraAccountMeta::new_with_seeds(
+            &[Seed::Literal {
+                bytes: HOOK_AUTHORITY_SEED.to_vec(),
+            }],
+            false,
+            false,
+        )?,
+    ])
+}
+
+// Token-2022 flags both token accounts for the length of the hook call, which
+// is what stops anyone invoking Execute directly with made-up accounts
+fn is_transferring(token_info: &AccountInfo) -> Result<bool> {
+    let data = token_info.try_borrow_data()?;
+    let account = StateWithExtensions::<Token2022Account>::unpack(&data)?;
+    let extension = account.get_extension::<TransferHookAccount>()?;
+    Ok(bool::from(extension.transferring))
+}
+
+#[derive(Accounts)]
+pub struct InitializeExtraAccountMetaList<'info> {
+    /// CHECK: Written in the handler as an ExtraAccountMetaList
+    #[account(
+        init,
+        payer = payer,
+        space = ExtraAccountMetaList::size_of(EXTRA_ACCOUNT_METAS_LEN)?,
+        seeds = [b"extra-account-metas", mint.key().as_ref()],
+        bump
+    )]
+    pub extra_account_meta_list: UncheckedAccount<'info>,
+
+    #[account(mint::authority = authority)]
+    pub mint: InterfaceAccount<'info, Mint>,
+
+    pub authority: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    /// CHECK: The coupon record being minted alongside the mint
+    #[account(owner = basic::ID)]
+    pub coupon: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct TransferHook<'info> {
+    #[account(token::mint = mint)]
+    pub source_token: InterfaceAccount<'info, TokenAccount>,
+
+    pub mint: InterfaceAccount<'info, Mint>,
+
+    #[account(token::mint = mint)]
+    pub destination_token: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: Source owner or delegate, already authorized by Token-2022
+    pub owner: UncheckedAccount<'info>,
+
+    /// CHECK: This mint's ExtraAccountMetaList
+    #[account(seeds = [b"extra-account-metas", mint.key().as_ref()], bump)]
+    pub extra_account_meta_list: UncheckedAccount<'info>,
+
+    #[account(mut, has_one = mint @ HookError::CouponMintMismatch)]
+    pub coupon: Account<'info, Coupon>,
+
+    pub basic_program: Program<'info, Basic>,
+
+    /// CHECK: The deals program's event authority, checked by the deals program
+    pub basic_event_authority: UncheckedAccount<'info>,
+
+    /// CHECK: Signs the owner sync; the deals program only takes it from us
+    #[account(seeds = [HOOK_AUTHORITY_SEED], bump)]
+    pub hook_authority: UncheckedAccount<'info>,
+}
+
+#[error_code]
+pub enum HookError {
+    #[msg("Transfer hook called outside a transfer")]
+    NotTransferring,
+    #[msg("Coupon record doesn't belong to this mint")]
+    CouponMintMismatch,
+    #[msg("Redeemed coupons can't be transferred")]
+    CouponRedeemed,
+    #[msg("Staked coupons can't be transferred")]
+    CouponStaked,
+    #[msg("Listed coupons can't be transferred")]
+    CouponListed,
+}
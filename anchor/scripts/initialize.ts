@@ -4,8 +4,9 @@
  * Platform Initialization Script
  *
  * This script initializes all necessary platform accounts:
+ * - Platform config (marketplace fee rate and treasury)
  * - Rewards pool for staking
- * - Funds the rewards pool with SOL for payouts
+ * - Funds the rewards vault with SOL for payouts
  *
  * Usage:
  *   ts-node initialize.ts
@@ -18,8 +19,11 @@ import { Program, BN } from '@coral-xyz/anchor'
 import { Basic } from '../target/types/basic'
 import { PublicKey, SystemProgram, LAMPORTS_PER_SOL } from '@solana/web3.js'
 
-const REWARDS_RATE_PER_DAY = new BN(100_000) // 100k lamports per day (~0.0001 SOL)
+const REWARDS_RATE_PER_DAY = new BN(100_000) // 100k lamports per day (~0.0001 SOL), split across all stakers
 const POOL_FUNDING_AMOUNT = 10 * LAMPORTS_PER_SOL // 10 SOL for rewards
+const PLATFORM_FEE_BPS = 250 // 2.5% marketplace fee
+const MAX_FEE_LAMPORTS = new BN(0) // No per-sale cap
+const BPF_LOADER_UPGRADEABLE_ID = new PublicKey('BPFLoaderUpgradeab1e11111111111111111111111')
 
 async function initialize() {
   // Set up provider
@@ -34,13 +38,59 @@ async function initialize() {
   console.log('Admin:', admin.toString())
   console.log('')
 
+  // Derive platform config PDA and the program data account that proves upgrade authority
+  const [platformConfigPda] = PublicKey.findProgramAddressSync(
+    [Buffer.from('platform_config')],
+    program.programId
+  )
+  const [programDataPda] = PublicKey.findProgramAddressSync(
+    [program.programId.toBuffer()],
+    BPF_LOADER_UPGRADEABLE_ID
+  )
+  const treasury = process.env.TREASURY_WALLET ? new PublicKey(process.env.TREASURY_WALLET) : admin
+
+  console.log('Platform Config PDA:', platformConfigPda.toString())
+
+  const existingConfig = await provider.connection.getAccountInfo(platformConfigPda)
+  if (existingConfig) {
+    const config = await program.account.platformConfig.fetch(platformConfigPda)
+    console.log('✅ Platform config already initialized')
+    console.log('   Fee:', config.feeBps, 'bps')
+    console.log('   Treasury:', config.treasury.toString())
+  } else {
+    console.log('📝 Initializing platform config...')
+
+    const configTx = await program.methods
+      .initializeConfig(treasury, PLATFORM_FEE_BPS, MAX_FEE_LAMPORTS)
+      .accounts({
+        platformConfig: platformConfigPda,
+        programData: programDataPda,
+        admin: admin,
+        systemProgram: SystemProgram.programId,
+      } as any)
+      .rpc()
+
+    console.log('✅ Platform config initialized')
+    console.log('   Transaction:', configTx)
+    console.log('   Fee:', PLATFORM_FEE_BPS, 'bps')
+    console.log('   Treasury:', treasury.toString())
+  }
+  console.log('')
+
   // Derive rewards pool PDA
   const [rewardsPoolPda] = PublicKey.findProgramAddressSync(
     [Buffer.from('rewards_pool')],
     program.programId
   )
 
+  // Rewards are paid out of a separate lamport-only vault PDA
+  const [rewardsVaultPda] = PublicKey.findProgramAddressSync(
+    [Buffer.from('rewards_vault')],
+    program.programId
+  )
+
   console.log('Rewards Pool PDA:', rewardsPoolPda.toString())
+  console.log('Rewards Vault PDA:', rewardsVaultPda.toString())
 
   try {
     // Check if rewards pool already exists
@@ -55,29 +105,24 @@ async function initialize() {
       console.log('   Reward Rate:', poolAccount.rewardRatePerDay.toString(), 'lamports/day')
       console.log('   Admin:', poolAccount.admin.toString())
 
-      // Check pool balance
-      const poolBalance = await provider.connection.getBalance(rewardsPoolPda)
-      console.log('   Pool Balance:', (poolBalance / LAMPORTS_PER_SOL).toFixed(4), 'SOL')
-
-      // Fund pool if balance is low
-      if (poolBalance < POOL_FUNDING_AMOUNT) {
-        console.log('\n💰 Funding rewards pool with additional SOL...')
-        const fundAmount = POOL_FUNDING_AMOUNT - poolBalance
-
-        const fundTx = await provider.sendAndConfirm(
-          new anchor.web3.Transaction().add(
-            SystemProgram.transfer({
-              fromPubkey: admin,
-              toPubkey: rewardsPoolPda,
-              lamports: fundAmount,
-            })
-          )
-        )
-
-        console.log('✅ Pool funded with', (fundAmount / LAMPORTS_PER_SOL).toFixed(4), 'SOL')
+      // Check vault balance
+      const vaultBalance = await provider.connection.getBalance(rewardsVaultPda)
+      console.log('   Vault Balance:', (vaultBalance / LAMPORTS_PER_SOL).toFixed(4), 'SOL')
+
+      // Fund vault if balance is low
+      if (vaultBalance < POOL_FUNDING_AMOUNT) {
+        console.log('\n💰 Funding rewards vault with additional SOL...')
+        const fundAmount = POOL_FUNDING_AMOUNT - vaultBalance
+
+        const fundTx = await program.methods
+          .fundRewardsPool(new BN(fundAmount))
+          .accounts({ funder: admin })
+          .rpc()
+
+        console.log('✅ Vault funded with', (fundAmount / LAMPORTS_PER_SOL).toFixed(4), 'SOL')
         console.log('   Transaction:', fundTx)
 
-        const newBalance = await provider.connection.getBalance(rewardsPoolPda)
+        const newBalance = await provider.connection.getBalance(rewardsVaultPda)
         console.log('   New Balance:', (newBalance / LAMPORTS_PER_SOL).toFixed(4), 'SOL')
       }
     } else {
@@ -97,24 +142,19 @@ async function initialize() {
       console.log('   Transaction:', initTx)
       console.log('   Reward Rate:', REWARDS_RATE_PER_DAY.toString(), 'lamports/day')
 
-      // Fund the rewards pool
-      console.log('\n💰 Funding rewards pool with', POOL_FUNDING_AMOUNT / LAMPORTS_PER_SOL, 'SOL...')
+      // Fund the rewards vault
+      console.log('\n💰 Funding rewards vault with', POOL_FUNDING_AMOUNT / LAMPORTS_PER_SOL, 'SOL...')
 
-      const fundTx = await provider.sendAndConfirm(
-        new anchor.web3.Transaction().add(
-          SystemProgram.transfer({
-            fromPubkey: admin,
-            toPubkey: rewardsPoolPda,
-            lamports: POOL_FUNDING_AMOUNT,
-          })
-        )
-      )
+      const fundTx = await program.methods
+        .fundRewardsPool(new BN(POOL_FUNDING_AMOUNT))
+        .accounts({ funder: admin })
+        .rpc()
 
-      console.log('✅ Pool funded')
+      console.log('✅ Vault funded')
       console.log('   Transaction:', fundTx)
 
-      const poolBalance = await provider.connection.getBalance(rewardsPoolPda)
-      console.log('   Pool Balance:', (poolBalance / LAMPORTS_PER_SOL).toFixed(4), 'SOL')
+      const vaultBalance = await provider.connection.getBalance(rewardsVaultPda)
+      console.log('   Vault Balance:', (vaultBalance / LAMPORTS_PER_SOL).toFixed(4), 'SOL')
     }
 
     console.log('\n✨ Platform initialization complete!\n')
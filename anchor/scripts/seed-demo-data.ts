@@ -108,6 +108,19 @@ async function seedDemoData() {
   console.log('Network:', provider.connection.rpcEndpoint)
   console.log('')
 
+  const [merchantStatePda] = PublicKey.findProgramAddressSync(
+    [Buffer.from('merchant'), merchant.toBuffer()],
+    program.programId
+  )
+  const existingDeals = await program.account.deal.all([
+    {
+      memcmp: {
+        offset: 8, // After discriminator
+        bytes: merchant.toBase58(),
+      },
+    },
+  ])
+
   let successCount = 0
   let failCount = 0
 
@@ -115,19 +128,21 @@ async function seedDemoData() {
     try {
       const expiryTimestamp = Math.floor(Date.now() / 1000) + deal.expiryDays * 24 * 60 * 60
 
-      // Derive deal PDA
-      const [dealPda] = PublicKey.findProgramAddressSync(
-        [Buffer.from('deal'), merchant.toBuffer(), Buffer.from(deal.title)],
-        program.programId
-      )
-
-      // Check if deal already exists
-      const existingDeal = await provider.connection.getAccountInfo(dealPda)
+      // Check if a deal with this title already exists for the merchant
+      const existingDeal = existingDeals.find((d) => d.account.title === deal.title)
       if (existingDeal) {
         console.log(`⏭️  Skipping "${deal.title}" (already exists)`)
         continue
       }
 
+      // Derive deal PDA from the merchant's next deal id
+      const merchantState = await program.account.merchantState.fetchNullable(merchantStatePda)
+      const nextDealId = merchantState ? merchantState.dealCount : new BN(0)
+      const [dealPda] = PublicKey.findProgramAddressSync(
+        [Buffer.from('deal'), merchant.toBuffer(), nextDealId.toArrayLike(Buffer, 'le', 8)],
+        program.programId
+      )
+
       console.log(`📝 Creating: "${deal.title}"`)
       console.log(`   Category: ${deal.category}`)
       console.log(`   Discount: ${deal.discountPercent}%`)
@@ -143,9 +158,22 @@ async function seedDemoData() {
           new BN(deal.maxSupply),
           new BN(expiryTimestamp),
           deal.category,
-          new BN(deal.priceLamports)
+          new BN(deal.priceLamports),
+          false,
+          new BN(0),
+          null,
+          null,
+          new BN(0),
+          [],
+          new BN(1),
+          { discount: {} },
+          '',
+          0,
+          { freeze: {} },
+          true
         )
         .accounts({
+          merchantState: merchantStatePda,
           deal: dealPda,
           merchant: merchant,
         } as any)
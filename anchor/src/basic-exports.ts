@@ -1,6 +1,6 @@
 // Here we export some useful types and functions for interacting with the Anchor program.
 import { AnchorProvider, Program } from '@coral-xyz/anchor'
-import { Cluster, PublicKey } from '@solana/web3.js'
+import { AccountMeta, Cluster, Connection, PublicKey } from '@solana/web3.js'
 import BasicIDL from '../target/idl/basic.json'
 import type { Basic } from '../target/types/basic'
 
@@ -10,6 +10,35 @@ export { Basic, BasicIDL }
 // The programId is imported from the program IDL.
 export const BASIC_PROGRAM_ID = new PublicKey(BasicIDL.address)
 
+// Coupon NFTs are Token-2022 mints whose transfers run through this hook program.
+export const COUPON_HOOK_PROGRAM_ID = new PublicKey('95rxFZaAjHeeh3aoTn8u4MiVV9e8HkQyURyX3Y3GyW6f')
+
+// The coupon hook's accounts, passed as remainingAccounts whenever the Basic program moves a coupon NFT
+// (transfer, list, buy and delist).
+export function getCouponHookAccounts(mint: PublicKey, coupon: PublicKey): AccountMeta[] {
+  const [extraAccountMetaList] = PublicKey.findProgramAddressSync(
+    [Buffer.from('extra-account-metas'), mint.toBuffer()],
+    COUPON_HOOK_PROGRAM_ID,
+  )
+  const [eventAuthority] = PublicKey.findProgramAddressSync([Buffer.from('__event_authority')], BASIC_PROGRAM_ID)
+  const [hookAuthority] = PublicKey.findProgramAddressSync([Buffer.from('hook_authority')], COUPON_HOOK_PROGRAM_ID)
+  return [
+    { pubkey: extraAccountMetaList, isSigner: false, isWritable: false },
+    { pubkey: COUPON_HOOK_PROGRAM_ID, isSigner: false, isWritable: false },
+    { pubkey: coupon, isSigner: false, isWritable: true },
+    { pubkey: BASIC_PROGRAM_ID, isSigner: false, isWritable: false },
+    { pubkey: eventAuthority, isSigner: false, isWritable: false },
+    { pubkey: hookAuthority, isSigner: false, isWritable: false },
+  ]
+}
+
+// Coupons minted before the hook existed are SPL Token NFTs, so the mint's owner says which token program to use.
+export async function getCouponTokenProgram(connection: Connection, mint: PublicKey): Promise<PublicKey> {
+  const mintInfo = await connection.getAccountInfo(mint)
+  if (!mintInfo) throw new Error(`Coupon mint ${mint.toBase58()} not found`)
+  return mintInfo.owner
+}
+
 // This is a helper function to get the Basic Anchor program.
 export function getBasicProgram(provider: AnchorProvider, address?: PublicKey): Program<Basic> {
   return new Program({ ...BasicIDL, address: address ? address.toBase58() : BasicIDL.address } as Basic, provider)
@@ -1,18 +1,31 @@
 import * as anchor from '@coral-xyz/anchor'
 import { Program, BN } from '@coral-xyz/anchor'
 import { Basic } from '../target/types/basic'
+import { COUPON_HOOK_PROGRAM_ID, getCouponHookAccounts } from '../src/basic-exports'
 import {
   Keypair,
   PublicKey,
   SystemProgram,
-  SYSVAR_RENT_PUBKEY,
+  Transaction,
+  sendAndConfirmTransaction,
 } from '@solana/web3.js'
 import {
   TOKEN_PROGRAM_ID,
-  ASSOCIATED_TOKEN_PROGRAM_ID,
+  TOKEN_2022_PROGRAM_ID,
   getAssociatedTokenAddressSync,
+  createMint,
+  getOrCreateAssociatedTokenAccount,
+  mintTo,
+  getMint,
+  getAccount,
+  getNonTransferable,
+  getTokenMetadata,
+  getTransferHook,
+  createAssociatedTokenAccountIdempotent,
+  createTransferCheckedWithTransferHookInstruction,
 } from '@solana/spl-token'
 import { assert } from 'chai'
+import { createHash } from 'crypto'
 
 describe('Deal Discovery Platform', () => {
   const provider = anchor.AnchorProvider.env()
@@ -29,6 +42,184 @@ describe('Deal Discovery Platform', () => {
   let dealTitle: string
   let dealAccount: any
 
+  // Decodes the first event emitted via emit_cpi! in a confirmed transaction
+  const fetchCpiEvent = async (signature: string) => {
+    await provider.connection.confirmTransaction(signature, 'confirmed')
+    const tx = await provider.connection.getTransaction(signature, {
+      commitment: 'confirmed',
+      maxSupportedTransactionVersion: 0,
+    })
+    const eventIx = tx.meta.innerInstructions
+      .flatMap((inner) => inner.instructions)
+      .find((ix) => tx.transaction.message.staticAccountKeys[ix.programIdIndex].equals(program.programId))
+    const ixData = anchor.utils.bytes.bs58.decode(eventIx.data)
+    // Skip the 8-byte event CPI tag, leaving the event discriminator and payload
+    return program.coder.events.decode(anchor.utils.bytes.base64.encode(ixData.subarray(8)))
+  }
+
+  const metadataPdaOf = (mint: PublicKey) =>
+    PublicKey.findProgramAddressSync(
+      [Buffer.from('metadata'), TOKEN_METADATA_PROGRAM_ID.toBuffer(), mint.toBuffer()],
+      TOKEN_METADATA_PROGRAM_ID
+    )[0]
+
+  const metadataNameOf = async (mint: PublicKey) => {
+    const { data } = await provider.connection.getAccountInfo(metadataPdaOf(mint))
+    const offset = 1 + 32 + 32
+    return data
+      .subarray(offset + 4, offset + 4 + data.readUInt32LE(offset))
+      .toString('utf8')
+      .replace(/\0+$/, '')
+  }
+
+  // The first creator listed in a coupon's Metaplex metadata and whether they verified it
+  const firstCreatorOf = async (mint: PublicKey) => {
+    const { data } = await provider.connection.getAccountInfo(metadataPdaOf(mint))
+    let offset = 1 + 32 + 32
+    for (let i = 0; i < 3; i++) offset += 4 + data.readUInt32LE(offset)
+    offset += 2 + 1 + 4 // seller fee bps, creators option and length
+    return { address: new PublicKey(data.subarray(offset, offset + 32)), verified: data[offset + 32] === 1 }
+  }
+
+  // Wallets and accounts of the pre-migration fixtures written by crates/legacy-fixtures
+  const legacyKeypair = (label: string) => Keypair.fromSeed(createHash('sha256').update(label).digest())
+  const legacyMerchant = legacyKeypair('legacy-merchant')
+  const legacyHolder = legacyKeypair('legacy-holder')
+  const legacySeller = legacyKeypair('legacy-seller')
+  const LEGACY_DEAL_TITLE = 'Legacy Coffee Deal'
+  const LEGACY_COUPONS = 4
+  const [legacyDealPda] = PublicKey.findProgramAddressSync(
+    [Buffer.from('deal'), legacyMerchant.publicKey.toBuffer(), Buffer.from(LEGACY_DEAL_TITLE)],
+    program.programId
+  )
+  // SPL Token mints of the legacy coupons, which the tests create from these keypairs
+  const legacyCouponOf = (index: number) => ({
+    couponPda: PublicKey.findProgramAddressSync(
+      [Buffer.from('coupon'), legacyDealPda.toBuffer(), new BN(index).toArrayLike(Buffer, 'le', 8)],
+      program.programId
+    )[0],
+    mint: legacyKeypair(`legacy-coupon-${index}`),
+  })
+  // Coupon #1 was staked under the legacy rewards pool
+  const [legacyStakedCouponPda] = PublicKey.findProgramAddressSync(
+    [Buffer.from('staked_coupon'), legacyCouponOf(1).couponPda.toBuffer()],
+    program.programId
+  )
+
+  type DealOptions = {
+    merchant?: Keypair
+    title?: string
+    description?: string
+    discountPercent?: number
+    maxSupply?: BN
+    expiryTimestamp?: BN
+    category?: string
+    priceLamports?: BN
+    escrowPayments?: boolean
+    maxPerWallet?: BN
+    allowlistRoot?: number[] | null
+    tokenGate?: any
+    startTimestamp?: BN
+    redemptionWindows?: { days: number; startMinute: number; endMinute: number }[]
+    usesPerCoupon?: BN
+    kind?: any
+    collectionUri?: string
+    royaltyBps?: number
+    redemptionAction?: any
+    transferable?: boolean
+    paymentMint?: PublicKey
+  }
+
+  // Creates a deal at the merchant's next id; anything not overridden is a free, one-use discount
+  const createDeal = async (options: DealOptions = {}) => {
+    const owner = options.merchant ?? merchant
+    const [merchantStatePda] = PublicKey.findProgramAddressSync(
+      [Buffer.from('merchant'), owner.publicKey.toBuffer()],
+      program.programId
+    )
+    const merchantState = await program.account.merchantState.fetchNullable(merchantStatePda)
+    const dealId = merchantState ? merchantState.dealCount : new BN(0)
+    const [deal] = PublicKey.findProgramAddressSync(
+      [Buffer.from('deal'), owner.publicKey.toBuffer(), dealId.toArrayLike(Buffer, 'le', 8)],
+      program.programId
+    )
+
+    const signature = await program.methods
+      .createDeal(
+        options.title ?? `Deal ${Date.now()}`,
+        options.description ?? 'Test deal',
+        options.discountPercent ?? 20,
+        options.maxSupply ?? new BN(10),
+        options.expiryTimestamp ?? new BN(Math.floor(Date.now() / 1000) + 86400),
+        options.category ?? 'Shopping',
+        options.priceLamports ?? new BN(0),
+        options.escrowPayments ?? false,
+        options.maxPerWallet ?? new BN(0),
+        options.allowlistRoot ?? null,
+        options.tokenGate ?? null,
+        options.startTimestamp ?? new BN(0),
+        options.redemptionWindows ?? [],
+        options.usesPerCoupon ?? new BN(1),
+        options.kind ?? { discount: {} },
+        options.collectionUri ?? '',
+        options.royaltyBps ?? 0,
+        options.redemptionAction ?? { freeze: {} },
+        options.transferable ?? true
+      )
+      .accountsPartial({
+        merchantState: merchantStatePda,
+        deal,
+        merchant: owner.publicKey,
+        paymentMint: options.paymentMint ?? null,
+      })
+      .signers([owner])
+      .rpc()
+
+    return { deal, signature }
+  }
+
+  type MintOptions = {
+    buyer?: Keypair
+    uri?: string
+    allowlist?: { allowance: BN; proof: number[][] } | null
+    accounts?: Record<string, PublicKey>
+    remainingAccounts?: anchor.web3.AccountMeta[]
+  }
+
+  // Mints the deal's next coupon to the buyer (the shared user by default)
+  const mintCoupon = async (deal: PublicKey, options: MintOptions = {}) => {
+    const buyer = options.buyer ?? user
+    const dealState = await program.account.deal.fetch(deal)
+    const mintKeypair = Keypair.generate()
+    const [couponPda] = PublicKey.findProgramAddressSync(
+      [Buffer.from('coupon'), deal.toBuffer(), dealState.currentSupply.toArrayLike(Buffer, 'le', 8)],
+      program.programId
+    )
+
+    const signature = await program.methods
+      .mintCoupon(deal, options.uri ?? 'ipfs://test-coupon', options.allowlist ?? null)
+      .accountsPartial({
+        deal,
+        coupon: couponPda,
+        mint: mintKeypair.publicKey,
+        tokenAccount: getAssociatedTokenAddressSync(
+          mintKeypair.publicKey,
+          buyer.publicKey,
+          false,
+          TOKEN_2022_PROGRAM_ID
+        ),
+        metadata: metadataPdaOf(mintKeypair.publicKey),
+        merchant: dealState.merchant,
+        user: buyer.publicKey,
+        ...options.accounts,
+      })
+      .remainingAccounts(options.remainingAccounts ?? [])
+      .signers([buyer, mintKeypair])
+      .rpc()
+
+    return { couponPda, mint: mintKeypair.publicKey, signature }
+  }
+
   before(async () => {
     merchant = Keypair.generate()
     user = Keypair.generate()
@@ -56,31 +247,25 @@ describe('Deal Discovery Platform', () => {
     const category = 'Food & Dining'
     const priceLamports = new BN(100_000_000) // 0.1 SOL
 
-    ;[dealPda] = PublicKey.findProgramAddressSync(
-      [Buffer.from('deal'), merchant.publicKey.toBuffer(), Buffer.from(dealTitle)],
+    const [merchantStatePda] = PublicKey.findProgramAddressSync(
+      [Buffer.from('merchant'), merchant.publicKey.toBuffer()],
       program.programId
     )
 
-    await program.methods
-      .createDeal(
-        dealTitle,
-        description,
-        discountPercent,
-        maxSupply,
-        expiryTimestamp,
-        category,
-        priceLamports
-      )
-      .accounts({
-        deal: dealPda,
-        merchant: merchant.publicKey,
-        systemProgram: SystemProgram.programId,
-      })
-      .signers([merchant])
-      .rpc()
+    // First deal for a fresh merchant gets id 0
+    ;({ deal: dealPda } = await createDeal({
+      title: dealTitle,
+      description,
+      discountPercent,
+      maxSupply,
+      expiryTimestamp,
+      category,
+      priceLamports,
+    }))
 
     dealAccount = await program.account.deal.fetch(dealPda)
 
+    assert.equal(dealAccount.dealId.toString(), '0')
     assert.equal(dealAccount.title, dealTitle)
     assert.equal(dealAccount.description, description)
     assert.equal(dealAccount.discountPercent, discountPercent)
@@ -90,13 +275,65 @@ describe('Deal Discovery Platform', () => {
     assert.equal(dealAccount.priceLamports.toString(), priceLamports.toString())
     assert.isTrue(dealAccount.isActive)
     assert.equal(dealAccount.merchant.toString(), merchant.publicKey.toString())
+    assert.isNull(dealAccount.paymentMint)
+    assert.isFalse(dealAccount.escrowPayments)
+    assert.equal(dealAccount.maxPerWallet.toString(), '0')
+    assert.isNull(dealAccount.allowlistRoot)
+    assert.isNull(dealAccount.tokenGate)
+    assert.equal(dealAccount.startTimestamp.toString(), '0')
+    assert.isEmpty(dealAccount.redemptionWindows)
+    assert.equal(dealAccount.usesPerCoupon.toString(), '1')
+    assert.deepEqual(dealAccount.kind, { discount: {} })
+    const [collectionMint] = PublicKey.findProgramAddressSync(
+      [Buffer.from('collection'), dealPda.toBuffer()],
+      program.programId
+    )
+    assert.equal(dealAccount.collectionMint.toString(), collectionMint.toString())
+    assert.deepEqual(dealAccount.redemptionAction, { freeze: {} })
+    assert.isTrue(dealAccount.transferable)
+
+    const merchantState = await program.account.merchantState.fetch(merchantStatePda)
+    assert.equal(merchantState.dealCount.toString(), '1')
+  })
+
+  it('Creates a deal with a long, duplicate-free title', async () => {
+    // Titles are no longer PDA seeds, so anything up to 100 bytes works
+    const longTitle = 'Two large pizzas, garlic bread and a bottle of soda for the whole family'
+
+    const [merchantStatePda] = PublicKey.findProgramAddressSync(
+      [Buffer.from('merchant'), merchant.publicKey.toBuffer()],
+      program.programId
+    )
+    const merchantState = await program.account.merchantState.fetch(merchantStatePda)
+    const { deal: longDealPda, signature } = await createDeal({
+      title: longTitle,
+      description: 'Family bundle',
+      category: 'Food & Dining',
+    })
+
+    const longDeal = await program.account.deal.fetch(longDealPda)
+    assert.equal(longDeal.title, longTitle)
+    assert.equal(longDeal.dealId.toString(), merchantState.dealCount.toString())
+
+    // DealCreated is emitted through a self-CPI so it survives log truncation
+    const event = await fetchCpiEvent(signature)
+    assert.equal(event.name, 'dealCreated')
+    assert.equal(event.data.deal.toString(), longDealPda.toString())
+    assert.equal(event.data.dealId.toString(), longDeal.dealId.toString())
+    assert.equal(event.data.title, longTitle)
+
+    // Coupon names keep their number and cut the title to Metaplex's 32 bytes
+    const { mint } = await mintCoupon(longDealPda)
+    assert.equal(await metadataNameOf(mint), 'Two large pizzas, ga - Coupon #1')
   })
 
   it('Updates a deal', async () => {
     const newPrice = new BN(50_000_000) // 0.05 SOL
 
+    const newTitle = `${dealTitle} (updated)`
+
     await program.methods
-      .updateDeal(false, newPrice)
+      .updateDeal(false, newPrice, newTitle, null, null, null, null, null, null)
       .accounts({
         deal: dealPda,
         merchant: merchant.publicKey,
@@ -107,10 +344,11 @@ describe('Deal Discovery Platform', () => {
     dealAccount = await program.account.deal.fetch(dealPda)
     assert.isFalse(dealAccount.isActive)
     assert.equal(dealAccount.priceLamports.toString(), newPrice.toString())
+    assert.equal(dealAccount.title, newTitle)
 
     // Reactivate for next tests
     await program.methods
-      .updateDeal(true, null)
+      .updateDeal(true, null, null, null, null, null, null, null, null)
       .accounts({
         deal: dealPda,
         merchant: merchant.publicKey,
@@ -119,60 +357,78 @@ describe('Deal Discovery Platform', () => {
       .rpc()
   })
 
-  it('Mints a coupon NFT', async () => {
+  it('Edits deal details', async () => {
+    const newDescription = '50% off any large pizza, dine-in or takeaway'
+    const newCategory = 'Restaurants'
+    const newExpiry = new BN(Math.floor(Date.now() / 1000) + 86400 * 60) // 60 days
+
+    await program.methods
+      .updateDeal(null, null, null, newDescription, newCategory, 40, new BN(200), newExpiry, null)
+      .accounts({
+        deal: dealPda,
+        merchant: merchant.publicKey,
+      })
+      .signers([merchant])
+      .rpc()
+
     dealAccount = await program.account.deal.fetch(dealPda)
+    assert.equal(dealAccount.description, newDescription)
+    assert.equal(dealAccount.category, newCategory)
+    assert.equal(dealAccount.discountPercent, 40)
+    assert.equal(dealAccount.maxSupply.toString(), '200')
+    assert.equal(dealAccount.expiryTimestamp.toString(), newExpiry.toString())
+  })
 
-    const mintKeypair = Keypair.generate()
-    const [couponPda] = PublicKey.findProgramAddressSync(
-      [
-        Buffer.from('coupon'),
-        dealPda.toBuffer(),
-        dealAccount.currentSupply.toArrayLike(Buffer, 'le', 8),
-      ],
-      program.programId
-    )
+  it('Prevents moving expiry into the past', async () => {
+    const pastExpiry = new BN(Math.floor(Date.now() / 1000) - 60)
 
-    const userTokenAccount = getAssociatedTokenAddressSync(
-      mintKeypair.publicKey,
-      user.publicKey
-    )
+    try {
+      await program.methods
+        .updateDeal(null, null, null, null, null, null, null, pastExpiry, null)
+        .accounts({
+          deal: dealPda,
+          merchant: merchant.publicKey,
+        })
+        .signers([merchant])
+        .rpc()
 
-    const [metadataPda] = PublicKey.findProgramAddressSync(
-      [
-        Buffer.from('metadata'),
-        TOKEN_METADATA_PROGRAM_ID.toBuffer(),
-        mintKeypair.publicKey.toBuffer(),
-      ],
-      TOKEN_METADATA_PROGRAM_ID
-    )
+      assert.fail('Should have thrown error')
+    } catch (error) {
+      assert.include(error.message, 'InvalidExpiry')
+    }
+  })
+
+  it('Prevents discounts above 100%', async () => {
+    try {
+      await program.methods
+        .updateDeal(null, null, null, null, null, 101, null, null, null)
+        .accounts({
+          deal: dealPda,
+          merchant: merchant.publicKey,
+        })
+        .signers([merchant])
+        .rpc()
+
+      assert.fail('Should have thrown error')
+    } catch (error) {
+      assert.include(error.message, 'InvalidDiscount')
+    }
+  })
+
+  it('Mints a coupon NFT', async () => {
+    dealAccount = await program.account.deal.fetch(dealPda)
 
     const userBalanceBefore = await provider.connection.getBalance(user.publicKey)
     const merchantBalanceBefore = await provider.connection.getBalance(merchant.publicKey)
 
-    await program.methods
-      .mintCoupon(dealPda)
-      .accounts({
-        deal: dealPda,
-        coupon: couponPda,
-        mint: mintKeypair.publicKey,
-        tokenAccount: userTokenAccount,
-        metadata: metadataPda,
-        merchant: merchant.publicKey,
-        user: user.publicKey,
-        rent: SYSVAR_RENT_PUBKEY,
-        systemProgram: SystemProgram.programId,
-        tokenProgram: TOKEN_PROGRAM_ID,
-        associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
-        tokenMetadataProgram: TOKEN_METADATA_PROGRAM_ID,
-      })
-      .signers([user, mintKeypair])
-      .rpc()
+    const { couponPda, mint } = await mintCoupon(dealPda)
 
     // Verify coupon account
     const couponAccount = await program.account.coupon.fetch(couponPda)
     assert.equal(couponAccount.deal.toString(), dealPda.toString())
     assert.equal(couponAccount.owner.toString(), user.publicKey.toString())
-    assert.equal(couponAccount.mint.toString(), mintKeypair.publicKey.toString())
+    assert.equal(couponAccount.mint.toString(), mint.toString())
+    assert.equal(couponAccount.couponIndex.toString(), dealAccount.currentSupply.toString())
     assert.isFalse(couponAccount.isRedeemed)
 
     // Verify deal supply increased
@@ -187,9 +443,27 @@ describe('Deal Discovery Platform', () => {
     assert.equal(merchantBalanceAfter - merchantBalanceBefore, dealAccount.priceLamports.toNumber())
   })
 
+  it('Prevents lowering max supply below minted coupons', async () => {
+    try {
+      await program.methods
+        .updateDeal(null, null, null, null, null, null, new BN(0), null, null)
+        .accounts({
+          deal: dealPda,
+          merchant: merchant.publicKey,
+        })
+        .signers([merchant])
+        .rpc()
+
+      assert.fail('Should have thrown error')
+    } catch (error) {
+      assert.include(error.message, 'InvalidSupply')
+    }
+  })
+
   it('Redeems a coupon', async () => {
     // Get the first coupon
-    const allCoupons = await program.account.coupon.all()
+    // Legacy coupon fixtures share the discriminator but not the layout, so only fetch this deal's
+    const allCoupons = await program.account.coupon.all([{ memcmp: { offset: 8, bytes: dealPda.toBase58() } }])
     const coupon = allCoupons[0]
 
     await program.methods
@@ -198,6 +472,7 @@ describe('Deal Discovery Platform', () => {
         coupon: coupon.publicKey,
         deal: dealPda,
         merchant: merchant.publicKey,
+        couponTokenProgram: TOKEN_2022_PROGRAM_ID,
       })
       .signers([merchant])
       .rpc()
@@ -205,73 +480,76 @@ describe('Deal Discovery Platform', () => {
     const couponAccount = await program.account.coupon.fetch(coupon.publicKey)
     assert.isTrue(couponAccount.isRedeemed)
     assert.isNotNull(couponAccount.redeemedAt)
+
+    const updatedDeal = await program.account.deal.fetch(dealPda)
+    assert.equal(updatedDeal.redeemedCount.toString(), '1')
   })
 
   it('Transfers a coupon to new owner', async () => {
     // Mint another coupon first
-    dealAccount = await program.account.deal.fetch(dealPda)
-    const mintKeypair = Keypair.generate()
-    const [couponPda] = PublicKey.findProgramAddressSync(
-      [
-        Buffer.from('coupon'),
-        dealPda.toBuffer(),
-        dealAccount.currentSupply.toArrayLike(Buffer, 'le', 8),
-      ],
-      program.programId
-    )
-
-    const userTokenAccount = getAssociatedTokenAddressSync(
-      mintKeypair.publicKey,
-      user.publicKey
-    )
-
-    const [metadataPda] = PublicKey.findProgramAddressSync(
-      [
-        Buffer.from('metadata'),
-        TOKEN_METADATA_PROGRAM_ID.toBuffer(),
-        mintKeypair.publicKey.toBuffer(),
-      ],
-      TOKEN_METADATA_PROGRAM_ID
-    )
-
-    await program.methods
-      .mintCoupon(dealPda)
-      .accounts({
-        deal: dealPda,
-        coupon: couponPda,
-        mint: mintKeypair.publicKey,
-        tokenAccount: userTokenAccount,
-        metadata: metadataPda,
-        merchant: merchant.publicKey,
-        user: user.publicKey,
-        rent: SYSVAR_RENT_PUBKEY,
-        systemProgram: SystemProgram.programId,
-        tokenProgram: TOKEN_PROGRAM_ID,
-        associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
-        tokenMetadataProgram: TOKEN_METADATA_PROGRAM_ID,
-      })
-      .signers([user, mintKeypair])
-      .rpc()
+    const { couponPda, mint } = await mintCoupon(dealPda)
+    const userTokenAccount = getAssociatedTokenAddressSync(mint, user.publicKey, false, TOKEN_2022_PROGRAM_ID)
 
     // Transfer to merchant
     const newOwner = merchant.publicKey
 
+    const newOwnerTokenAccount = getAssociatedTokenAddressSync(
+      mint,
+      newOwner,
+      false,
+      TOKEN_2022_PROGRAM_ID
+    )
+
     await program.methods
       .transferCoupon()
       .accounts({
         coupon: couponPda,
+        mint,
+        fromTokenAccount: userTokenAccount,
+        toTokenAccount: newOwnerTokenAccount,
         currentOwner: user.publicKey,
         newOwner: newOwner,
+        tokenProgram: TOKEN_2022_PROGRAM_ID,
       })
+      .remainingAccounts(getCouponHookAccounts(mint, couponPda))
       .signers([user])
       .rpc()
 
     const couponAccount = await program.account.coupon.fetch(couponPda)
     assert.equal(couponAccount.owner.toString(), newOwner.toString())
+
+    // The NFT itself moved along with the record
+    const fromBalance = await provider.connection.getTokenAccountBalance(userTokenAccount)
+    const toBalance = await provider.connection.getTokenAccountBalance(newOwnerTokenAccount)
+    assert.equal(fromBalance.value.amount, '0')
+    assert.equal(toBalance.value.amount, '1')
+
+    // The previous holder can no longer move it
+    try {
+      await program.methods
+        .transferCoupon()
+        .accounts({
+          coupon: couponPda,
+          mint,
+          fromTokenAccount: userTokenAccount,
+          toTokenAccount: userTokenAccount,
+          currentOwner: user.publicKey,
+          newOwner: user.publicKey,
+          tokenProgram: TOKEN_2022_PROGRAM_ID,
+        })
+        .remainingAccounts(getCouponHookAccounts(mint, couponPda))
+        .signers([user])
+        .rpc()
+
+      assert.fail('Should have thrown error')
+    } catch (error) {
+      assert.include(error.message, 'NotOwner')
+    }
   })
 
   it('Prevents non-merchant from redeeming', async () => {
-    const allCoupons = await program.account.coupon.all()
+    // Legacy coupon fixtures share the discriminator but not the layout, so only fetch this deal's
+    const allCoupons = await program.account.coupon.all([{ memcmp: { offset: 8, bytes: dealPda.toBase58() } }])
     const unredeemed = allCoupons.find((c) => !c.account.isRedeemed)
 
     if (!unredeemed) {
@@ -286,6 +564,7 @@ describe('Deal Discovery Platform', () => {
           coupon: unredeemed.publicKey,
           deal: dealPda,
           merchant: user.publicKey, // Wrong merchant!
+          couponTokenProgram: TOKEN_2022_PROGRAM_ID,
         })
         .signers([user])
         .rpc()
@@ -299,7 +578,7 @@ describe('Deal Discovery Platform', () => {
   it('Prevents minting when deal is inactive', async () => {
     // Deactivate deal
     await program.methods
-      .updateDeal(false, null)
+      .updateDeal(false, null, null, null, null, null, null, null, null)
       .accounts({
         deal: dealPda,
         merchant: merchant.publicKey,
@@ -307,65 +586,75 @@ describe('Deal Discovery Platform', () => {
       .signers([merchant])
       .rpc()
 
-    dealAccount = await program.account.deal.fetch(dealPda)
-    const mintKeypair = Keypair.generate()
-    const [couponPda] = PublicKey.findProgramAddressSync(
-      [
-        Buffer.from('coupon'),
-        dealPda.toBuffer(),
-        dealAccount.currentSupply.toArrayLike(Buffer, 'le', 8),
-      ],
-      program.programId
-    )
-
-    const userTokenAccount = getAssociatedTokenAddressSync(
-      mintKeypair.publicKey,
-      user.publicKey
-    )
+    try {
+      await mintCoupon(dealPda)
+      assert.fail('Should have thrown error')
+    } catch (error) {
+      assert.include(error.message, 'DealInactive')
+    }
 
-    const [metadataPda] = PublicKey.findProgramAddressSync(
-      [
-        Buffer.from('metadata'),
-        TOKEN_METADATA_PROGRAM_ID.toBuffer(),
-        mintKeypair.publicKey.toBuffer(),
-      ],
-      TOKEN_METADATA_PROGRAM_ID
-    )
+    // Reactivate for rating/comment tests
+    await program.methods
+      .updateDeal(true, null, null, null, null, null, null, null, null)
+      .accounts({
+        deal: dealPda,
+        merchant: merchant.publicKey,
+      })
+      .signers([merchant])
+      .rpc()
+  })
 
+  it('Prevents closing an active deal', async () => {
     try {
       await program.methods
-        .mintCoupon(dealPda)
+        .closeDeal()
         .accounts({
           deal: dealPda,
-          coupon: couponPda,
-          mint: mintKeypair.publicKey,
-          tokenAccount: userTokenAccount,
-          metadata: metadataPda,
           merchant: merchant.publicKey,
-          user: user.publicKey,
-          rent: SYSVAR_RENT_PUBKEY,
-          systemProgram: SystemProgram.programId,
-          tokenProgram: TOKEN_PROGRAM_ID,
-          associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
-          tokenMetadataProgram: TOKEN_METADATA_PROGRAM_ID,
         })
-        .signers([user, mintKeypair])
+        .signers([merchant])
         .rpc()
 
       assert.fail('Should have thrown error')
     } catch (error) {
-      assert.include(error.message, 'DealInactive')
+      assert.include(error.message, 'DealNotClosable')
     }
+  })
+
+  it('Closes a deactivated deal with no outstanding coupons', async () => {
+    const { deal: closableDealPda } = await createDeal({
+      title: `Closable Deal ${Date.now()}`,
+      description: 'Short-lived promotion',
+      discountPercent: 10,
+      maxSupply: new BN(5),
+    })
 
-    // Reactivate for rating/comment tests
     await program.methods
-      .updateDeal(true, null)
+      .updateDeal(false, null, null, null, null, null, null, null, null)
       .accounts({
-        deal: dealPda,
+        deal: closableDealPda,
         merchant: merchant.publicKey,
       })
       .signers([merchant])
       .rpc()
+
+    const merchantBalanceBefore = await provider.connection.getBalance(merchant.publicKey)
+
+    await program.methods
+      .closeDeal()
+      .accounts({
+        deal: closableDealPda,
+        merchant: merchant.publicKey,
+      })
+      .signers([merchant])
+      .rpc()
+
+    const closedDeal = await provider.connection.getAccountInfo(closableDealPda)
+    assert.isNull(closedDeal)
+
+    // Rent comes back to the merchant (minus the transaction fee)
+    const merchantBalanceAfter = await provider.connection.getBalance(merchant.publicKey)
+    assert.isAbove(merchantBalanceAfter, merchantBalanceBefore)
   })
 
   it('Rates a deal', async () => {
@@ -602,76 +891,2638 @@ describe('Deal Discovery Platform', () => {
     }
   })
 
-  // Marketplace Tests
-  describe('Secondary Marketplace', () => {
-    let listingCouponPda: PublicKey
-    let listingPda: PublicKey
-    let seller: Keypair
-    let buyer: Keypair
+  // Redemption must be bound to the coupon's own deal and that deal's merchant
+  describe('Cross-deal redemption', () => {
+    let victimMerchant: Keypair
+    let attackerMerchant: Keypair
+    let holder: Keypair
+    let victimDealPda: PublicKey
+    let attackerDealPda: PublicKey
+    let victimCouponPda: PublicKey
+    let attackerCouponPda: PublicKey
+
+    const airdrop = async (to: PublicKey) => {
+      const sig = await provider.connection.requestAirdrop(to, 2 * anchor.web3.LAMPORTS_PER_SOL)
+      await provider.connection.confirmTransaction(sig)
+    }
 
     before(async () => {
-      seller = Keypair.generate()
-      buyer = Keypair.generate()
+      victimMerchant = Keypair.generate()
+      attackerMerchant = Keypair.generate()
+      holder = Keypair.generate()
+
+      await airdrop(victimMerchant.publicKey)
+      await airdrop(attackerMerchant.publicKey)
+      await airdrop(holder.publicKey)
+
+      ;({ deal: victimDealPda } = await createDeal({ merchant: victimMerchant, title: `Victim Deal ${Date.now()}` }))
+      ;({ deal: attackerDealPda } = await createDeal({
+        merchant: attackerMerchant,
+        title: `Attacker Deal ${Date.now()}`,
+      }))
+
+      ;({ couponPda: victimCouponPda } = await mintCoupon(victimDealPda, { buyer: holder }))
+      ;({ couponPda: attackerCouponPda } = await mintCoupon(attackerDealPda, { buyer: holder }))
+    })
 
-      // Airdrop to both
-      const sellerAirdrop = await provider.connection.requestAirdrop(
-        seller.publicKey,
-        2 * anchor.web3.LAMPORTS_PER_SOL
-      )
-      const buyerAirdrop = await provider.connection.requestAirdrop(
-        buyer.publicKey,
-        2 * anchor.web3.LAMPORTS_PER_SOL
-      )
+    it("Rejects redeeming a competitor's coupon against the attacker's own deal", async () => {
+      try {
+        await program.methods
+          .redeemCoupon()
+          .accounts({
+            coupon: victimCouponPda,
+            deal: attackerDealPda,
+            merchant: attackerMerchant.publicKey,
+            couponTokenProgram: TOKEN_2022_PROGRAM_ID,
+          })
+          .signers([attackerMerchant])
+          .rpc()
 
-      await provider.connection.confirmTransaction(sellerAirdrop)
-      await provider.connection.confirmTransaction(buyerAirdrop)
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'ConstraintSeeds')
+      }
+    })
 
-      // Mint a coupon for the seller
-      dealAccount = await program.account.deal.fetch(dealPda)
-      const mintKeypair = Keypair.generate()
+    it("Rejects redeeming against the coupon's real deal without being its merchant", async () => {
+      try {
+        await program.methods
+          .redeemCoupon()
+          .accounts({
+            coupon: victimCouponPda,
+            deal: victimDealPda,
+            merchant: attackerMerchant.publicKey,
+            couponTokenProgram: TOKEN_2022_PROGRAM_ID,
+          })
+          .signers([attackerMerchant])
+          .rpc()
 
-      listingCouponPda = PublicKey.findProgramAddressSync(
-        [
-          Buffer.from('coupon'),
-          dealPda.toBuffer(),
-          dealAccount.currentSupply.toArrayLike(Buffer, 'le', 8),
-        ],
-        program.programId
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'UnauthorizedMerchant')
+      }
+    })
+
+    it("Rejects the rightful merchant redeeming a coupon from another merchant's deal", async () => {
+      try {
+        await program.methods
+          .redeemCoupon()
+          .accounts({
+            coupon: attackerCouponPda,
+            deal: victimDealPda,
+            merchant: victimMerchant.publicKey,
+            couponTokenProgram: TOKEN_2022_PROGRAM_ID,
+          })
+          .signers([victimMerchant])
+          .rpc()
+
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'ConstraintSeeds')
+      }
+    })
+
+    it('Leaves the targeted coupon and deal untouched after failed attempts', async () => {
+      const coupon = await program.account.coupon.fetch(victimCouponPda)
+      assert.isFalse(coupon.isRedeemed)
+      assert.isNull(coupon.redeemedAt)
+
+      const attackerDeal = await program.account.deal.fetch(attackerDealPda)
+      assert.equal(attackerDeal.redeemedCount.toString(), '0')
+    })
+
+    it("Lets the deal's own merchant redeem the coupon", async () => {
+      await program.methods
+        .redeemCoupon()
+        .accounts({
+          coupon: victimCouponPda,
+          deal: victimDealPda,
+          merchant: victimMerchant.publicKey,
+          couponTokenProgram: TOKEN_2022_PROGRAM_ID,
+        })
+        .signers([victimMerchant])
+        .rpc()
+
+      const coupon = await program.account.coupon.fetch(victimCouponPda)
+      assert.isTrue(coupon.isRedeemed)
+
+      const victimDeal = await program.account.deal.fetch(victimDealPda)
+      assert.equal(victimDeal.redeemedCount.toString(), '1')
+    })
+  })
+
+  // Platform configuration (fee rate and treasury)
+  const [platformConfigPda] = PublicKey.findProgramAddressSync(
+    [Buffer.from('platform_config')],
+    program.programId
+  )
+  const BPF_LOADER_UPGRADEABLE_ID = new PublicKey('BPFLoaderUpgradeab1e11111111111111111111111')
+  const [programDataPda] = PublicKey.findProgramAddressSync(
+    [program.programId.toBuffer()],
+    BPF_LOADER_UPGRADEABLE_ID
+  )
+  const treasury = Keypair.generate().publicKey
+
+  describe('Platform Config', () => {
+    it('Initializes the platform config as the upgrade authority', async () => {
+      const existing = await program.account.platformConfig.fetchNullable(platformConfigPda)
+
+      if (!existing) {
+        await program.methods
+          .initializeConfig(treasury, 250, new BN(0))
+          .accounts({
+            platformConfig: platformConfigPda,
+            programData: programDataPda,
+            admin: provider.wallet.publicKey,
+            systemProgram: SystemProgram.programId,
+          })
+          .rpc()
+      } else {
+        await program.methods
+          .updateConfig(null, treasury, 250, new BN(0))
+          .accounts({
+            platformConfig: platformConfigPda,
+            admin: provider.wallet.publicKey,
+          })
+          .rpc()
+      }
+
+      const config = await program.account.platformConfig.fetch(platformConfigPda)
+      assert.equal(config.admin.toString(), provider.wallet.publicKey.toString())
+      assert.equal(config.treasury.toString(), treasury.toString())
+      assert.equal(config.feeBps, 250)
+    })
+
+    it('Prevents non-admin config updates', async () => {
+      try {
+        await program.methods
+          .updateConfig(null, user.publicKey, 0, null)
+          .accounts({
+            platformConfig: platformConfigPda,
+            admin: user.publicKey,
+          })
+          .signers([user])
+          .rpc()
+
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'UnauthorizedAdmin')
+      }
+    })
+
+    it('Prevents fees above the hard cap', async () => {
+      try {
+        await program.methods
+          .updateConfig(null, null, 1001, null)
+          .accounts({
+            platformConfig: platformConfigPda,
+            admin: provider.wallet.publicKey,
+          })
+          .rpc()
+
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'FeeTooHigh')
+      }
+    })
+  })
+
+  // Deals and resales priced in an SPL token (e.g. USDC)
+  describe('Token-priced deals', () => {
+    const payer = (provider.wallet as anchor.Wallet).payer
+    const price = new BN(5_000_000) // 5 USDC
+    let usdcMint: PublicKey
+    let tokenDealPda: PublicKey
+    let holder: Keypair
+    let couponPda: PublicKey
+    let couponMint: PublicKey
+
+    // Creates (or finds) the wallet's USDC account, optionally topping it up
+    const usdcAccount = async (owner: PublicKey, amount = 0) => {
+      const account = await getOrCreateAssociatedTokenAccount(provider.connection, payer, usdcMint, owner)
+      if (amount > 0) {
+        await mintTo(provider.connection, payer, usdcMint, account.address, payer, amount)
+      }
+      return account.address
+    }
+
+    const usdcBalance = async (account: PublicKey) =>
+      Number((await provider.connection.getTokenAccountBalance(account)).value.amount)
+
+    before(async () => {
+      usdcMint = await createMint(provider.connection, payer, payer.publicKey, null, 6)
+      holder = Keypair.generate()
+      const airdrop = await provider.connection.requestAirdrop(holder.publicKey, 2 * anchor.web3.LAMPORTS_PER_SOL)
+      await provider.connection.confirmTransaction(airdrop)
+    })
+
+    it('Creates a deal priced in an SPL token', async () => {
+      ;({ deal: tokenDealPda } = await createDeal({
+        title: `USDC Deal ${Date.now()}`,
+        description: 'Priced in USDC',
+        priceLamports: price,
+        paymentMint: usdcMint,
+      }))
+
+      const deal = await program.account.deal.fetch(tokenDealPda)
+      assert.equal(deal.paymentMint.toString(), usdcMint.toString())
+    })
+
+    it('Rejects minting without token payment accounts', async () => {
+      try {
+        await mintCoupon(tokenDealPda, { buyer: holder, uri: 'ipfs://usdc-coupon' })
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'MissingPaymentAccount')
+      }
+    })
+
+    it('Mints a coupon paid in the SPL token', async () => {
+      const holderUsdc = await usdcAccount(holder.publicKey, price.toNumber())
+      const merchantUsdc = await usdcAccount(merchant.publicKey)
+      const merchantBefore = await usdcBalance(merchantUsdc)
+
+      ;({ couponPda, mint: couponMint } = await mintCoupon(tokenDealPda, {
+        buyer: holder,
+        uri: 'ipfs://usdc-coupon',
+        accounts: { paymentMint: usdcMint, userPaymentAccount: holderUsdc, merchantPaymentAccount: merchantUsdc },
+      }))
+
+      assert.equal(await usdcBalance(holderUsdc), 0)
+      assert.equal((await usdcBalance(merchantUsdc)) - merchantBefore, price.toNumber())
+    })
+
+    it('Sells a coupon for the SPL token with the platform fee split', async () => {
+      const buyer = Keypair.generate()
+      const airdrop = await provider.connection.requestAirdrop(buyer.publicKey, 2 * anchor.web3.LAMPORTS_PER_SOL)
+      await provider.connection.confirmTransaction(airdrop)
+
+      const [listingPda] = PublicKey.findProgramAddressSync(
+        [Buffer.from('listing'), couponPda.toBuffer()],
+        program.programId
+      )
+      const resalePrice = new BN(8_000_000)
+
+      await program.methods
+        .listCoupon(resalePrice)
+        .accountsPartial({
+          coupon: couponPda,
+          mint: couponMint,
+          sellerTokenAccount: getAssociatedTokenAddressSync(couponMint, holder.publicKey, false, TOKEN_2022_PROGRAM_ID),
+          listing: listingPda,
+          seller: holder.publicKey,
+          paymentMint: usdcMint,
+          tokenProgram: TOKEN_2022_PROGRAM_ID,
+        })
+        .remainingAccounts(getCouponHookAccounts(couponMint, couponPda))
+        .signers([holder])
+        .rpc()
+
+      const listing = await program.account.listing.fetch(listingPda)
+      assert.equal(listing.paymentMint.toString(), usdcMint.toString())
+
+      const config = await program.account.platformConfig.fetch(platformConfigPda)
+      const buyerUsdc = await usdcAccount(buyer.publicKey, resalePrice.toNumber())
+      const sellerUsdc = await usdcAccount(holder.publicKey)
+      const treasuryUsdc = await usdcAccount(config.treasury)
+      const treasuryBefore = await usdcBalance(treasuryUsdc)
+
+      await program.methods
+        .buyCoupon()
+        .accountsPartial({
+          listing: listingPda,
+          coupon: couponPda,
+          mint: couponMint,
+          couponTokenProgram: TOKEN_2022_PROGRAM_ID,
+          seller: holder.publicKey,
+          buyer: buyer.publicKey,
+          platformConfig: platformConfigPda,
+          platformWallet: config.treasury,
+          paymentMint: usdcMint,
+          buyerPaymentAccount: buyerUsdc,
+          sellerPaymentAccount: sellerUsdc,
+          treasuryPaymentAccount: treasuryUsdc,
+        })
+        .remainingAccounts(getCouponHookAccounts(couponMint, couponPda))
+        .signers([buyer])
+        .rpc()
+
+      const platformFee = Math.floor((resalePrice.toNumber() * config.feeBps) / 10_000)
+      assert.equal(await usdcBalance(buyerUsdc), 0)
+      assert.equal(await usdcBalance(sellerUsdc), resalePrice.toNumber() - platformFee)
+      assert.equal((await usdcBalance(treasuryUsdc)) - treasuryBefore, platformFee)
+
+      const coupon = await program.account.coupon.fetch(couponPda)
+      assert.equal(coupon.owner.toString(), buyer.publicKey.toString())
+    })
+
+    it("Sweeps and closes the vault's token account when closing the deal", async () => {
+      const { deal: closableDealPda } = await createDeal({
+        title: `USDC Escrow Deal ${Date.now()}`,
+        description: 'Closed before any sales',
+        priceLamports: price,
+        escrowPayments: true,
+        paymentMint: usdcMint,
+      })
+      const [vaultPda] = PublicKey.findProgramAddressSync(
+        [Buffer.from('deal_vault'), closableDealPda.toBuffer()],
+        program.programId
+      )
+      const vaultUsdc = (await getOrCreateAssociatedTokenAccount(provider.connection, payer, usdcMint, vaultPda, true))
+        .address
+      await mintTo(provider.connection, payer, usdcMint, vaultUsdc, payer, 1_000)
+      const merchantUsdc = await usdcAccount(merchant.publicKey)
+      const merchantBefore = await usdcBalance(merchantUsdc)
+
+      await program.methods
+        .updateDeal(false, null, null, null, null, null, null, null, null)
+        .accounts({
+          deal: closableDealPda,
+          merchant: merchant.publicKey,
+        })
+        .signers([merchant])
+        .rpc()
+
+      await program.methods
+        .closeDeal()
+        .accountsPartial({
+          deal: closableDealPda,
+          merchant: merchant.publicKey,
+          paymentMint: usdcMint,
+          merchantPaymentAccount: merchantUsdc,
+          vaultPaymentAccount: vaultUsdc,
+        })
+        .signers([merchant])
+        .rpc()
+
+      assert.equal((await usdcBalance(merchantUsdc)) - merchantBefore, 1_000)
+      assert.isNull(await provider.connection.getAccountInfo(vaultUsdc))
+      assert.isNull(await provider.connection.getAccountInfo(closableDealPda))
+    })
+  })
+
+  // Mint payments held in a deal vault until redemption or refund
+  describe('Buyer-protection escrow', () => {
+    const price = new BN(50_000_000) // 0.05 SOL
+    let escrowDealPda: PublicKey
+    let vaultPda: PublicKey
+    let expiry: number
+    let redeemedCouponPda: PublicKey
+    let refundCouponPda: PublicKey
+    let refundMint: PublicKey
+
+    it('Creates an escrowed deal and seeds its vault', async () => {
+      // Short-lived so the refund path can be exercised after expiry
+      expiry = Math.floor(Date.now() / 1000) + 10
+      ;({ deal: escrowDealPda } = await createDeal({
+        title: `Escrow Deal ${Date.now()}`,
+        description: 'Paid into escrow',
+        discountPercent: 15,
+        expiryTimestamp: new BN(expiry),
+        priceLamports: price,
+        escrowPayments: true,
+      }))
+      ;[vaultPda] = PublicKey.findProgramAddressSync(
+        [Buffer.from('deal_vault'), escrowDealPda.toBuffer()],
+        program.programId
+      )
+
+      const deal = await program.account.deal.fetch(escrowDealPda)
+      assert.isTrue(deal.escrowPayments)
+      const reserve = await provider.connection.getMinimumBalanceForRentExemption(0)
+      assert.equal(await provider.connection.getBalance(vaultPda), reserve)
+    })
+
+    it('Holds mint payments in the deal vault', async () => {
+      const merchantBefore = await provider.connection.getBalance(merchant.publicKey)
+      const vaultBefore = await provider.connection.getBalance(vaultPda)
+
+      ;({ couponPda: redeemedCouponPda } = await mintCoupon(escrowDealPda))
+      ;({ couponPda: refundCouponPda, mint: refundMint } = await mintCoupon(escrowDealPda))
+
+      assert.equal(await provider.connection.getBalance(merchant.publicKey), merchantBefore)
+      assert.equal((await provider.connection.getBalance(vaultPda)) - vaultBefore, 2 * price.toNumber())
+
+      const coupon = await program.account.coupon.fetch(redeemedCouponPda)
+      assert.equal(coupon.escrowedAmount.toString(), price.toString())
+    })
+
+    it('Prevents extending the expiry while payments are escrowed', async () => {
+      try {
+        await program.methods
+          .updateDeal(null, null, null, null, null, null, null, new BN(expiry + 86400), null)
+          .accounts({
+            deal: escrowDealPda,
+            merchant: merchant.publicKey,
+          })
+          .signers([merchant])
+          .rpc()
+
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'ExpiryExtensionLocked')
+      }
+    })
+
+    it('Releases the escrowed payment to the merchant on redemption', async () => {
+      const merchantBefore = await provider.connection.getBalance(merchant.publicKey)
+
+      await program.methods
+        .redeemCoupon()
+        .accountsPartial({
+          coupon: redeemedCouponPda,
+          deal: escrowDealPda,
+          merchant: merchant.publicKey,
+          couponTokenProgram: TOKEN_2022_PROGRAM_ID,
+        })
+        .signers([merchant])
+        .rpc()
+
+      const coupon = await program.account.coupon.fetch(redeemedCouponPda)
+      assert.isTrue(coupon.isRedeemed)
+      assert.equal(coupon.escrowedAmount.toString(), '0')
+      // The merchant also pays the transaction fee, so allow for it
+      const gained = (await provider.connection.getBalance(merchant.publicKey)) - merchantBefore
+      assert.isAbove(gained, price.toNumber() - 10_000)
+    })
+
+    it('Prevents refunds before the deal expires', async () => {
+      try {
+        await program.methods
+          .refundUnredeemed()
+          .accountsPartial({
+            coupon: refundCouponPda,
+            deal: escrowDealPda,
+            mint: refundMint,
+            holderTokenAccount: getAssociatedTokenAddressSync(refundMint, user.publicKey, false, TOKEN_2022_PROGRAM_ID),
+            holder: user.publicKey,
+            couponTokenProgram: TOKEN_2022_PROGRAM_ID,
+          })
+          .signers([user])
+          .rpc()
+
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'DealNotExpired')
+      }
+    })
+
+    it('Refunds an unredeemed coupon after expiry', async () => {
+      const wait = expiry * 1000 - Date.now() + 2000
+      if (wait > 0) await new Promise((resolve) => setTimeout(resolve, wait))
+
+      const vaultBefore = await provider.connection.getBalance(vaultPda)
+
+      const signature = await program.methods
+        .refundUnredeemed()
+        .accountsPartial({
+          coupon: refundCouponPda,
+          deal: escrowDealPda,
+          mint: refundMint,
+          holderTokenAccount: getAssociatedTokenAddressSync(refundMint, user.publicKey, false, TOKEN_2022_PROGRAM_ID),
+          holder: user.publicKey,
+          couponTokenProgram: TOKEN_2022_PROGRAM_ID,
+        })
+        .signers([user])
+        .rpc()
+
+      const event = await fetchCpiEvent(signature)
+      assert.equal(event.name, 'couponRefunded')
+      assert.equal(event.data.amount.toString(), price.toString())
+      assert.equal(vaultBefore - (await provider.connection.getBalance(vaultPda)), price.toNumber())
+
+      const coupon = await program.account.coupon.fetch(refundCouponPda)
+      assert.isTrue(coupon.isRefunded)
+      assert.equal(coupon.escrowedAmount.toString(), '0')
+
+      // The coupon NFT is burned so it can't be resold, staked or redeemed
+      const tokenBalance = await provider.connection.getTokenAccountBalance(
+        getAssociatedTokenAddressSync(refundMint, user.publicKey, false, TOKEN_2022_PROGRAM_ID)
+      )
+      assert.equal(tokenBalance.value.amount, '0')
+
+      const deal = await program.account.deal.fetch(escrowDealPda)
+      assert.equal(deal.refundedCount.toString(), '1')
+    })
+
+    it('Prevents refunding the same coupon twice', async () => {
+      try {
+        await program.methods
+          .refundUnredeemed()
+          .accountsPartial({
+            coupon: refundCouponPda,
+            deal: escrowDealPda,
+            mint: refundMint,
+            holderTokenAccount: getAssociatedTokenAddressSync(refundMint, user.publicKey, false, TOKEN_2022_PROGRAM_ID),
+            holder: user.publicKey,
+            couponTokenProgram: TOKEN_2022_PROGRAM_ID,
+          })
+          .signers([user])
+          .rpc()
+
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        // The holder no longer has the coupon to refund
+        assert.include(error.message, 'NotOwner')
+      }
+    })
+
+    it('Prevents redeeming a refunded coupon', async () => {
+      try {
+        await program.methods
+          .redeemCoupon()
+          .accountsPartial({
+            coupon: refundCouponPda,
+            deal: escrowDealPda,
+            merchant: merchant.publicKey,
+            couponTokenProgram: TOKEN_2022_PROGRAM_ID,
+          })
+          .signers([merchant])
+          .rpc()
+
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        // Its burned NFT can't be presented
+        assert.include(error.message, 'NotOwner')
+      }
+    })
+
+    it('Closes the settled deal and returns the vault reserve', async () => {
+      await program.methods
+        .closeDeal()
+        .accountsPartial({
+          deal: escrowDealPda,
+          merchant: merchant.publicKey,
+        })
+        .signers([merchant])
+        .rpc()
+
+      assert.equal(await provider.connection.getBalance(vaultPda), 0)
+    })
+  })
+
+  // Merchant cancels a deal and holders claim refunds from the deal vault
+  describe('Deal cancellation', () => {
+    const price = new BN(20_000_000) // 0.02 SOL
+    const repricedPrice = new BN(30_000_000)
+    let cancelDealPda: PublicKey
+    let vaultPda: PublicKey
+    const coupons: { couponPda: PublicKey; mint: PublicKey }[] = []
+
+    const refundAccounts = (coupon: { couponPda: PublicKey; mint: PublicKey }) => ({
+      coupon: coupon.couponPda,
+      deal: cancelDealPda,
+      mint: coupon.mint,
+      holderTokenAccount: getAssociatedTokenAddressSync(coupon.mint, user.publicKey, false, TOKEN_2022_PROGRAM_ID),
+      holder: user.publicKey,
+      couponTokenProgram: TOKEN_2022_PROGRAM_ID,
+    })
+
+    before(async () => {
+      ;({ deal: cancelDealPda } = await createDeal({
+        title: `Cancelled Deal ${Date.now()}`,
+        description: 'Merchant closes early',
+        discountPercent: 30,
+        priceLamports: price,
+      }))
+      ;[vaultPda] = PublicKey.findProgramAddressSync(
+        [Buffer.from('deal_vault'), cancelDealPda.toBuffer()],
+        program.programId
+      )
+
+      for (let i = 0; i < 2; i++) {
+        coupons.push(await mintCoupon(cancelDealPda))
+      }
+    })
+
+    it('Prevents refund claims before cancellation', async () => {
+      try {
+        await program.methods
+          .claimCancellationRefund()
+          .accountsPartial(refundAccounts(coupons[0]))
+          .signers([user])
+          .rpc()
+
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'DealNotCancelled')
+      }
+    })
+
+    it('Prevents non-merchants from cancelling', async () => {
+      try {
+        await program.methods
+          .cancelDeal()
+          .accountsPartial({
+            deal: cancelDealPda,
+            merchant: user.publicKey,
+          })
+          .signers([user])
+          .rpc()
+
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'UnauthorizedMerchant')
+      }
+    })
+
+    it('Keeps the price each coupon sold at when the deal is repriced', async () => {
+      await program.methods
+        .updateDeal(null, repricedPrice, null, null, null, null, null, null, null)
+        .accounts({
+          deal: cancelDealPda,
+          merchant: merchant.publicKey,
+        })
+        .signers([merchant])
+        .rpc()
+      coupons.push(await mintCoupon(cancelDealPda))
+
+      const first = await program.account.coupon.fetch(coupons[0].couponPda)
+      assert.equal(first.pricePaid.toString(), price.toString())
+      const repriced = await program.account.coupon.fetch(coupons[2].couponPda)
+      assert.equal(repriced.pricePaid.toString(), repricedPrice.toString())
+
+      const deal = await program.account.deal.fetch(cancelDealPda)
+      assert.equal(deal.outstandingPayments.toString(), price.muln(2).add(repricedPrice).toString())
+    })
+
+    it('Cancels the deal and funds refunds for outstanding coupons', async () => {
+      const signature = await program.methods
+        .cancelDeal()
+        .accountsPartial({
+          deal: cancelDealPda,
+          merchant: merchant.publicKey,
+        })
+        .signers([merchant])
+        .rpc()
+
+      const deal = await program.account.deal.fetch(cancelDealPda)
+      assert.isTrue(deal.isCancelled)
+      assert.isFalse(deal.isActive)
+
+      const event = await fetchCpiEvent(signature)
+      assert.equal(event.name, 'dealCancelled')
+      assert.equal(event.data.outstandingCoupons.toString(), '3')
+      const owed = price.muln(2).add(repricedPrice)
+      assert.equal(event.data.refundsOwed.toString(), owed.toString())
+
+      const reserve = await provider.connection.getMinimumBalanceForRentExemption(0)
+      assert.equal(await provider.connection.getBalance(vaultPda), reserve + owed.toNumber())
+    })
+
+    it('Prevents updating a cancelled deal', async () => {
+      try {
+        await program.methods
+          .updateDeal(true, null, null, null, null, null, null, null, null)
+          .accounts({
+            deal: cancelDealPda,
+            merchant: merchant.publicKey,
+          })
+          .signers([merchant])
+          .rpc()
+
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'DealCancelled')
+      }
+    })
+
+    it('Refunds the holder and burns the coupon', async () => {
+      const userBefore = await provider.connection.getBalance(user.publicKey)
+
+      await program.methods
+        .claimCancellationRefund()
+        .accountsPartial(refundAccounts(coupons[0]))
+        .signers([user])
+        .rpc()
+
+      const coupon = await program.account.coupon.fetch(coupons[0].couponPda)
+      assert.isTrue(coupon.isRefunded)
+
+      const tokenBalance = await provider.connection.getTokenAccountBalance(
+        getAssociatedTokenAddressSync(coupons[0].mint, user.publicKey, false, TOKEN_2022_PROGRAM_ID)
+      )
+      assert.equal(tokenBalance.value.amount, '0')
+
+      // The holder pays the transaction fee, so allow for it
+      const gained = (await provider.connection.getBalance(user.publicKey)) - userBefore
+      assert.isAbove(gained, price.toNumber() - 10_000)
+    })
+
+    it('Refunds a coupon sold after the repricing at the price it sold for', async () => {
+      const userBefore = await provider.connection.getBalance(user.publicKey)
+
+      await program.methods
+        .claimCancellationRefund()
+        .accountsPartial(refundAccounts(coupons[2]))
+        .signers([user])
+        .rpc()
+
+      const gained = (await provider.connection.getBalance(user.publicKey)) - userBefore
+      assert.isAbove(gained, repricedPrice.toNumber() - 10_000)
+      assert.isAtMost(gained, repricedPrice.toNumber())
+    })
+
+    it('Prevents claiming a refund twice', async () => {
+      try {
+        await program.methods
+          .claimCancellationRefund()
+          .accountsPartial(refundAccounts(coupons[0]))
+          .signers([user])
+          .rpc()
+
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'NotOwner')
+      }
+    })
+
+    it('Prevents redeeming coupons of a cancelled deal', async () => {
+      try {
+        await program.methods
+          .redeemCoupon()
+          .accountsPartial({
+            coupon: coupons[1].couponPda,
+            deal: cancelDealPda,
+            merchant: merchant.publicKey,
+            couponTokenProgram: TOKEN_2022_PROGRAM_ID,
+          })
+          .signers([merchant])
+          .rpc()
+
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'DealCancelled')
+      }
+    })
+  })
+
+  // Per-wallet mint caps tracked by a [b"purchase", deal, user] PDA
+  describe('Per-wallet purchase limits', () => {
+    let limitedDealPda: PublicKey
+
+    before(async () => {
+      ;({ deal: limitedDealPda } = await createDeal({
+        title: `Limited Deal ${Date.now()}`,
+        description: 'One per customer',
+        discountPercent: 40,
+        maxPerWallet: new BN(1),
+      }))
+    })
+
+    it('Counts mints per wallet', async () => {
+      await mintCoupon(limitedDealPda)
+
+      const [purchasePda] = PublicKey.findProgramAddressSync(
+        [Buffer.from('purchase'), limitedDealPda.toBuffer(), user.publicKey.toBuffer()],
+        program.programId
+      )
+      const purchase = await program.account.purchaseRecord.fetch(purchasePda)
+      assert.equal(purchase.count.toString(), '1')
+      assert.equal(purchase.buyer.toString(), user.publicKey.toString())
+    })
+
+    it('Rejects mints beyond the per-wallet limit', async () => {
+      try {
+        await mintCoupon(limitedDealPda)
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'PurchaseLimitExceeded')
+      }
+    })
+
+    it('Lets the merchant raise the limit', async () => {
+      await program.methods
+        .updateDeal(null, null, null, null, null, null, null, null, new BN(2))
+        .accounts({
+          deal: limitedDealPda,
+          merchant: merchant.publicKey,
+        })
+        .signers([merchant])
+        .rpc()
+
+      await mintCoupon(limitedDealPda)
+
+      const deal = await program.account.deal.fetch(limitedDealPda)
+      assert.equal(deal.maxPerWallet.toString(), '2')
+      assert.equal(deal.currentSupply.toString(), '2')
+    })
+  })
+
+  describe('Merkle allowlists', () => {
+    let allowlistDealPda: PublicKey
+
+    // Mirrors allowlist_leaf / allowlist_node in the program
+    const sha256 = (...parts: Buffer[]) => createHash('sha256').update(Buffer.concat(parts)).digest()
+    const leaf = (wallet: PublicKey, allowance: number) =>
+      sha256(Buffer.from([0]), wallet.toBuffer(), new BN(allowance).toArrayLike(Buffer, 'le', 8))
+    const node = (a: Buffer, b: Buffer) =>
+      Buffer.compare(a, b) <= 0 ? sha256(Buffer.from([1]), a, b) : sha256(Buffer.from([1]), b, a)
+
+    // Two-wallet tree: the user may mint one coupon, the merchant two
+    const userLeaf = () => leaf(user.publicKey, 1)
+    const merchantLeaf = () => leaf(merchant.publicKey, 2)
+    const userProof = (allowance: number) => ({ allowance: new BN(allowance), proof: [Array.from(merchantLeaf())] })
+
+    before(async () => {
+      const root = node(userLeaf(), merchantLeaf())
+      ;({ deal: allowlistDealPda } = await createDeal({
+        title: `VIP Drop ${Date.now()}`,
+        description: 'Allowlisted wallets only',
+        discountPercent: 50,
+        allowlistRoot: Array.from(root),
+      }))
+
+      const deal = await program.account.deal.fetch(allowlistDealPda)
+      assert.deepEqual(Buffer.from(deal.allowlistRoot), root)
+    })
+
+    it('Requires a proof on allowlisted deals', async () => {
+      try {
+        await mintCoupon(allowlistDealPda)
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'AllowlistProofRequired')
+      }
+    })
+
+    it('Rejects a proof for the wrong allowance', async () => {
+      try {
+        await mintCoupon(allowlistDealPda, { allowlist: userProof(5) })
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'NotOnAllowlist')
+      }
+    })
+
+    it('Mints for an allowlisted wallet', async () => {
+      await mintCoupon(allowlistDealPda, { allowlist: userProof(1) })
+
+      const [purchasePda] = PublicKey.findProgramAddressSync(
+        [Buffer.from('purchase'), allowlistDealPda.toBuffer(), user.publicKey.toBuffer()],
+        program.programId
+      )
+      const purchase = await program.account.purchaseRecord.fetch(purchasePda)
+      assert.equal(purchase.count.toString(), '1')
+    })
+
+    it('Stops the wallet once its allowance is claimed', async () => {
+      try {
+        await mintCoupon(allowlistDealPda, { allowlist: userProof(1) })
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'AllowanceExhausted')
+      }
+    })
+  })
+
+  describe('Token-gated deals', () => {
+    const payer = (provider.wallet as anchor.Wallet).payer
+    let loyaltyMint: PublicKey
+    let gatedDealPda: PublicKey
+
+    const gateAccounts = (pubkey: PublicKey) => [{ pubkey, isSigner: false, isWritable: false }]
+
+    before(async () => {
+      loyaltyMint = await createMint(provider.connection, payer, payer.publicKey, null, 0)
+
+      ;({ deal: gatedDealPda } = await createDeal({
+        title: `Loyalty Deal ${Date.now()}`,
+        description: 'Hold 100 loyalty points',
+        discountPercent: 30,
+        tokenGate: { tokenBalance: { mint: loyaltyMint, minAmount: new BN(100) } },
+      }))
+    })
+
+    it('Requires the holder token account', async () => {
+      try {
+        await mintCoupon(gatedDealPda)
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'TokenGateAccountsMissing')
+      }
+    })
+
+    it('Rejects holders below the minimum balance', async () => {
+      const account = await getOrCreateAssociatedTokenAccount(provider.connection, payer, loyaltyMint, user.publicKey)
+      await mintTo(provider.connection, payer, loyaltyMint, account.address, payer, 50)
+
+      try {
+        await mintCoupon(gatedDealPda, { remainingAccounts: gateAccounts(account.address) })
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'InsufficientGateBalance')
+      }
+    })
+
+    it("Rejects another wallet's token account", async () => {
+      const account = await getOrCreateAssociatedTokenAccount(provider.connection, payer, loyaltyMint, payer.publicKey)
+      await mintTo(provider.connection, payer, loyaltyMint, account.address, payer, 500)
+
+      try {
+        await mintCoupon(gatedDealPda, { remainingAccounts: gateAccounts(account.address) })
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'InvalidGateAccount')
+      }
+    })
+
+    it('Mints once the holder meets the minimum balance', async () => {
+      const account = getAssociatedTokenAddressSync(loyaltyMint, user.publicKey)
+      await mintTo(provider.connection, payer, loyaltyMint, account, payer, 50)
+
+      await mintCoupon(gatedDealPda, { remainingAccounts: gateAccounts(account) })
+
+      const deal = await program.account.deal.fetch(gatedDealPda)
+      assert.equal(deal.currentSupply.toString(), '1')
+    })
+  })
+
+  describe('Scheduled deals', () => {
+    const now = () => Math.floor(Date.now() / 1000)
+
+    it('Rejects a start time after the expiry', async () => {
+      try {
+        await createDeal({ title: `Happy Hour ${Date.now()}`, startTimestamp: new BN(now() + 86400 * 30) })
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'InvalidStartTime')
+      }
+    })
+
+    it('Rejects malformed redemption windows', async () => {
+      try {
+        await createDeal({
+          title: `Happy Hour ${Date.now()}`,
+          redemptionWindows: [{ days: 0b0011111, startMinute: 1080, endMinute: 900 }],
+        })
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'InvalidRedemptionWindow')
+      }
+    })
+
+    it('Blocks minting before the start time', async () => {
+      const { deal } = await createDeal({ title: `Happy Hour ${Date.now()}`, startTimestamp: new BN(now() + 3600) })
+
+      try {
+        await mintCoupon(deal)
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'DealNotStarted')
+      }
+    })
+
+    it('Blocks redemption outside the redemption windows', async () => {
+      // Every day except today (Monday = bit 0), so the window can't be open now
+      const today = (Math.floor(now() / 86400) + 3) % 7
+      const { deal } = await createDeal({
+        title: `Happy Hour ${Date.now()}`,
+        redemptionWindows: [{ days: 0b1111111 & ~(1 << today), startMinute: 0, endMinute: 1440 }],
+      })
+      const { couponPda: coupon } = await mintCoupon(deal)
+
+      try {
+        await program.methods
+          .redeemCoupon()
+          .accounts({
+            coupon,
+            deal,
+            merchant: merchant.publicKey,
+            couponTokenProgram: TOKEN_2022_PROGRAM_ID,
+          })
+          .signers([merchant])
+          .rpc()
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'OutsideRedemptionWindow')
+      }
+    })
+
+    it('Redeems inside a redemption window', async () => {
+      const { deal } = await createDeal({
+        title: `Happy Hour ${Date.now()}`,
+        redemptionWindows: [{ days: 0b1111111, startMinute: 0, endMinute: 1440 }],
+      })
+      const { couponPda: coupon } = await mintCoupon(deal)
+
+      await program.methods
+        .redeemCoupon()
+        .accounts({
+          coupon,
+          deal,
+          merchant: merchant.publicKey,
+          couponTokenProgram: TOKEN_2022_PROGRAM_ID,
+        })
+        .signers([merchant])
+        .rpc()
+
+      const couponAccount = await program.account.coupon.fetch(coupon)
+      assert.isTrue(couponAccount.isRedeemed)
+    })
+  })
+
+  describe('Multi-use coupons', () => {
+    let punchCardDealPda: PublicKey
+    let punchCardCouponPda: PublicKey
+
+    const redeemPunchCard = () =>
+      program.methods
+        .redeemCoupon()
+        .accounts({
+          coupon: punchCardCouponPda,
+          deal: punchCardDealPda,
+          merchant: merchant.publicKey,
+          couponTokenProgram: TOKEN_2022_PROGRAM_ID,
+        })
+        .signers([merchant])
+        .rpc()
+
+    before(async () => {
+      ;({ deal: punchCardDealPda } = await createDeal({
+        title: `Coffee Card ${Date.now()}`,
+        description: 'Three coffees',
+        discountPercent: 100,
+        category: 'Food & Dining',
+        usesPerCoupon: new BN(3),
+      }))
+      ;({ couponPda: punchCardCouponPda } = await mintCoupon(punchCardDealPda, { uri: 'ipfs://punch-card' }))
+    })
+
+    it('Rejects deals with zero uses per coupon', async () => {
+      try {
+        await createDeal({
+          title: `Empty Card ${Date.now()}`,
+          description: 'No uses',
+          discountPercent: 10,
+          category: 'Food & Dining',
+          usesPerCoupon: new BN(0),
+        })
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'InvalidUsesPerCoupon')
+      }
+    })
+
+    it('Mints coupons with the deal uses', async () => {
+      const coupon = await program.account.coupon.fetch(punchCardCouponPda)
+      assert.equal(coupon.usesTotal.toString(), '3')
+      assert.equal(coupon.usesRemaining.toString(), '3')
+    })
+
+    it('Counts down uses and logs each one', async () => {
+      const signature = await redeemPunchCard()
+      const event = await fetchCpiEvent(signature)
+      assert.equal(event.name, 'couponRedeemed')
+      assert.equal(event.data.usesRemaining.toString(), '2')
+
+      await redeemPunchCard()
+
+      const coupon = await program.account.coupon.fetch(punchCardCouponPda)
+      assert.equal(coupon.usesRemaining.toString(), '1')
+      assert.isFalse(coupon.isRedeemed)
+      const deal = await program.account.deal.fetch(punchCardDealPda)
+      assert.equal(deal.redeemedCount.toString(), '0')
+    })
+
+    it('Marks the coupon redeemed after its last use', async () => {
+      await redeemPunchCard()
+
+      const coupon = await program.account.coupon.fetch(punchCardCouponPda)
+      assert.equal(coupon.usesRemaining.toString(), '0')
+      assert.isTrue(coupon.isRedeemed)
+      const deal = await program.account.deal.fetch(punchCardDealPda)
+      assert.equal(deal.redeemedCount.toString(), '1')
+
+      try {
+        await redeemPunchCard()
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'AlreadyRedeemed')
+      }
+    })
+  })
+
+  describe('Stored-value gift cards', () => {
+    let giftDealPda: PublicKey
+    let giftCouponPda: PublicKey
+    let giftMint: PublicKey
+
+    const redemptionEntryFor = (index: number) =>
+      PublicKey.findProgramAddressSync(
+        [Buffer.from('redemption'), giftCouponPda.toBuffer(), new BN(index).toArrayLike(Buffer, 'le', 8)],
+        program.programId
       )[0]
 
-      const userTokenAccount = getAssociatedTokenAddressSync(
-        mintKeypair.publicKey,
-        seller.publicKey
+    const chargeGiftCard = (amount: number, index: number) =>
+      program.methods
+        .redeemAmount(new BN(amount))
+        .accountsPartial({
+          coupon: giftCouponPda,
+          deal: giftDealPda,
+          redemptionEntry: redemptionEntryFor(index),
+          mint: giftMint,
+          holderTokenAccount: getAssociatedTokenAddressSync(giftMint, user.publicKey, false, TOKEN_2022_PROGRAM_ID),
+          merchant: merchant.publicKey,
+          holder: user.publicKey,
+          couponTokenProgram: TOKEN_2022_PROGRAM_ID,
+        })
+        .signers([merchant, user])
+        .rpc()
+
+    before(async () => {
+      ;({ deal: giftDealPda } = await createDeal({
+        title: `Gift Card ${Date.now()}`,
+        description: '$50 store credit',
+        discountPercent: 0,
+        kind: { storedValue: { faceValue: new BN(5000) } },
+        redemptionAction: { burn: {} },
+      }))
+      ;({ couponPda: giftCouponPda, mint: giftMint } = await mintCoupon(giftDealPda, { uri: 'ipfs://gift-card' }))
+    })
+
+    it('Loads the face value onto the coupon', async () => {
+      const coupon = await program.account.coupon.fetch(giftCouponPda)
+      assert.equal(coupon.balance.toString(), '5000')
+    })
+
+    it('Rejects whole-coupon redemption for gift cards', async () => {
+      try {
+        await program.methods
+          .redeemCoupon()
+          .accounts({
+            coupon: giftCouponPda,
+            deal: giftDealPda,
+            merchant: merchant.publicKey,
+            couponTokenProgram: TOKEN_2022_PROGRAM_ID,
+          })
+          .signers([merchant])
+          .rpc()
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'WrongCouponKind')
+      }
+    })
+
+    it('Rejects charges above the balance', async () => {
+      try {
+        await chargeGiftCard(6000, 0)
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'InsufficientBalance')
+      }
+    })
+
+    it('Deducts a partial charge and logs it', async () => {
+      await chargeGiftCard(2000, 0)
+
+      const coupon = await program.account.coupon.fetch(giftCouponPda)
+      assert.equal(coupon.balance.toString(), '3000')
+      assert.equal(coupon.redemptionCount.toString(), '1')
+      assert.isFalse(coupon.isRedeemed)
+
+      const entry = await program.account.redemptionEntry.fetch(redemptionEntryFor(0))
+      assert.equal(entry.amount.toString(), '2000')
+      assert.equal(entry.balanceAfter.toString(), '3000')
+      assert.equal(entry.holder.toString(), user.publicKey.toString())
+    })
+
+    it('Closes the coupon once the balance is spent', async () => {
+      await chargeGiftCard(3000, 1)
+
+      assert.isNull(await program.account.coupon.fetchNullable(giftCouponPda))
+      const giftTokenAccount = getAssociatedTokenAddressSync(giftMint, user.publicKey, false, TOKEN_2022_PROGRAM_ID)
+      assert.isNull(await provider.connection.getAccountInfo(giftTokenAccount))
+
+      // The log outlives the card
+      const entry = await program.account.redemptionEntry.fetch(redemptionEntryFor(1))
+      assert.equal(entry.balanceAfter.toString(), '0')
+      const deal = await program.account.deal.fetch(giftDealPda)
+      assert.equal(deal.redeemedCount.toString(), '1')
+    })
+  })
+
+  describe('Deal collections', () => {
+    let collectionDealPda: PublicKey
+    let collectionMint: PublicKey
+
+    // Walks the variable-length metadata layout up to the collection field
+    const collectionOf = (data: Buffer) => {
+      let offset = 1 + 32 + 32
+      for (let i = 0; i < 3; i++) offset += 4 + data.readUInt32LE(offset)
+      offset += 2
+      if (data[offset++] === 1) offset += 4 + data.readUInt32LE(offset) * 34
+      offset += 2
+      if (data[offset++] === 1) offset += 1
+      if (data[offset++] === 1) offset += 1
+      if (data[offset++] !== 1) return null
+      return { verified: data[offset] === 1, key: new PublicKey(data.subarray(offset + 1, offset + 33)) }
+    }
+
+    before(async () => {
+      ;({ deal: collectionDealPda } = await createDeal({
+        title: `Collection Deal ${Date.now()}`,
+        description: 'Every coupon joins the deal collection',
+        collectionUri: 'ipfs://deal-collection',
+      }))
+      ;[collectionMint] = PublicKey.findProgramAddressSync(
+        [Buffer.from('collection'), collectionDealPda.toBuffer()],
+        program.programId
+      )
+    })
+
+    it('Mints a collection NFT controlled by the deal', async () => {
+      const deal = await program.account.deal.fetch(collectionDealPda)
+      assert.equal(deal.collectionMint.toString(), collectionMint.toString())
+
+      const mintInfo = await getMint(provider.connection, collectionMint)
+      assert.equal(mintInfo.supply.toString(), '1')
+      assert.equal(mintInfo.mintAuthority.toString(), collectionDealPda.toString())
+
+      const metadata = await provider.connection.getAccountInfo(metadataPdaOf(collectionMint))
+      assert.equal(new PublicKey(metadata.data.subarray(1, 33)).toString(), collectionDealPda.toString())
+    })
+
+    it('Verifies every coupon into the deal collection', async () => {
+      const { mint } = await mintCoupon(collectionDealPda, { uri: 'ipfs://collection-coupon' })
+
+      const metadata = await provider.connection.getAccountInfo(metadataPdaOf(mint))
+      const collection = collectionOf(metadata.data)
+      assert.isNotNull(collection)
+      assert.isTrue(collection.verified)
+      assert.equal(collection.key.toString(), collectionMint.toString())
+    })
+  })
+
+  describe('Resale royalties', () => {
+    const royaltyBps = 500
+    const resalePrice = new BN(anchor.web3.LAMPORTS_PER_SOL)
+    const buyer = Keypair.generate()
+    let royaltyDealPda: PublicKey
+    let couponPda: PublicKey
+    let couponMint: PublicKey
+    let listingPda: PublicKey
+
+    before(async () => {
+      const airdrop = await provider.connection.requestAirdrop(buyer.publicKey, 2 * anchor.web3.LAMPORTS_PER_SOL)
+      await provider.connection.confirmTransaction(airdrop)
+
+      ;({ deal: royaltyDealPda } = await createDeal({
+        title: `Royalty Deal ${Date.now()}`,
+        description: 'The merchant earns on every resale',
+        discountPercent: 25,
+        royaltyBps,
+      }))
+      ;({ couponPda, mint: couponMint } = await mintCoupon(royaltyDealPda, { uri: 'ipfs://royalty-coupon' }))
+      ;[listingPda] = PublicKey.findProgramAddressSync(
+        [Buffer.from('listing'), couponPda.toBuffer()],
+        program.programId
+      )
+
+      await program.methods
+        .listCoupon(resalePrice)
+        .accountsPartial({
+          coupon: couponPda,
+          mint: couponMint,
+          sellerTokenAccount: getAssociatedTokenAddressSync(couponMint, user.publicKey, false, TOKEN_2022_PROGRAM_ID),
+          listing: listingPda,
+          escrowTokenAccount: getAssociatedTokenAddressSync(couponMint, listingPda, true, TOKEN_2022_PROGRAM_ID),
+          seller: user.publicKey,
+          tokenProgram: TOKEN_2022_PROGRAM_ID,
+        })
+        .remainingAccounts(getCouponHookAccounts(couponMint, couponPda))
+        .signers([user])
+        .rpc()
+    })
+
+    it('Rejects a royalty above the cap', async () => {
+      try {
+        await createDeal({ royaltyBps: 2_501 })
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'RoyaltyTooHigh')
+      }
+    })
+
+    it('Writes the royalty and the merchant creator into the coupon metadata', async () => {
+      const { data } = await provider.connection.getAccountInfo(metadataPdaOf(couponMint))
+
+      // The deal PDA is the update authority
+      assert.equal(new PublicKey(data.subarray(1, 33)).toString(), royaltyDealPda.toString())
+
+      let offset = 1 + 32 + 32
+      for (let i = 0; i < 3; i++) offset += 4 + data.readUInt32LE(offset)
+      assert.equal(data.readUInt16LE(offset), royaltyBps)
+      offset += 2
+      assert.equal(data[offset++], 1)
+      assert.equal(data.readUInt32LE(offset), 1)
+      offset += 4
+      assert.equal(new PublicKey(data.subarray(offset, offset + 32)).toString(), merchant.publicKey.toString())
+      // Unverified until the merchant signs
+      assert.equal(data[offset + 32], 0)
+      assert.equal(data[offset + 33], 100)
+    })
+
+    it('Only lets the merchant verify themselves as the coupon creator', async () => {
+      try {
+        await program.methods
+          .verifyCouponCreator()
+          .accountsPartial({
+            coupon: couponPda,
+            deal: royaltyDealPda,
+            merchant: user.publicKey,
+            metadata: metadataPdaOf(couponMint),
+          })
+          .signers([user])
+          .rpc()
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'UnauthorizedMerchant')
+      }
+
+      const signature = await program.methods
+        .verifyCouponCreator()
+        .accountsPartial({
+          coupon: couponPda,
+          deal: royaltyDealPda,
+          merchant: merchant.publicKey,
+          metadata: metadataPdaOf(couponMint),
+        })
+        .signers([merchant])
+        .rpc()
+
+      const event = await fetchCpiEvent(signature)
+      assert.equal(event.name, 'couponCreatorVerified')
+      assert.equal(event.data.coupon.toString(), couponPda.toString())
+      assert.equal(event.data.merchant.toString(), merchant.publicKey.toString())
+
+      const creator = await firstCreatorOf(couponMint)
+      assert.equal(creator.address.toString(), merchant.publicKey.toString())
+      assert.isTrue(creator.verified)
+    })
+
+    it('Verifies a batch of coupons in one merchant transaction', async () => {
+      const batch: { couponPda: PublicKey; mint: PublicKey }[] = []
+      for (let i = 0; i < 3; i++) {
+        batch.push(await mintCoupon(royaltyDealPda))
+      }
+      for (const { mint } of batch) {
+        assert.isFalse((await firstCreatorOf(mint)).verified)
+      }
+
+      const tx = new Transaction()
+      for (const { couponPda: batchCouponPda, mint } of batch) {
+        tx.add(
+          await program.methods
+            .verifyCouponCreator()
+            .accountsPartial({
+              coupon: batchCouponPda,
+              deal: royaltyDealPda,
+              merchant: merchant.publicKey,
+              metadata: metadataPdaOf(mint),
+            })
+            .instruction()
+        )
+      }
+      await provider.sendAndConfirm(tx, [merchant])
+
+      for (const { mint } of batch) {
+        assert.isTrue((await firstCreatorOf(mint)).verified)
+      }
+    })
+
+    it('Pays the merchant royalty when the coupon resells', async () => {
+      const config = await program.account.platformConfig.fetch(platformConfigPda)
+      const sellerBefore = await provider.connection.getBalance(user.publicKey)
+      const merchantBefore = await provider.connection.getBalance(merchant.publicKey)
+      // The seller also gets back the rent on the listing and its escrow
+      const rentRefund =
+        (await provider.connection.getBalance(listingPda)) +
+        (await provider.connection.getBalance(
+          getAssociatedTokenAddressSync(couponMint, listingPda, true, TOKEN_2022_PROGRAM_ID)
+        ))
+
+      const signature = await program.methods
+        .buyCoupon()
+        .accountsPartial({
+          listing: listingPda,
+          coupon: couponPda,
+          deal: royaltyDealPda,
+          merchant: merchant.publicKey,
+          mint: couponMint,
+          couponTokenProgram: TOKEN_2022_PROGRAM_ID,
+          seller: user.publicKey,
+          buyer: buyer.publicKey,
+          platformConfig: platformConfigPda,
+          platformWallet: config.treasury,
+        })
+        .remainingAccounts(getCouponHookAccounts(couponMint, couponPda))
+        .signers([buyer])
+        .rpc()
+
+      const royalty = Math.floor((resalePrice.toNumber() * royaltyBps) / 10_000)
+      const event = await fetchCpiEvent(signature)
+      assert.equal(event.name, 'couponSold')
+      assert.equal(event.data.royaltyAmount.toString(), royalty.toString())
+
+      // The royalty comes out of the seller's share, next to the platform fee
+      const platformFee = event.data.platformFee.toNumber()
+      assert.equal((await provider.connection.getBalance(merchant.publicKey)) - merchantBefore, royalty)
+      assert.equal(
+        (await provider.connection.getBalance(user.publicKey)) - sellerBefore,
+        resalePrice.toNumber() - platformFee - royalty + rentRefund
+      )
+    })
+  })
+
+  describe('Retiring redeemed coupons', () => {
+    it('Rejects a gift card deal that freezes instead of burning', async () => {
+      try {
+        await createDeal({
+          title: `Frozen Gift Card ${Date.now()}`,
+          description: 'Gift cards are burned when spent',
+          discountPercent: 0,
+          kind: { storedValue: { faceValue: new BN(1000) } },
+        })
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'InvalidCouponKind')
+      }
+    })
+
+    it('Freezes the token account and marks the metadata redeemed', async () => {
+      const { deal: freezeDealPda } = await createDeal({ redemptionAction: { freeze: {} } })
+      const { couponPda, mint } = await mintCoupon(freezeDealPda)
+
+      await program.methods
+        .redeemCoupon()
+        .accountsPartial({
+          coupon: couponPda,
+          deal: freezeDealPda,
+          merchant: merchant.publicKey,
+          couponTokenProgram: TOKEN_2022_PROGRAM_ID,
+        })
+        .signers([merchant])
+        .rpc()
+
+      const tokenAccount = await getAccount(
+        provider.connection,
+        getAssociatedTokenAddressSync(mint, user.publicKey, false, TOKEN_2022_PROGRAM_ID),
+        'confirmed',
+        TOKEN_2022_PROGRAM_ID
+      )
+      assert.isTrue(tokenAccount.isFrozen)
+      assert.equal(tokenAccount.amount.toString(), '1')
+      assert.match(await metadataNameOf(mint), /^REDEEMED /)
+    })
+
+    it('Burns the coupon once the holder co-signs', async () => {
+      const { deal: burnDealPda } = await createDeal({ redemptionAction: { burn: {} } })
+      const { couponPda, mint } = await mintCoupon(burnDealPda)
+
+      try {
+        await program.methods
+          .redeemCoupon()
+          .accountsPartial({
+            coupon: couponPda,
+            deal: burnDealPda,
+            merchant: merchant.publicKey,
+            couponTokenProgram: TOKEN_2022_PROGRAM_ID,
+          })
+          .signers([merchant])
+          .rpc()
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'HolderSignatureRequired')
+      }
+
+      await program.methods
+        .redeemCoupon()
+        .accountsPartial({
+          coupon: couponPda,
+          deal: burnDealPda,
+          merchant: merchant.publicKey,
+          holder: user.publicKey,
+          couponTokenProgram: TOKEN_2022_PROGRAM_ID,
+        })
+        .signers([merchant, user])
+        .rpc()
+
+      const holderAccount = await provider.connection.getAccountInfo(
+        getAssociatedTokenAddressSync(mint, user.publicKey, false, TOKEN_2022_PROGRAM_ID)
+      )
+      assert.isNull(holderAccount)
+      const mintAccount = await getMint(provider.connection, mint, 'confirmed', TOKEN_2022_PROGRAM_ID)
+      assert.equal(mintAccount.supply.toString(), '0')
+      assert.match(await metadataNameOf(mint), /^REDEEMED /)
+
+      const coupon = await program.account.coupon.fetch(couponPda)
+      assert.isTrue(coupon.isRedeemed)
+    })
+  })
+
+  describe('Soulbound coupons', () => {
+    let soulboundDealPda: PublicKey
+    let couponPda: PublicKey
+    let mint: PublicKey
+    let holderTokenAccount: PublicKey
+
+    before(async () => {
+      ;({ deal: soulboundDealPda } = await createDeal({
+        title: `Staff Perk ${Date.now()}`,
+        description: 'Free lunch for employees, not for resale',
+        discountPercent: 100,
+        category: 'Food',
+        transferable: false,
+      }))
+      ;({ couponPda, mint } = await mintCoupon(soulboundDealPda, { uri: 'ipfs://staff-perk' }))
+      holderTokenAccount = getAssociatedTokenAddressSync(mint, user.publicKey, false, TOKEN_2022_PROGRAM_ID)
+    })
+
+    it('Mints a non-transferable Token-2022 coupon with metadata on the mint', async () => {
+      const deal = await program.account.deal.fetch(soulboundDealPda)
+      assert.isFalse(deal.transferable)
+
+      const mintInfo = await provider.connection.getAccountInfo(mint)
+      assert.equal(mintInfo.owner.toString(), TOKEN_2022_PROGRAM_ID.toString())
+      const mintAccount = await getMint(provider.connection, mint, 'confirmed', TOKEN_2022_PROGRAM_ID)
+      assert.isNotNull(getNonTransferable(mintAccount))
+      assert.equal(mintAccount.freezeAuthority.toString(), soulboundDealPda.toString())
+      assert.isNull(mintAccount.mintAuthority)
+
+      const metadata = await getTokenMetadata(provider.connection, mint)
+      assert.equal(metadata.updateAuthority.toString(), soulboundDealPda.toString())
+      assert.equal(metadata.uri, 'ipfs://staff-perk')
+
+      const tokenAccount = await getAccount(provider.connection, holderTokenAccount, 'confirmed', TOKEN_2022_PROGRAM_ID)
+      assert.equal(tokenAccount.amount.toString(), '1')
+    })
+
+    it('Rejects transferring the coupon', async () => {
+      const newOwner = Keypair.generate().publicKey
+      try {
+        await program.methods
+          .transferCoupon()
+          .accountsPartial({
+            coupon: couponPda,
+            mint,
+            fromTokenAccount: holderTokenAccount,
+            toTokenAccount: getAssociatedTokenAddressSync(mint, newOwner, false, TOKEN_2022_PROGRAM_ID),
+            currentOwner: user.publicKey,
+            newOwner,
+            tokenProgram: TOKEN_2022_PROGRAM_ID,
+          })
+          .signers([user])
+          .rpc()
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'CouponNotTransferable')
+      }
+    })
+
+    it('Rejects listing the coupon', async () => {
+      const [listingPda] = PublicKey.findProgramAddressSync(
+        [Buffer.from('listing'), couponPda.toBuffer()],
+        program.programId
+      )
+      try {
+        await program.methods
+          .listCoupon(new BN(1_000_000))
+          .accountsPartial({
+            coupon: couponPda,
+            mint,
+            sellerTokenAccount: holderTokenAccount,
+            listing: listingPda,
+            escrowTokenAccount: getAssociatedTokenAddressSync(mint, listingPda, true, TOKEN_2022_PROGRAM_ID),
+            seller: user.publicKey,
+            paymentMint: null,
+            tokenProgram: TOKEN_2022_PROGRAM_ID,
+          })
+          .signers([user])
+          .rpc()
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'CouponNotTransferable')
+      }
+    })
+
+    it('Redeems the coupon and marks its metadata redeemed', async () => {
+      await program.methods
+        .redeemCoupon()
+        .accountsPartial({
+          coupon: couponPda,
+          deal: soulboundDealPda,
+          merchant: merchant.publicKey,
+          mint,
+          holderTokenAccount,
+          couponTokenProgram: TOKEN_2022_PROGRAM_ID,
+        })
+        .signers([merchant])
+        .rpc()
+
+      const tokenAccount = await getAccount(provider.connection, holderTokenAccount, 'confirmed', TOKEN_2022_PROGRAM_ID)
+      assert.isTrue(tokenAccount.isFrozen)
+      const metadata = await getTokenMetadata(provider.connection, mint)
+      assert.match(metadata.name, /^REDEEMED /)
+
+      const coupon = await program.account.coupon.fetch(couponPda)
+      assert.isTrue(coupon.isRedeemed)
+    })
+  })
+
+  // Runs before the hook and staking tests, which need the rewards pool migrated
+  describe('Legacy account migrations', () => {
+    const [legacyMerchantStatePda] = PublicKey.findProgramAddressSync(
+      [Buffer.from('merchant'), legacyMerchant.publicKey.toBuffer()],
+      program.programId
+    )
+
+    const payer = (provider.wallet as anchor.Wallet).payer
+
+    const migrateCoupon = (index: number, couponIndex = index) =>
+      program.methods
+        .migrateCoupon(new BN(couponIndex))
+        .accountsPartial({
+          coupon: legacyCouponOf(index).couponPda,
+          deal: legacyDealPda,
+          payer: provider.wallet.publicKey,
+        })
+        .rpc()
+
+    const listingOf = (couponPda: PublicKey) =>
+      PublicKey.findProgramAddressSync([Buffer.from('listing'), couponPda.toBuffer()], program.programId)[0]
+
+    const closeStaleListing = (index: number, seller: PublicKey, authority = payer) =>
+      program.methods
+        .closeStaleListing()
+        .accountsPartial({ coupon: legacyCouponOf(index).couponPda, seller, authority: authority.publicKey })
+        .signers([authority])
+        .rpc()
+
+    const listLegacyCoupon = (index: number, price: BN) => {
+      const { couponPda, mint } = legacyCouponOf(index)
+      const listingPda = listingOf(couponPda)
+      return program.methods
+        .listCoupon(price)
+        .accountsPartial({
+          coupon: couponPda,
+          mint: mint.publicKey,
+          sellerTokenAccount: getAssociatedTokenAddressSync(mint.publicKey, legacyHolder.publicKey),
+          listing: listingPda,
+          escrowTokenAccount: getAssociatedTokenAddressSync(mint.publicKey, listingPda, true),
+          seller: legacyHolder.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([legacyHolder])
+        .rpc()
+    }
+
+    before(async () => {
+      for (const wallet of [legacyMerchant, legacyHolder]) {
+        const airdrop = await provider.connection.requestAirdrop(wallet.publicKey, 2 * anchor.web3.LAMPORTS_PER_SOL)
+        await provider.connection.confirmTransaction(airdrop)
+      }
+
+      // Legacy coupons were plain SPL Token NFTs held in the owner's ATA
+      for (let index = 0; index < LEGACY_COUPONS; index++) {
+        const { mint } = legacyCouponOf(index)
+        await createMint(provider.connection, payer, payer.publicKey, null, 0, mint, undefined, TOKEN_PROGRAM_ID)
+        const account = await getOrCreateAssociatedTokenAccount(
+          provider.connection,
+          payer,
+          mint.publicKey,
+          legacyHolder.publicKey
+        )
+        await mintTo(provider.connection, payer, mint.publicKey, account.address, payer, 1)
+      }
+    })
+
+    it('Loads the deal in its title-seeded layout', async () => {
+      const account = await provider.connection.getAccountInfo(legacyDealPda)
+      assert.isTrue(account.owner.equals(program.programId))
+      assert.isBelow(account.data.length, program.account.deal.size)
+    })
+
+    it("Rejects migrating another merchant's deal", async () => {
+      try {
+        await program.methods
+          .migrateDeal()
+          .accountsPartial({ deal: legacyDealPda, merchant: merchant.publicKey })
+          .signers([merchant])
+          .rpc()
+
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'UnauthorizedMerchant')
+      }
+    })
+
+    it('Migrates the deal onto the current layout, keeping its fields', async () => {
+      const signature = await program.methods
+        .migrateDeal()
+        .accountsPartial({ deal: legacyDealPda, merchant: legacyMerchant.publicKey })
+        .signers([legacyMerchant])
+        .rpc()
+
+      const account = await provider.connection.getAccountInfo(legacyDealPda)
+      assert.equal(account.data.length, program.account.deal.size)
+
+      const deal = await program.account.deal.fetch(legacyDealPda)
+      assert.equal(deal.merchant.toString(), legacyMerchant.publicKey.toString())
+      assert.equal(deal.dealId.toString(), '0')
+      assert.equal(deal.title, LEGACY_DEAL_TITLE)
+      assert.equal(deal.description, 'Created before deals had ids')
+      assert.equal(deal.discountPercent, 25)
+      assert.equal(deal.maxSupply.toString(), '10')
+      assert.equal(deal.currentSupply.toString(), '4')
+      assert.equal(deal.expiryTimestamp.toString(), '4102444800')
+      assert.equal(deal.category, 'Food')
+      assert.equal(deal.priceLamports.toString(), '10000000')
+      assert.isTrue(deal.isActive)
+      // Every legacy coupon counts as outstanding until migrate_coupon says otherwise
+      assert.equal(deal.redeemedCount.toString(), '0')
+      assert.equal(deal.outstandingPayments.toString(), '40000000')
+      assert.isNull(deal.paymentMint)
+      assert.equal(deal.usesPerCoupon.toString(), '1')
+
+      const merchantState = await program.account.merchantState.fetch(legacyMerchantStatePda)
+      assert.equal(merchantState.dealCount.toString(), '1')
+
+      const event = await fetchCpiEvent(signature)
+      assert.equal(event.name, 'dealMigrated')
+      assert.equal(event.data.deal.toString(), legacyDealPda.toString())
+      assert.equal(event.data.dealId.toString(), '0')
+    })
+
+    it('Rejects migrating the deal twice', async () => {
+      try {
+        await program.methods
+          .migrateDeal()
+          .accountsPartial({ deal: legacyDealPda, merchant: legacyMerchant.publicKey })
+          .signers([legacyMerchant])
+          .rpc()
+
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'DealAlreadyMigrated')
+      }
+    })
+
+    it('Lets the merchant update the migrated deal', async () => {
+      await program.methods
+        .updateDeal(false, null, null, null, null, null, null, null, null)
+        .accountsPartial({ deal: legacyDealPda, merchant: legacyMerchant.publicKey })
+        .signers([legacyMerchant])
+        .rpc()
+      assert.isFalse((await program.account.deal.fetch(legacyDealPda)).isActive)
+
+      await program.methods
+        .updateDeal(true, null, null, null, null, null, null, null, null)
+        .accountsPartial({ deal: legacyDealPda, merchant: legacyMerchant.publicKey })
+        .signers([legacyMerchant])
+        .rpc()
+      assert.isTrue((await program.account.deal.fetch(legacyDealPda)).isActive)
+    })
+
+    it('Rejects migrating a coupon at the wrong index', async () => {
+      try {
+        await migrateCoupon(0, 1)
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'InvalidCoupon')
+      }
+    })
+
+    it('Migrates a coupon onto the current layout, keeping its fields', async () => {
+      const { couponPda, mint } = legacyCouponOf(0)
+      const signature = await migrateCoupon(0)
+
+      const coupon = await program.account.coupon.fetch(couponPda)
+      assert.equal(coupon.deal.toString(), legacyDealPda.toString())
+      assert.equal(coupon.owner.toString(), legacyHolder.publicKey.toString())
+      assert.equal(coupon.mint.toString(), mint.publicKey.toString())
+      assert.equal(coupon.couponIndex.toString(), '0')
+      assert.isFalse(coupon.isRedeemed)
+      assert.equal(coupon.mintedAt.toString(), '1700000000')
+      assert.isNull(coupon.redeemedAt)
+      assert.isFalse(coupon.isListed)
+      assert.isFalse(coupon.isStaked)
+      assert.equal(coupon.usesRemaining.toString(), '1')
+      assert.equal(coupon.pricePaid.toString(), '10000000')
+
+      const event = await fetchCpiEvent(signature)
+      assert.equal(event.name, 'couponMigrated')
+      assert.equal(event.data.coupon.toString(), couponPda.toString())
+    })
+
+    it('Rejects migrating the coupon twice', async () => {
+      try {
+        await migrateCoupon(0)
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'CouponAlreadyMigrated')
+      }
+    })
+
+    it('Redeems the migrated coupon', async () => {
+      const { couponPda, mint } = legacyCouponOf(0)
+      await program.methods
+        .redeemCoupon()
+        .accounts({
+          coupon: couponPda,
+          deal: legacyDealPda,
+          merchant: legacyMerchant.publicKey,
+          couponTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([legacyMerchant])
+        .rpc()
+
+      const coupon = await program.account.coupon.fetch(couponPda)
+      assert.isTrue(coupon.isRedeemed)
+      assert.equal(coupon.usesRemaining.toString(), '0')
+
+      const deal = await program.account.deal.fetch(legacyDealPda)
+      assert.equal(deal.redeemedCount.toString(), '1')
+      assert.equal(deal.outstandingPayments.toString(), '30000000')
+
+      // The deal can't sign for the mint's metadata or freeze its token account
+      assert.equal(await metadataNameOf(mint.publicKey), `${LEGACY_DEAL_TITLE} - Coupon #1`)
+      const holderAccount = await getAccount(
+        provider.connection,
+        getAssociatedTokenAddressSync(mint.publicKey, legacyHolder.publicKey)
+      )
+      assert.isFalse(holderAccount.isFrozen)
+    })
+
+    it('Only lets the admin migrate the rewards pool', async () => {
+      try {
+        await program.methods.migrateRewardsPool().accounts({ admin: provider.wallet.publicKey }).rpc()
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'UnauthorizedAdmin')
+      }
+    })
+
+    it('Migrates the rewards pool, keeping its fields', async () => {
+      const [rewardsPoolPda] = PublicKey.findProgramAddressSync([Buffer.from('rewards_pool')], program.programId)
+      const signature = await program.methods
+        .migrateRewardsPool()
+        .accounts({ admin: legacyMerchant.publicKey })
+        .signers([legacyMerchant])
+        .rpc()
+
+      const pool = await program.account.rewardsPool.fetch(rewardsPoolPda)
+      assert.equal(pool.totalStaked.toString(), '1')
+      assert.equal(pool.rewardRatePerDay.toString(), '100000')
+      assert.equal(pool.admin.toString(), legacyMerchant.publicKey.toString())
+      assert.equal(pool.accRewardPerShare.toString(), '0')
+      assert.isTrue(pool.lastUpdateTs.gtn(0))
+
+      const event = await fetchCpiEvent(signature)
+      assert.equal(event.name, 'rewardsPoolMigrated')
+      assert.equal(event.data.totalStaked.toString(), '1')
+    })
+
+    it('Carries the stake lock over when migrating a staked coupon', async () => {
+      await migrateCoupon(1)
+
+      const coupon = await program.account.coupon.fetch(legacyCouponOf(1).couponPda)
+      assert.isTrue(coupon.isStaked)
+      assert.isFalse(coupon.isListed)
+    })
+
+    it('Migrates the stake record, keeping its fields', async () => {
+      const signature = await program.methods
+        .migrateStakedCoupon()
+        .accounts({ stakedCoupon: legacyStakedCouponPda, payer: provider.wallet.publicKey })
+        .rpc()
+
+      const staked = await program.account.stakedCoupon.fetch(legacyStakedCouponPda)
+      assert.equal(staked.coupon.toString(), legacyCouponOf(1).couponPda.toString())
+      assert.equal(staked.staker.toString(), legacyHolder.publicKey.toString())
+      assert.equal(staked.stakedAt.toString(), '1700003600')
+      assert.equal(staked.lastClaimAt.toString(), '1700003600')
+      assert.equal(staked.rewardDebt.toString(), '0')
+
+      const event = await fetchCpiEvent(signature)
+      assert.equal(event.name, 'stakedCouponMigrated')
+      assert.equal(event.data.staker.toString(), legacyHolder.publicKey.toString())
+
+      try {
+        await program.methods
+          .migrateStakedCoupon()
+          .accounts({ stakedCoupon: legacyStakedCouponPda, payer: provider.wallet.publicKey })
+          .rpc()
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'StakedCouponAlreadyMigrated')
+      }
+    })
+
+    it('Prevents redeeming the migrated coupon while it is staked', async () => {
+      try {
+        await program.methods
+          .redeemCoupon()
+          .accounts({
+            coupon: legacyCouponOf(1).couponPda,
+            deal: legacyDealPda,
+            merchant: legacyMerchant.publicKey,
+            couponTokenProgram: TOKEN_PROGRAM_ID,
+          })
+          .signers([legacyMerchant])
+          .rpc()
+
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'CouponLocked')
+      }
+    })
+
+    it('Refuses to migrate a coupon with an active legacy listing', async () => {
+      try {
+        await migrateCoupon(2)
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'LegacyListingActive')
+      }
+    })
+
+    it('Only lets the seller withdraw an active legacy listing', async () => {
+      try {
+        await closeStaleListing(2, legacyHolder.publicKey)
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'NotOwner')
+      }
+    })
+
+    it('Migrates and relists the coupon once its seller withdraws the legacy listing', async () => {
+      const { couponPda } = legacyCouponOf(2)
+      const signature = await closeStaleListing(2, legacyHolder.publicKey, legacyHolder)
+
+      const event = await fetchCpiEvent(signature)
+      assert.equal(event.name, 'staleListingClosed')
+      assert.isTrue(event.data.wasActive)
+      assert.isNull(await provider.connection.getAccountInfo(listingOf(couponPda)))
+
+      await migrateCoupon(2)
+      assert.isFalse((await program.account.coupon.fetch(couponPda)).isListed)
+
+      await listLegacyCoupon(2, new BN(20_000_000))
+      const listing = await program.account.listing.fetch(listingOf(couponPda))
+      assert.isTrue(listing.isActive)
+      assert.isTrue((await program.account.coupon.fetch(couponPda)).isListed)
+    })
+
+    it('Migrates a resold coupon whose old listing was left open', async () => {
+      await migrateCoupon(3)
+
+      const coupon = await program.account.coupon.fetch(legacyCouponOf(3).couponPda)
+      assert.equal(coupon.owner.toString(), legacyHolder.publicKey.toString())
+      assert.isFalse(coupon.isListed)
+
+      const listing = await provider.connection.getAccountInfo(listingOf(legacyCouponOf(3).couponPda))
+      assert.isNotNull(listing)
+    })
+
+    it('Blocks relisting while the stale listing is open', async () => {
+      try {
+        await listLegacyCoupon(3, new BN(20_000_000))
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        // list_coupon creates the listing afresh, which the system program refuses
+        assert.include(error.logs.join('\n'), 'already in use')
+      }
+    })
+
+    it("Rejects refunding a stale listing's rent to anyone but its seller", async () => {
+      try {
+        await closeStaleListing(3, legacyHolder.publicKey)
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'InvalidListing')
+      }
+    })
+
+    it('Lets anyone close an inactive stale listing, refunding its seller', async () => {
+      const listingPda = listingOf(legacyCouponOf(3).couponPda)
+      const rent = (await provider.connection.getAccountInfo(listingPda)).lamports
+      const sellerBefore = await provider.connection.getBalance(legacySeller.publicKey)
+
+      const signature = await closeStaleListing(3, legacySeller.publicKey)
+
+      assert.isNull(await provider.connection.getAccountInfo(listingPda))
+      assert.equal((await provider.connection.getBalance(legacySeller.publicKey)) - sellerBefore, rent)
+
+      const event = await fetchCpiEvent(signature)
+      assert.equal(event.name, 'staleListingClosed')
+      assert.equal(event.data.seller.toString(), legacySeller.publicKey.toString())
+      assert.isFalse(event.data.wasActive)
+    })
+
+    it('Relists the coupon into escrow once the stale listing is closed', async () => {
+      const { couponPda, mint } = legacyCouponOf(3)
+      await listLegacyCoupon(3, new BN(20_000_000))
+
+      const listing = await program.account.listing.fetch(listingOf(couponPda))
+      assert.isTrue(listing.isActive)
+      assert.equal(listing.seller.toString(), legacyHolder.publicKey.toString())
+      assert.isTrue((await program.account.coupon.fetch(couponPda)).isListed)
+
+      const escrow = await getAccount(
+        provider.connection,
+        getAssociatedTokenAddressSync(mint.publicKey, listingOf(couponPda), true)
+      )
+      assert.equal(escrow.amount.toString(), '1')
+    })
+
+    it('Leaves escrowed listings to delist_coupon', async () => {
+      try {
+        await closeStaleListing(3, legacyHolder.publicKey, legacyHolder)
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'ListingEscrowed')
+      }
+    })
+  })
+
+  describe('Coupon transfer hook', () => {
+    const holder = Keypair.generate()
+    let mint: PublicKey
+    let couponPda: PublicKey
+
+    // Moves a coupon straight through Token-2022, the way a wallet would
+    const transferDirectly = async (from: Keypair, to: PublicKey, couponMint = mint) => {
+      const destination = await createAssociatedTokenAccountIdempotent(
+        provider.connection,
+        from,
+        couponMint,
+        to,
+        {},
+        TOKEN_2022_PROGRAM_ID
+      )
+      const ix = await createTransferCheckedWithTransferHookInstruction(
+        provider.connection,
+        getAssociatedTokenAddressSync(couponMint, from.publicKey, false, TOKEN_2022_PROGRAM_ID),
+        couponMint,
+        destination,
+        from.publicKey,
+        BigInt(1),
+        0,
+        [],
+        'confirmed',
+        TOKEN_2022_PROGRAM_ID
+      )
+      return sendAndConfirmTransaction(provider.connection, new Transaction().add(ix), [from])
+    }
+
+    before(async () => {
+      const airdrop = await provider.connection.requestAirdrop(holder.publicKey, 2 * anchor.web3.LAMPORTS_PER_SOL)
+      await provider.connection.confirmTransaction(airdrop)
+
+      ;({ couponPda, mint } = await mintCoupon(dealPda, { buyer: holder, uri: 'ipfs://hooked-coupon' }))
+    })
+
+    it('Points the mint at the hook and writes its account list', async () => {
+      const mintAccount = await getMint(provider.connection, mint, 'confirmed', TOKEN_2022_PROGRAM_ID)
+      assert.equal(getTransferHook(mintAccount).programId.toString(), COUPON_HOOK_PROGRAM_ID.toString())
+      // The mint authority is revoked once the single coupon is minted
+      assert.isNull(mintAccount.mintAuthority)
+      assert.equal(mintAccount.supply.toString(), '1')
+
+      const [extraAccountMetaList] = PublicKey.findProgramAddressSync(
+        [Buffer.from('extra-account-metas'), mint.toBuffer()],
+        COUPON_HOOK_PROGRAM_ID
+      )
+      const listInfo = await provider.connection.getAccountInfo(extraAccountMetaList)
+      assert.equal(listInfo.owner.toString(), COUPON_HOOK_PROGRAM_ID.toString())
+    })
+
+    it('Follows the NFT when it moves outside the program', async () => {
+      await transferDirectly(holder, merchant.publicKey)
+
+      const coupon = await program.account.coupon.fetch(couponPda)
+      assert.equal(coupon.owner.toString(), merchant.publicKey.toString())
+      const balance = await provider.connection.getTokenAccountBalance(
+        getAssociatedTokenAddressSync(mint, merchant.publicKey, false, TOKEN_2022_PROGRAM_ID)
+      )
+      assert.equal(balance.value.amount, '1')
+    })
+
+    it('Blocks moving a staked coupon', async () => {
+      const [rewardsPoolPda] = PublicKey.findProgramAddressSync([Buffer.from('rewards_pool')], program.programId)
+      if (!(await provider.connection.getAccountInfo(rewardsPoolPda))) {
+        await program.methods
+          .initializeRewardsPool(new BN(100_000))
+          .accounts({ admin: provider.wallet.publicKey })
+          .rpc()
+      }
+      const [stakedCouponPda] = PublicKey.findProgramAddressSync(
+        [Buffer.from('staked_coupon'), couponPda.toBuffer()],
+        program.programId
+      )
+      await program.methods
+        .stakeCoupon()
+        .accountsPartial({
+          coupon: couponPda,
+          stakerTokenAccount: getAssociatedTokenAddressSync(mint, merchant.publicKey, false, TOKEN_2022_PROGRAM_ID),
+          stakedCoupon: stakedCouponPda,
+          rewardsPool: rewardsPoolPda,
+          staker: merchant.publicKey,
+        })
+        .signers([merchant])
+        .rpc()
+
+      try {
+        await transferDirectly(merchant, holder.publicKey)
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        // HookError::CouponStaked, raised by the hook program
+        assert.include(error.message, '0x1773')
+      }
+
+      const coupon = await program.account.coupon.fetch(couponPda)
+      assert.equal(coupon.owner.toString(), merchant.publicKey.toString())
+
+      // The rewards vault is not funded yet
+      await program.methods
+        .unstakeCoupon(true)
+        .accountsPartial({
+          stakedCoupon: stakedCouponPda,
+          coupon: couponPda,
+          rewardsPool: rewardsPoolPda,
+          staker: merchant.publicKey,
+        })
+        .signers([merchant])
+        .rpc()
+      assert.isFalse((await program.account.coupon.fetch(couponPda)).isStaked)
+    })
+
+    it("Lets the program's own transfers through without syncing", async () => {
+      const signature = await program.methods
+        .transferCoupon()
+        .accounts({
+          coupon: couponPda,
+          mint,
+          fromTokenAccount: getAssociatedTokenAddressSync(mint, merchant.publicKey, false, TOKEN_2022_PROGRAM_ID),
+          toTokenAccount: getAssociatedTokenAddressSync(mint, holder.publicKey, false, TOKEN_2022_PROGRAM_ID),
+          currentOwner: merchant.publicKey,
+          newOwner: holder.publicKey,
+          tokenProgram: TOKEN_2022_PROGRAM_ID,
+        })
+        .remainingAccounts(getCouponHookAccounts(mint, couponPda))
+        .signers([merchant])
+        .rpc()
+
+      await provider.connection.confirmTransaction(signature, 'confirmed')
+      const tx = await provider.connection.getTransaction(signature, {
+        commitment: 'confirmed',
+        maxSupportedTransactionVersion: 0,
+      })
+      assert.isFalse(tx.meta.logMessages.some((log) => log.includes('Coupon owner synced from transfer hook')))
+
+      const coupon = await program.account.coupon.fetch(couponPda)
+      assert.equal(coupon.owner.toString(), holder.publicKey.toString())
+      assert.isFalse(coupon.inProgramTransfer)
+    })
+
+    it('Blocks moving a listed coupon', async () => {
+      const [listingPda] = PublicKey.findProgramAddressSync(
+        [Buffer.from('listing'), couponPda.toBuffer()],
+        program.programId
+      )
+      await program.methods
+        .listCoupon(new BN(10_000_000))
+        .accountsPartial({
+          coupon: couponPda,
+          mint,
+          sellerTokenAccount: getAssociatedTokenAddressSync(mint, holder.publicKey, false, TOKEN_2022_PROGRAM_ID),
+          listing: listingPda,
+          escrowTokenAccount: getAssociatedTokenAddressSync(mint, listingPda, true, TOKEN_2022_PROGRAM_ID),
+          seller: holder.publicKey,
+          tokenProgram: TOKEN_2022_PROGRAM_ID,
+        })
+        .remainingAccounts(getCouponHookAccounts(mint, couponPda))
+        .signers([holder])
+        .rpc()
+
+      try {
+        await transferDirectly(holder, merchant.publicKey)
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        // The NFT sits in the listing's escrow, so Token-2022 finds nothing to move
+        assert.match(error.message, /custom program error: 0x1\b/)
+      }
+
+      const coupon = await program.account.coupon.fetch(couponPda)
+      assert.equal(coupon.owner.toString(), holder.publicKey.toString())
+      assert.isTrue(coupon.isListed)
+    })
+
+    it('Blocks moving a redeemed coupon', async () => {
+      const { deal: redeemedDealPda } = await createDeal({ redemptionAction: { freeze: {} } })
+      const { couponPda: redeemedCouponPda, mint: redeemedMint } = await mintCoupon(redeemedDealPda, { buyer: holder })
+
+      await program.methods
+        .redeemCoupon()
+        .accountsPartial({
+          coupon: redeemedCouponPda,
+          deal: redeemedDealPda,
+          merchant: merchant.publicKey,
+          couponTokenProgram: TOKEN_2022_PROGRAM_ID,
+        })
+        .signers([merchant])
+        .rpc()
+
+      try {
+        await transferDirectly(holder, merchant.publicKey, redeemedMint)
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        // Redemption froze the holder's token account
+        assert.include(error.message, '0x11')
+      }
+
+      const coupon = await program.account.coupon.fetch(redeemedCouponPda)
+      assert.equal(coupon.owner.toString(), holder.publicKey.toString())
+    })
+  })
+
+  // Marketplace Tests
+  describe('Staking Rewards', () => {
+    const [rewardsPoolPda] = PublicKey.findProgramAddressSync(
+      [Buffer.from('rewards_pool')],
+      program.programId
+    )
+    const [rewardsVaultPda] = PublicKey.findProgramAddressSync(
+      [Buffer.from('rewards_vault')],
+      program.programId
+    )
+
+    const holder = Keypair.generate()
+
+    const stakeNewCoupon = async () => {
+      const { couponPda, mint } = await mintCoupon(dealPda, { buyer: holder, uri: 'ipfs://test-staking' })
+      const [stakedCouponPda] = PublicKey.findProgramAddressSync(
+        [Buffer.from('staked_coupon'), couponPda.toBuffer()],
+        program.programId
+      )
+      await program.methods
+        .stakeCoupon()
+        .accountsPartial({
+          coupon: couponPda,
+          stakerTokenAccount: getAssociatedTokenAddressSync(mint, holder.publicKey, false, TOKEN_2022_PROGRAM_ID),
+          stakedCoupon: stakedCouponPda,
+          rewardsPool: rewardsPoolPda,
+          staker: holder.publicKey,
+        })
+        .signers([holder])
+        .rpc()
+      return { couponPda, mint, stakedCouponPda }
+    }
+
+    const unstake = (couponPda: PublicKey, stakedCouponPda: PublicKey, forfeitRewards = false) =>
+      program.methods
+        .unstakeCoupon(forfeitRewards)
+        .accountsPartial({
+          stakedCoupon: stakedCouponPda,
+          coupon: couponPda,
+          rewardsPool: rewardsPoolPda,
+          staker: holder.publicKey,
+        })
+        .signers([holder])
+        .rpc()
+
+    before(async () => {
+      const existing = await provider.connection.getAccountInfo(rewardsPoolPda)
+      if (!existing) {
+        await program.methods
+          .initializeRewardsPool(new BN(100_000))
+          .accounts({ admin: provider.wallet.publicKey })
+          .rpc()
+      }
+
+      const airdrop = await provider.connection.requestAirdrop(holder.publicKey, 2 * anchor.web3.LAMPORTS_PER_SOL)
+      await provider.connection.confirmTransaction(airdrop)
+    })
+
+    it('Forfeits the rewards an empty vault cannot pay only when asked to', async () => {
+      // The vault is only funded by the next test
+      assert.equal(await provider.connection.getBalance(rewardsVaultPda), 0)
+
+      const { couponPda, stakedCouponPda } = await stakeNewCoupon()
+      await new Promise((resolve) => setTimeout(resolve, 3000))
+
+      try {
+        await unstake(couponPda, stakedCouponPda)
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'InsufficientRewardsFunds')
+      }
+      assert.isTrue((await program.account.coupon.fetch(couponPda)).isStaked)
+
+      const signature = await unstake(couponPda, stakedCouponPda, true)
+
+      const event = await fetchCpiEvent(signature)
+      assert.equal(event.name, 'couponUnstaked')
+      assert.equal(event.data.rewards.toString(), '0')
+      assert.isTrue(event.data.forfeited.gtn(0))
+
+      const coupon = await program.account.coupon.fetch(couponPda)
+      assert.isFalse(coupon.isStaked)
+      assert.isNull(await provider.connection.getAccountInfo(stakedCouponPda))
+    })
+
+    it('Funds the rewards vault', async () => {
+      const amount = anchor.web3.LAMPORTS_PER_SOL
+      const balanceBefore = await provider.connection.getBalance(rewardsVaultPda)
+
+      const signature = await program.methods
+        .fundRewardsPool(new BN(amount))
+        .accounts({ funder: provider.wallet.publicKey })
+        .rpc()
+
+      const balanceAfter = await provider.connection.getBalance(rewardsVaultPda)
+      assert.equal(balanceAfter - balanceBefore, amount)
+
+      const event = await fetchCpiEvent(signature)
+      assert.equal(event.name, 'rewardsPoolFunded')
+      assert.equal(event.data.amount.toString(), amount.toString())
+      assert.equal(event.data.vaultBalance.toString(), balanceAfter.toString())
+    })
+
+    it('Pays and releases a stake migrated from the legacy pool', async () => {
+      const { couponPda } = legacyCouponOf(1)
+      await new Promise((resolve) => setTimeout(resolve, 3000))
+
+      const signature = await program.methods
+        .claimRewards()
+        .accountsPartial({
+          stakedCoupon: legacyStakedCouponPda,
+          rewardsPool: rewardsPoolPda,
+          staker: legacyHolder.publicKey,
+        })
+        .signers([legacyHolder])
+        .rpc()
+
+      // The migrated record starts earning from the pool migration, not its legacy stake time
+      const event = await fetchCpiEvent(signature)
+      assert.equal(event.name, 'rewardsClaimed')
+      assert.isTrue(event.data.amount.gtn(0))
+      assert.isBelow(event.data.amount.toNumber(), 100_000)
+
+      await program.methods
+        .unstakeCoupon(false)
+        .accountsPartial({
+          stakedCoupon: legacyStakedCouponPda,
+          coupon: couponPda,
+          rewardsPool: rewardsPoolPda,
+          staker: legacyHolder.publicKey,
+        })
+        .signers([legacyHolder])
+        .rpc()
+
+      assert.isFalse((await program.account.coupon.fetch(couponPda)).isStaked)
+      assert.isNull(await provider.connection.getAccountInfo(legacyStakedCouponPda))
+    })
+
+    it('Rejects empty funding', async () => {
+      try {
+        await program.methods
+          .fundRewardsPool(new BN(0))
+          .accounts({ funder: provider.wallet.publicKey })
+          .rpc()
+
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'InvalidFundingAmount')
+      }
+    })
+
+    it('Accrues rewards per second, locks the coupon while staked and pays from the vault', async () => {
+      const staker = Keypair.generate()
+      const airdrop = await provider.connection.requestAirdrop(
+        staker.publicKey,
+        2 * anchor.web3.LAMPORTS_PER_SOL
       )
+      await provider.connection.confirmTransaction(airdrop)
+
+      const { couponPda, mint } = await mintCoupon(dealPda, { buyer: staker, uri: 'ipfs://test-staking' })
+      const stakerTokenAccount = getAssociatedTokenAddressSync(mint, staker.publicKey, false, TOKEN_2022_PROGRAM_ID)
 
-      const [metadataPda] = PublicKey.findProgramAddressSync(
-        [
-          Buffer.from('metadata'),
-          new PublicKey('metaqbxxUerdq28cj1RbAWkYQm3ybzjb6a8bt518x1s').toBuffer(),
-          mintKeypair.publicKey.toBuffer(),
-        ],
-        new PublicKey('metaqbxxUerdq28cj1RbAWkYQm3ybzjb6a8bt518x1s')
+      const [stakedCouponPda] = PublicKey.findProgramAddressSync(
+        [Buffer.from('staked_coupon'), couponPda.toBuffer()],
+        program.programId
       )
 
       await program.methods
-        .mintCoupon(dealPda, 'ipfs://test-metadata')
-        .accounts({
-          deal: dealPda,
-          coupon: listingCouponPda,
-          mint: mintKeypair.publicKey,
-          tokenAccount: userTokenAccount,
-          metadata: metadataPda,
-          merchant: merchant.publicKey,
-          user: seller.publicKey,
-          rent: SYSVAR_RENT_PUBKEY,
-          systemProgram: SystemProgram.programId,
-          tokenProgram: TOKEN_PROGRAM_ID,
-          associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
-          tokenMetadataProgram: new PublicKey('metaqbxxUerdq28cj1RbAWkYQm3ybzjb6a8bt518x1s'),
+        .stakeCoupon()
+        .accountsPartial({
+          coupon: couponPda,
+          stakerTokenAccount,
+          stakedCoupon: stakedCouponPda,
+          rewardsPool: rewardsPoolPda,
+          staker: staker.publicKey,
+        })
+        .signers([staker])
+        .rpc()
+
+      // The new stake starts with a debt equal to the current accumulator
+      const poolAfterStake = await program.account.rewardsPool.fetch(rewardsPoolPda)
+      const staked = await program.account.stakedCoupon.fetch(stakedCouponPda)
+      assert.equal(staked.rewardDebt.toString(), poolAfterStake.accRewardPerShare.toString())
+
+      // Staked coupons are locked against transfers
+      const stakedCoupon = await program.account.coupon.fetch(couponPda)
+      assert.isTrue(stakedCoupon.isStaked)
+      try {
+        await program.methods
+          .transferCoupon()
+          .accounts({
+            coupon: couponPda,
+            mint,
+            fromTokenAccount: stakerTokenAccount,
+            toTokenAccount: getAssociatedTokenAddressSync(
+              mint,
+              merchant.publicKey,
+              false,
+              TOKEN_2022_PROGRAM_ID
+            ),
+            currentOwner: staker.publicKey,
+            newOwner: merchant.publicKey,
+            tokenProgram: TOKEN_2022_PROGRAM_ID,
+          })
+          .remainingAccounts(getCouponHookAccounts(mint, couponPda))
+          .signers([staker])
+          .rpc()
+
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'CouponLocked')
+      }
+
+      // Let a few seconds of emissions accrue
+      await new Promise((resolve) => setTimeout(resolve, 3000))
+
+      const balanceBefore = await provider.connection.getBalance(staker.publicKey)
+      const signature = await program.methods
+        .claimRewards()
+        .accountsPartial({
+          stakedCoupon: stakedCouponPda,
+          rewardsPool: rewardsPoolPda,
+          staker: staker.publicKey,
+        })
+        .signers([staker])
+        .rpc()
+
+      const event = await fetchCpiEvent(signature)
+      assert.equal(event.name, 'rewardsClaimed')
+      assert.isTrue(event.data.amount.gtn(0))
+
+      // The claim moved the accumulator forward and settled the debt
+      const poolAfterClaim = await program.account.rewardsPool.fetch(rewardsPoolPda)
+      const stakedAfterClaim = await program.account.stakedCoupon.fetch(stakedCouponPda)
+      assert.isTrue(poolAfterClaim.lastUpdateTs.gt(poolAfterStake.lastUpdateTs))
+      assert.equal(stakedAfterClaim.rewardDebt.toString(), poolAfterClaim.accRewardPerShare.toString())
+
+      // The provider wallet pays the fee, so the staker gains exactly the reward
+      const balanceAfter = await provider.connection.getBalance(staker.publicKey)
+      assert.equal(balanceAfter - balanceBefore, event.data.amount.toNumber())
+
+      // Only the staker can unstake
+      try {
+        await program.methods
+          .unstakeCoupon(false)
+          .accountsPartial({
+            stakedCoupon: stakedCouponPda,
+            coupon: couponPda,
+            rewardsPool: rewardsPoolPda,
+            staker: merchant.publicKey,
+          })
+          .signers([merchant])
+          .rpc()
+
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'NotOwner')
+      }
+
+      await program.methods
+        .unstakeCoupon(false)
+        .accountsPartial({
+          stakedCoupon: stakedCouponPda,
+          coupon: couponPda,
+          rewardsPool: rewardsPoolPda,
+          staker: staker.publicKey,
         })
-        .signers([seller, mintKeypair])
+        .signers([staker])
         .rpc()
 
+      const unstakedCoupon = await program.account.coupon.fetch(couponPda)
+      assert.isFalse(unstakedCoupon.isStaked)
+      assert.isNull(await provider.connection.getAccountInfo(stakedCouponPda))
+    })
+
+    it('Prevents listing a staked coupon', async () => {
+      const { couponPda, mint, stakedCouponPda } = await stakeNewCoupon()
+      const [listingPda] = PublicKey.findProgramAddressSync(
+        [Buffer.from('listing'), couponPda.toBuffer()],
+        program.programId
+      )
+
+      try {
+        await program.methods
+          .listCoupon(new BN(anchor.web3.LAMPORTS_PER_SOL))
+          .accountsPartial({
+            coupon: couponPda,
+            mint,
+            sellerTokenAccount: getAssociatedTokenAddressSync(mint, holder.publicKey, false, TOKEN_2022_PROGRAM_ID),
+            listing: listingPda,
+            seller: holder.publicKey,
+            tokenProgram: TOKEN_2022_PROGRAM_ID,
+          })
+          .remainingAccounts(getCouponHookAccounts(mint, couponPda))
+          .signers([holder])
+          .rpc()
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'CouponLocked')
+      }
+
+      await unstake(couponPda, stakedCouponPda)
+    })
+
+    it('Prevents redeeming a staked coupon', async () => {
+      const { couponPda, stakedCouponPda } = await stakeNewCoupon()
+
+      try {
+        await program.methods
+          .redeemCoupon()
+          .accountsPartial({
+            coupon: couponPda,
+            deal: dealPda,
+            merchant: merchant.publicKey,
+            couponTokenProgram: TOKEN_2022_PROGRAM_ID,
+          })
+          .signers([merchant])
+          .rpc()
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'CouponLocked')
+      }
+
+      const coupon = await program.account.coupon.fetch(couponPda)
+      assert.isFalse(coupon.isRedeemed)
+      await unstake(couponPda, stakedCouponPda)
+    })
+
+    it("Rejects unstaking with another coupon's stake record", async () => {
+      const first = await stakeNewCoupon()
+      const second = await stakeNewCoupon()
+
+      try {
+        await unstake(second.couponPda, first.stakedCouponPda)
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'ConstraintSeeds')
+      }
+
+      await unstake(first.couponPda, first.stakedCouponPda)
+      await unstake(second.couponPda, second.stakedCouponPda)
+    })
+  })
+
+  describe('Secondary Marketplace', () => {
+    let listingCouponPda: PublicKey
+    let listingMint: PublicKey
+    let listingPda: PublicKey
+    let seller: Keypair
+    let buyer: Keypair
+    let delistSeller: Keypair
+    let delistCouponPda: PublicKey
+    let delistMint: PublicKey
+    let delistListingPda: PublicKey
+
+    before(async () => {
+      seller = Keypair.generate()
+      buyer = Keypair.generate()
+
+      // Airdrop to both
+      const sellerAirdrop = await provider.connection.requestAirdrop(
+        seller.publicKey,
+        2 * anchor.web3.LAMPORTS_PER_SOL
+      )
+      const buyerAirdrop = await provider.connection.requestAirdrop(
+        buyer.publicKey,
+        2 * anchor.web3.LAMPORTS_PER_SOL
+      )
+
+      await provider.connection.confirmTransaction(sellerAirdrop)
+      await provider.connection.confirmTransaction(buyerAirdrop)
+
+      ;({ couponPda: listingCouponPda, mint: listingMint } = await mintCoupon(dealPda, {
+        buyer: seller,
+        uri: 'ipfs://test-metadata',
+      }))
       listingPda = PublicKey.findProgramAddressSync(
         [Buffer.from('listing'), listingCouponPda.toBuffer()],
         program.programId
@@ -685,10 +3536,20 @@ describe('Deal Discovery Platform', () => {
         .listCoupon(price)
         .accounts({
           coupon: listingCouponPda,
+          mint: listingMint,
+          sellerTokenAccount: getAssociatedTokenAddressSync(
+            listingMint,
+            seller.publicKey,
+            false,
+            TOKEN_2022_PROGRAM_ID
+          ),
           listing: listingPda,
+          escrowTokenAccount: getAssociatedTokenAddressSync(listingMint, listingPda, true, TOKEN_2022_PROGRAM_ID),
           seller: seller.publicKey,
           systemProgram: SystemProgram.programId,
+          tokenProgram: TOKEN_2022_PROGRAM_ID,
         })
+        .remainingAccounts(getCouponHookAccounts(listingMint, listingCouponPda))
         .signers([seller])
         .rpc()
 
@@ -697,6 +3558,68 @@ describe('Deal Discovery Platform', () => {
       assert.equal(listing.seller.toString(), seller.publicKey.toString())
       assert.equal(listing.priceLamports.toString(), price.toString())
       assert.isTrue(listing.isActive)
+
+      // The NFT is held in the listing's escrow until it sells or is delisted
+      const sellerBalance = await provider.connection.getTokenAccountBalance(
+        getAssociatedTokenAddressSync(listingMint, seller.publicKey, false, TOKEN_2022_PROGRAM_ID)
+      )
+      const escrowBalance = await provider.connection.getTokenAccountBalance(
+        getAssociatedTokenAddressSync(listingMint, listingPda, true, TOKEN_2022_PROGRAM_ID)
+      )
+      assert.equal(sellerBalance.value.amount, '0')
+      assert.equal(escrowBalance.value.amount, '1')
+
+      const coupon = await program.account.coupon.fetch(listingCouponPda)
+      assert.isTrue(coupon.isListed)
+    })
+
+    it('Prevents staking a listed coupon', async () => {
+      const [rewardsPoolPda] = PublicKey.findProgramAddressSync(
+        [Buffer.from('rewards_pool')],
+        program.programId
+      )
+
+      try {
+        await program.methods
+          .stakeCoupon()
+          .accountsPartial({
+            coupon: listingCouponPda,
+            stakerTokenAccount: getAssociatedTokenAddressSync(
+              listingMint,
+              seller.publicKey,
+              false,
+              TOKEN_2022_PROGRAM_ID
+            ),
+            rewardsPool: rewardsPoolPda,
+            staker: seller.publicKey,
+          })
+          .signers([seller])
+          .rpc()
+
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        // The seller's token account is empty while the NFT sits in escrow
+        assert.include(error.message, 'NotOwner')
+      }
+    })
+
+    it('Prevents redeeming a listed coupon', async () => {
+      try {
+        await program.methods
+          .redeemCoupon()
+          .accountsPartial({
+            coupon: listingCouponPda,
+            deal: dealPda,
+            merchant: merchant.publicKey,
+            couponTokenProgram: TOKEN_2022_PROGRAM_ID,
+          })
+          .signers([merchant])
+          .rpc()
+
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'CouponLocked')
+      }
     })
 
     it('Prevents listing with zero price', async () => {
@@ -707,52 +3630,14 @@ describe('Deal Discovery Platform', () => {
       )
       await provider.connection.confirmTransaction(airdrop)
 
-      // Mint another coupon
-      dealAccount = await program.account.deal.fetch(dealPda)
-      const mintKeypair = Keypair.generate()
-
-      const couponPda = PublicKey.findProgramAddressSync(
-        [
-          Buffer.from('coupon'),
-          dealPda.toBuffer(),
-          dealAccount.currentSupply.toArrayLike(Buffer, 'le', 8),
-        ],
-        program.programId
-      )[0]
-
+      const { couponPda, mint } = await mintCoupon(dealPda, { buyer: anotherSeller, uri: 'ipfs://test-metadata-2' })
       const userTokenAccount = getAssociatedTokenAddressSync(
-        mintKeypair.publicKey,
-        anotherSeller.publicKey
-      )
-
-      const [metadataPda] = PublicKey.findProgramAddressSync(
-        [
-          Buffer.from('metadata'),
-          new PublicKey('metaqbxxUerdq28cj1RbAWkYQm3ybzjb6a8bt518x1s').toBuffer(),
-          mintKeypair.publicKey.toBuffer(),
-        ],
-        new PublicKey('metaqbxxUerdq28cj1RbAWkYQm3ybzjb6a8bt518x1s')
+        mint,
+        anotherSeller.publicKey,
+        false,
+        TOKEN_2022_PROGRAM_ID
       )
 
-      await program.methods
-        .mintCoupon(dealPda, 'ipfs://test-metadata-2')
-        .accounts({
-          deal: dealPda,
-          coupon: couponPda,
-          mint: mintKeypair.publicKey,
-          tokenAccount: userTokenAccount,
-          metadata: metadataPda,
-          merchant: merchant.publicKey,
-          user: anotherSeller.publicKey,
-          rent: SYSVAR_RENT_PUBKEY,
-          systemProgram: SystemProgram.programId,
-          tokenProgram: TOKEN_PROGRAM_ID,
-          associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
-          tokenMetadataProgram: new PublicKey('metaqbxxUerdq28cj1RbAWkYQm3ybzjb6a8bt518x1s'),
-        })
-        .signers([anotherSeller, mintKeypair])
-        .rpc()
-
       const testListingPda = PublicKey.findProgramAddressSync(
         [Buffer.from('listing'), couponPda.toBuffer()],
         program.programId
@@ -763,10 +3648,14 @@ describe('Deal Discovery Platform', () => {
           .listCoupon(new BN(0))
           .accounts({
             coupon: couponPda,
+            mint,
+            sellerTokenAccount: userTokenAccount,
             listing: testListingPda,
             seller: anotherSeller.publicKey,
             systemProgram: SystemProgram.programId,
+            tokenProgram: TOKEN_2022_PROGRAM_ID,
           })
+          .remainingAccounts(getCouponHookAccounts(mint, couponPda))
           .signers([anotherSeller])
           .rpc()
 
@@ -776,24 +3665,68 @@ describe('Deal Discovery Platform', () => {
       }
     })
 
+    it('Prevents routing the fee away from the treasury', async () => {
+      try {
+        await program.methods
+          .buyCoupon()
+          .accounts({
+            listing: listingPda,
+            coupon: listingCouponPda,
+            mint: listingMint,
+            couponTokenProgram: TOKEN_2022_PROGRAM_ID,
+            escrowTokenAccount: getAssociatedTokenAddressSync(listingMint, listingPda, true, TOKEN_2022_PROGRAM_ID),
+            buyerTokenAccount: getAssociatedTokenAddressSync(
+              listingMint,
+              buyer.publicKey,
+              false,
+              TOKEN_2022_PROGRAM_ID
+            ),
+            seller: seller.publicKey,
+            buyer: buyer.publicKey,
+            platformConfig: platformConfigPda,
+            platformWallet: buyer.publicKey,
+            systemProgram: SystemProgram.programId,
+          })
+          .remainingAccounts(getCouponHookAccounts(listingMint, listingCouponPda))
+          .signers([buyer])
+          .rpc()
+
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        assert.include(error.message, 'InvalidTreasury')
+      }
+    })
+
     it('Buys a listed coupon', async () => {
-      const platformWallet = Keypair.generate().publicKey
+      const config = await program.account.platformConfig.fetch(platformConfigPda)
 
       const sellerBalanceBefore = await provider.connection.getBalance(seller.publicKey)
       const buyerBalanceBefore = await provider.connection.getBalance(buyer.publicKey)
 
       const listing = await program.account.listing.fetch(listingPda)
+      // The seller gets back the rent on the listing and its escrow
+      const rentRefund =
+        (await provider.connection.getBalance(listingPda)) +
+        (await provider.connection.getBalance(
+          getAssociatedTokenAddressSync(listingMint, listingPda, true, TOKEN_2022_PROGRAM_ID)
+        ))
 
       await program.methods
         .buyCoupon()
         .accounts({
           listing: listingPda,
           coupon: listingCouponPda,
+          mint: listingMint,
+          couponTokenProgram: TOKEN_2022_PROGRAM_ID,
+          escrowTokenAccount: getAssociatedTokenAddressSync(listingMint, listingPda, true, TOKEN_2022_PROGRAM_ID),
+          buyerTokenAccount: getAssociatedTokenAddressSync(listingMint, buyer.publicKey, false, TOKEN_2022_PROGRAM_ID),
           seller: seller.publicKey,
           buyer: buyer.publicKey,
-          platformWallet: platformWallet,
+          platformConfig: platformConfigPda,
+          platformWallet: config.treasury,
           systemProgram: SystemProgram.programId,
         })
+        .remainingAccounts(getCouponHookAccounts(listingMint, listingCouponPda))
         .signers([buyer])
         .rpc()
 
@@ -801,96 +3734,84 @@ describe('Deal Discovery Platform', () => {
       const coupon = await program.account.coupon.fetch(listingCouponPda)
       assert.equal(coupon.owner.toString(), buyer.publicKey.toString())
 
-      // Verify listing deactivated
-      const updatedListing = await program.account.listing.fetch(listingPda)
-      assert.isFalse(updatedListing.isActive)
+      const buyerBalance = await provider.connection.getTokenAccountBalance(
+        getAssociatedTokenAddressSync(listingMint, buyer.publicKey, false, TOKEN_2022_PROGRAM_ID)
+      )
+      assert.equal(buyerBalance.value.amount, '1')
+      assert.isFalse(coupon.isListed)
+
+      // The emptied escrow is closed
+      const escrowInfo = await provider.connection.getAccountInfo(
+        getAssociatedTokenAddressSync(listingMint, listingPda, true, TOKEN_2022_PROGRAM_ID)
+      )
+      assert.isNull(escrowInfo)
+
+      // The sold listing is closed
+      assert.isNull(await provider.connection.getAccountInfo(listingPda))
 
-      // Verify payments (seller got 97.5%, platform got 2.5%)
+      // Verify payments are split by the configured fee rate
       const sellerBalanceAfter = await provider.connection.getBalance(seller.publicKey)
-      const platformFee = (listing.priceLamports.toNumber() * 25) / 1000
+      const platformFee = Math.floor((listing.priceLamports.toNumber() * config.feeBps) / 10_000)
       const sellerAmount = listing.priceLamports.toNumber() - platformFee
 
-      assert.equal(sellerBalanceAfter - sellerBalanceBefore, sellerAmount)
+      assert.equal(sellerBalanceAfter - sellerBalanceBefore, sellerAmount + rentRefund)
+      assert.equal(await provider.connection.getBalance(config.treasury), platformFee)
     })
 
-    it('Prevents buying inactive listing', async () => {
+    it('Prevents buying a sold listing', async () => {
       try {
         await program.methods
           .buyCoupon()
           .accounts({
             listing: listingPda,
             coupon: listingCouponPda,
+            mint: listingMint,
+            couponTokenProgram: TOKEN_2022_PROGRAM_ID,
+            escrowTokenAccount: getAssociatedTokenAddressSync(listingMint, listingPda, true, TOKEN_2022_PROGRAM_ID),
+            buyerTokenAccount: getAssociatedTokenAddressSync(
+              listingMint,
+              merchant.publicKey,
+              false,
+              TOKEN_2022_PROGRAM_ID
+            ),
             seller: seller.publicKey,
             buyer: merchant.publicKey,
-            platformWallet: Keypair.generate().publicKey,
+            platformConfig: platformConfigPda,
+            platformWallet: treasury,
             systemProgram: SystemProgram.programId,
           })
+          .remainingAccounts(getCouponHookAccounts(listingMint, listingCouponPda))
           .signers([merchant])
           .rpc()
 
         assert.fail('Should have thrown error')
       } catch (error) {
-        assert.include(error.message, 'ListingInactive')
+        // The sale closed the listing
+        assert.include(error.message, 'AccountNotInitialized')
       }
     })
 
-    it('Delists a coupon', async () => {
-      // Create another listing first
-      const anotherSeller = Keypair.generate()
+    it('Lists a second coupon into escrow', async () => {
+      delistSeller = Keypair.generate()
       const airdrop = await provider.connection.requestAirdrop(
-        anotherSeller.publicKey,
+        delistSeller.publicKey,
         2 * anchor.web3.LAMPORTS_PER_SOL
       )
       await provider.connection.confirmTransaction(airdrop)
 
-      // Mint coupon
-      dealAccount = await program.account.deal.fetch(dealPda)
-      const mintKeypair = Keypair.generate()
-
-      const couponPda = PublicKey.findProgramAddressSync(
-        [
-          Buffer.from('coupon'),
-          dealPda.toBuffer(),
-          dealAccount.currentSupply.toArrayLike(Buffer, 'le', 8),
-        ],
-        program.programId
-      )[0]
-
+      ;({ couponPda: delistCouponPda, mint: delistMint } = await mintCoupon(dealPda, {
+        buyer: delistSeller,
+        uri: 'ipfs://test-metadata-3',
+      }))
       const userTokenAccount = getAssociatedTokenAddressSync(
-        mintKeypair.publicKey,
-        anotherSeller.publicKey
-      )
-
-      const [metadataPda] = PublicKey.findProgramAddressSync(
-        [
-          Buffer.from('metadata'),
-          new PublicKey('metaqbxxUerdq28cj1RbAWkYQm3ybzjb6a8bt518x1s').toBuffer(),
-          mintKeypair.publicKey.toBuffer(),
-        ],
-        new PublicKey('metaqbxxUerdq28cj1RbAWkYQm3ybzjb6a8bt518x1s')
+        delistMint,
+        delistSeller.publicKey,
+        false,
+        TOKEN_2022_PROGRAM_ID
       )
 
-      await program.methods
-        .mintCoupon(dealPda, 'ipfs://test-metadata-3')
-        .accounts({
-          deal: dealPda,
-          coupon: couponPda,
-          mint: mintKeypair.publicKey,
-          tokenAccount: userTokenAccount,
-          metadata: metadataPda,
-          merchant: merchant.publicKey,
-          user: anotherSeller.publicKey,
-          rent: SYSVAR_RENT_PUBKEY,
-          systemProgram: SystemProgram.programId,
-          tokenProgram: TOKEN_PROGRAM_ID,
-          associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
-          tokenMetadataProgram: new PublicKey('metaqbxxUerdq28cj1RbAWkYQm3ybzjb6a8bt518x1s'),
-        })
-        .signers([anotherSeller, mintKeypair])
-        .rpc()
-
-      const delistListingPda = PublicKey.findProgramAddressSync(
-        [Buffer.from('listing'), couponPda.toBuffer()],
+      delistListingPda = PublicKey.findProgramAddressSync(
+        [Buffer.from('listing'), delistCouponPda.toBuffer()],
         program.programId
       )[0]
 
@@ -898,27 +3819,41 @@ describe('Deal Discovery Platform', () => {
       await program.methods
         .listCoupon(new BN(30_000_000))
         .accounts({
-          coupon: couponPda,
+          coupon: delistCouponPda,
+          mint: delistMint,
+          sellerTokenAccount: userTokenAccount,
           listing: delistListingPda,
-          seller: anotherSeller.publicKey,
+          escrowTokenAccount: getAssociatedTokenAddressSync(delistMint, delistListingPda, true, TOKEN_2022_PROGRAM_ID),
+          seller: delistSeller.publicKey,
           systemProgram: SystemProgram.programId,
+          tokenProgram: TOKEN_2022_PROGRAM_ID,
         })
-        .signers([anotherSeller])
+        .remainingAccounts(getCouponHookAccounts(delistMint, delistCouponPda))
+        .signers([delistSeller])
         .rpc()
 
-      // Now delist
-      await program.methods
-        .delistCoupon()
-        .accounts({
-          listing: delistListingPda,
-          coupon: couponPda,
-          seller: anotherSeller.publicKey,
-        })
-        .signers([anotherSeller])
-        .rpc()
+      // Transfers are refused while the coupon is in escrow
+      try {
+        await program.methods
+          .transferCoupon()
+          .accounts({
+            coupon: delistCouponPda,
+            mint: delistMint,
+            fromTokenAccount: userTokenAccount,
+            toTokenAccount: getAssociatedTokenAddressSync(delistMint, merchant.publicKey, false, TOKEN_2022_PROGRAM_ID),
+            currentOwner: delistSeller.publicKey,
+            newOwner: merchant.publicKey,
+            tokenProgram: TOKEN_2022_PROGRAM_ID,
+          })
+          .remainingAccounts(getCouponHookAccounts(delistMint, delistCouponPda))
+          .signers([delistSeller])
+          .rpc()
 
-      const listing = await program.account.listing.fetch(delistListingPda)
-      assert.isFalse(listing.isActive)
+        assert.fail('Should have thrown error')
+      } catch (error) {
+        // The seller's token account is empty while the NFT sits in escrow
+        assert.include(error.message, 'NotOwner')
+      }
     })
 
     it('Prevents non-owner from delisting', async () => {
@@ -926,10 +3861,25 @@ describe('Deal Discovery Platform', () => {
         await program.methods
           .delistCoupon()
           .accounts({
-            listing: listingPda,
-            coupon: listingCouponPda,
+            listing: delistListingPda,
+            coupon: delistCouponPda,
+            mint: delistMint,
+            tokenProgram: TOKEN_2022_PROGRAM_ID,
+            escrowTokenAccount: getAssociatedTokenAddressSync(
+              delistMint,
+              delistListingPda,
+              true,
+              TOKEN_2022_PROGRAM_ID
+            ),
+            sellerTokenAccount: getAssociatedTokenAddressSync(
+              delistMint,
+              merchant.publicKey,
+              false,
+              TOKEN_2022_PROGRAM_ID
+            ),
             seller: merchant.publicKey,
           })
+          .remainingAccounts(getCouponHookAccounts(delistMint, delistCouponPda))
           .signers([merchant])
           .rpc()
 
@@ -938,5 +3888,70 @@ describe('Deal Discovery Platform', () => {
         assert.include(error.message, 'NotOwner')
       }
     })
+
+    it('Delists a coupon', async () => {
+      const userTokenAccount = getAssociatedTokenAddressSync(
+        delistMint,
+        delistSeller.publicKey,
+        false,
+        TOKEN_2022_PROGRAM_ID
+      )
+
+      await program.methods
+        .delistCoupon()
+        .accounts({
+          listing: delistListingPda,
+          coupon: delistCouponPda,
+          mint: delistMint,
+          tokenProgram: TOKEN_2022_PROGRAM_ID,
+          escrowTokenAccount: getAssociatedTokenAddressSync(delistMint, delistListingPda, true, TOKEN_2022_PROGRAM_ID),
+          sellerTokenAccount: userTokenAccount,
+          seller: delistSeller.publicKey,
+        })
+        .remainingAccounts(getCouponHookAccounts(delistMint, delistCouponPda))
+        .signers([delistSeller])
+        .rpc()
+
+      assert.isNull(await provider.connection.getAccountInfo(delistListingPda))
+
+      // The NFT is back with the seller and the coupon is unlocked
+      const sellerBalance = await provider.connection.getTokenAccountBalance(userTokenAccount)
+      assert.equal(sellerBalance.value.amount, '1')
+
+      const coupon = await program.account.coupon.fetch(delistCouponPda)
+      assert.isFalse(coupon.isListed)
+    })
+
+    it('Relists a delisted coupon', async () => {
+      const price = new BN(40_000_000)
+
+      await program.methods
+        .listCoupon(price)
+        .accounts({
+          coupon: delistCouponPda,
+          mint: delistMint,
+          sellerTokenAccount: getAssociatedTokenAddressSync(
+            delistMint,
+            delistSeller.publicKey,
+            false,
+            TOKEN_2022_PROGRAM_ID
+          ),
+          listing: delistListingPda,
+          escrowTokenAccount: getAssociatedTokenAddressSync(delistMint, delistListingPda, true, TOKEN_2022_PROGRAM_ID),
+          seller: delistSeller.publicKey,
+          systemProgram: SystemProgram.programId,
+          tokenProgram: TOKEN_2022_PROGRAM_ID,
+        })
+        .remainingAccounts(getCouponHookAccounts(delistMint, delistCouponPda))
+        .signers([delistSeller])
+        .rpc()
+
+      const listing = await program.account.listing.fetch(delistListingPda)
+      assert.isTrue(listing.isActive)
+      assert.equal(listing.priceLamports.toString(), price.toString())
+
+      const coupon = await program.account.coupon.fetch(delistCouponPda)
+      assert.isTrue(coupon.isListed)
+    })
   })
 })
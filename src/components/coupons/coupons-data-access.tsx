@@ -1,8 +1,8 @@
 'use client'
 
-import { getBasicProgram, getBasicProgramId } from '@project/anchor'
-import { useWallet } from '@solana/wallet-adapter-react'
-import { Cluster, PublicKey } from '@solana/web3.js'
+import { getBasicProgram, getBasicProgramId, getCouponHookAccounts, getCouponTokenProgram } from '@project/anchor'
+import { useConnection, useWallet } from '@solana/wallet-adapter-react'
+import { Cluster, PublicKey, Transaction, TransactionInstruction } from '@solana/web3.js'
 import { useMutation, useQuery } from '@tanstack/react-query'
 import { useMemo } from 'react'
 import { useCluster } from '../cluster/cluster-data-access'
@@ -10,19 +10,34 @@ import { useAnchorProvider } from '../solana/solana-provider'
 import { useTransactionToast } from '../use-transaction-toast'
 import { toast } from 'sonner'
 import { BN } from '@coral-xyz/anchor'
+import { createAssociatedTokenAccountIdempotentInstruction, getAssociatedTokenAddressSync } from '@solana/spl-token'
 
 export interface Coupon {
   publicKey: PublicKey
   deal: PublicKey
   owner: PublicKey
   mint: PublicKey
+  couponIndex: BN
   isRedeemed: boolean
   mintedAt: BN
   redeemedAt: BN | null
+  isListed: boolean
+  isStaked: boolean
+  escrowedAmount: BN
+  isRefunded: boolean
+  // Punch-card coupons can be redeemed usesTotal times
+  usesTotal: BN
+  usesRemaining: BN
+  // Value left on a stored-value (gift card) coupon; zero for discount coupons
+  balance: BN
+  redemptionCount: BN
+  // Price paid at mint, which refunds are worked out from
+  pricePaid: BN
 }
 
 export function useCouponsProgram() {
-  const { publicKey } = useWallet()
+  const { publicKey, signTransaction } = useWallet()
+  const { connection } = useConnection()
   const { cluster } = useCluster()
   const transactionToast = useTransactionToast()
   const provider = useAnchorProvider()
@@ -56,13 +71,33 @@ export function useCouponsProgram() {
     mutationFn: async ({ couponAddress, dealAddress }: { couponAddress: PublicKey; dealAddress: PublicKey }) => {
       if (!publicKey) throw new Error('Wallet not connected')
 
+      // Escrowed token payments are released from the deal vault's ATA to the merchant's
+      const { paymentMint, escrowPayments } = await program.account.deal.fetch(dealAddress)
+      const { mint } = await program.account.coupon.fetch(couponAddress)
+      const couponTokenProgram = await getCouponTokenProgram(connection, mint)
+      const tokenEscrow = escrowPayments && paymentMint
+      const [dealVaultPda] = PublicKey.findProgramAddressSync(
+        [Buffer.from('deal_vault'), dealAddress.toBuffer()],
+        program.programId
+      )
+      const merchantPaymentAccount = tokenEscrow ? getAssociatedTokenAddressSync(paymentMint, publicKey) : null
+      const preInstructions: TransactionInstruction[] = tokenEscrow
+        ? [createAssociatedTokenAccountIdempotentInstruction(publicKey, merchantPaymentAccount!, publicKey, paymentMint)]
+        : []
+
       const signature = await program.methods
         .redeemCoupon()
-        .accounts({
+        .accountsPartial({
           coupon: couponAddress,
           deal: dealAddress,
           merchant: publicKey,
+          paymentMint: tokenEscrow ? paymentMint : null,
+          vaultPaymentAccount: tokenEscrow ? getAssociatedTokenAddressSync(paymentMint, dealVaultPda, true) : null,
+          merchantPaymentAccount,
+          holder: null,
+          couponTokenProgram,
         })
+        .preInstructions(preInstructions)
         .rpc()
 
       return signature
@@ -76,19 +111,253 @@ export function useCouponsProgram() {
     },
   })
 
+  // Deals that burn redeemed coupons need the holder's signature on the last
+  // use, so the holder pre-signs the redemption and shows it as a QR code
+  const approveRedemption = useMutation({
+    mutationKey: ['coupons', 'approve-redemption', { cluster }],
+    mutationFn: async ({ couponAddress }: { couponAddress: PublicKey }) => {
+      if (!publicKey || !signTransaction) throw new Error('Wallet not connected')
+
+      const coupon = await program.account.coupon.fetch(couponAddress)
+      const { merchant, paymentMint, escrowPayments } = await program.account.deal.fetch(coupon.deal)
+      const couponTokenProgram = await getCouponTokenProgram(connection, coupon.mint)
+      const tokenEscrow = escrowPayments && paymentMint
+      const [dealVaultPda] = PublicKey.findProgramAddressSync(
+        [Buffer.from('deal_vault'), coupon.deal.toBuffer()],
+        program.programId
+      )
+      const merchantPaymentAccount = tokenEscrow ? getAssociatedTokenAddressSync(paymentMint, merchant) : null
+
+      const tx = await program.methods
+        .redeemCoupon()
+        .accountsPartial({
+          coupon: couponAddress,
+          deal: coupon.deal,
+          merchant,
+          holder: publicKey,
+          paymentMint: tokenEscrow ? paymentMint : null,
+          vaultPaymentAccount: tokenEscrow ? getAssociatedTokenAddressSync(paymentMint, dealVaultPda, true) : null,
+          merchantPaymentAccount,
+          couponTokenProgram,
+        })
+        .preInstructions(
+          tokenEscrow
+            ? [createAssociatedTokenAccountIdempotentInstruction(merchant, merchantPaymentAccount!, merchant, paymentMint)]
+            : []
+        )
+        .transaction()
+      tx.feePayer = merchant
+      tx.recentBlockhash = (await connection.getLatestBlockhash()).blockhash
+
+      const signed = await signTransaction(tx)
+      return signed.serialize({ requireAllSignatures: false }).toString('base64')
+    },
+    onError: (error) => {
+      toast.error(`Failed to approve redemption: ${error}`)
+    },
+  })
+
+  // Gift card charges need both signatures: the holder approves the amount
+  // here, and hands the half-signed transaction to the merchant as a QR code
+  const approveGiftCardCharge = useMutation({
+    mutationKey: ['coupons', 'approve-charge', { cluster }],
+    mutationFn: async ({ couponAddress, amount }: { couponAddress: PublicKey; amount: number }) => {
+      if (!publicKey || !signTransaction) throw new Error('Wallet not connected')
+
+      const coupon = await program.account.coupon.fetch(couponAddress)
+      const { merchant, paymentMint, escrowPayments } = await program.account.deal.fetch(coupon.deal)
+      const couponTokenProgram = await getCouponTokenProgram(connection, coupon.mint)
+      const tokenEscrow = escrowPayments && paymentMint
+      const [dealVaultPda] = PublicKey.findProgramAddressSync(
+        [Buffer.from('deal_vault'), coupon.deal.toBuffer()],
+        program.programId
+      )
+      const [redemptionEntryPda] = PublicKey.findProgramAddressSync(
+        [Buffer.from('redemption'), couponAddress.toBuffer(), coupon.redemptionCount.toArrayLike(Buffer, 'le', 8)],
+        program.programId
+      )
+      const merchantPaymentAccount = tokenEscrow ? getAssociatedTokenAddressSync(paymentMint, merchant) : null
+
+      const tx = await program.methods
+        .redeemAmount(new BN(amount))
+        .accountsPartial({
+          coupon: couponAddress,
+          deal: coupon.deal,
+          redemptionEntry: redemptionEntryPda,
+          mint: coupon.mint,
+          holderTokenAccount: getAssociatedTokenAddressSync(coupon.mint, publicKey, false, couponTokenProgram),
+          merchant,
+          holder: publicKey,
+          paymentMint: tokenEscrow ? paymentMint : null,
+          vaultPaymentAccount: tokenEscrow ? getAssociatedTokenAddressSync(paymentMint, dealVaultPda, true) : null,
+          merchantPaymentAccount,
+          couponTokenProgram,
+        })
+        .preInstructions(
+          tokenEscrow
+            ? [createAssociatedTokenAccountIdempotentInstruction(merchant, merchantPaymentAccount!, merchant, paymentMint)]
+            : []
+        )
+        .transaction()
+      // The merchant pays the fee and the log entry's rent
+      tx.feePayer = merchant
+      tx.recentBlockhash = (await connection.getLatestBlockhash()).blockhash
+
+      const signed = await signTransaction(tx)
+      return signed.serialize({ requireAllSignatures: false }).toString('base64')
+    },
+    onError: (error) => {
+      toast.error(`Failed to approve gift card charge: ${error}`)
+    },
+  })
+
+  // Merchant counter-signs and submits a transaction pre-signed by the holder
+  const countersign = async (approval: string) => {
+    if (!publicKey || !signTransaction) throw new Error('Wallet not connected')
+
+    const tx = Transaction.from(Buffer.from(approval, 'base64'))
+    if (!tx.feePayer?.equals(publicKey)) throw new Error('This approval is for a different merchant')
+    const signed = await signTransaction(tx)
+    const signature = await connection.sendRawTransaction(signed.serialize())
+    await connection.confirmTransaction(signature, 'confirmed')
+    return signature
+  }
+
+  const redeemApproved = useMutation({
+    mutationKey: ['coupons', 'redeem-approved', { cluster }],
+    mutationFn: ({ approval }: { approval: string }) => countersign(approval),
+    onSuccess: (signature) => {
+      transactionToast(signature)
+      userCoupons.refetch()
+    },
+    onError: (error) => {
+      toast.error(`Failed to redeem coupon: ${error}`)
+    },
+  })
+
+  const redeemAmount = useMutation({
+    mutationKey: ['coupons', 'redeem-amount', { cluster }],
+    mutationFn: ({ charge }: { charge: string }) => countersign(charge),
+    onSuccess: (signature) => {
+      transactionToast(signature)
+      userCoupons.refetch()
+    },
+    onError: (error) => {
+      toast.error(`Failed to charge gift card: ${error}`)
+    },
+  })
+
+  // Reclaim the escrowed payment for a coupon the merchant never honored
+  const refundUnredeemed = useMutation({
+    mutationKey: ['coupons', 'refund', { cluster }],
+    mutationFn: async ({ couponAddress }: { couponAddress: PublicKey }) => {
+      if (!publicKey) throw new Error('Wallet not connected')
+
+      const { deal, mint } = await program.account.coupon.fetch(couponAddress)
+      const { paymentMint } = await program.account.deal.fetch(deal)
+      const couponTokenProgram = await getCouponTokenProgram(connection, mint)
+      const [dealVaultPda] = PublicKey.findProgramAddressSync(
+        [Buffer.from('deal_vault'), deal.toBuffer()],
+        program.programId
+      )
+      const holderPaymentAccount = paymentMint ? getAssociatedTokenAddressSync(paymentMint, publicKey) : null
+      const preInstructions: TransactionInstruction[] = paymentMint
+        ? [createAssociatedTokenAccountIdempotentInstruction(publicKey, holderPaymentAccount!, publicKey, paymentMint)]
+        : []
+
+      const signature = await program.methods
+        .refundUnredeemed()
+        .accountsPartial({
+          coupon: couponAddress,
+          deal,
+          mint,
+          holderTokenAccount: getAssociatedTokenAddressSync(mint, publicKey, false, couponTokenProgram),
+          holder: publicKey,
+          paymentMint,
+          vaultPaymentAccount: paymentMint ? getAssociatedTokenAddressSync(paymentMint, dealVaultPda, true) : null,
+          holderPaymentAccount,
+          couponTokenProgram,
+        })
+        .preInstructions(preInstructions)
+        .rpc()
+
+      return signature
+    },
+    onSuccess: (signature) => {
+      transactionToast(signature)
+      userCoupons.refetch()
+    },
+    onError: (error) => {
+      toast.error(`Failed to refund coupon: ${error}`)
+    },
+  })
+
+  // Claim price_lamports back for a coupon of a cancelled deal (burns the coupon)
+  const claimCancellationRefund = useMutation({
+    mutationKey: ['coupons', 'cancellation-refund', { cluster }],
+    mutationFn: async ({ couponAddress }: { couponAddress: PublicKey }) => {
+      if (!publicKey) throw new Error('Wallet not connected')
+
+      const { deal, mint } = await program.account.coupon.fetch(couponAddress)
+      const { paymentMint } = await program.account.deal.fetch(deal)
+      const couponTokenProgram = await getCouponTokenProgram(connection, mint)
+      const [dealVaultPda] = PublicKey.findProgramAddressSync(
+        [Buffer.from('deal_vault'), deal.toBuffer()],
+        program.programId
+      )
+      const holderPaymentAccount = paymentMint ? getAssociatedTokenAddressSync(paymentMint, publicKey) : null
+      const preInstructions: TransactionInstruction[] = paymentMint
+        ? [createAssociatedTokenAccountIdempotentInstruction(publicKey, holderPaymentAccount!, publicKey, paymentMint)]
+        : []
+
+      const signature = await program.methods
+        .claimCancellationRefund()
+        .accountsPartial({
+          coupon: couponAddress,
+          deal,
+          mint,
+          holderTokenAccount: getAssociatedTokenAddressSync(mint, publicKey, false, couponTokenProgram),
+          holder: publicKey,
+          paymentMint,
+          vaultPaymentAccount: paymentMint ? getAssociatedTokenAddressSync(paymentMint, dealVaultPda, true) : null,
+          holderPaymentAccount,
+          couponTokenProgram,
+        })
+        .preInstructions(preInstructions)
+        .rpc()
+
+      return signature
+    },
+    onSuccess: (signature) => {
+      transactionToast(signature)
+      userCoupons.refetch()
+    },
+    onError: (error) => {
+      toast.error(`Failed to claim cancellation refund: ${error}`)
+    },
+  })
+
   // Transfer coupon
   const transferCoupon = useMutation({
     mutationKey: ['coupons', 'transfer', { cluster }],
     mutationFn: async ({ couponAddress, newOwner }: { couponAddress: PublicKey; newOwner: PublicKey }) => {
       if (!publicKey) throw new Error('Wallet not connected')
 
+      const { mint } = await program.account.coupon.fetch(couponAddress)
+      const tokenProgram = await getCouponTokenProgram(connection, mint)
+
       const signature = await program.methods
         .transferCoupon()
-        .accounts({
+        .accountsPartial({
           coupon: couponAddress,
+          mint,
+          fromTokenAccount: getAssociatedTokenAddressSync(mint, publicKey, false, tokenProgram),
+          toTokenAccount: getAssociatedTokenAddressSync(mint, newOwner, false, tokenProgram),
           currentOwner: publicKey,
           newOwner: newOwner,
+          tokenProgram,
         })
+        .remainingAccounts(getCouponHookAccounts(mint, couponAddress))
         .rpc()
 
       return signature
@@ -117,6 +386,12 @@ export function useCouponsProgram() {
     programId,
     userCoupons,
     redeemCoupon,
+    approveRedemption,
+    redeemApproved,
+    approveGiftCardCharge,
+    redeemAmount,
+    refundUnredeemed,
+    claimCancellationRefund,
     transferCoupon,
     generateRedemptionQR,
   }
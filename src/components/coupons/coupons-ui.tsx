@@ -15,21 +15,60 @@ import QRCode from 'qrcode'
 import { Gift, QrCode, Ticket } from 'lucide-react'
 
 export function CouponCard({ coupon, deal }: { coupon: Coupon; deal?: Deal }) {
-  const { transferCoupon, generateRedemptionQR } = useCouponsProgram()
+  const {
+    transferCoupon,
+    refundUnredeemed,
+    claimCancellationRefund,
+    generateRedemptionQR,
+    approveRedemption,
+    approveGiftCardCharge,
+  } = useCouponsProgram()
   const [showQR, setShowQR] = useState(false)
+  const [showCharge, setShowCharge] = useState(false)
+  const [chargeAmount, setChargeAmount] = useState(0)
+  const [chargeQrDataUrl, setChargeQrDataUrl] = useState('')
+  const isGiftCard = !!deal && 'storedValue' in deal.kind
   const [showTransfer, setShowTransfer] = useState(false)
   const [qrDataUrl, setQrDataUrl] = useState('')
   const [transferAddress, setTransferAddress] = useState('')
 
   const mintedDate = new Date(coupon.mintedAt.toNumber() * 1000)
   const redeemedDate = coupon.redeemedAt ? new Date(coupon.redeemedAt.toNumber() * 1000) : null
+  // Escrowed payments for coupons never redeemed can be reclaimed once the deal expires
+  const refundable =
+    !!deal &&
+    !deal.isCancelled &&
+    !coupon.isRedeemed &&
+    !coupon.isListed &&
+    !coupon.isStaked &&
+    !coupon.escrowedAmount.isZero() &&
+    Date.now() / 1000 >= deal.expiryTimestamp.toNumber()
+  // Holders of a cancelled deal's coupons get the deal price back
+  const cancellationRefundable =
+    !!deal?.isCancelled && !coupon.isRedeemed && !coupon.isRefunded && !coupon.isListed && !coupon.isStaked
+
+  // The last use of a coupon on a burning deal carries the holder's signature
+  const needsApproval = !!deal && 'burn' in deal.redemptionAction && coupon.usesRemaining.eqn(1)
 
   useEffect(() => {
-    if (showQR) {
+    if (!showQR) return
+    if (needsApproval) {
+      approveRedemption
+        .mutateAsync({ couponAddress: coupon.publicKey })
+        .then((approval) => QRCode.toDataURL(JSON.stringify({ approvedRedemption: approval }), { width: 400 }))
+        .then(setQrDataUrl)
+        .catch(() => setShowQR(false))
+    } else {
       const qrData = generateRedemptionQR(coupon)
       QRCode.toDataURL(qrData, { width: 300 }).then(setQrDataUrl)
     }
-  }, [showQR, coupon, generateRedemptionQR])
+    // eslint-disable-next-line react-hooks/exhaustive-deps
+  }, [showQR, coupon, needsApproval, generateRedemptionQR])
+
+  const handleApproveCharge = async () => {
+    const charge = await approveGiftCardCharge.mutateAsync({ couponAddress: coupon.publicKey, amount: chargeAmount })
+    setChargeQrDataUrl(await QRCode.toDataURL(JSON.stringify({ giftCardCharge: charge }), { width: 400 }))
+  }
 
   const handleTransfer = async () => {
     try {
@@ -61,8 +100,20 @@ export function CouponCard({ coupon, deal }: { coupon: Coupon; deal?: Deal }) {
                 Minted {mintedDate.toLocaleDateString()}
               </CardDescription>
             </div>
-            <Badge variant={coupon.isRedeemed ? 'secondary' : 'default'}>
-              {coupon.isRedeemed ? 'Redeemed' : 'Active'}
+            <Badge
+              variant={
+                coupon.isRedeemed || coupon.isRefunded || coupon.isListed || coupon.isStaked ? 'secondary' : 'default'
+              }
+            >
+              {coupon.isRedeemed
+                ? 'Redeemed'
+                : coupon.isRefunded
+                  ? 'Refunded'
+                  : coupon.isListed
+                    ? 'Listed'
+                    : coupon.isStaked
+                      ? 'Staked'
+                      : 'Active'}
             </Badge>
           </div>
         </CardHeader>
@@ -75,27 +126,67 @@ export function CouponCard({ coupon, deal }: { coupon: Coupon; deal?: Deal }) {
               <span className="text-xs text-muted-foreground">•</span>
               <span className="text-xs text-muted-foreground">{deal.category}</span>
             </div>
-            {coupon.isRedeemed && redeemedDate && (
+            {isGiftCard && (
+              <p className="text-sm font-medium">Balance: {coupon.balance.toString()}</p>
+            )}
+            {coupon.usesTotal.gtn(1) && (
               <p className="text-xs text-muted-foreground">
-                Redeemed on {redeemedDate.toLocaleDateString()}
+                {coupon.usesRemaining.toString()}/{coupon.usesTotal.toString()} uses left
+              </p>
+            )}
+            {redeemedDate && (
+              <p className="text-xs text-muted-foreground">
+                {coupon.isRedeemed ? 'Redeemed' : 'Last used'} on {redeemedDate.toLocaleDateString()}
               </p>
             )}
           </CardContent>
         )}
 
         <CardFooter className="gap-2">
-          {!coupon.isRedeemed && (
-            <>
-              <Button size="sm" variant="outline" onClick={() => setShowQR(true)}>
-                <QrCode className="h-4 w-4 mr-1" />
-                Show QR
-              </Button>
-              <Button size="sm" variant="outline" onClick={() => setShowTransfer(true)}>
-                <Gift className="h-4 w-4 mr-1" />
-                Transfer
-              </Button>
-            </>
+          {refundable && (
+            <Button
+              size="sm"
+              onClick={() => refundUnredeemed.mutateAsync({ couponAddress: coupon.publicKey })}
+              disabled={refundUnredeemed.isPending}
+            >
+              {refundUnredeemed.isPending ? 'Refunding...' : 'Claim Refund'}
+            </Button>
+          )}
+          {cancellationRefundable && (
+            <Button
+              size="sm"
+              onClick={() => claimCancellationRefund.mutateAsync({ couponAddress: coupon.publicKey })}
+              disabled={claimCancellationRefund.isPending}
+            >
+              {claimCancellationRefund.isPending ? 'Claiming...' : 'Claim Cancellation Refund'}
+            </Button>
           )}
+          {!coupon.isRedeemed &&
+            !coupon.isRefunded &&
+            !coupon.isListed &&
+            !coupon.isStaked &&
+            !refundable &&
+            !cancellationRefundable && (
+              <>
+                {isGiftCard ? (
+                  <Button size="sm" variant="outline" onClick={() => setShowCharge(true)}>
+                    <QrCode className="h-4 w-4 mr-1" />
+                    Pay
+                  </Button>
+                ) : (
+                  <Button size="sm" variant="outline" onClick={() => setShowQR(true)}>
+                    <QrCode className="h-4 w-4 mr-1" />
+                    Show QR
+                  </Button>
+                )}
+                {deal?.transferable !== false && (
+                  <Button size="sm" variant="outline" onClick={() => setShowTransfer(true)}>
+                    <Gift className="h-4 w-4 mr-1" />
+                    Transfer
+                  </Button>
+                )}
+              </>
+            )}
         </CardFooter>
       </Card>
 
@@ -121,6 +212,52 @@ export function CouponCard({ coupon, deal }: { coupon: Coupon; deal?: Deal }) {
         </DialogContent>
       </Dialog>
 
+      <Dialog
+        open={showCharge}
+        onOpenChange={(open) => {
+          setShowCharge(open)
+          if (!open) setChargeQrDataUrl('')
+        }}
+      >
+        <DialogContent>
+          <DialogHeader>
+            <DialogTitle>Pay with Gift Card</DialogTitle>
+          </DialogHeader>
+          {chargeQrDataUrl ? (
+            <div className="flex flex-col items-center space-y-4">
+              <p className="text-sm text-muted-foreground text-center">
+                Show this QR code to the merchant to complete the charge
+              </p>
+              <div className="bg-white p-4 rounded-lg">
+                {/* eslint-disable-next-line @next/next/no-img-element */}
+                <img src={chargeQrDataUrl} alt="Gift card charge QR Code" className="w-full" />
+              </div>
+            </div>
+          ) : (
+            <div className="space-y-4">
+              <div>
+                <Label htmlFor="charge-amount">Amount (balance {coupon.balance.toString()})</Label>
+                <Input
+                  id="charge-amount"
+                  type="number"
+                  value={chargeAmount}
+                  onChange={(e) => setChargeAmount(Number(e.target.value))}
+                  min="1"
+                  max={coupon.balance.toString()}
+                />
+              </div>
+              <Button
+                onClick={handleApproveCharge}
+                disabled={approveGiftCardCharge.isPending || chargeAmount <= 0 || coupon.balance.ltn(chargeAmount)}
+                className="w-full"
+              >
+                {approveGiftCardCharge.isPending ? 'Approving...' : 'Approve Charge'}
+              </Button>
+            </div>
+          )}
+        </DialogContent>
+      </Dialog>
+
       <Dialog open={showTransfer} onOpenChange={setShowTransfer}>
         <DialogContent>
           <DialogHeader>
@@ -198,7 +335,7 @@ export function MyCoupons() {
 
 export function MerchantScanner() {
   const { publicKey } = useWallet()
-  const { redeemCoupon } = useCouponsProgram()
+  const { redeemCoupon, redeemApproved, redeemAmount } = useCouponsProgram()
   const [couponData, setCouponData] = useState('')
   const [isProcessing, setIsProcessing] = useState(false)
 
@@ -208,6 +345,16 @@ export function MerchantScanner() {
     setIsProcessing(true)
     try {
       const data = JSON.parse(couponData)
+      if (data.approvedRedemption) {
+        await redeemApproved.mutateAsync({ approval: data.approvedRedemption })
+        setCouponData('')
+        return
+      }
+      if (data.giftCardCharge) {
+        await redeemAmount.mutateAsync({ charge: data.giftCardCharge })
+        setCouponData('')
+        return
+      }
       const couponAddress = new PublicKey(data.coupon)
       const dealAddress = new PublicKey(data.deal)
 
@@ -1,8 +1,18 @@
 'use client'
 
-import { getBasicProgram, getBasicProgramId } from '@project/anchor'
+import { getBasicProgram, getBasicProgramId, getCouponHookAccounts, getCouponTokenProgram } from '@project/anchor'
 import { useConnection, useWallet } from '@solana/wallet-adapter-react'
-import { Cluster, Keypair, PublicKey, SystemProgram, SYSVAR_RENT_PUBKEY, Transaction } from '@solana/web3.js'
+import {
+  AccountInfo,
+  AccountMeta,
+  Cluster,
+  Connection,
+  Keypair,
+  PublicKey,
+  SystemProgram,
+  SYSVAR_RENT_PUBKEY,
+  Transaction,
+} from '@solana/web3.js'
 import { useMutation, useQuery } from '@tanstack/react-query'
 import { useMemo } from 'react'
 import { useCluster } from '../cluster/cluster-data-access'
@@ -14,26 +24,176 @@ import { toast } from 'sonner'
 import { BN } from '@coral-xyz/anchor'
 import {
   ASSOCIATED_TOKEN_PROGRAM_ID,
+  TOKEN_2022_PROGRAM_ID,
   TOKEN_PROGRAM_ID,
+  createAssociatedTokenAccountIdempotentInstruction,
   getAssociatedTokenAddressSync
 } from '@solana/spl-token'
 import { ExternalDealsResponse } from '@/types/external-deals'
-import { generateCouponMetadata, uploadMetadataToIPFS } from '@/lib/metadata-upload'
+import { generateCollectionMetadata, generateCouponMetadata, uploadMetadataToIPFS } from '@/lib/metadata-upload'
 
 export interface Deal {
   publicKey: PublicKey
   merchant: PublicKey
+  dealId: BN
   title: string
   description: string
   discountPercent: number
   maxSupply: BN
   currentSupply: BN
+  redeemedCount: BN
   expiryTimestamp: BN
   category: string
   priceLamports: BN
   isActive: boolean
   totalRatings: BN
   ratingSum: BN
+  // SPL token the price is denominated in; null means native SOL
+  paymentMint: PublicKey | null
+  // Mint payments wait in the deal vault until redemption (or refund after expiry)
+  escrowPayments: boolean
+  refundedCount: BN
+  // Cancelled deals refund every outstanding coupon holder the price they paid
+  isCancelled: boolean
+  // Most coupons one wallet may mint; 0 means unlimited
+  maxPerWallet: BN
+  // Merkle root of the wallets allowed to mint; null means anyone can
+  allowlistRoot: number[] | null
+  // What a wallet must hold to mint; null means no holding is required
+  tokenGate: TokenGate | null
+  // Coupons can't be minted before this unix timestamp
+  startTimestamp: BN
+  // Recurring UTC slots when coupons can be redeemed; empty means any time
+  redemptionWindows: RedemptionWindow[]
+  // How many times each coupon can be redeemed, e.g. 5 for a punch card
+  usesPerCoupon: BN
+  kind: CouponKind
+  // Collection NFT every coupon is verified into; null for migrated legacy deals
+  collectionMint: PublicKey | null
+  // Merchant's cut of every marketplace resale, in basis points
+  resaleRoyaltyBps: number
+  redemptionAction: RedemptionAction
+  // False for soulbound deals, whose Token-2022 coupons can't be moved or resold
+  transferable: boolean
+  // What holders paid for coupons not yet redeemed or refunded, owed back on cancellation
+  outstandingPayments: BN
+}
+
+// What happens to a coupon NFT once it is fully redeemed; burning needs the holder's signature
+export type RedemptionAction = { freeze: Record<string, never> } | { burn: Record<string, never> }
+
+// Discount coupons are redeemed whole; stored-value coupons are gift cards
+// spent down from faceValue
+export type CouponKind = { discount: Record<string, never> } | { storedValue: { faceValue: BN } }
+
+// Mirrors RedemptionWindow in the program: `days` has bit 0 = Monday through
+// bit 6 = Sunday, and minutes count from midnight UTC with an exclusive end
+export interface RedemptionWindow {
+  days: number
+  startMinute: number
+  endMinute: number
+}
+
+export type TokenGate =
+  | { collection: { collectionMint: PublicKey } }
+  | { tokenBalance: { mint: PublicKey; minAmount: BN } }
+
+// A wallet's entry from the allowlist builder's output: its allowance and the
+// hex-encoded sibling hashes up to the root
+export interface AllowlistClaim {
+  allowance: number
+  proof: string[]
+}
+
+const TOKEN_METADATA_PROGRAM_ID = new PublicKey('metaqbxxUerdq28cj1RbAWkYQm3ybzjb6a8bt518x1s')
+
+const hexToBytes = (hex: string) => Array.from(Buffer.from(hex.replace(/^0x/, ''), 'hex'))
+
+// Reads the verified collection key out of a raw Metaplex metadata account, or
+// null if the NFT isn't a verified member of any collection
+function verifiedCollectionOf(data: Buffer): PublicKey | null {
+  let offset = 1 + 32 + 32 // key, update authority, mint
+  for (let i = 0; i < 3; i++) offset += 4 + data.readUInt32LE(offset) // name, symbol, uri
+  offset += 2 // seller fee bps
+  if (data[offset++] === 1) offset += 4 + data.readUInt32LE(offset) * 34 // creators
+  offset += 2 // primary sale happened, is mutable
+  if (data[offset++] === 1) offset += 1 // edition nonce
+  if (data[offset++] === 1) offset += 1 // token standard
+  if (data[offset++] !== 1 || data[offset] !== 1) return null
+  return new PublicKey(data.subarray(offset + 1, offset + 33))
+}
+
+// Whether `creator` has verified a raw Metaplex metadata account, or null if
+// they aren't one of its creators
+function creatorVerifiedOf(data: Buffer, creator: PublicKey): boolean | null {
+  let offset = 1 + 32 + 32 // key, update authority, mint
+  for (let i = 0; i < 3; i++) offset += 4 + data.readUInt32LE(offset) // name, symbol, uri
+  offset += 2 // seller fee bps
+  if (data[offset++] !== 1) return null
+  const count = data.readUInt32LE(offset)
+  offset += 4
+  for (let i = 0; i < count; i++, offset += 34) {
+    if (creator.equals(new PublicKey(data.subarray(offset, offset + 32)))) return data[offset + 32] === 1
+  }
+  return null
+}
+
+// verify_coupon_creator only touches the coupon and its metadata, so a
+// transaction fits this many of them
+const VERIFY_BATCH_SIZE = 10
+
+// Finds the accounts mint_coupon checks for a token-gated deal: the wallet's
+// gating token account, plus the NFT's metadata for collection gates
+async function resolveTokenGateAccounts(
+  connection: Connection,
+  gate: TokenGate,
+  owner: PublicKey
+): Promise<AccountMeta[]> {
+  if ('tokenBalance' in gate) {
+    const tokenAccount = getAssociatedTokenAddressSync(gate.tokenBalance.mint, owner)
+    return [{ pubkey: tokenAccount, isSigner: false, isWritable: false }]
+  }
+
+  // Coupon NFTs live under Token-2022, older and third-party NFTs under SPL Token
+  const value = (
+    await Promise.all(
+      [TOKEN_PROGRAM_ID, TOKEN_2022_PROGRAM_ID].map((programId) =>
+        connection.getParsedTokenAccountsByOwner(owner, { programId })
+      )
+    )
+  ).flatMap(({ value }) => value)
+  const nfts = value.filter(({ account }) => {
+    const { tokenAmount } = account.data.parsed.info
+    return tokenAmount.decimals === 0 && tokenAmount.amount === '1'
+  })
+  const metadataPdas = nfts.map(
+    ({ account }) =>
+      PublicKey.findProgramAddressSync(
+        [
+          Buffer.from('metadata'),
+          TOKEN_METADATA_PROGRAM_ID.toBuffer(),
+          new PublicKey(account.data.parsed.info.mint).toBuffer(),
+        ],
+        TOKEN_METADATA_PROGRAM_ID
+      )[0]
+  )
+  const metadataAccounts = await connection.getMultipleAccountsInfo(metadataPdas)
+  const index = metadataAccounts.findIndex(
+    (account) => account && verifiedCollectionOf(account.data)?.equals(gate.collection.collectionMint)
+  )
+  if (index < 0) throw new Error('You need an NFT from this collection to claim this deal')
+
+  return [
+    { pubkey: nfts[index].pubkey, isSigner: false, isWritable: false },
+    { pubkey: metadataPdas[index], isSigner: false, isWritable: false },
+  ]
+}
+
+// Mirrors Deal::is_closable in the program: the deal must have stopped selling
+// (expired or deactivated) and every coupon it sold must have been redeemed or refunded
+export function isDealClosable(deal: Deal, now: number = Math.floor(Date.now() / 1000)): boolean {
+  const stopped = !deal.isActive || now >= deal.expiryTimestamp.toNumber()
+  return stopped && deal.redeemedCount.add(deal.refundedCount).gte(deal.currentSupply)
 }
 
 export interface DealRating {
@@ -207,6 +367,18 @@ export function useDealsProgram() {
       expiryTimestamp,
       category,
       priceLamports,
+      paymentMint,
+      escrowPayments,
+      maxPerWallet,
+      allowlistRoot,
+      tokenGate,
+      startTimestamp,
+      redemptionWindows,
+      usesPerCoupon,
+      giftCardValue,
+      resaleRoyaltyBps,
+      burnOnRedeem,
+      soulbound,
     }: {
       title: string
       description: string
@@ -215,9 +387,48 @@ export function useDealsProgram() {
       expiryTimestamp: number
       category: string
       priceLamports: number
+      paymentMint?: PublicKey
+      escrowPayments?: boolean
+      // 0 or omitted means no per-wallet limit
+      maxPerWallet?: number
+      // Hex root printed by the allowlist builder; omitted means an open deal
+      allowlistRoot?: string
+      tokenGate?: TokenGate
+      // Omitted means the deal goes live immediately
+      startTimestamp?: number
+      redemptionWindows?: RedemptionWindow[]
+      // Omitted means single-use coupons
+      usesPerCoupon?: number
+      // Sells a gift card with this balance instead of a discount
+      giftCardValue?: number
+      // Omitted means no royalty on resales
+      resaleRoyaltyBps?: number
+      // Burn instead of freezing fully redeemed coupons; gift cards always burn
+      burnOnRedeem?: boolean
+      // Mint non-transferable coupons, e.g. for employee perks
+      soulbound?: boolean
     }) => {
       if (!publicKey) throw new Error('Wallet not connected')
 
+      // Deals are addressed by the merchant's next sequential id
+      const [merchantStatePda] = PublicKey.findProgramAddressSync(
+        [Buffer.from('merchant'), publicKey.toBuffer()],
+        program.programId
+      )
+      const merchantState = await program.account.merchantState.fetchNullable(merchantStatePda)
+      const nextDealId = merchantState ? merchantState.dealCount : new BN(0)
+      const [dealPda] = PublicKey.findProgramAddressSync(
+        [Buffer.from('deal'), publicKey.toBuffer(), nextDealId.toArrayLike(Buffer, 'le', 8)],
+        program.programId
+      )
+
+      // Metadata for the deal's collection NFT
+      const collectionUri = await uploadMetadataToIPFS(
+        generateCollectionMetadata({ title, description, merchant: publicKey.toString(), category })
+      )
+
+      const redemptionAction = burnOnRedeem || giftCardValue ? { burn: {} } : { freeze: {} }
+
       let signature: string
 
       // Check if Gateway is enabled and configured
@@ -243,10 +454,25 @@ export function useDealsProgram() {
               new BN(maxSupply),
               new BN(expiryTimestamp),
               category,
-              new BN(priceLamports)
+              new BN(priceLamports),
+              escrowPayments ?? false,
+              new BN(maxPerWallet ?? 0),
+              allowlistRoot ? hexToBytes(allowlistRoot) : null,
+              tokenGate ?? null,
+              new BN(startTimestamp ?? 0),
+              redemptionWindows ?? [],
+              new BN(usesPerCoupon ?? 1),
+              giftCardValue ? { storedValue: { faceValue: new BN(giftCardValue) } } : { discount: {} },
+              collectionUri,
+              resaleRoyaltyBps ?? 0,
+              redemptionAction,
+              !soulbound
             )
-            .accounts({
+            .accountsPartial({
+              merchantState: merchantStatePda,
+              deal: dealPda,
               merchant: publicKey,
+              paymentMint: paymentMint ?? null,
             })
             .transaction()
 
@@ -324,19 +550,29 @@ export function useDealsProgram() {
             new BN(maxSupply),
             new BN(expiryTimestamp),
             category,
-            new BN(priceLamports)
+            new BN(priceLamports),
+            escrowPayments ?? false,
+            new BN(maxPerWallet ?? 0),
+            allowlistRoot ? hexToBytes(allowlistRoot) : null,
+            tokenGate ?? null,
+            new BN(startTimestamp ?? 0),
+            redemptionWindows ?? [],
+            new BN(usesPerCoupon ?? 1),
+            giftCardValue ? { storedValue: { faceValue: new BN(giftCardValue) } } : { discount: {} },
+            collectionUri,
+            resaleRoyaltyBps ?? 0,
+            redemptionAction,
+            !soulbound
           )
-          .accounts({
+          .accountsPartial({
+            merchantState: merchantStatePda,
+            deal: dealPda,
             merchant: publicKey,
+            paymentMint: paymentMint ?? null,
           })
           .rpc()
       }
 
-      const [dealPda] = PublicKey.findProgramAddressSync(
-        [Buffer.from('deal'), publicKey.toBuffer(), Buffer.from(title)],
-        program.programId
-      )
-
       return { signature, dealPda }
     },
     onSuccess: ({ signature }) => {
@@ -356,10 +592,24 @@ export function useDealsProgram() {
       dealAddress,
       isActive,
       priceLamports,
+      title,
+      description,
+      category,
+      discountPercent,
+      maxSupply,
+      expiryTimestamp,
+      maxPerWallet,
     }: {
       dealAddress: PublicKey
       isActive?: boolean
       priceLamports?: number
+      title?: string
+      description?: string
+      category?: string
+      discountPercent?: number
+      maxSupply?: number
+      expiryTimestamp?: number
+      maxPerWallet?: number
     }) => {
       if (!publicKey) throw new Error('Wallet not connected')
 
@@ -370,7 +620,14 @@ export function useDealsProgram() {
         const tx = await program.methods
           .updateDeal(
             isActive !== undefined ? isActive : null,
-            priceLamports !== undefined ? new BN(priceLamports) : null
+            priceLamports !== undefined ? new BN(priceLamports) : null,
+            title !== undefined ? title : null,
+            description !== undefined ? description : null,
+            category !== undefined ? category : null,
+            discountPercent !== undefined ? discountPercent : null,
+            maxSupply !== undefined ? new BN(maxSupply) : null,
+            expiryTimestamp !== undefined ? new BN(expiryTimestamp) : null,
+            maxPerWallet !== undefined ? new BN(maxPerWallet) : null
           )
           .accountsPartial({
             deal: dealAddress,
@@ -392,7 +649,14 @@ export function useDealsProgram() {
         signature = await program.methods
           .updateDeal(
             isActive !== undefined ? isActive : null,
-            priceLamports !== undefined ? new BN(priceLamports) : null
+            priceLamports !== undefined ? new BN(priceLamports) : null,
+            title !== undefined ? title : null,
+            description !== undefined ? description : null,
+            category !== undefined ? category : null,
+            discountPercent !== undefined ? discountPercent : null,
+            maxSupply !== undefined ? new BN(maxSupply) : null,
+            expiryTimestamp !== undefined ? new BN(expiryTimestamp) : null,
+            maxPerWallet !== undefined ? new BN(maxPerWallet) : null
           )
           .accountsPartial({
             deal: dealAddress,
@@ -412,14 +676,186 @@ export function useDealsProgram() {
     },
   })
 
+  // Cancel a deal, funding the deal vault so every outstanding coupon can be refunded
+  const cancelDeal = useMutation({
+    mutationKey: ['deals', 'cancel', { cluster }],
+    mutationFn: async ({ dealAddress }: { dealAddress: PublicKey }) => {
+      if (!publicKey) throw new Error('Wallet not connected')
+
+      // Token-priced deals top up the vault's ATA from the merchant's
+      const { paymentMint } = await program.account.deal.fetch(dealAddress)
+      const [dealVaultPda] = PublicKey.findProgramAddressSync(
+        [Buffer.from('deal_vault'), dealAddress.toBuffer()],
+        program.programId
+      )
+      const vaultPaymentAccount = paymentMint ? getAssociatedTokenAddressSync(paymentMint, dealVaultPda, true) : null
+      const preInstructions = paymentMint
+        ? [createAssociatedTokenAccountIdempotentInstruction(publicKey, vaultPaymentAccount!, dealVaultPda, paymentMint)]
+        : []
+
+      const signature = await program.methods
+        .cancelDeal()
+        .accountsPartial({
+          deal: dealAddress,
+          merchant: publicKey,
+          paymentMint,
+          merchantPaymentAccount: paymentMint ? getAssociatedTokenAddressSync(paymentMint, publicKey) : null,
+          vaultPaymentAccount,
+        })
+        .preInstructions(preInstructions)
+        .rpc()
+
+      return signature
+    },
+    onSuccess: (signature) => {
+      transactionToast(signature)
+      deals.refetch()
+    },
+    onError: (error) => {
+      toast.error(`Failed to cancel deal: ${error}`)
+    },
+  })
+
+  // Merchant signs every coupon of a deal it hasn't verified yet as their creator,
+  // batching the instructions so the wallet only prompts once
+  const verifyCouponCreators = useMutation({
+    mutationKey: ['deals', 'verify-creators', { cluster }],
+    mutationFn: async ({ dealAddress }: { dealAddress: PublicKey }) => {
+      if (!publicKey) throw new Error('Wallet not connected')
+
+      const coupons = await program.account.coupon.all([{ memcmp: { offset: 8, bytes: dealAddress.toBase58() } }])
+      const metadataPdas = coupons.map(
+        ({ account }) =>
+          PublicKey.findProgramAddressSync(
+            [Buffer.from('metadata'), TOKEN_METADATA_PROGRAM_ID.toBuffer(), account.mint.toBuffer()],
+            TOKEN_METADATA_PROGRAM_ID
+          )[0]
+      )
+      const metadataAccounts: (AccountInfo<Buffer> | null)[] = []
+      for (let i = 0; i < metadataPdas.length; i += 100) {
+        metadataAccounts.push(...(await connection.getMultipleAccountsInfo(metadataPdas.slice(i, i + 100))))
+      }
+
+      // Soulbound coupons have no Metaplex metadata, and legacy ones may not list the merchant
+      const unverified = coupons
+        .map((coupon, i) => ({ coupon: coupon.publicKey, metadata: metadataPdas[i], account: metadataAccounts[i] }))
+        .filter(({ account }) => account && creatorVerifiedOf(account.data, publicKey) === false)
+      if (unverified.length === 0) throw new Error('Every coupon is already verified')
+
+      const transactions: { tx: Transaction }[] = []
+      for (let i = 0; i < unverified.length; i += VERIFY_BATCH_SIZE) {
+        const instructions = await Promise.all(
+          unverified.slice(i, i + VERIFY_BATCH_SIZE).map(({ coupon, metadata }) =>
+            program.methods
+              .verifyCouponCreator()
+              .accountsPartial({
+                coupon,
+                deal: dealAddress,
+                merchant: publicKey,
+                metadata,
+              })
+              .instruction()
+          )
+        )
+        transactions.push({ tx: new Transaction().add(...instructions) })
+      }
+
+      return provider.sendAll(transactions)
+    },
+    onSuccess: (signatures) => {
+      signatures.forEach((signature) => transactionToast(signature))
+    },
+    onError: (error) => {
+      toast.error(`Failed to verify coupons: ${error}`)
+    },
+  })
+
+  // Close deal and reclaim its rent
+  const closeDeal = useMutation({
+    mutationKey: ['deals', 'close', { cluster }],
+    mutationFn: async ({ dealAddress }: { dealAddress: PublicKey }) => {
+      if (!publicKey) throw new Error('Wallet not connected')
+
+      // Token-priced deals sweep whatever the vault's ATA still holds back to the merchant
+      const { paymentMint } = await program.account.deal.fetch(dealAddress)
+      const [dealVaultPda] = PublicKey.findProgramAddressSync(
+        [Buffer.from('deal_vault'), dealAddress.toBuffer()],
+        program.programId
+      )
+      const vaultPaymentAccount = paymentMint ? getAssociatedTokenAddressSync(paymentMint, dealVaultPda, true) : null
+      const hasVaultPaymentAccount = !!vaultPaymentAccount && !!(await connection.getAccountInfo(vaultPaymentAccount))
+      const merchantPaymentAccount = paymentMint ? getAssociatedTokenAddressSync(paymentMint, publicKey) : null
+      const closeAccounts = {
+        deal: dealAddress,
+        merchant: publicKey,
+        paymentMint: hasVaultPaymentAccount ? paymentMint : null,
+        merchantPaymentAccount: hasVaultPaymentAccount ? merchantPaymentAccount : null,
+        vaultPaymentAccount: hasVaultPaymentAccount ? vaultPaymentAccount : null,
+      }
+      const preInstructions = hasVaultPaymentAccount
+        ? [
+            createAssociatedTokenAccountIdempotentInstruction(
+              publicKey,
+              merchantPaymentAccount!,
+              publicKey,
+              paymentMint!
+            ),
+          ]
+        : []
+
+      let signature: string
+
+      // Check if Gateway is enabled
+      if (gateway.isEnabled && gateway.apiKey) {
+        const tx = await program.methods
+          .closeDeal()
+          .accountsPartial(closeAccounts)
+          .preInstructions(preInstructions)
+          .transaction()
+
+        signature = await sendTransactionViaGateway({
+          transaction: tx,
+          connection,
+          publicKey,
+          signTransaction,
+          gateway,
+          txLabel: 'close-deal',
+        })
+      } else {
+        // Fallback to standard RPC
+        toast.info('Sending transaction via standard RPC...')
+        signature = await program.methods
+          .closeDeal()
+          .accountsPartial(closeAccounts)
+          .preInstructions(preInstructions)
+          .rpc()
+      }
+
+      return signature
+    },
+    onSuccess: (signature) => {
+      transactionToast(signature)
+      deals.refetch()
+    },
+    onError: (error) => {
+      toast.error(`Failed to close deal: ${error}`)
+    },
+  })
+
   // Mint coupon
   const mintCoupon = useMutation({
     mutationKey: ['deals', 'mintCoupon', { cluster }],
-    mutationFn: async ({ dealAddress }: { dealAddress: PublicKey }) => {
+    mutationFn: async ({ dealAddress, allowlist }: { dealAddress: PublicKey; allowlist?: AllowlistClaim }) => {
       if (!publicKey) throw new Error('Wallet not connected')
+      const allowlistProof = allowlist
+        ? { allowance: new BN(allowlist.allowance), proof: allowlist.proof.map(hexToBytes) }
+        : null
 
       // Get the deal account to read current_supply
       const dealAccount = await program.account.deal.fetch(dealAddress)
+      const gateAccounts = dealAccount.tokenGate
+        ? await resolveTokenGateAccounts(connection, dealAccount.tokenGate as TokenGate, publicKey)
+        : []
 
       // Generate metadata and upload to IPFS
       const metadata = generateCouponMetadata(
@@ -449,16 +885,16 @@ export function useDealsProgram() {
         program.programId
       )
 
-      // Derive the associated token account for the user
+      // Derive the associated token account for the user; coupons are
+      // minted under Token-2022
       const userTokenAccount = getAssociatedTokenAddressSync(
         mintKeypair.publicKey,
-        publicKey
+        publicKey,
+        false,
+        TOKEN_2022_PROGRAM_ID
       )
 
       // Derive the metadata PDA (Metaplex standard)
-      const TOKEN_METADATA_PROGRAM_ID = new PublicKey(
-        'metaqbxxUerdq28cj1RbAWkYQm3ybzjb6a8bt518x1s'
-      )
       const [metadataPda] = PublicKey.findProgramAddressSync(
         [
           Buffer.from('metadata'),
@@ -468,12 +904,42 @@ export function useDealsProgram() {
         TOKEN_METADATA_PROGRAM_ID
       )
 
+      // Token-priced deals pay the merchant's ATA, or the deal vault's ATA when
+      // payments are escrowed; the recipient ATA is created on the fly if needed
+      const paymentMint = dealAccount.paymentMint
+      const [dealVaultPda] = PublicKey.findProgramAddressSync(
+        [Buffer.from('deal_vault'), dealAddress.toBuffer()],
+        program.programId
+      )
+      const paymentAccounts = paymentMint
+        ? {
+            paymentMint,
+            userPaymentAccount: getAssociatedTokenAddressSync(paymentMint, publicKey),
+            merchantPaymentAccount: dealAccount.escrowPayments
+              ? null
+              : getAssociatedTokenAddressSync(paymentMint, dealAccount.merchant),
+            vaultPaymentAccount: dealAccount.escrowPayments
+              ? getAssociatedTokenAddressSync(paymentMint, dealVaultPda, true)
+              : null,
+          }
+        : { paymentMint: null, userPaymentAccount: null, merchantPaymentAccount: null, vaultPaymentAccount: null }
+      const paymentPreInstructions = paymentMint
+        ? [
+            createAssociatedTokenAccountIdempotentInstruction(
+              publicKey,
+              (paymentAccounts.vaultPaymentAccount ?? paymentAccounts.merchantPaymentAccount)!,
+              dealAccount.escrowPayments ? dealVaultPda : dealAccount.merchant,
+              paymentMint
+            ),
+          ]
+        : []
+
       let signature: string
 
       // Check if Gateway is enabled
       if (gateway.isEnabled && gateway.apiKey) {
         const tx = await program.methods
-          .mintCoupon(dealAddress, metadataUri)
+          .mintCoupon(dealAddress, metadataUri, allowlistProof)
           .accountsPartial({
             deal: dealAddress,
             coupon: couponPda,
@@ -487,7 +953,10 @@ export function useDealsProgram() {
             tokenProgram: TOKEN_PROGRAM_ID,
             associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
             tokenMetadataProgram: TOKEN_METADATA_PROGRAM_ID,
+            ...paymentAccounts,
           })
+          .preInstructions(paymentPreInstructions)
+          .remainingAccounts(gateAccounts)
           .transaction()
 
         // Add the mint keypair as a signer
@@ -505,7 +974,7 @@ export function useDealsProgram() {
         // Fallback to standard RPC
         toast.info('Sending transaction via standard RPC...')
         signature = await program.methods
-          .mintCoupon(dealAddress, metadataUri)
+          .mintCoupon(dealAddress, metadataUri, allowlistProof)
           .accountsPartial({
             deal: dealAddress,
             coupon: couponPda,
@@ -519,7 +988,10 @@ export function useDealsProgram() {
             tokenProgram: TOKEN_PROGRAM_ID,
             associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
             tokenMetadataProgram: TOKEN_METADATA_PROGRAM_ID,
+            ...paymentAccounts,
           })
+          .preInstructions(paymentPreInstructions)
+          .remainingAccounts(gateAccounts)
           .signers([mintKeypair])
           .rpc()
       }
@@ -684,7 +1156,15 @@ export function useDealsProgram() {
   // List coupon for sale
   const listCoupon = useMutation({
     mutationKey: ['coupons', 'list', { cluster }],
-    mutationFn: async ({ couponAddress, priceLamports }: { couponAddress: PublicKey; priceLamports: number }) => {
+    mutationFn: async ({
+      couponAddress,
+      priceLamports,
+      paymentMint,
+    }: {
+      couponAddress: PublicKey
+      priceLamports: number
+      paymentMint?: PublicKey
+    }) => {
       if (!publicKey) throw new Error('Wallet not connected')
 
       // Derive the listing PDA
@@ -693,6 +1173,29 @@ export function useDealsProgram() {
         program.programId
       )
 
+      // The NFT moves into an escrow ATA owned by the listing
+      const couponAccount = await program.account.coupon.fetch(couponAddress)
+      const tokenProgram = await getCouponTokenProgram(connection, couponAccount.mint)
+      const sellerTokenAccount = getAssociatedTokenAddressSync(couponAccount.mint, publicKey, false, tokenProgram)
+      const escrowTokenAccount = getAssociatedTokenAddressSync(couponAccount.mint, listingPda, true, tokenProgram)
+      const hookAccounts = getCouponHookAccounts(couponAccount.mint, couponAddress)
+
+      // Older versions of the program left listings open after a sale or delisting,
+      // so close any that's still there first, refunding its seller
+      const staleListing = await connection.getAccountInfo(listingPda)
+      const preInstructions = staleListing
+        ? [
+            await program.methods
+              .closeStaleListing()
+              .accountsPartial({
+                coupon: couponAddress,
+                seller: new PublicKey(staleListing.data.subarray(8 + 32, 8 + 64)),
+                authority: publicKey,
+              })
+              .instruction(),
+          ]
+        : []
+
       let signature: string
 
       // Check if Gateway is enabled
@@ -701,10 +1204,17 @@ export function useDealsProgram() {
           .listCoupon(new BN(priceLamports))
           .accountsPartial({
             coupon: couponAddress,
+            mint: couponAccount.mint,
+            sellerTokenAccount,
             listing: listingPda,
+            escrowTokenAccount,
             seller: publicKey,
+            paymentMint: paymentMint ?? null,
             systemProgram: SystemProgram.programId,
+            tokenProgram,
           })
+          .remainingAccounts(hookAccounts)
+          .preInstructions(preInstructions)
           .transaction()
 
         signature = await sendTransactionViaGateway({
@@ -722,10 +1232,17 @@ export function useDealsProgram() {
           .listCoupon(new BN(priceLamports))
           .accountsPartial({
             coupon: couponAddress,
+            mint: couponAccount.mint,
+            sellerTokenAccount,
             listing: listingPda,
+            escrowTokenAccount,
             seller: publicKey,
+            paymentMint: paymentMint ?? null,
             systemProgram: SystemProgram.programId,
+            tokenProgram,
           })
+          .remainingAccounts(hookAccounts)
+          .preInstructions(preInstructions)
           .rpc()
       }
 
@@ -743,12 +1260,73 @@ export function useDealsProgram() {
   // Buy a listed coupon
   const buyCoupon = useMutation({
     mutationKey: ['coupons', 'buy', { cluster }],
-    mutationFn: async ({ listingAddress, platformWallet }: { listingAddress: PublicKey; platformWallet: PublicKey }) => {
+    mutationFn: async ({ listingAddress }: { listingAddress: PublicKey }) => {
       if (!publicKey) throw new Error('Wallet not connected')
 
       // Fetch listing to get coupon and seller
       const listingAccount = await program.account.listing.fetch(listingAddress)
 
+      // Fees go to the treasury recorded in the platform config
+      const [platformConfigPda] = PublicKey.findProgramAddressSync(
+        [Buffer.from('platform_config')],
+        program.programId
+      )
+      const platformConfig = await program.account.platformConfig.fetch(platformConfigPda)
+
+      // The NFT is released from the listing's escrow to the buyer
+      const couponAccount = await program.account.coupon.fetch(listingAccount.coupon)
+      // The deal's merchant is paid a royalty on every resale
+      const dealAccount = await program.account.deal.fetch(couponAccount.deal)
+      const couponTokenProgram = await getCouponTokenProgram(connection, couponAccount.mint)
+      const escrowTokenAccount = getAssociatedTokenAddressSync(
+        couponAccount.mint,
+        listingAddress,
+        true,
+        couponTokenProgram,
+      )
+      const buyerTokenAccount = getAssociatedTokenAddressSync(couponAccount.mint, publicKey, false, couponTokenProgram)
+      const hookAccounts = getCouponHookAccounts(couponAccount.mint, listingAccount.coupon)
+
+      // Token-priced listings settle between ATAs; seller, treasury and merchant ATAs are created if missing
+      const paymentMint = listingAccount.paymentMint
+      const paymentAccounts = paymentMint
+        ? {
+            paymentMint,
+            buyerPaymentAccount: getAssociatedTokenAddressSync(paymentMint, publicKey),
+            sellerPaymentAccount: getAssociatedTokenAddressSync(paymentMint, listingAccount.seller),
+            treasuryPaymentAccount: getAssociatedTokenAddressSync(paymentMint, platformConfig.treasury),
+            merchantPaymentAccount: getAssociatedTokenAddressSync(paymentMint, dealAccount.merchant),
+          }
+        : {
+            paymentMint: null,
+            buyerPaymentAccount: null,
+            sellerPaymentAccount: null,
+            treasuryPaymentAccount: null,
+            merchantPaymentAccount: null,
+          }
+      const paymentPreInstructions = paymentMint
+        ? [
+            createAssociatedTokenAccountIdempotentInstruction(
+              publicKey,
+              paymentAccounts.sellerPaymentAccount!,
+              listingAccount.seller,
+              paymentMint
+            ),
+            createAssociatedTokenAccountIdempotentInstruction(
+              publicKey,
+              paymentAccounts.treasuryPaymentAccount!,
+              platformConfig.treasury,
+              paymentMint
+            ),
+            createAssociatedTokenAccountIdempotentInstruction(
+              publicKey,
+              paymentAccounts.merchantPaymentAccount!,
+              dealAccount.merchant,
+              paymentMint
+            ),
+          ]
+        : []
+
       let signature: string
 
       // Check if Gateway is enabled
@@ -758,11 +1336,21 @@ export function useDealsProgram() {
           .accountsPartial({
             listing: listingAddress,
             coupon: listingAccount.coupon,
+            deal: couponAccount.deal,
+            merchant: dealAccount.merchant,
+            mint: couponAccount.mint,
+            couponTokenProgram,
+            escrowTokenAccount,
+            buyerTokenAccount,
             seller: listingAccount.seller,
             buyer: publicKey,
-            platformWallet: platformWallet,
+            platformConfig: platformConfigPda,
+            platformWallet: platformConfig.treasury,
+            ...paymentAccounts,
             systemProgram: SystemProgram.programId,
           })
+          .remainingAccounts(hookAccounts)
+          .preInstructions(paymentPreInstructions)
           .transaction()
 
         signature = await sendTransactionViaGateway({
@@ -781,11 +1369,21 @@ export function useDealsProgram() {
           .accountsPartial({
             listing: listingAddress,
             coupon: listingAccount.coupon,
+            deal: couponAccount.deal,
+            merchant: dealAccount.merchant,
+            mint: couponAccount.mint,
+            couponTokenProgram,
+            escrowTokenAccount,
+            buyerTokenAccount,
             seller: listingAccount.seller,
             buyer: publicKey,
-            platformWallet: platformWallet,
+            platformConfig: platformConfigPda,
+            platformWallet: platformConfig.treasury,
+            ...paymentAccounts,
             systemProgram: SystemProgram.programId,
           })
+          .remainingAccounts(hookAccounts)
+          .preInstructions(paymentPreInstructions)
           .rpc()
       }
 
@@ -806,6 +1404,13 @@ export function useDealsProgram() {
     mutationFn: async ({ listingAddress, couponAddress }: { listingAddress: PublicKey; couponAddress: PublicKey }) => {
       if (!publicKey) throw new Error('Wallet not connected')
 
+      // The NFT returns from the listing's escrow to the seller
+      const couponAccount = await program.account.coupon.fetch(couponAddress)
+      const tokenProgram = await getCouponTokenProgram(connection, couponAccount.mint)
+      const escrowTokenAccount = getAssociatedTokenAddressSync(couponAccount.mint, listingAddress, true, tokenProgram)
+      const sellerTokenAccount = getAssociatedTokenAddressSync(couponAccount.mint, publicKey, false, tokenProgram)
+      const hookAccounts = getCouponHookAccounts(couponAccount.mint, couponAddress)
+
       let signature: string
 
       // Check if Gateway is enabled
@@ -815,8 +1420,13 @@ export function useDealsProgram() {
           .accountsPartial({
             listing: listingAddress,
             coupon: couponAddress,
+            mint: couponAccount.mint,
+            escrowTokenAccount,
+            sellerTokenAccount,
             seller: publicKey,
+            tokenProgram,
           })
+          .remainingAccounts(hookAccounts)
           .transaction()
 
         signature = await sendTransactionViaGateway({
@@ -835,8 +1445,13 @@ export function useDealsProgram() {
           .accountsPartial({
             listing: listingAddress,
             coupon: couponAddress,
+            mint: couponAccount.mint,
+            escrowTokenAccount,
+            sellerTokenAccount,
             seller: publicKey,
+            tokenProgram,
           })
+          .remainingAccounts(hookAccounts)
           .rpc()
       }
 
@@ -880,6 +1495,9 @@ export function useDealsProgram() {
     useDealsByMerchant,
     createDeal,
     updateDeal,
+    cancelDeal,
+    verifyCouponCreators,
+    closeDeal,
     mintCoupon,
     rateDeal,
     addComment,
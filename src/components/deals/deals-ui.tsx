@@ -2,7 +2,15 @@
 
 import { PublicKey } from '@solana/web3.js'
 import { useState } from 'react'
-import { useDealsProgram, Deal, useExternalDeals } from './deals-data-access'
+import { BN } from '@coral-xyz/anchor'
+import {
+  useDealsProgram,
+  Deal,
+  RedemptionWindow,
+  TokenGate,
+  useExternalDeals,
+  isDealClosable,
+} from './deals-data-access'
 import { Button } from '../ui/button'
 import { Card, CardContent, CardDescription, CardFooter, CardHeader, CardTitle } from '../ui/card'
 import { Input } from '../ui/input'
@@ -15,6 +23,28 @@ import { ExternalDeal } from '@/types/external-deals'
 import { Textarea } from '../ui/textarea'
 import { toast } from 'sonner'
 
+const WINDOW_DAY_PRESETS = {
+  any: { label: 'Any time', days: 0 },
+  everyday: { label: 'Every day', days: 0b1111111 },
+  weekdays: { label: 'Weekdays', days: 0b0011111 },
+  weekends: { label: 'Weekends', days: 0b1100000 },
+}
+const WEEKDAY_NAMES = ['Mon', 'Tue', 'Wed', 'Thu', 'Fri', 'Sat', 'Sun']
+
+const timeToMinutes = (time: string) => {
+  const [hours, minutes] = time.split(':').map(Number)
+  return hours * 60 + minutes
+}
+
+const minutesToTime = (minutes: number) =>
+  `${String(Math.floor(minutes / 60)).padStart(2, '0')}:${String(minutes % 60).padStart(2, '0')}`
+
+function formatRedemptionWindow(window: RedemptionWindow) {
+  const preset = Object.values(WINDOW_DAY_PRESETS).find((p) => p.days === window.days)
+  const days = preset ? preset.label : WEEKDAY_NAMES.filter((_, i) => window.days & (1 << i)).join('/')
+  return `${days} ${minutesToTime(window.startMinute)}–${minutesToTime(window.endMinute)} UTC`
+}
+
 export function DealsCreate() {
   const { createDeal } = useDealsProgram()
   const [isOpen, setIsOpen] = useState(false)
@@ -25,9 +55,48 @@ export function DealsCreate() {
   const [category, setCategory] = useState('')
   const [priceLamports, setPriceLamports] = useState(1000000)
   const [expiryDays, setExpiryDays] = useState(30)
+  const [escrowPayments, setEscrowPayments] = useState(false)
+  const [maxPerWallet, setMaxPerWallet] = useState(0)
+  const [allowlistRoot, setAllowlistRoot] = useState('')
+  const [gateKind, setGateKind] = useState<'none' | 'collection' | 'tokenBalance'>('none')
+  const [gateMint, setGateMint] = useState('')
+  const [gateMinAmount, setGateMinAmount] = useState(1)
+  const [startsAt, setStartsAt] = useState('')
+  const [windowDays, setWindowDays] = useState<keyof typeof WINDOW_DAY_PRESETS>('any')
+  const [windowStart, setWindowStart] = useState('15:00')
+  const [windowEnd, setWindowEnd] = useState('18:00')
+  const [usesPerCoupon, setUsesPerCoupon] = useState(1)
+  const [giftCardValue, setGiftCardValue] = useState(0)
+  const [resaleRoyaltyPercent, setResaleRoyaltyPercent] = useState(0)
+  const [burnOnRedeem, setBurnOnRedeem] = useState(false)
+  const [soulbound, setSoulbound] = useState(false)
 
   const handleSubmit = () => {
     const expiryTimestamp = Math.floor(Date.now() / 1000) + expiryDays * 24 * 60 * 60
+    let tokenGate: TokenGate | undefined
+    if (gateKind !== 'none') {
+      let mint: PublicKey
+      try {
+        mint = new PublicKey(gateMint.trim())
+      } catch {
+        toast.error('Enter a valid gating mint address')
+        return
+      }
+      tokenGate =
+        gateKind === 'collection'
+          ? { collection: { collectionMint: mint } }
+          : { tokenBalance: { mint, minAmount: new BN(gateMinAmount) } }
+    }
+    const redemptionWindows: RedemptionWindow[] =
+      windowDays === 'any'
+        ? []
+        : [
+            {
+              days: WINDOW_DAY_PRESETS[windowDays].days,
+              startMinute: timeToMinutes(windowStart),
+              endMinute: timeToMinutes(windowEnd),
+            },
+          ]
     createDeal.mutateAsync({
       title,
       description,
@@ -36,6 +105,17 @@ export function DealsCreate() {
       expiryTimestamp,
       category,
       priceLamports,
+      escrowPayments,
+      maxPerWallet,
+      allowlistRoot: allowlistRoot.trim() || undefined,
+      tokenGate,
+      startTimestamp: startsAt ? Math.floor(Date.parse(`${startsAt}Z`) / 1000) : undefined,
+      redemptionWindows,
+      usesPerCoupon,
+      giftCardValue: giftCardValue > 0 ? giftCardValue : undefined,
+      resaleRoyaltyBps: Math.round(resaleRoyaltyPercent * 100),
+      burnOnRedeem,
+      soulbound,
     })
     setIsOpen(false)
   }
@@ -121,6 +201,171 @@ export function DealsCreate() {
               />
             </div>
           </div>
+          <div className="grid grid-cols-2 gap-4">
+            <div>
+              <Label htmlFor="starts-at">Starts at (UTC, optional)</Label>
+              <Input
+                id="starts-at"
+                type="datetime-local"
+                value={startsAt}
+                onChange={(e) => setStartsAt(e.target.value)}
+              />
+            </div>
+            <div>
+              <Label htmlFor="window-days">Redeemable</Label>
+              <select
+                id="window-days"
+                value={windowDays}
+                onChange={(e) => setWindowDays(e.target.value as keyof typeof WINDOW_DAY_PRESETS)}
+                className="w-full h-9 rounded-md border bg-background px-3 text-sm"
+              >
+                {Object.entries(WINDOW_DAY_PRESETS).map(([value, preset]) => (
+                  <option key={value} value={value}>
+                    {preset.label}
+                  </option>
+                ))}
+              </select>
+            </div>
+          </div>
+          {windowDays !== 'any' && (
+            <div className="grid grid-cols-2 gap-4">
+              <div>
+                <Label htmlFor="window-start">From (UTC)</Label>
+                <Input id="window-start" type="time" value={windowStart} onChange={(e) => setWindowStart(e.target.value)} />
+              </div>
+              <div>
+                <Label htmlFor="window-end">Until (UTC)</Label>
+                <Input id="window-end" type="time" value={windowEnd} onChange={(e) => setWindowEnd(e.target.value)} />
+              </div>
+            </div>
+          )}
+          <div className="grid grid-cols-2 gap-4">
+            <div>
+              <Label htmlFor="max-per-wallet">Max per wallet (0 = unlimited)</Label>
+              <Input
+                id="max-per-wallet"
+                type="number"
+                value={maxPerWallet}
+                onChange={(e) => setMaxPerWallet(Number(e.target.value))}
+                min="0"
+              />
+            </div>
+            <div>
+              <Label htmlFor="uses-per-coupon">Uses per coupon</Label>
+              <Input
+                id="uses-per-coupon"
+                type="number"
+                value={usesPerCoupon}
+                onChange={(e) => setUsesPerCoupon(Number(e.target.value))}
+                min="1"
+                disabled={giftCardValue > 0}
+              />
+            </div>
+          </div>
+          <div>
+            <Label htmlFor="gift-card-value">Gift card value (0 = discount coupon)</Label>
+            <Input
+              id="gift-card-value"
+              type="number"
+              value={giftCardValue}
+              onChange={(e) => {
+                setGiftCardValue(Number(e.target.value))
+                setUsesPerCoupon(1)
+              }}
+              min="0"
+            />
+          </div>
+          <div>
+            <Label htmlFor="resale-royalty">Resale royalty % (paid to you on marketplace sales, max 25)</Label>
+            <Input
+              id="resale-royalty"
+              type="number"
+              value={resaleRoyaltyPercent}
+              onChange={(e) => setResaleRoyaltyPercent(Number(e.target.value))}
+              min="0"
+              max="25"
+              step="0.5"
+            />
+          </div>
+          <div>
+            <Label htmlFor="allowlist-root">Allowlist root (optional)</Label>
+            <Input
+              id="allowlist-root"
+              value={allowlistRoot}
+              onChange={(e) => setAllowlistRoot(e.target.value)}
+              placeholder="Hex root from the allowlist builder; leave empty for an open deal"
+            />
+          </div>
+          <div className="grid grid-cols-3 gap-4">
+            <div>
+              <Label htmlFor="gate-kind">Holder gate</Label>
+              <select
+                id="gate-kind"
+                value={gateKind}
+                onChange={(e) => setGateKind(e.target.value as typeof gateKind)}
+                className="w-full h-9 rounded-md border bg-background px-3 text-sm"
+              >
+                <option value="none">None</option>
+                <option value="collection">NFT collection</option>
+                <option value="tokenBalance">Token balance</option>
+              </select>
+            </div>
+            {gateKind !== 'none' && (
+              <div className={gateKind === 'collection' ? 'col-span-2' : undefined}>
+                <Label htmlFor="gate-mint">{gateKind === 'collection' ? 'Collection mint' : 'Token mint'}</Label>
+                <Input id="gate-mint" value={gateMint} onChange={(e) => setGateMint(e.target.value)} />
+              </div>
+            )}
+            {gateKind === 'tokenBalance' && (
+              <div>
+                <Label htmlFor="gate-min-amount">Minimum (base units)</Label>
+                <Input
+                  id="gate-min-amount"
+                  type="number"
+                  value={gateMinAmount}
+                  onChange={(e) => setGateMinAmount(Number(e.target.value))}
+                  min="1"
+                />
+              </div>
+            )}
+          </div>
+          <div className="flex items-center space-x-2">
+            <input
+              id="escrow-payments"
+              type="checkbox"
+              checked={escrowPayments}
+              onChange={(e) => setEscrowPayments(e.target.checked)}
+              className="w-4 h-4 text-primary bg-background border-gray-300 rounded focus:ring-primary"
+            />
+            <Label htmlFor="escrow-payments" className="text-sm font-normal">
+              Buyer protection: hold payments in escrow until each coupon is redeemed
+            </Label>
+          </div>
+          <div className="flex items-center space-x-2">
+            <input
+              id="burn-on-redeem"
+              type="checkbox"
+              checked={burnOnRedeem || giftCardValue > 0}
+              onChange={(e) => setBurnOnRedeem(e.target.checked)}
+              disabled={giftCardValue > 0}
+              className="w-4 h-4 text-primary bg-background border-gray-300 rounded focus:ring-primary"
+            />
+            <Label htmlFor="burn-on-redeem" className="text-sm font-normal">
+              Burn coupons when redeemed (the customer co-signs); otherwise they are frozen in the wallet
+            </Label>
+          </div>
+          <div className="flex items-center space-x-2">
+            <input
+              id="soulbound"
+              type="checkbox"
+              checked={soulbound}
+              onChange={(e) => setSoulbound(e.target.checked)}
+              className="w-4 h-4 text-primary bg-background border-gray-300 rounded focus:ring-primary"
+            />
+            <Label htmlFor="soulbound" className="text-sm font-normal">
+              Non-transferable: coupons can&apos;t be moved or resold, e.g. employee perks
+            </Label>
+          </div>
           <Button
             onClick={handleSubmit}
             disabled={createDeal.isPending || !title || !description}
@@ -157,17 +402,29 @@ function StarRating({ value, onChange, readonly = false }: { value: number; onCh
 }
 
 export function DealCard({ deal }: { deal: Deal }) {
-  const { mintCoupon, updateDeal, rateDeal, addComment, useCommentsByDeal } = useDealsProgram()
+  const {
+    mintCoupon,
+    updateDeal,
+    cancelDeal,
+    verifyCouponCreators,
+    closeDeal,
+    rateDeal,
+    addComment,
+    useCommentsByDeal,
+  } = useDealsProgram()
   const { publicKey } = useWallet()
   const isMerchant = publicKey && deal.merchant.equals(publicKey)
   const [showComments, setShowComments] = useState(false)
   const [newComment, setNewComment] = useState('')
   const [userRating, setUserRating] = useState(0)
+  const [allowlistClaim, setAllowlistClaim] = useState('')
 
   const comments = useCommentsByDeal(deal.publicKey)
 
   const expiryDate = new Date(deal.expiryTimestamp.toNumber() * 1000)
   const isExpired = expiryDate < new Date()
+  const startDate = new Date(deal.startTimestamp.toNumber() * 1000)
+  const notStarted = startDate > new Date()
   const supplyPercent = (deal.currentSupply.toNumber() / deal.maxSupply.toNumber()) * 100
 
   // Calculate average rating
@@ -255,6 +512,10 @@ export function DealCard({ deal }: { deal: Deal }) {
               {isExpired ? 'Expired' : `Expires ${expiryDate.toLocaleDateString()}`}
             </span>
           </div>
+          {notStarted && <span>Starts {startDate.toLocaleString()}</span>}
+          {deal.redemptionWindows.length > 0 && (
+            <span>Redeem {deal.redemptionWindows.map(formatRedemptionWindow).join(', ')}</span>
+          )}
         </div>
 
         <div className="h-2 w-full overflow-hidden rounded-full bg-secondary">
@@ -266,7 +527,39 @@ export function DealCard({ deal }: { deal: Deal }) {
 
         <div className="flex items-center justify-between">
           <div className="text-sm font-medium">
-            Price: {(deal.priceLamports.toNumber() / 1e9).toFixed(4)} SOL
+            Price:{' '}
+            {deal.paymentMint
+              ? `${deal.priceLamports.toString()} units of ${deal.paymentMint.toString().slice(0, 4)}...`
+              : `${(deal.priceLamports.toNumber() / 1e9).toFixed(4)} SOL`}
+            {!deal.maxPerWallet.isZero() && (
+              <span className="ml-2 text-xs text-muted-foreground">
+                Limit {deal.maxPerWallet.toString()} per wallet
+              </span>
+            )}
+            {deal.allowlistRoot && (
+              <span className="ml-2 text-xs text-muted-foreground">Allowlist only</span>
+            )}
+            {'storedValue' in deal.kind && (
+              <span className="ml-2 text-xs text-muted-foreground">
+                Gift card worth {deal.kind.storedValue.faceValue.toString()}
+              </span>
+            )}
+            {!deal.transferable && (
+              <span className="ml-2 text-xs text-muted-foreground">Non-transferable</span>
+            )}
+            {deal.resaleRoyaltyBps > 0 && (
+              <span className="ml-2 text-xs text-muted-foreground">
+                {deal.resaleRoyaltyBps / 100}% resale royalty
+              </span>
+            )}
+            {deal.usesPerCoupon.gtn(1) && (
+              <span className="ml-2 text-xs text-muted-foreground">{deal.usesPerCoupon.toString()} uses</span>
+            )}
+            {deal.tokenGate && (
+              <span className="ml-2 text-xs text-muted-foreground">
+                {'collection' in deal.tokenGate ? 'Collection holders only' : 'Token holders only'}
+              </span>
+            )}
           </div>
           <div className="flex gap-2">
             <Button size="sm" variant="ghost" onClick={() => setShowComments(!showComments)}>
@@ -315,33 +608,88 @@ export function DealCard({ deal }: { deal: Deal }) {
       <CardFooter className="gap-2">
         {isMerchant ? (
           <>
+            {deal.isCancelled ? (
+              <span className="text-sm text-muted-foreground">Cancelled</span>
+            ) : (
+              <>
+                <Button
+                  size="sm"
+                  variant="outline"
+                  onClick={() =>
+                    updateDeal.mutateAsync({
+                      dealAddress: deal.publicKey,
+                      isActive: !deal.isActive,
+                    })
+                  }
+                  disabled={updateDeal.isPending}
+                >
+                  {deal.isActive ? 'Deactivate' : 'Activate'}
+                </Button>
+                <Button
+                  size="sm"
+                  variant="destructive"
+                  onClick={() => cancelDeal.mutateAsync({ dealAddress: deal.publicKey })}
+                  disabled={cancelDeal.isPending}
+                >
+                  {cancelDeal.isPending ? 'Cancelling...' : 'Cancel & Refund'}
+                </Button>
+              </>
+            )}
+            {deal.transferable && !deal.currentSupply.isZero() && (
+              <Button
+                size="sm"
+                variant="outline"
+                onClick={() => verifyCouponCreators.mutateAsync({ dealAddress: deal.publicKey })}
+                disabled={verifyCouponCreators.isPending}
+              >
+                {verifyCouponCreators.isPending ? 'Verifying...' : 'Verify Coupons'}
+              </Button>
+            )}
+            {isDealClosable(deal) && (
+              <Button
+                size="sm"
+                variant="destructive"
+                onClick={() => closeDeal.mutateAsync({ dealAddress: deal.publicKey })}
+                disabled={closeDeal.isPending}
+              >
+                {closeDeal.isPending ? 'Closing...' : 'Close Deal'}
+              </Button>
+            )}
+          </>
+        ) : (
+          <div className="w-full space-y-2">
+            {deal.allowlistRoot && (
+              <Input
+                value={allowlistClaim}
+                onChange={(e) => setAllowlistClaim(e.target.value)}
+                placeholder='Your allowlist entry, e.g. {"allowance": 1, "proof": ["..."]}'
+              />
+            )}
             <Button
-              size="sm"
-              variant="outline"
-              onClick={() =>
-                updateDeal.mutateAsync({
-                  dealAddress: deal.publicKey,
-                  isActive: !deal.isActive,
-                })
+              onClick={() => {
+                let allowlist
+                if (deal.allowlistRoot) {
+                  try {
+                    allowlist = JSON.parse(allowlistClaim)
+                  } catch {
+                    toast.error('Paste your allowlist entry to claim this deal')
+                    return
+                  }
+                }
+                mintCoupon.mutateAsync({ dealAddress: deal.publicKey, allowlist })
+              }}
+              disabled={
+                mintCoupon.isPending ||
+                !deal.isActive ||
+                isExpired ||
+                notStarted ||
+                deal.currentSupply.gte(deal.maxSupply)
               }
-              disabled={updateDeal.isPending}
+              className="w-full"
             >
-              {deal.isActive ? 'Deactivate' : 'Activate'}
+              {mintCoupon.isPending ? 'Minting...' : 'Claim Coupon'}
             </Button>
-          </>
-        ) : (
-          <Button
-            onClick={() => mintCoupon.mutateAsync({ dealAddress: deal.publicKey })}
-            disabled={
-              mintCoupon.isPending ||
-              !deal.isActive ||
-              isExpired ||
-              deal.currentSupply.gte(deal.maxSupply)
-            }
-            className="w-full"
-          >
-            {mintCoupon.isPending ? 'Minting...' : 'Claim Coupon'}
-          </Button>
+          </div>
         )}
       </CardFooter>
     </Card>
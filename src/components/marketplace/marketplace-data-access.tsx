@@ -1,6 +1,6 @@
 'use client'
 
-import { getBasicProgram, getBasicProgramId } from '@project/anchor'
+import { getBasicProgram, getBasicProgramId, getCouponHookAccounts, getCouponTokenProgram } from '@project/anchor'
 import { useWallet } from '@solana/wallet-adapter-react'
 import { Cluster, PublicKey, SystemProgram } from '@solana/web3.js'
 import { useMutation, useQuery } from '@tanstack/react-query'
@@ -10,6 +10,7 @@ import { useAnchorProvider } from '../solana/solana-provider'
 import { useTransactionToast } from '../use-transaction-toast'
 import { toast } from 'sonner'
 import { BN } from '@coral-xyz/anchor'
+import { createAssociatedTokenAccountIdempotentInstruction, getAssociatedTokenAddressSync } from '@solana/spl-token'
 
 export interface Listing {
   publicKey: PublicKey
@@ -18,6 +19,8 @@ export interface Listing {
   priceLamports: BN
   isActive: boolean
   createdAt: BN
+  // SPL token the price is denominated in; null means native SOL
+  paymentMint: PublicKey | null
 }
 
 export interface CouponWithListing {
@@ -27,6 +30,8 @@ export interface CouponWithListing {
   mint: PublicKey
   isRedeemed: boolean
   mintedAt: BN
+  isListed: boolean
+  isStaked: boolean
   listing: Listing | null
 }
 
@@ -38,6 +43,18 @@ export function useMarketplaceProgram() {
   const programId = useMemo(() => getBasicProgramId(cluster.network as Cluster), [cluster])
   const program = useMemo(() => getBasicProgram(provider, programId), [provider, programId])
 
+  const [platformConfigPda] = useMemo(
+    () => PublicKey.findProgramAddressSync([Buffer.from('platform_config')], programId),
+    [programId]
+  )
+
+  // Fetch platform fee rate and treasury
+  const platformConfig = useQuery({
+    queryKey: ['platform-config', { cluster }],
+    queryFn: () => program.account.platformConfig.fetch(platformConfigPda),
+    enabled: !!program,
+  })
+
   // Fetch all active listings
   const listings = useQuery({
     queryKey: ['listings', 'all', { cluster }],
@@ -107,9 +124,11 @@ export function useMarketplaceProgram() {
     mutationFn: async ({
       couponPubkey,
       priceLamports,
+      paymentMint,
     }: {
       couponPubkey: PublicKey
       priceLamports: number
+      paymentMint?: PublicKey
     }) => {
       if (!publicKey) throw new Error('Wallet not connected')
 
@@ -118,14 +137,24 @@ export function useMarketplaceProgram() {
         program.programId
       )
 
+      // The NFT moves into an escrow ATA owned by the listing
+      const { mint } = await program.account.coupon.fetch(couponPubkey)
+      const tokenProgram = await getCouponTokenProgram(provider.connection, mint)
+
       const signature = await program.methods
         .listCoupon(new BN(priceLamports))
         .accounts({
           coupon: couponPubkey,
+          mint,
+          sellerTokenAccount: getAssociatedTokenAddressSync(mint, publicKey, false, tokenProgram),
+          escrowTokenAccount: getAssociatedTokenAddressSync(mint, listingPda, true, tokenProgram),
           listing: listingPda,
           seller: publicKey,
+          paymentMint: paymentMint ?? null,
           systemProgram: SystemProgram.programId,
+          tokenProgram,
         } as any)
+        .remainingAccounts(getCouponHookAccounts(mint, couponPubkey))
         .rpc()
 
       return signature
@@ -147,25 +176,63 @@ export function useMarketplaceProgram() {
       listingPubkey,
       couponPubkey,
       sellerPubkey,
-      platformWallet,
     }: {
       listingPubkey: PublicKey
       couponPubkey: PublicKey
       sellerPubkey: PublicKey
-      platformWallet: PublicKey
     }) => {
       if (!publicKey) throw new Error('Wallet not connected')
 
+      // Fees go to the treasury recorded in the platform config
+      const { treasury } = await program.account.platformConfig.fetch(platformConfigPda)
+      const { mint } = await program.account.coupon.fetch(couponPubkey)
+
+      // Token-priced listings settle between ATAs; seller and treasury ATAs are created if missing
+      const { paymentMint } = await program.account.listing.fetch(listingPubkey)
+      const paymentAccounts = paymentMint
+        ? {
+            paymentMint,
+            buyerPaymentAccount: getAssociatedTokenAddressSync(paymentMint, publicKey),
+            sellerPaymentAccount: getAssociatedTokenAddressSync(paymentMint, sellerPubkey),
+            treasuryPaymentAccount: getAssociatedTokenAddressSync(paymentMint, treasury),
+          }
+        : { paymentMint: null, buyerPaymentAccount: null, sellerPaymentAccount: null, treasuryPaymentAccount: null }
+      const paymentPreInstructions = paymentMint
+        ? [
+            createAssociatedTokenAccountIdempotentInstruction(
+              publicKey,
+              paymentAccounts.sellerPaymentAccount!,
+              sellerPubkey,
+              paymentMint
+            ),
+            createAssociatedTokenAccountIdempotentInstruction(
+              publicKey,
+              paymentAccounts.treasuryPaymentAccount!,
+              treasury,
+              paymentMint
+            ),
+          ]
+        : []
+
+      const couponTokenProgram = await getCouponTokenProgram(provider.connection, mint)
       const signature = await program.methods
         .buyCoupon()
         .accounts({
           listing: listingPubkey,
           coupon: couponPubkey,
+          mint,
+          couponTokenProgram,
+          escrowTokenAccount: getAssociatedTokenAddressSync(mint, listingPubkey, true, couponTokenProgram),
+          buyerTokenAccount: getAssociatedTokenAddressSync(mint, publicKey, false, couponTokenProgram),
           seller: sellerPubkey,
           buyer: publicKey,
-          platformWallet,
+          platformConfig: platformConfigPda,
+          platformWallet: treasury,
+          ...paymentAccounts,
           systemProgram: SystemProgram.programId,
         } as any)
+        .remainingAccounts(getCouponHookAccounts(mint, couponPubkey))
+        .preInstructions(paymentPreInstructions)
         .rpc()
 
       return signature
@@ -192,13 +259,21 @@ export function useMarketplaceProgram() {
     }) => {
       if (!publicKey) throw new Error('Wallet not connected')
 
+      const { mint } = await program.account.coupon.fetch(couponPubkey)
+      const tokenProgram = await getCouponTokenProgram(provider.connection, mint)
+
       const signature = await program.methods
         .delistCoupon()
         .accounts({
           listing: listingPubkey,
           coupon: couponPubkey,
+          mint,
+          escrowTokenAccount: getAssociatedTokenAddressSync(mint, listingPubkey, true, tokenProgram),
+          sellerTokenAccount: getAssociatedTokenAddressSync(mint, publicKey, false, tokenProgram),
           seller: publicKey,
+          tokenProgram,
         } as any)
+        .remainingAccounts(getCouponHookAccounts(mint, couponPubkey))
         .rpc()
 
       return signature
@@ -216,6 +291,7 @@ export function useMarketplaceProgram() {
   return {
     program,
     programId,
+    platformConfig,
     listings,
     userCoupons,
     listCoupon,
@@ -4,7 +4,7 @@ import { useWallet } from '@solana/wallet-adapter-react'
 import { PublicKey } from '@solana/web3.js'
 import { WalletButton } from '../solana/solana-provider'
 import { useMarketplaceProgram } from './marketplace-data-access'
-import { MarketplaceListings, UserCouponsManager, PLATFORM_WALLET } from './marketplace-ui'
+import { MarketplaceListings, UserCouponsManager } from './marketplace-ui'
 import { Card, CardContent } from '@/components/ui/card'
 import { Tabs, TabsContent, TabsList, TabsTrigger } from '@/components/ui/tabs'
 
@@ -19,7 +19,6 @@ export default function MarketplaceFeature() {
       listingPubkey: listing.publicKey,
       couponPubkey: listing.coupon,
       sellerPubkey: listing.seller,
-      platformWallet: PLATFORM_WALLET,
     })
   }
 
@@ -150,6 +149,7 @@ export default function MarketplaceFeature() {
                 onList={handleList}
                 onDelist={handleDelist}
                 isLoading={marketplace.listCoupon.isPending || marketplace.delistCoupon.isPending}
+                feeBps={marketplace.platformConfig.data?.feeBps ?? 0}
               />
             )}
           </div>
@@ -7,12 +7,9 @@ import { Input } from '@/components/ui/input'
 import { Label } from '@/components/ui/label'
 import { Badge } from '@/components/ui/badge'
 import { Listing, CouponWithListing } from './marketplace-data-access'
-import { LAMPORTS_PER_SOL, PublicKey, Keypair } from '@solana/web3.js'
+import { LAMPORTS_PER_SOL, PublicKey } from '@solana/web3.js'
 import { Dialog, DialogContent, DialogDescription, DialogHeader, DialogTitle, DialogTrigger } from '@/components/ui/dialog'
 
-// Platform wallet for receiving fees - replace with real wallet for production
-const PLATFORM_WALLET = Keypair.generate().publicKey
-
 export function MarketplaceListings({
   listings,
   onBuy,
@@ -48,7 +45,11 @@ export function MarketplaceListings({
           </CardHeader>
           <CardContent className="space-y-4">
             <div>
-              <p className="text-2xl font-bold">{formatSOL(listing.priceLamports.toNumber())} SOL</p>
+              <p className="text-2xl font-bold">
+                {listing.paymentMint
+                  ? `${listing.priceLamports.toString()} units of ${listing.paymentMint.toString().slice(0, 4)}...`
+                  : `${formatSOL(listing.priceLamports.toNumber())} SOL`}
+              </p>
               <p className="text-xs text-muted-foreground mt-1">
                 Seller: {listing.seller.toString().slice(0, 8)}...
               </p>
@@ -72,13 +73,17 @@ export function UserCouponsManager({
   onList,
   onDelist,
   isLoading,
+  feeBps,
 }: {
   coupons: CouponWithListing[]
   onList: (couponPubkey: PublicKey, price: number) => void
   onDelist: (listingPubkey: PublicKey, couponPubkey: PublicKey) => void
   isLoading: boolean
+  feeBps: number
 }) {
   const [listingPrices, setListingPrices] = useState<Record<string, string>>({})
+  const feeRate = feeBps / 10_000
+  const feePercent = feeBps / 100
 
   const handleList = (couponPubkey: PublicKey) => {
     const priceSOL = listingPrices[couponPubkey.toString()]
@@ -114,6 +119,9 @@ export function UserCouponsManager({
                   {coupon.listing && (
                     <Badge variant="secondary">Listed</Badge>
                   )}
+                  {coupon.isStaked && (
+                    <Badge variant="secondary">Staked</Badge>
+                  )}
                 </div>
                 <p className="text-xs text-muted-foreground mt-1">
                   Mint: {coupon.mint.toString().slice(0, 16)}...
@@ -137,6 +145,8 @@ export function UserCouponsManager({
                     Delist
                   </Button>
                 </div>
+              ) : coupon.isStaked ? (
+                <p className="text-xs text-muted-foreground">Unstake to list for sale</p>
               ) : (
                 <Dialog>
                   <DialogTrigger asChild>
@@ -146,7 +156,7 @@ export function UserCouponsManager({
                     <DialogHeader>
                       <DialogTitle>List Coupon for Sale</DialogTitle>
                       <DialogDescription>
-                        Set a price for your coupon. Buyers will pay 2.5% platform fee.
+                        Set a price for your coupon. A {feePercent}% platform fee is deducted from the sale.
                       </DialogDescription>
                     </DialogHeader>
                     <div className="space-y-4 py-4">
@@ -177,15 +187,15 @@ export function UserCouponsManager({
                             <span>{listingPrices[coupon.couponPublicKey.toString()] || '0'} SOL</span>
                           </div>
                           <div className="flex justify-between">
-                            <span>You receive (97.5%):</span>
+                            <span>You receive ({100 - feePercent}%):</span>
                             <span>
-                              {(parseFloat(listingPrices[coupon.couponPublicKey.toString()] || '0') * 0.975).toFixed(4)} SOL
+                              {(parseFloat(listingPrices[coupon.couponPublicKey.toString()] || '0') * (1 - feeRate)).toFixed(4)} SOL
                             </span>
                           </div>
                           <div className="flex justify-between">
-                            <span>Platform fee (2.5%):</span>
+                            <span>Platform fee ({feePercent}%):</span>
                             <span>
-                              {(parseFloat(listingPrices[coupon.couponPublicKey.toString()] || '0') * 0.025).toFixed(4)} SOL
+                              {(parseFloat(listingPrices[coupon.couponPublicKey.toString()] || '0') * feeRate).toFixed(4)} SOL
                             </span>
                           </div>
                         </div>
@@ -212,5 +222,3 @@ export function UserCouponsManager({
     </div>
   )
 }
-
-export { PLATFORM_WALLET }
@@ -1,6 +1,6 @@
 'use client'
 
-import { getBasicProgram, getBasicProgramId } from '@project/anchor'
+import { getBasicProgram, getBasicProgramId, getCouponTokenProgram } from '@project/anchor'
 import { useWallet } from '@solana/wallet-adapter-react'
 import { Cluster, PublicKey, SystemProgram } from '@solana/web3.js'
 import { useMutation, useQuery } from '@tanstack/react-query'
@@ -10,12 +10,15 @@ import { useAnchorProvider } from '../solana/solana-provider'
 import { useTransactionToast } from '../use-transaction-toast'
 import { toast } from 'sonner'
 import { BN } from '@coral-xyz/anchor'
+import { getAssociatedTokenAddressSync } from '@solana/spl-token'
 
 export interface RewardsPool {
   publicKey: PublicKey
   totalStaked: BN
   rewardRatePerDay: BN
   admin: PublicKey
+  accRewardPerShare: BN
+  lastUpdateTs: BN
 }
 
 export interface StakedCoupon {
@@ -24,6 +27,7 @@ export interface StakedCoupon {
   staker: PublicKey
   stakedAt: BN
   lastClaimAt: BN
+  rewardDebt: BN
 }
 
 export interface CouponWithStaking {
@@ -33,10 +37,30 @@ export interface CouponWithStaking {
   mint: PublicKey
   isRedeemed: boolean
   mintedAt: BN
+  isListed: boolean
+  isStaked: boolean
   staked: StakedCoupon | null
   pendingRewards: number
 }
 
+// Mirrors RewardsPool::accrue / pending_rewards in the program
+const REWARD_PRECISION = new BN('1000000000000')
+const SECONDS_PER_DAY = 86_400
+
+export function calculatePendingRewards(pool: RewardsPool, rewardDebt: BN, now: number): number {
+  let acc = pool.accRewardPerShare
+  const elapsed = now - pool.lastUpdateTs.toNumber()
+  if (elapsed > 0 && !pool.totalStaked.isZero()) {
+    acc = acc.add(
+      new BN(elapsed)
+        .mul(pool.rewardRatePerDay)
+        .mul(REWARD_PRECISION)
+        .div(new BN(SECONDS_PER_DAY).mul(pool.totalStaked))
+    )
+  }
+  return acc.sub(rewardDebt).div(REWARD_PRECISION).toNumber()
+}
+
 export function useStakingProgram() {
   const { publicKey } = useWallet()
   const { cluster } = useCluster()
@@ -103,9 +127,7 @@ export function useStakingProgram() {
 
             // Calculate pending rewards
             if (rewardsPool.data) {
-              const timeSinceClaim = currentTime - staked.lastClaimAt.toNumber()
-              const daysStaked = timeSinceClaim / 86400
-              pendingRewards = Math.floor(daysStaked * rewardsPool.data.rewardRatePerDay.toNumber())
+              pendingRewards = calculatePendingRewards(rewardsPool.data, staked.rewardDebt, currentTime)
             }
           } catch {
             // Not staked, that's okay
@@ -142,10 +164,14 @@ export function useStakingProgram() {
         program.programId
       )
 
+      const { mint } = await program.account.coupon.fetch(couponPubkey)
+      const tokenProgram = await getCouponTokenProgram(provider.connection, mint)
+
       const signature = await program.methods
         .stakeCoupon()
         .accounts({
           coupon: couponPubkey,
+          stakerTokenAccount: getAssociatedTokenAddressSync(mint, publicKey, false, tokenProgram),
           stakedCoupon: stakedCouponPda,
           rewardsPool: poolPda,
           staker: publicKey,
@@ -171,9 +197,12 @@ export function useStakingProgram() {
     mutationFn: async ({
       stakedCouponPubkey,
       couponPubkey,
+      forfeitRewards = false,
     }: {
       stakedCouponPubkey: PublicKey
       couponPubkey: PublicKey
+      // Unstake even if the vault cannot pay the rewards owed
+      forfeitRewards?: boolean
     }) => {
       if (!publicKey) throw new Error('Wallet not connected')
 
@@ -181,13 +210,18 @@ export function useStakingProgram() {
         [Buffer.from('rewards_pool')],
         program.programId
       )
+      const [vaultPda] = PublicKey.findProgramAddressSync(
+        [Buffer.from('rewards_vault')],
+        program.programId
+      )
 
       const signature = await program.methods
-        .unstakeCoupon()
+        .unstakeCoupon(forfeitRewards)
         .accounts({
           stakedCoupon: stakedCouponPubkey,
           coupon: couponPubkey,
           rewardsPool: poolPda,
+          rewardsVault: vaultPda,
           staker: publicKey,
           systemProgram: SystemProgram.programId,
         } as any)
@@ -215,12 +249,17 @@ export function useStakingProgram() {
         [Buffer.from('rewards_pool')],
         program.programId
       )
+      const [vaultPda] = PublicKey.findProgramAddressSync(
+        [Buffer.from('rewards_vault')],
+        program.programId
+      )
 
       const signature = await program.methods
         .claimRewards()
         .accounts({
           stakedCoupon: stakedCouponPubkey,
           rewardsPool: poolPda,
+          rewardsVault: vaultPda,
           staker: publicKey,
           systemProgram: SystemProgram.programId,
         } as any)
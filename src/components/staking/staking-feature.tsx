@@ -16,10 +16,21 @@ export default function StakingFeature() {
   }
 
   const handleUnstake = async (stakedCouponPubkey: PublicKey, couponPubkey: PublicKey) => {
-    await staking.unstakeCoupon.mutateAsync({
-      stakedCouponPubkey,
-      couponPubkey,
-    })
+    try {
+      await staking.unstakeCoupon.mutateAsync({
+        stakedCouponPubkey,
+        couponPubkey,
+      })
+    } catch (error) {
+      if (!String(error).includes('InsufficientRewardsFunds')) throw error
+      const forfeit = window.confirm('The rewards vault cannot pay what you have earned. Unstake and forfeit the rest?')
+      if (!forfeit) return
+      await staking.unstakeCoupon.mutateAsync({
+        stakedCouponPubkey,
+        couponPubkey,
+        forfeitRewards: true,
+      })
+    }
   }
 
   const handleClaim = async (stakedCouponPubkey: PublicKey) => {
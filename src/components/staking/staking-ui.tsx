@@ -26,16 +26,16 @@ export function RewardsPoolInfo({ pool }: { pool: RewardsPool }) {
             <p className="text-xs text-muted-foreground">coupons</p>
           </div>
           <div>
-            <p className="text-sm text-muted-foreground">Daily Reward Rate</p>
+            <p className="text-sm text-muted-foreground">Daily Reward Budget</p>
             <p className="text-2xl font-bold">{formatSOL(pool.rewardRatePerDay.toNumber())}</p>
-            <p className="text-xs text-muted-foreground">SOL per day</p>
+            <p className="text-xs text-muted-foreground">SOL per day, shared by all stakers</p>
           </div>
         </div>
 
         <Alert>
           <AlertDescription>
-            Stake your unredeemed coupons to earn {formatSOL(pool.rewardRatePerDay.toNumber())} SOL
-            per day. You can claim rewards anytime or unstake to get your coupon back.
+            Stake your unredeemed coupons to earn a share of {formatSOL(pool.rewardRatePerDay.toNumber())} SOL
+            per day, accrued every second. You can claim rewards anytime or unstake to get your coupon back.
           </AlertDescription>
         </Alert>
       </CardContent>
@@ -73,7 +73,8 @@ export function StakingCouponsManager({
   }
 
   const stakedCoupons = coupons.filter((c) => c.staked)
-  const unstakedCoupons = coupons.filter((c) => !c.staked)
+  // Listed coupons sit in marketplace escrow and cannot be staked
+  const unstakedCoupons = coupons.filter((c) => !c.staked && !c.isListed)
 
   if (coupons.length === 0) {
     return (
@@ -116,3 +116,32 @@ export function generateCouponMetadata(
     },
   }
 }
+
+/**
+ * Generate NFT metadata for a deal's collection, which every coupon joins
+ */
+export function generateCollectionMetadata(deal: {
+  title: string
+  description: string
+  merchant: string
+  category: string
+}): NFTMetadata {
+  return {
+    name: deal.title,
+    description: deal.description,
+    image: `https://ui-avatars.com/api/?name=${encodeURIComponent(deal.title)}&size=400&background=random`,
+    attributes: [
+      {
+        trait_type: 'Category',
+        value: deal.category,
+      },
+      {
+        trait_type: 'Merchant',
+        value: deal.merchant,
+      },
+    ],
+    properties: {
+      category: deal.category,
+    },
+  }
+}